@@ -0,0 +1,86 @@
+use chrono::{TimeZone, Utc};
+use data::{Archive, Scan, SnapshotFormat, Store};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// How long a store takes to come up from a snapshot in each format: until it
+// can answer its first read, and until every scan is in memory. JSON and
+// binary snapshots are decoded whole before either; an archive answers reads
+// as soon as it is mapped and checked.
+//
+//   cargo bench --bench startup [-- <scans>]
+const DEFAULT_SCANS: usize = 200_000;
+const RUNS: usize = 5;
+const TENANTS: [&str; 4] = ["default", "team-a", "team-b", "team-c"];
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Cargo passes `--bench` to benchmarks without a harness.
+    let scans = match std::env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(arg) => arg.parse()?,
+        None => DEFAULT_SCANS,
+    };
+
+    let dir = std::env::temp_dir().join(format!("startup-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let source = store_of(scans)?;
+
+    println!("{} scans, best of {} runs", scans, RUNS);
+    println!("{:<8} {:>12} {:>14} {:>14}", "format", "bytes", "first read", "fully loaded");
+    for format in [SnapshotFormat::Json, SnapshotFormat::Binary, SnapshotFormat::Archive] {
+        let path = dir.join(format!("scans.{}", format.extension()));
+        let snapshot = source.save_snapshot(&path, format)?;
+        let probe = &snapshot.tenants[TENANTS[0]][scans / TENANTS.len() / 2];
+
+        let mut first_read = Duration::MAX;
+        let mut loaded = Duration::MAX;
+        for _ in 0..RUNS {
+            let (to_first_read, to_loaded) = start(&path, format, &probe.ip, probe.port)?;
+            first_read = first_read.min(to_first_read);
+            loaded = loaded.min(to_loaded);
+        }
+
+        println!(
+            "{:<8} {:>12} {:>14} {:>14}",
+            format.name(), fs::metadata(&path)?.len(), format!("{:.1?}", first_read), format!("{:.1?}", loaded),
+        );
+    }
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn store_of(scans: usize) -> Result<Store, Box<dyn Error>> {
+    let mut store = Store::new();
+    for i in 0..scans {
+        let scan = Scan{
+            ip: format!("10.{}.{}.{}", i >> 16 & 0xff, i >> 8 & 0xff, i & 0xff),
+            port: [80, 443, 8080][i % 3],
+            load_time_nanosec: 1_000 + i as i64,
+            content_hash: format!("{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)),
+            timestamp: Utc.with_ymd_and_hms(2022, 7, 31, 14, 10, 0).unwrap() + chrono::Duration::seconds(i as i64),
+            http_status: Some(200),
+            body_size: Some(4_096),
+            ..Default::default()
+        };
+        store.insert_record_in(TENANTS[i % TENANTS.len()], scan)?;
+    }
+    Ok(store)
+}
+
+// Brings a store up from `path` and returns how long it took to read one scan
+// back and to hold every scan in memory.
+fn start(path: &Path, format: SnapshotFormat, ip: &str, port: i16) -> Result<(Duration, Duration), Box<dyn Error>> {
+    let started = Instant::now();
+    let mut store = Store::new();
+    match format {
+        SnapshotFormat::Archive => store.attach_archive(Archive::open(path)?)?,
+        _ => store.load_snapshot(path)?,
+    };
+    assert!(store.get_record_in(TENANTS[0], ip, port).is_some());
+    let first_read = started.elapsed();
+
+    store.warm(usize::MAX)?;
+    Ok((first_read, started.elapsed()))
+}
@@ -0,0 +1,43 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    grpc::generate();
+}
+
+// Generates the tonic server and client for `proto/scans.proto` around the
+// hand-written messages in `src/grpc/proto.rs`, so no protoc is needed.
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    const CODEC: &str = "tonic::codec::ProstCodec";
+
+    fn method(name: &str, route: &str, input: &str, output: &str) -> tonic_build::manual::MethodBuilder {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::proto::{}", input))
+            .output_type(output)
+            .codec_path(CODEC)
+    }
+
+    pub fn generate() {
+        let service = Service::builder()
+            .name("ScanService")
+            .package("scans.v1")
+            .method(method("get_scan", "GetScan", "GetScanRequest", "crate::grpc::proto::Scan").build())
+            .method(method(
+                "list_scans", "ListScans", "ListScansRequest", "crate::grpc::proto::ListScansResponse",
+            ).build())
+            .method(method("create_scan", "CreateScan", "CreateScanRequest", "crate::grpc::proto::Empty").build())
+            .method(method("update_scan", "UpdateScan", "UpdateScanRequest", "crate::grpc::proto::Empty").build())
+            .method(method("delete_scan", "DeleteScan", "DeleteScanRequest", "crate::grpc::proto::Empty").build())
+            .method(method(
+                "watch_scans", "WatchScans", "WatchScansRequest", "crate::grpc::proto::ScanEvent",
+            ).server_streaming().build())
+            .build();
+
+        Builder::new().compile(&[service]);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::config::Config;
+use crate::problem::Problem;
+use crate::store::db::Db;
+use crate::store::snapshot::{Snapshot, SnapshotFormat};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+use utoipa::ToSchema;
+
+pub const SNAPSHOTS_PATH: &str = "/v1/admin/snapshots";
+
+// Takes snapshots of the store on request, and every `SCANS_SNAPSHOT_INTERVAL`
+// if set, into `SCANS_SNAPSHOT_DIR`. Without a directory there is nowhere to
+// put them, so the endpoint reports 404. Clones share one writer, so build
+// it once and hand clones to the schedule and the endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshots {
+    dir: Option<PathBuf>,
+    interval: Option<Duration>,
+    format: SnapshotFormat,
+    writing: Arc<Mutex<()>>,
+}
+
+// What the caller is told about the snapshot they asked for.
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Taken {
+    pub path: String,
+    #[schema(value_type = String, example = "json")]
+    pub format: &'static str,
+    pub scans: usize,
+    pub seq: u64,
+    pub bytes: u64,
+    pub taken_at: DateTime<Utc>,
+}
+
+impl Snapshots {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Snapshots { dir, ..Default::default() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Snapshots {
+            dir: config.snapshot_dir.clone(),
+            interval: config.snapshot_interval.filter(|interval| !interval.is_zero()),
+            format: config.snapshot_format,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration, format: SnapshotFormat) -> Self {
+        self.interval = Some(interval).filter(|interval| !interval.is_zero());
+        self.format = format;
+        self
+    }
+
+    // Snapshots the store in the `format` the query names, or the configured
+    // one. The store is only locked while its scans are copied; encoding and
+    // writing the file don't hold up writers.
+    pub async fn take(&self, db: &Db, query: Option<&str>) -> Result<Taken, Problem> {
+        let format = format(query)?.unwrap_or(self.format);
+        self.take_as(db, format).await
+    }
+
+    // Takes a snapshot every interval, the first one interval after starting,
+    // so the mutation log never has to be replayed from further back than
+    // that. Must be called from within a tokio runtime.
+    pub fn schedule(&self, db: &Db) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if self.dir.is_none() {
+            tracing::warn!("SCANS_SNAPSHOT_INTERVAL is set without SCANS_SNAPSHOT_DIR, not taking snapshots");
+            return;
+        }
+
+        let (snapshots, db) = (self.clone(), db.clone());
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                // Failures are logged where they happen; the next tick tries
+                // again.
+                let _ = snapshots.take_as(&db, snapshots.format).await;
+            }
+        });
+    }
+
+    async fn take_as(&self, db: &Db, format: SnapshotFormat) -> Result<Taken, Problem> {
+        let dir = self.dir.clone().ok_or_else(|| {
+            Problem::new(404, "Not Found").with_detail("snapshots are disabled; set SCANS_SNAPSHOT_DIR to enable them")
+        })?;
+
+        // One snapshot is written at a time, so a scheduled one and one
+        // asked for don't race each other into the directory.
+        let _writing = self.writing.lock().await;
+        let snapshot = db.read().await.snapshot();
+        tokio::task::spawn_blocking(move || write(&snapshot, &dir, format))
+            .await
+            .expect("snapshot writer does not panic")
+            .map_err(|e| {
+                tracing::error!(error = %e, "failed to write snapshot");
+                Problem::new(500, "Internal Server Error").with_detail(format!("failed to write snapshot: {}", e))
+            })
+    }
+}
+
+fn format(query: Option<&str>) -> Result<Option<SnapshotFormat>, Problem> {
+    let bad_request = |detail: String| Problem::new(400, "Bad Request").with_detail(detail);
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query.unwrap_or_default())
+        .map_err(|e| bad_request(format!("invalid query: {}", e)))?;
+
+    match pairs.into_iter().find(|(name, _)| name == "format") {
+        None => Ok(None),
+        Some((_, value)) => value.parse().map(Some)
+            .map_err(|_| bad_request(format!("format must be json, binary or archive, not {:?}", value))),
+    }
+}
+
+fn write(snapshot: &Snapshot, dir: &Path, format: SnapshotFormat) -> std::io::Result<Taken> {
+    std::fs::create_dir_all(dir)?;
+    let path = snapshot.path_in(dir, format);
+    let bytes = snapshot.write_to(&path, format)?;
+    tracing::info!(path = %path.display(), scans = snapshot.len(), seq = snapshot.seq, "wrote snapshot");
+
+    Ok(Taken {
+        path: path.display().to_string(),
+        format: format.name(),
+        scans: snapshot.len(),
+        seq: snapshot.seq,
+        bytes,
+        taken_at: snapshot.taken_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Scan;
+    use crate::store::store::Store;
+
+    #[tokio::test]
+    async fn snapshots_are_written_into_the_directory() {
+        let mut store = Store::new();
+        store.insert_record(Scan{ ip: "1.2.3.4".to_owned(), port: 80, ..Default::default() }).unwrap();
+        let db = Db::new(store);
+
+        let disabled = Snapshots::default().take(&db, None).await.unwrap_err();
+        assert_eq!(disabled.status, 404);
+
+        let dir = std::env::temp_dir().join(format!("admin-snapshots-{}", std::process::id()));
+        let snapshots = Snapshots::new(Some(dir.clone()));
+        assert_eq!(snapshots.take(&db, Some("format=xml")).await.unwrap_err().status, 400);
+
+        let taken = snapshots.take(&db, Some("format=binary")).await.unwrap();
+        assert_eq!((taken.format, taken.scans), ("binary", 1));
+        assert!(taken.path.ends_with(".snap"));
+
+        let mut restored = Store::new();
+        restored.load_snapshot(Path::new(&taken.path)).unwrap();
+        assert!(restored.get_record("1.2.3.4", 80).is_some());
+
+        let taken = snapshots.take(&db, None).await.unwrap();
+        assert_eq!(std::fs::metadata(&taken.path).unwrap().len(), taken.bytes);
+
+        // A clone waits for a snapshot the original is writing.
+        let writing = snapshots.writing.clone().lock_owned().await;
+        let clone = snapshots.clone();
+        assert!(tokio::time::timeout(Duration::from_millis(20), clone.take(&db, None)).await.is_err());
+        drop(writing);
+        assert!(clone.take(&db, None).await.is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_taken_on_schedule() {
+        let db = Db::new(Store::new());
+        let dir = std::env::temp_dir().join(format!("admin-scheduled-{}", std::process::id()));
+        Snapshots::new(Some(dir.clone()))
+            .with_interval(Duration::from_millis(20), SnapshotFormat::Binary)
+            .schedule(&db);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let taken: Vec<_> = std::fs::read_dir(&dir).unwrap().collect::<Result<_, _>>().unwrap();
+        assert!(taken.iter().any(|file| file.path().extension().is_some_and(|ext| ext == "snap")));
+
+        // The next snapshot may be being written.
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
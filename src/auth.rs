@@ -0,0 +1,653 @@
+use crate::config::Config;
+use crate::problem::Problem;
+use crate::store::tenant::{self, DEFAULT_TENANT};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+pub const CHALLENGE: &str = "Bearer realm=\"scans\", ApiKey realm=\"scans\"";
+
+// What a caller may do. Each role includes the ones below it: writers can
+// read and admins can do anything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Role {
+    // The role a request needs, going by its method alone so every route is
+    // held to the same rules.
+    pub fn required_for(method: &str) -> Role {
+        match method {
+            "GET" | "HEAD" | "OPTIONS" => Role::Read,
+            "DELETE" => Role::Admin,
+            _ => Role::Write,
+        }
+    }
+
+    // Admin endpoints act on the whole service rather than on scans, so they
+    // need the admin role whatever their method.
+    pub fn required_for_path(method: &str, path: &str) -> Role {
+        match is_admin(path) {
+            true => Role::Admin,
+            false => Role::required_for(method),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// The tenants a caller may use: every one, written `*`, or those named, e.g.
+// `team-a,team-b`. Callers that aren't granted any only get the default
+// tenant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tenants {
+    All,
+    Only(Vec<String>),
+}
+
+impl Tenants {
+    pub fn allows(&self, tenant: &str) -> bool {
+        match self {
+            Tenants::All => true,
+            Tenants::Only(names) => names.iter().any(|name| name == tenant),
+        }
+    }
+}
+
+impl Default for Tenants {
+    fn default() -> Self {
+        Tenants::Only(vec![DEFAULT_TENANT.to_owned()])
+    }
+}
+
+impl FromStr for Tenants {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "*" => Ok(Tenants::All),
+            names => names.split(',')
+                .map(|name| match tenant::is_valid(name) {
+                    true => Ok(name.to_owned()),
+                    false => Err(()),
+                })
+                .collect::<Result<_, _>>()
+                .map(Tenants::Only),
+        }
+    }
+}
+
+pub const ANONYMOUS: &str = "anonymous";
+
+// Who made a request, as established by an API key or a bearer token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    pub subject: String,
+    pub role: Role,
+    pub tenants: Tenants,
+}
+
+impl Principal {
+    // Callers let through without credentials, and tokens that don't name
+    // their subject, can't be told apart from one another.
+    pub fn is_anonymous(&self) -> bool {
+        self.subject.is_empty() || self.subject == ANONYMOUS
+    }
+
+    // Checks the caller may read or change the scans of `tenant`.
+    pub fn check_tenant(&self, tenant: &str) -> Result<(), AuthError> {
+        match self.tenants.allows(tenant) {
+            true => Ok(()),
+            false => Err(AuthError::TenantForbidden(Some(tenant.to_owned()))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    InvalidApiKey,
+    InvalidToken(String),
+    Forbidden { required: Role, granted: Role },
+    // The caller may not use the tenant, or `None` when the request needs
+    // every tenant.
+    TenantForbidden(Option<String>),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "no API key or bearer token was supplied"),
+            AuthError::InvalidApiKey => write!(f, "the API key is not recognised"),
+            AuthError::InvalidToken(reason) => write!(f, "the bearer token is invalid: {}", reason),
+            AuthError::Forbidden { required, granted } => write!(
+                f, "this request needs the {} role but the caller only has {}",
+                required.as_str(), granted.as_str(),
+            ),
+            AuthError::TenantForbidden(Some(tenant)) => write!(f, "this caller may not use tenant {:?}", tenant),
+            AuthError::TenantForbidden(None) => write!(f, "this request needs access to every tenant"),
+        }
+    }
+}
+
+impl AuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::Forbidden { .. } | AuthError::TenantForbidden(_) => 403,
+            _ => 401,
+        }
+    }
+
+    // Only failed authentication asks the caller to try other credentials.
+    pub fn challenge(&self) -> Option<&'static str> {
+        match self.status() {
+            403 => None,
+            _ => Some(CHALLENGE),
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let title = match self.status() {
+            403 => "Forbidden",
+            _ => "Unauthorized",
+        };
+
+        Problem::new(self.status(), title).with_detail(self.to_string())
+    }
+}
+
+struct ApiKey {
+    name: String,
+    key: String,
+    role: Role,
+    tenants: Tenants,
+}
+
+// Tokens carry their role either as a `role` claim or as OAuth-style
+// space-separated `scope`s; the highest one named wins. Their tenants are
+// listed in a `tenants` claim.
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    role: Option<String>,
+    scope: Option<String>,
+    tenants: Option<Vec<String>>,
+}
+
+impl Claims {
+    fn role(&self) -> Role {
+        self.role.iter()
+            .chain(self.scope.iter())
+            .flat_map(|value| value.split_whitespace())
+            .filter_map(|name| name.parse().ok())
+            .max()
+            .unwrap_or(Role::Read)
+    }
+
+    // Names that aren't tenants are ignored rather than failing the token.
+    fn tenants(&self) -> Tenants {
+        match &self.tenants {
+            Some(names) if names.iter().any(|name| name == "*") => Tenants::All,
+            Some(names) => Tenants::Only(names.iter().filter(|name| tenant::is_valid(name)).cloned().collect()),
+            None => Tenants::default(),
+        }
+    }
+}
+
+struct Inner {
+    api_keys: Vec<ApiKey>,
+    hs256: Option<DecodingKey>,
+    rs256: Option<DecodingKey>,
+    validation: Validation,
+    // The role of callers when no credentials are configured.
+    anonymous: Role,
+}
+
+// Checks the credentials on requests to `/v1`. With nothing configured every
+// request is let through as an anonymous writer, which can't delete scans or
+// reach the admin endpoints unless authentication was explicitly disabled.
+#[derive(Clone)]
+pub struct Authenticator {
+    inner: Arc<Inner>,
+}
+
+impl Authenticator {
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let api_keys = match &config.api_keys_file {
+            Some(path) => parse_api_keys(&fs::read_to_string(path)?)
+                .map_err(|e| invalid(path, e))?,
+            None => Vec::new(),
+        };
+
+        let hs256 = match &config.jwt_secret_file {
+            Some(path) => {
+                let secret = fs::read(path)?;
+                Some(DecodingKey::from_secret(secret.trim_ascii_end()))
+            },
+            None => None,
+        };
+
+        let rs256 = match &config.jwt_public_key_file {
+            Some(path) => Some(
+                DecodingKey::from_rsa_pem(&fs::read(path)?).map_err(|e| invalid(path, e))?,
+            ),
+            None => None,
+        };
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.algorithms = vec![Algorithm::HS256, Algorithm::RS256];
+        validation.validate_aud = config.jwt_audience.is_some();
+        if let Some(audience) = &config.jwt_audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &config.jwt_issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let enabled = !api_keys.is_empty() || hs256.is_some() || rs256.is_some();
+        let anonymous = match (enabled, config.auth_disabled) {
+            (true, true) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SCANS_AUTH_DISABLED is set but API keys or JWT keys are configured",
+            )),
+            (false, true) => {
+                tracing::warn!("authentication is disabled; anyone can delete scans and use the admin endpoints");
+                Role::Admin
+            },
+            _ => Role::Write,
+        };
+
+        let inner = Inner { api_keys, hs256, rs256, validation, anonymous };
+        Ok(Authenticator { inner: Arc::new(inner) })
+    }
+
+    // Lets every caller do anything, as `SCANS_AUTH_DISABLED=true` does.
+    pub fn disabled() -> Self {
+        let config = Config { auth_disabled: true, ..Config::default() };
+        Authenticator::from_config(&config).expect("default config needs no files")
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.inner.api_keys.is_empty() || self.inner.hs256.is_some() || self.inner.rs256.is_some()
+    }
+
+    // Takes the raw `Authorization` and `X-Api-Key` header values.
+    pub fn authenticate(
+        &self, authorization: Option<&str>, api_key: Option<&str>,
+    ) -> Result<Principal, AuthError> {
+        if !self.is_enabled() {
+            let (subject, role) = (ANONYMOUS.to_owned(), self.inner.anonymous);
+            return Ok(Principal { subject, role, tenants: Tenants::All });
+        }
+
+        if let Some(key) = api_key {
+            return self.check_api_key(key);
+        }
+
+        match authorization.and_then(|value| value.split_once(' ')) {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                self.check_token(token.trim())
+            },
+            Some((scheme, key)) if scheme.eq_ignore_ascii_case("apikey") => {
+                self.check_api_key(key.trim())
+            },
+            _ => Err(AuthError::Missing),
+        }
+    }
+
+    // Authenticates the caller and checks their role covers `method` on
+    // `path`, and that they may use the tenant the path names. GraphQL names
+    // tenants in its queries, so it checks them as they are resolved.
+    pub fn authorize(
+        &self, method: &str, path: &str, authorization: Option<&str>, api_key: Option<&str>,
+    ) -> Result<Principal, AuthError> {
+        let principal = self.authenticate(authorization, api_key)?;
+        let required = Role::required_for_path(method, path);
+        if principal.role < required {
+            return Err(AuthError::Forbidden { required, granted: principal.role });
+        }
+
+        match scope_of(path) {
+            Scope::Tenant(tenant) => principal.check_tenant(tenant)?,
+            Scope::Every if principal.tenants != Tenants::All => return Err(AuthError::TenantForbidden(None)),
+            _ => {},
+        }
+        Ok(principal)
+    }
+
+    fn check_api_key(&self, key: &str) -> Result<Principal, AuthError> {
+        self.inner.api_keys.iter()
+            .find(|candidate| constant_time_eq(candidate.key.as_bytes(), key.as_bytes()))
+            .map(|candidate| Principal {
+                subject: candidate.name.clone(),
+                role: candidate.role,
+                tenants: candidate.tenants.clone(),
+            })
+            .ok_or(AuthError::InvalidApiKey)
+    }
+
+    fn check_token(&self, token: &str) -> Result<Principal, AuthError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+
+        let key = match header.alg {
+            Algorithm::HS256 => self.inner.hs256.as_ref(),
+            Algorithm::RS256 => self.inner.rs256.as_ref(),
+            _ => None,
+        };
+        let key = key.ok_or_else(|| {
+            AuthError::InvalidToken(format!("{:?} tokens are not accepted", header.alg))
+        })?;
+
+        let mut validation = self.inner.validation.clone();
+        validation.algorithms = vec![header.alg];
+
+        let data = jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+
+        let (role, tenants) = (data.claims.role(), data.claims.tenants());
+        Ok(Principal { subject: data.claims.sub.unwrap_or_default(), role, tenants })
+    }
+}
+
+// Probes, metrics and the API docs stay public; only the scan API is guarded.
+pub fn is_protected(path: &str) -> bool {
+    path.split('/').find(|s| !s.is_empty()) == Some("v1")
+}
+
+pub fn is_admin(path: &str) -> bool {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    segments.next() == Some("v1") && segments.next() == Some("admin")
+}
+
+// Which tenants a request to `path` acts on.
+enum Scope<'a> {
+    Tenant(&'a str),
+    Every,
+    Unscoped,
+}
+
+// Tenant names are compared as they appear in the path. One that was
+// percent-encoded doesn't match the name it decodes to, so it is refused
+// rather than let through.
+fn scope_of(path: &str) -> Scope<'_> {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    if segments.next() != Some("v1") {
+        return Scope::Unscoped;
+    }
+
+    match (segments.next(), segments.next()) {
+        (Some("scans"), _) => Scope::Tenant(DEFAULT_TENANT),
+        (Some("tenants"), Some(tenant)) => Scope::Tenant(tenant),
+        (Some("admin"), _) => Scope::Every,
+        _ => Scope::Unscoped,
+    }
+}
+
+// One key per line as `<name> <key> [role [tenants]]`, where the role
+// defaults to `read` and the tenants to the default one; blank lines and `#`
+// comments are skipped.
+fn parse_api_keys(contents: &str) -> Result<Vec<ApiKey>, String> {
+    contents.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, key, role, tenants) = match fields.as_slice() {
+                [name, key] => (name, key, None, None),
+                [name, key, role] => (name, key, Some(role), None),
+                [name, key, role, tenants] => (name, key, Some(role), Some(tenants)),
+                _ => return Err(format!("line {}: expected `<name> <key> [role [tenants]]`", n)),
+            };
+            let role = match role {
+                Some(role) => role.parse().map_err(|_| format!("line {}: unknown role {:?}", n, role))?,
+                None => Role::Read,
+            };
+            let tenants = match tenants {
+                Some(tenants) => tenants.parse().map_err(|_| format!("line {}: invalid tenants {:?}", n, tenants))?,
+                None => Tenants::default(),
+            };
+
+            Ok(ApiKey { name: (*name).to_owned(), key: (*key).to_owned(), role, tenants })
+        })
+        .collect()
+}
+
+fn invalid(path: &Path, e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("scans-auth-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn authenticator() -> Authenticator {
+        let config = Config {
+            api_keys_file: Some(write_temp(
+                "keys", "# comment\n\ndashboard s3cret\nscanner w-key write team-a,default\nops a-key admin *\n",
+            )),
+            jwt_secret_file: Some(write_temp("secret", "hmac-secret\n")),
+            jwt_issuer: Some("issuer".to_owned()),
+            ..Config::default()
+        };
+
+        Authenticator::from_config(&config).unwrap()
+    }
+
+    fn token(secret: &str, claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes()),
+        ).unwrap()
+    }
+
+    fn exp() -> u64 {
+        jsonwebtoken::get_current_timestamp() + 600
+    }
+
+    #[test]
+    fn disabled_lets_everything_through() {
+        let auth = Authenticator::disabled();
+
+        assert!(!auth.is_enabled());
+        assert!(auth.authenticate(None, None).is_ok());
+        assert!(auth.authorize("DELETE", "/v1/scans/1.2.3.4/80", None, None).is_ok());
+        assert!(auth.authorize("POST", "/v1/admin/snapshots", None, None).is_ok());
+    }
+
+    #[test]
+    fn anonymous_callers_only_write_unless_disabled() {
+        let auth = Authenticator::from_config(&Config::default()).unwrap();
+
+        assert_eq!(auth.authorize("POST", "/v1/scans", None, None).unwrap().role, Role::Write);
+        assert_eq!(
+            auth.authorize("DELETE", "/v1/scans/1.2.3.4/80", None, None),
+            Err(AuthError::Forbidden { required: Role::Admin, granted: Role::Write }),
+        );
+        assert_eq!(
+            auth.authorize("POST", "/v1/admin/snapshots", None, None),
+            Err(AuthError::Forbidden { required: Role::Admin, granted: Role::Write }),
+        );
+
+        let config = Config {
+            api_keys_file: Some(write_temp("disabled-keys", "ops a-key admin\n")),
+            auth_disabled: true,
+            ..Config::default()
+        };
+        assert!(Authenticator::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn api_keys() {
+        let auth = authenticator();
+
+        assert_eq!(auth.authenticate(None, Some("s3cret")).unwrap().subject, "dashboard");
+        assert_eq!(auth.authenticate(Some("ApiKey s3cret"), None).unwrap().subject, "dashboard");
+        assert_eq!(auth.authenticate(None, Some("wrong")), Err(AuthError::InvalidApiKey));
+        assert_eq!(auth.authenticate(None, None), Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn hs256_tokens() {
+        let auth = authenticator();
+
+        let good = token("hmac-secret", json!({ "sub": "scanner", "iss": "issuer", "exp": exp() }));
+        let bearer = format!("Bearer {}", good);
+        assert_eq!(auth.authenticate(Some(&bearer), None).unwrap().subject, "scanner");
+
+        let forged = token("other", json!({ "sub": "scanner", "iss": "issuer", "exp": exp() }));
+        let expired = token("hmac-secret", json!({ "sub": "scanner", "iss": "issuer", "exp": 1 }));
+        let wrong_issuer = token("hmac-secret", json!({ "sub": "scanner", "iss": "x", "exp": exp() }));
+
+        for bad in [forged, expired, wrong_issuer, "garbage".to_owned()] {
+            let bearer = format!("Bearer {}", bad);
+            assert!(matches!(auth.authenticate(Some(&bearer), None), Err(AuthError::InvalidToken(_))));
+        }
+    }
+
+    #[test]
+    fn rs256_needs_a_public_key() {
+        let auth = authenticator();
+        // {"alg":"RS256","typ":"JWT"}.{}
+        let token = "Bearer eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.e30.sig";
+
+        let err = auth.authenticate(Some(token), None).unwrap_err();
+        assert_eq!(err, AuthError::InvalidToken("RS256 tokens are not accepted".to_owned()));
+    }
+
+    #[test]
+    fn roles_from_api_keys() {
+        let auth = authenticator();
+
+        assert!(auth.authorize("GET", "/v1/scans", None, Some("s3cret")).is_ok());
+        assert_eq!(
+            auth.authorize("POST", "/v1/scans", None, Some("s3cret")),
+            Err(AuthError::Forbidden { required: Role::Write, granted: Role::Read }),
+        );
+        assert!(auth.authorize("PUT", "/v1/scans", None, Some("w-key")).is_ok());
+        assert!(auth.authorize("DELETE", "/v1/scans", None, Some("w-key")).is_err());
+        assert_eq!(auth.authorize("DELETE", "/v1/scans", None, Some("a-key")).unwrap().role, Role::Admin);
+
+        assert_eq!(
+            auth.authorize("POST", "/v1/admin/snapshots", None, Some("w-key")),
+            Err(AuthError::Forbidden { required: Role::Admin, granted: Role::Write }),
+        );
+        assert!(auth.authorize("POST", "/v1/admin/snapshots", None, Some("a-key")).is_ok());
+    }
+
+    #[test]
+    fn roles_from_token_claims() {
+        let auth = authenticator();
+        let bearer = |claims| format!("Bearer {}", token("hmac-secret", claims));
+
+        let plain = bearer(json!({ "iss": "issuer", "exp": exp() }));
+        assert_eq!(auth.authenticate(Some(&plain), None).unwrap().role, Role::Read);
+
+        let scoped = bearer(json!({ "iss": "issuer", "exp": exp(), "scope": "openid write read" }));
+        assert_eq!(auth.authenticate(Some(&scoped), None).unwrap().role, Role::Write);
+
+        let admin = bearer(json!({ "iss": "issuer", "exp": exp(), "role": "admin" }));
+        assert!(auth.authorize("DELETE", "/v1/scans", Some(&admin), None).is_ok());
+    }
+
+    #[test]
+    fn tenants_from_api_keys_and_claims() {
+        let auth = authenticator();
+
+        assert!(auth.authorize("GET", "/v1/scans", None, Some("s3cret")).is_ok());
+        assert_eq!(
+            auth.authorize("GET", "/v1/tenants/team-a/scans", None, Some("s3cret")),
+            Err(AuthError::TenantForbidden(Some("team-a".to_owned()))),
+        );
+        assert!(auth.authorize("POST", "/v1/tenants/team-a/scans", None, Some("w-key")).is_ok());
+        assert!(auth.authorize("POST", "/v1/tenants/team-b/scans", None, Some("w-key")).is_err());
+        assert!(auth.authorize("GET", "/v1/tenants/team-%61/scans", None, Some("w-key")).is_err());
+        assert!(auth.authorize("DELETE", "/v1/tenants/team-b/scans/1.2.3.4/80", None, Some("a-key")).is_ok());
+
+        let claims = json!({ "sub": "scanner", "iss": "issuer", "exp": exp(), "tenants": ["team-b"] });
+        let bearer = format!("Bearer {}", token("hmac-secret", claims));
+        assert!(auth.authorize("GET", "/v1/tenants/team-b/scans", Some(&bearer), None).is_ok());
+        assert!(auth.authorize("GET", "/v1/scans", Some(&bearer), None).is_err());
+
+        let claims = json!({ "sub": "ops", "iss": "issuer", "exp": exp(), "role": "admin", "tenants": ["team-b"] });
+        let bearer = format!("Bearer {}", token("hmac-secret", claims));
+        assert_eq!(
+            auth.authorize("POST", "/v1/admin/snapshots", Some(&bearer), None),
+            Err(AuthError::TenantForbidden(None)),
+        );
+
+        assert_eq!("team-a,default".parse(), Ok(Tenants::Only(vec!["team-a".to_owned(), "default".to_owned()])));
+        assert!("team-a,".parse::<Tenants>().is_err());
+        assert!(parse_api_keys("k secret read Team-A\n").is_err());
+    }
+
+    #[test]
+    fn forbidden_is_not_a_challenge() {
+        let err = AuthError::Forbidden { required: Role::Admin, granted: Role::Write };
+
+        assert_eq!(err.problem().status, 403);
+        assert!(err.challenge().is_none());
+        assert_eq!(AuthError::Missing.challenge(), Some(CHALLENGE));
+    }
+
+    #[test]
+    fn only_the_api_is_protected() {
+        assert!(is_protected("/v1/scans"));
+        assert!(is_protected("/v1/scans/1.2.3.4/80"));
+        assert!(!is_protected("/healthz"));
+        assert!(!is_protected("/openapi.json"));
+
+        assert!(is_admin("/v1/admin/snapshots"));
+        assert!(!is_admin("/v1/scans/admin"));
+        assert!(!is_admin("/admin"));
+    }
+
+    #[test]
+    fn malformed_key_file() {
+        assert!(parse_api_keys("just-a-key\n").is_err());
+        assert!(parse_api_keys("a b owner\n").is_err());
+        assert_eq!(parse_api_keys("a b\n c d admin \n").unwrap().len(), 2);
+    }
+}
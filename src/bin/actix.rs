@@ -1,72 +1,380 @@
-use data::{Store,Scan};
-use actix_web::{get,post,put,delete,App,HttpServer,HttpResponse,web};
+use data::{admin,auth,graphql,grpc,health,idempotency,metrics,negotiate,openapi,problem,ratelimit,telemetry,tenant,tls,validation,Config,Db,Shutdown,Store,QUOTA_EXCEEDED,STALE_SCAN};
+use data::problem::Problem;
+use data::telemetry::{RequestContext,REQUEST_ID_HEADER,TRACEPARENT_HEADER};
+use actix_web::{get,post,put,delete,route,App,HttpMessage,HttpRequest,HttpServer,HttpResponse,web};
+use actix_web::http::{header,StatusCode};
+use actix_web::dev::{Service,ServiceResponse};
+use actix_web::middleware::{ErrorHandlerResponse,ErrorHandlers};
+use actix_web::http::header::{HeaderName,HeaderValue};
 use actix_web::web::Data;
-use tokio::sync::RwLock;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::time::Instant;
+use tracing::Instrument;
 
-type Db = RwLock<Store>;
+fn header<'a>(req: &'a impl HttpMessage, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn denied(err: auth::AuthError) -> HttpResponse {
+    let status = StatusCode::from_u16(err.status()).unwrap_or(StatusCode::UNAUTHORIZED);
+    let mut res = HttpResponse::build(status);
+    if let Some(challenge) = err.challenge() {
+        res.insert_header((header::WWW_AUTHENTICATE, challenge));
+    }
+
+    res.content_type(problem::CONTENT_TYPE).body(err.problem().body())
+}
+
+fn too_many_requests(decision: &ratelimit::Decision) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .content_type(problem::CONTENT_TYPE)
+        .body(decision.problem().body())
+}
+
+fn problem_response(problem: &Problem) -> HttpResponse {
+    let status = StatusCode::from_u16(problem.status).unwrap_or(StatusCode::BAD_REQUEST);
+    HttpResponse::build(status)
+        .content_type(problem::CONTENT_TYPE)
+        .body(problem.body())
+}
+
+fn negotiated(reply: Result<negotiate::Reply, Problem>) -> HttpResponse {
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(problem) => return problem_response(&problem),
+    };
+
+    let mut res = HttpResponse::Ok();
+    for header in reply.headers() {
+        res.insert_header(header);
+    }
+    res.body(reply.body)
+}
+
+fn recorded(recorded: idempotency::Recorded) -> HttpResponse {
+    let mut res = HttpResponse::build(StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::OK));
+    for header in recorded.headers() {
+        res.insert_header(header);
+    }
+    res.body(recorded.body)
+}
+
+fn idempotent<'a>(req: &'a HttpRequest, body: &'a [u8]) -> idempotency::Request<'a> {
+    idempotency::Request {
+        key: header(req, idempotency::HEADER),
+        path: req.path(),
+        authorization: header(req, auth::AUTHORIZATION_HEADER),
+        api_key: header(req, auth::API_KEY_HEADER),
+        content_type: header(req, negotiate::CONTENT_TYPE_HEADER),
+        body,
+    }
+}
+
+fn accepts(req: &HttpRequest) -> (Option<&str>, Option<&str>) {
+    (header(req, negotiate::ACCEPT_HEADER), header(req, negotiate::ACCEPT_ENCODING_HEADER))
+}
+
+// Routes under `/v1/tenants/{tenant}` name their tenant; the plain `/v1`
+// routes belong to the default one.
+fn tenant(req: &HttpRequest) -> &str {
+    req.match_info().get("tenant").unwrap_or(tenant::DEFAULT_TENANT)
+}
+
+#[derive(Deserialize)]
+struct Target {
+    ip: String,
+    port: i16,
+}
 
 #[get("")]
-async fn get_all_scans(store: Data<Db>) -> HttpResponse {
-    HttpResponse::Ok().json(store.read().await.get_all())
+async fn get_all_scans(req: HttpRequest, store: Data<Db>) -> HttpResponse {
+    let (accept, accept_encoding) = accepts(&req);
+    let reply = match negotiate::sort_key(Some(req.query_string())) {
+        Ok(sort) => negotiate::scans_reply(accept, accept_encoding, &store.read().await.get_all_sorted_in(tenant(&req), sort)),
+        Err(problem) => Err(problem),
+    };
+    negotiated(reply)
 }
 
 #[get("/{ip}/{port}")]
-async fn get_scan(store: Data<Db>, path_param: web::Path<(String, i16)>) -> HttpResponse {
-    let params = path_param.into_inner();
-    let ip = params.0;
-    let port = params.1;
-
-    HttpResponse::Ok().json(store.read().await.get_record(&ip, port))
+async fn get_scan(req: HttpRequest, store: Data<Db>, target: web::Path<Target>) -> HttpResponse {
+    let (accept, accept_encoding) = accepts(&req);
+    let scan = store.read().await.get_record_in(tenant(&req), &target.ip, target.port);
+    negotiated(negotiate::scan_reply(accept, accept_encoding, scan.as_ref()))
 }
 
 #[post("")]
-async fn create_scan(store: Data<Db>, item: web::Json<Scan>) -> HttpResponse {
-    match store.write().await.insert_record(item.0) {
-        Err(x) => HttpResponse::BadRequest().body(x),
-        Ok(_) => HttpResponse::Created().finish()
-    }
+async fn create_scan(
+    req: HttpRequest, store: Data<Db>, idempotency: Data<idempotency::Idempotency>,
+    bodies: Data<negotiate::BodyPolicy>, body: web::Bytes,
+) -> HttpResponse {
+    recorded(idempotency.run(idempotent(&req, &body), async {
+        let item = match bodies.decode_scan(header(&req, negotiate::CONTENT_TYPE_HEADER), &body) {
+            Ok(item) => item,
+            Err(problem) => return idempotency::Recorded::problem(&problem),
+        };
+
+        match store.write().await.insert_record_in(tenant(&req), item) {
+            Err(QUOTA_EXCEEDED) => idempotency::Recorded::problem(&problem::quota_exceeded()),
+            Err(x) => idempotency::Recorded::new(400).with_body(x),
+            Ok(_) => idempotency::Recorded::new(201),
+        }
+    }).await)
 }
 
 #[put("")]
-async fn update_scan(store: Data<Db>, item: web::Json<Scan>) -> HttpResponse {
-    match store.write().await.update_record(item.0) {
+async fn update_scan(
+    req: HttpRequest, store: Data<Db>, bodies: Data<negotiate::BodyPolicy>, body: web::Bytes,
+) -> HttpResponse {
+    let item = match bodies.decode_scan(header(&req, negotiate::CONTENT_TYPE_HEADER), &body) {
+        Ok(item) => item,
+        Err(problem) => return problem_response(&problem),
+    };
+
+    match store.write().await.update_record_in(tenant(&req), item) {
+        Err(STALE_SCAN) => problem_response(&problem::stale_scan()),
         Err(x) => HttpResponse::BadRequest().body(x),
         Ok(_) => HttpResponse::Ok().finish()
     }
 }
 
 #[delete("/{ip}/{port}")]
-async fn delete_scan(store: Data<Db>, path_param: web::Path<(String, i16)>) -> HttpResponse {
-    let params = path_param.into_inner();
-    let ip = params.0;
-    let port = params.1;
-
-    match store.write().await.delete_record(&ip, port) {
+async fn delete_scan(req: HttpRequest, store: Data<Db>, target: web::Path<Target>) -> HttpResponse {
+    match store.write().await.delete_record_in(tenant(&req), &target.ip, target.port) {
         Ok(_) => HttpResponse::Ok().finish(),
         Err(x) => HttpResponse::BadRequest().body(x)
     }
 }
 
+fn scans(path: &str) -> actix_web::Scope {
+    web::scope(path)
+        .service(get_all_scans)
+        .service(get_scan)
+        .service(create_scan)
+        .service(update_scan)
+        .service(delete_scan)
+}
+
+#[route("/graphql", method = "GET", method = "POST")]
+async fn graphql_endpoint(req: HttpRequest, graphql: Data<graphql::GraphQl>, body: web::Bytes) -> HttpResponse {
+    let reply = graphql.execute(graphql::HttpRequest {
+        method: req.method().as_str(),
+        query_string: Some(req.query_string()),
+        body: &body,
+        accept: header(&req, negotiate::ACCEPT_HEADER),
+        authorization: header(&req, auth::AUTHORIZATION_HEADER),
+        api_key: header(&req, auth::API_KEY_HEADER),
+    }).await;
+
+    match reply {
+        Err(problem) => problem_response(&problem),
+        Ok(graphql::Reply::Json(body)) => HttpResponse::Ok().content_type(graphql::JSON).body(body),
+        Ok(graphql::Reply::Events(events)) => HttpResponse::Ok()
+            .content_type(graphql::EVENT_STREAM)
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .streaming(events.map(|event| Ok::<_, Infallible>(web::Bytes::from(event.to_bytes())))),
+    }
+}
+
+#[post("/admin/snapshots")]
+async fn create_snapshot(req: HttpRequest, store: Data<Db>, snapshots: Data<admin::Snapshots>) -> HttpResponse {
+    match snapshots.take(&store, Some(req.query_string())).await {
+        Ok(taken) => HttpResponse::Created().json(taken),
+        Err(problem) => problem_response(&problem),
+    }
+}
+
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(health::liveness())
+}
+
+#[get("/readyz")]
+async fn readyz(store: Data<Db>, shutdown: Data<Shutdown>) -> HttpResponse {
+    let report = health::readiness(&store, &shutdown).await;
+
+    match report.is_up() {
+        true => HttpResponse::Ok().json(report),
+        false => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+#[get("/metrics")]
+async fn get_metrics(store: Data<Db>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(metrics::render(&store).await)
+}
+
+#[get("/openapi.json")]
+async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(openapi::json())
+}
+
+#[get("/docs")]
+async fn get_docs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(openapi::DOCS_HTML)
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let store = Data::new(RwLock::new(Store::new()));
+    let config = Config::from_env()?.with_args(std::env::args().skip(1))?;
+    let _telemetry = telemetry::init("actix", &config)?;
+    let store = Data::new(Db::new(Store::from_config(&config)?));
+    store.warm_in_background();
+    store.compact_in_background(config.compact_interval, config.snapshot_dir.as_deref());
+    let snapshots = admin::Snapshots::from_config(&config);
+    snapshots.schedule(&store);
+    let authenticator = auth::Authenticator::from_config(&config)?;
+    let limiter = ratelimit::RateLimiter::from_config(&config);
+    let tls = tls::Tls::from_config(&config)?;
+    let shutdown = Shutdown::on_signals();
+    let grpc = grpc::start(&config, store.get_ref().clone(), authenticator.clone(), limiter.clone(), shutdown.clone()).await?;
 
-    HttpServer::new(move || {
+    let validator = validation::Validator::from_config(&config);
+    let app_graphql = Data::new(graphql::GraphQl::new(store.get_ref().clone(), authenticator.clone(), validator, shutdown.clone()));
+    let app_idempotency = Data::new(idempotency::Idempotency::from_config(&config));
+    let app_snapshots = Data::new(snapshots);
+    let bodies = negotiate::BodyPolicy::from_config(&config);
+    let app_store = store.clone();
+    let app_shutdown = Data::new(shutdown.clone());
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(store.clone())
-            .app_data(web::JsonConfig::default())
+            .app_data(app_store.clone())
+            .app_data(app_shutdown.clone())
+            .app_data(app_graphql.clone())
+            .app_data(app_idempotency.clone())
+            .app_data(app_snapshots.clone())
+            .app_data(Data::new(bodies))
+            .app_data(web::PayloadConfig::new(bodies.limit))
+            // Oversized bodies are refused by the extractor before a handler
+            // runs; this gives them the same problem body as everything else.
+            .wrap(ErrorHandlers::new().handler(StatusCode::PAYLOAD_TOO_LARGE, move |res: ServiceResponse<_>| {
+                let (req, _) = res.into_parts();
+                let res = ServiceResponse::new(req, problem_response(&bodies.too_large()));
+                Ok(ErrorHandlerResponse::Response(res.map_into_right_body()))
+            }))
+            .wrap_fn({
+                let authenticator = authenticator.clone();
+                let limiter = limiter.clone();
+                move |req, srv| {
+                    // Callers are authenticated before they are charged, so
+                    // a failed attempt is charged to its address.
+                    let (decision, rejection) = match auth::is_protected(req.path()) {
+                        true => {
+                            let authorized = authenticator.authorize(
+                                req.method().as_str(),
+                                req.path(),
+                                header(&req, auth::AUTHORIZATION_HEADER),
+                                header(&req, auth::API_KEY_HEADER),
+                            );
+                            let decision = limiter.check(
+                                req.method().as_str(),
+                                authorized.as_ref().ok(),
+                                req.peer_addr().map(|addr| addr.ip()),
+                            );
+                            (decision, authorized.err())
+                        },
+                        false => (None, None),
+                    };
+
+                    let res = match (&decision, rejection) {
+                        (Some(decision), _) if !decision.allowed => {
+                            Err(req.into_response(too_many_requests(decision)))
+                        },
+                        (_, Some(err)) => Err(req.into_response(denied(err))),
+                        _ => Ok(srv.call(req)),
+                    };
+
+                    async move {
+                        let mut res = match res {
+                            Ok(res) => res.await?.map_into_left_body(),
+                            Err(res) => res.map_into_right_body(),
+                        };
+
+                        for (name, value) in decision.iter().flat_map(|d| d.headers()) {
+                            if let Ok(value) = HeaderValue::from_str(&value) {
+                                res.headers_mut().insert(HeaderName::from_static(name), value);
+                            }
+                        }
+
+                        Ok(res)
+                    }
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let start = Instant::now();
+                let method = req.method().to_string();
+                let path = req.path().to_owned();
+                let ctx = RequestContext::from_headers(
+                    header(&req, REQUEST_ID_HEADER), header(&req, TRACEPARENT_HEADER),
+                );
+                let span = telemetry::request_span(&method, &path);
+                ctx.record(&span);
+
+                let res = span.in_scope(|| srv.call(req));
+
+                async move {
+                    let mut res = res.await?;
+                    let status = res.status().as_u16();
+                    metrics::global().observe_request(&method, &path, status, start.elapsed());
+                    telemetry::finish(&tracing::Span::current(), status, start.elapsed());
+
+                    for (name, value) in ctx.response_headers() {
+                        if let Ok(value) = HeaderValue::from_str(&value) {
+                            res.headers_mut().insert(HeaderName::from_static(name), value);
+                        }
+                    }
+
+                    Ok(res)
+                }.instrument(span)
+            })
+            .service(healthz)
+            .service(readyz)
+            .service(get_metrics)
+            .service(get_openapi)
+            .service(get_docs)
             .service(
-                web::scope("/v1").service(
-                    web::scope("/scans")
-                    .service(get_all_scans)
-                    .service(get_scan)
-                    .service(create_scan)
-                    .service(update_scan)
-                    .service(delete_scan)
-                )
+                web::scope("/v1")
+                    .service(scans("/scans"))
+                    .service(scans("/tenants/{tenant}/scans"))
+                    .service(graphql_endpoint)
+                    .service(create_snapshot)
             )
-    })
-    .bind(("127.0.0.1", 8080))?
-        .run()
-        .await
+    });
+
+    let server = match &tls {
+        Some(tls) => server.bind_rustls(("127.0.0.1", 8080), (*tls.server_config()).clone())?,
+        None => server.bind(("127.0.0.1", 8080))?,
+    };
+
+    let server = server
+        .disable_signals()
+        .shutdown_timeout(config.shutdown_timeout.as_secs())
+        .run();
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown.wait().await;
+        tracing::info!("shutdown requested, draining in-flight requests");
+        handle.stop(true).await;
+    });
+
+    tracing::info!(addr = "127.0.0.1:8080", tls = tls.is_some(), "listening");
+    server.await?;
+
+    if let Some(grpc) = grpc {
+        grpc.drain(config.shutdown_timeout).await;
+    }
+
+    tracing::info!("server stopped, flushing store");
+    store.write().await.flush()?;
+
+    Ok(())
 }
@@ -1,36 +1,124 @@
-use poem::{get,post,put,delete,handler,Route,EndpointExt,Server,Response};
+use poem::{get,handler,post,Body,Request,Route,Endpoint,EndpointExt,IntoResponse,Server,Response};
+use poem::error::ReadBodyError;
 use poem::web::{Path,Data,Json};
 use poem::http::StatusCode;
-use poem::listener::TcpListener;
-use data::Store;
-use tokio::sync::RwLock;
-use std::sync::Arc;
+use poem::http::uri::Scheme;
+use poem::listener::{Acceptor,AcceptorExt,Listener,TcpListener};
+use poem::web::{LocalAddr,RemoteAddr};
+use data::{admin,auth,graphql,grpc,health,idempotency,metrics,negotiate,openapi,problem,ratelimit,telemetry,tenant,tls,validation,Config,Db,Shutdown,Store,QUOTA_EXCEEDED,STALE_SCAN};
+use data::problem::Problem;
+use data::telemetry::{RequestContext,REQUEST_ID_HEADER,TRACEPARENT_HEADER};
+use poem::http::header::{HeaderName,HeaderValue};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::time::Instant;
+use tracing::Instrument;
 
-pub type Db = Arc<RwLock<Store>>;
+// Routes under `/v1/tenants/:tenant` name their tenant; the plain `/v1`
+// routes belong to the default one.
+fn tenant(req: &Request) -> &str {
+    req.raw_path_param("tenant").unwrap_or(tenant::DEFAULT_TENANT)
+}
+
+#[derive(Deserialize)]
+struct Target {
+    ip: String,
+    port: i16,
+}
+
+fn problem_response(problem: &Problem) -> Response {
+    Response::builder()
+        .status(StatusCode::from_u16(problem.status).unwrap_or(StatusCode::BAD_REQUEST))
+        .content_type(problem::CONTENT_TYPE)
+        .body(problem.body())
+}
+
+fn negotiated(reply: Result<negotiate::Reply, Problem>) -> Response {
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(problem) => return problem_response(&problem),
+    };
+
+    let mut res = Response::builder();
+    for (name, value) in reply.headers() {
+        res = res.header(name, value);
+    }
+    res.body(reply.body)
+}
+
+fn recorded(recorded: idempotency::Recorded) -> Response {
+    let mut res = Response::builder()
+        .status(StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::OK));
+    for (name, value) in recorded.headers() {
+        res = res.header(name, value);
+    }
+    res.body(recorded.body)
+}
+
+// Nested routes see their path without the `/v1` prefix, so the original
+// URI is what a key gets scoped to.
+fn idempotent<'a>(req: &'a Request, body: &'a [u8]) -> idempotency::Request<'a> {
+    idempotency::Request {
+        key: req.header(idempotency::HEADER),
+        path: req.original_uri().path(),
+        authorization: req.header(auth::AUTHORIZATION_HEADER),
+        api_key: req.header(auth::API_KEY_HEADER),
+        content_type: req.header(negotiate::CONTENT_TYPE_HEADER),
+        body,
+    }
+}
 
 #[handler]
-async fn get_all_scans(store: Data<&Db>) -> Json<Vec<data::Scan>> {
-    Json(store.read().await.get_all())
+async fn get_all_scans(req: &Request, store: Data<&Db>) -> Response {
+    let (accept, accept_encoding) = (req.header(negotiate::ACCEPT_HEADER), req.header(negotiate::ACCEPT_ENCODING_HEADER));
+    let reply = match negotiate::sort_key(req.uri().query()) {
+        Ok(sort) => negotiate::scans_reply(accept, accept_encoding, &store.read().await.get_all_sorted_in(tenant(req), sort)),
+        Err(problem) => Err(problem),
+    };
+    negotiated(reply)
 }
 
 #[handler]
-async fn get_scan(store: Data<&Db>, path: Path<(String, i16)>) -> Json<Option<data::Scan>> {
-    Json(store.read().await.get_record(&path.0.0, path.0.1))
+async fn get_scan(req: &Request, store: Data<&Db>, target: Path<Target>) -> Response {
+    let scan = store.read().await.get_record_in(tenant(req), &target.ip, target.port);
+    negotiated(negotiate::scan_reply(
+        req.header(negotiate::ACCEPT_HEADER), req.header(negotiate::ACCEPT_ENCODING_HEADER), scan.as_ref(),
+    ))
 }
 
 #[handler]
-async fn create_scan(store: Data<&Db>, scan: Json<data::Scan>) -> Response {
-    let status = match store.write().await.insert_record(scan.0.clone()) {
-        Err(_) => StatusCode::BAD_REQUEST,
-        Ok(_) => StatusCode::CREATED,
-    };
+async fn create_scan(
+    req: &Request, store: Data<&Db>, cache: Data<&idempotency::Idempotency>,
+    bodies: Data<&negotiate::BodyPolicy>, body: Vec<u8>,
+) -> Response {
+    let res = cache.run(idempotent(req, &body), async {
+        let scan = match bodies.decode_scan(req.header(negotiate::CONTENT_TYPE_HEADER), &body) {
+            Ok(scan) => scan,
+            Err(problem) => return idempotency::Recorded::problem(&problem),
+        };
 
-    Response::builder().status(status).finish()
+        match store.write().await.insert_record_in(tenant(req), scan) {
+            Err(QUOTA_EXCEEDED) => idempotency::Recorded::problem(&problem::quota_exceeded()),
+            Err(_) => idempotency::Recorded::new(400),
+            Ok(_) => idempotency::Recorded::new(201),
+        }
+    }).await;
+
+    recorded(res)
 }
 
 #[handler]
-async fn update_scan(store: Data<&Db>, scan: Json<data::Scan>) -> Response {
-    let status = match store.write().await.update_record(scan.0.clone()) {
+async fn update_scan(
+    req: &Request, store: Data<&Db>, bodies: Data<&negotiate::BodyPolicy>, body: Vec<u8>,
+) -> Response {
+    let scan = match bodies.decode_scan(req.header(negotiate::CONTENT_TYPE_HEADER), &body) {
+        Ok(scan) => scan,
+        Err(problem) => return problem_response(&problem),
+    };
+
+    let status = match store.write().await.update_record_in(tenant(req), scan) {
+        Err(STALE_SCAN) => return problem_response(&problem::stale_scan()),
         Err(_) => StatusCode::BAD_REQUEST,
         Ok(_) => StatusCode::OK,
     };
@@ -39,8 +127,8 @@ async fn update_scan(store: Data<&Db>, scan: Json<data::Scan>) -> Response {
 }
 
 #[handler]
-async fn delete_scan(store: Data<&Db>, path: Path<(String, i16)>) -> Response {
-    let status = match store.write().await.delete_record(&path.0.0, path.0.1) {
+async fn delete_scan(req: &Request, store: Data<&Db>, target: Path<Target>) -> Response {
+    let status = match store.write().await.delete_record_in(tenant(req), &target.ip, target.port) {
         Err(_) => StatusCode::BAD_REQUEST,
         Ok(_) => StatusCode::OK,
     };
@@ -48,18 +136,229 @@ async fn delete_scan(store: Data<&Db>, path: Path<(String, i16)>) -> Response {
     Response::builder().status(status).finish()
 }
 
+#[handler]
+async fn graphql_endpoint(req: &Request, api: Data<&graphql::GraphQl>, body: Vec<u8>) -> Response {
+    let reply = api.execute(graphql::HttpRequest {
+        method: req.method().as_str(),
+        query_string: req.uri().query(),
+        body: &body,
+        accept: req.header(negotiate::ACCEPT_HEADER),
+        authorization: req.header(auth::AUTHORIZATION_HEADER),
+        api_key: req.header(auth::API_KEY_HEADER),
+    }).await;
+
+    match reply {
+        Err(problem) => problem_response(&problem),
+        Ok(graphql::Reply::Json(body)) => Response::builder().content_type(graphql::JSON).body(body),
+        Ok(graphql::Reply::Events(events)) => Response::builder()
+            .content_type(graphql::EVENT_STREAM)
+            .header("cache-control", "no-cache")
+            .body(Body::from_bytes_stream(events.map(|event| Ok::<_, Infallible>(event.to_bytes())))),
+    }
+}
+
+#[handler]
+async fn create_snapshot(req: &Request, store: Data<&Db>, snapshots: Data<&admin::Snapshots>) -> Response {
+    match snapshots.take(&store, req.uri().query()).await {
+        Ok(taken) => Json(taken).with_status(StatusCode::CREATED).into_response(),
+        Err(problem) => problem_response(&problem),
+    }
+}
+
+#[handler]
+async fn healthz() -> Json<health::HealthReport> {
+    Json(health::liveness())
+}
+
+#[handler]
+async fn readyz(store: Data<&Db>, shutdown: Data<&Shutdown>) -> Response {
+    let report = health::readiness(&store, &shutdown).await;
+    let status = match report.is_up() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    Json(report).with_status(status).into_response()
+}
+
+#[handler]
+async fn get_metrics(store: Data<&Db>) -> Response {
+    Response::builder()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(metrics::render(&store).await)
+}
+
+#[handler]
+fn get_openapi() -> Response {
+    Response::builder()
+        .content_type("application/json")
+        .body(openapi::json())
+}
+
+#[handler]
+fn get_docs() -> poem::web::Html<&'static str> {
+    poem::web::Html(openapi::DOCS_HTML)
+}
+
+// Hands poem connections that have already completed their TLS handshake.
+struct TlsAcceptor(tls::Incoming);
+
+#[poem::async_trait]
+impl Acceptor for TlsAcceptor {
+    type Io = tls::Connection;
+
+    fn local_addr(&self) -> Vec<LocalAddr> {
+        vec![LocalAddr(self.0.local_addr().into())]
+    }
+
+    async fn accept(&mut self) -> std::io::Result<(Self::Io, LocalAddr, RemoteAddr, Scheme)> {
+        let conn = self.0.accept().await.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "tls listener stopped")
+        })?;
+        let remote = RemoteAddr(conn.peer_addr().into());
+
+        Ok((conn, LocalAddr(self.0.local_addr().into()), remote, Scheme::HTTPS))
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let store = Arc::new(RwLock::new(Store::new()));
+    let config = Config::from_env()?.with_args(std::env::args().skip(1))?;
+    let _telemetry = telemetry::init("poem", &config)?;
+    let store = Db::new(Store::from_config(&config)?);
+    store.warm_in_background();
+    store.compact_in_background(config.compact_interval, config.snapshot_dir.as_deref());
+    let snapshots = admin::Snapshots::from_config(&config);
+    snapshots.schedule(&store);
+    let authenticator = auth::Authenticator::from_config(&config)?;
+    let limiter = ratelimit::RateLimiter::from_config(&config);
+    let tls = tls::Tls::from_config(&config)?;
+    let shutdown = Shutdown::on_signals();
+    let grpc = grpc::start(&config, store.clone(), authenticator.clone(), limiter.clone(), shutdown.clone()).await?;
+    let validator = validation::Validator::from_config(&config);
+    let graphql = graphql::GraphQl::new(store.clone(), authenticator.clone(), validator, shutdown.clone());
+    let bodies = negotiate::BodyPolicy::from_config(&config);
 
     let scans = Route::new()
         .at("/scans", get(get_all_scans).post(create_scan).put(update_scan))
         .at("/scans/:ip/:port", get(get_scan).delete(delete_scan))
-        .data(store);
+        .at("/tenants/:tenant/scans", get(get_all_scans).post(create_scan).put(update_scan))
+        .at("/tenants/:tenant/scans/:ip/:port", get(get_scan).delete(delete_scan))
+        .at("/graphql", get(graphql_endpoint).post(graphql_endpoint).data(graphql))
+        .at("/admin/snapshots", post(create_snapshot).data(snapshots))
+        .data(store.clone())
+        .data(idempotency::Idempotency::from_config(&config))
+        .data(bodies)
+        // Bodies are read up front so ones over the limit are refused before
+        // any handler buffers them.
+        .around(move |ep, mut req| async move {
+            match req.take_body().into_bytes_limit(bodies.limit).await {
+                Ok(body) => req.set_body(body),
+                Err(ReadBodyError::PayloadTooLarge) => return Ok(problem_response(&bodies.too_large())),
+                Err(e) => return Ok(problem_response(&Problem::new(400, "Bad Request").with_detail(e.to_string()))),
+            }
+            Ok(ep.get_response(req).await)
+        })
+        .around(move |ep, req| {
+            let authenticator = authenticator.clone();
+            let limiter = limiter.clone();
+            async move {
+                // Callers are authenticated before they are charged, so a
+                // failed attempt is charged to its address.
+                // Nested routes only see the path below `/v1`.
+                let authorized = authenticator.authorize(
+                    req.method().as_str(),
+                    req.original_uri().path(),
+                    req.header(auth::AUTHORIZATION_HEADER),
+                    req.header(auth::API_KEY_HEADER),
+                );
+                let decision = limiter.check(
+                    req.method().as_str(),
+                    authorized.as_ref().ok(),
+                    req.remote_addr().as_socket_addr().map(|addr| addr.ip()),
+                );
+
+                let mut res = match (&decision, authorized) {
+                    (Some(decision), _) if !decision.allowed => Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .content_type(problem::CONTENT_TYPE)
+                        .body(decision.problem().body()),
+                    (_, Err(err)) => {
+                        let mut res = Response::builder()
+                            .status(StatusCode::from_u16(err.status()).unwrap_or(StatusCode::UNAUTHORIZED))
+                            .content_type(problem::CONTENT_TYPE);
+                        if let Some(challenge) = err.challenge() {
+                            res = res.header("www-authenticate", challenge);
+                        }
+                        res.body(err.problem().body())
+                    },
+                    (_, Ok(_)) => ep.get_response(req).await,
+                };
+
+                for (name, value) in decision.iter().flat_map(|d| d.headers()) {
+                    if let Ok(value) = HeaderValue::from_str(&value) {
+                        res.headers_mut().insert(name, value);
+                    }
+                }
+
+                Ok(res)
+            }
+        });
+
+    let app = Route::new()
+        .nest("/v1", scans)
+        .at("/healthz", get(healthz))
+        .at("/readyz", get(readyz).data(store.clone()).data(shutdown.clone()))
+        .at("/metrics", get(get_metrics).data(store.clone()))
+        .at("/openapi.json", get(get_openapi))
+        .at("/docs", get(get_docs))
+        .around(|ep, req| async move {
+            let start = Instant::now();
+            let method = req.method().to_string();
+            let path = req.uri().path().to_owned();
+            let ctx = RequestContext::from_headers(
+                req.header(REQUEST_ID_HEADER), req.header(TRACEPARENT_HEADER),
+            );
+            let span = telemetry::request_span(&method, &path);
+            ctx.record(&span);
+
+            let mut res = ep.get_response(req).instrument(span.clone()).await;
+            let status = res.status().as_u16();
+            metrics::global().observe_request(&method, &path, status, start.elapsed());
+            telemetry::finish(&span, status, start.elapsed());
+
+            for (name, value) in ctx.response_headers() {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    res.headers_mut().insert(HeaderName::from_static(name), value);
+                }
+            }
+
+            Ok(res)
+        });
+
+    let acceptor = match &tls {
+        Some(tls) => TlsAcceptor(tls.bind("127.0.0.1:8080").await?).boxed(),
+        None => TcpListener::bind("127.0.0.1:8080").into_acceptor().await?.boxed(),
+    };
+
+    tracing::info!(addr = "127.0.0.1:8080", tls = tls.is_some(), "listening");
+    Server::new_with_acceptor(acceptor)
+        .run_with_graceful_shutdown(
+            app,
+            async move {
+                shutdown.wait().await;
+                tracing::info!("shutdown requested, draining in-flight requests");
+            },
+            Some(config.shutdown_timeout),
+        )
+        .await?;
+
+    if let Some(grpc) = grpc {
+        grpc.drain(config.shutdown_timeout).await;
+    }
+
+    tracing::info!("server stopped, flushing store");
+    store.write().await.flush()?;
 
-    let app = Route::new().nest("/v1", scans);
-                                
-    Server::new(TcpListener::bind("127.0.0.1:8080"))
-        .run(app)
-        .await
+    Ok(())
 }
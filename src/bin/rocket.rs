@@ -1,39 +1,397 @@
 #[macro_use] extern crate rocket;
 
-use data::{Scan,Store};
-use rocket::State;
-use rocket::http::Status;
+use data::{admin,auth,graphql,grpc,health,idempotency,metrics,negotiate,openapi,problem,ratelimit,telemetry,tenant,tls,validation,Config,Db,Shutdown,Store,QUOTA_EXCEEDED,STALE_SCAN};
+use data::problem::Problem;
+use data::telemetry::{RequestContext,REQUEST_ID_HEADER,TRACEPARENT_HEADER};
+use rocket::{Request,Response,Route,State};
+use rocket::data::{Capped,FromData,Outcome as DataOutcome};
+use rocket::fairing::{AdHoc,Fairing,Info,Kind};
+use rocket::http::{ContentType,Header,Status};
+use rocket::http::uri::Origin;
+use rocket::request::{self,FromRequest};
+use rocket::response::{self,Responder};
+use rocket::response::stream::ByteStream;
+use rocket::route::{Handler,Outcome};
 use rocket::serde::json::Json;
-use std::sync::Arc;
-use rocket::tokio::sync::RwLock;
+use futures_util::StreamExt;
+use hyper::service::{make_service_fn,service_fn};
+use std::convert::Infallible;
+use std::time::Instant;
+use tracing::Instrument;
 
-type Db = Arc<RwLock<Store>>;
+struct RequestTrace {
+    start: Instant,
+    ctx: RequestContext,
+    span: tracing::Span,
+}
+
+impl RequestTrace {
+    fn from_request(req: &Request<'_>) -> Self {
+        let ctx = RequestContext::from_headers(
+            req.headers().get_one(REQUEST_ID_HEADER),
+            req.headers().get_one(TRACEPARENT_HEADER),
+        );
+        let span = telemetry::request_span(req.method().as_str(), req.uri().path().as_str());
+        ctx.record(&span);
+
+        RequestTrace { start: Instant::now(), ctx, span }
+    }
+}
+
+struct RequestTelemetry;
+
+#[rocket::async_trait]
+impl Fairing for RequestTelemetry {
+    fn info(&self) -> Info {
+        Info { name: "Request telemetry", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        req.local_cache(|| RequestTrace::from_request(req));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let trace = req.local_cache(|| RequestTrace::from_request(req));
+        let status = res.status().code;
+        let elapsed = trace.start.elapsed();
+
+        metrics::global().observe_request(
+            req.method().as_str(), req.uri().path().as_str(), status, elapsed,
+        );
+        telemetry::finish(&trace.span, status, elapsed);
+
+        for (name, value) in trace.ctx.response_headers() {
+            res.set_header(Header::new(name, value));
+        }
+    }
+}
+
+// Fairings can't wrap the handler's future, so each route's handler is
+// wrapped instead to run inside the request span.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: rocket::Data<'r>) -> Outcome<'r> {
+        let span = req.local_cache(|| RequestTrace::from_request(req)).span.clone();
+        self.0.handle(req, data).instrument(span).await
+    }
+}
+
+// Refuses requests that reached the loopback backend without passing the
+// TLS terminator.
+#[derive(Clone)]
+struct Terminated(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Terminated {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: rocket::Data<'r>) -> Outcome<'r> {
+        let backend = req.rocket().state::<tls::Backend>().expect("backend is managed");
+        match backend.admits(req.headers().get_one(tls::TERMINATOR_HEADER)) {
+            true => self.0.handle(req, data).await,
+            false => Outcome::from(req, Rejected(Problem::new(403, "Forbidden").with_detail(
+                "requests must come through the TLS listener",
+            ))),
+        }
+    }
+}
+
+fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(Box::new(Terminated(route.handler))));
+            route
+        })
+        .collect()
+}
+
+struct Denied(auth::AuthError);
+
+impl<'r> Responder<'r, 'static> for Denied {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = self.0.problem().body();
+        let mut res = Response::build();
+        res.status(Status::new(self.0.status()))
+            .raw_header("content-type", problem::CONTENT_TYPE)
+            .sized_body(body.len(), std::io::Cursor::new(body));
+        if let Some(challenge) = self.0.challenge() {
+            res.raw_header("www-authenticate", challenge);
+        }
+        res.ok()
+    }
+}
+
+struct TooManyRequests(ratelimit::Decision);
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = self.0.problem().body();
+        Response::build()
+            .status(Status::TooManyRequests)
+            .raw_header("content-type", problem::CONTENT_TYPE)
+            .sized_body(body.len(), std::io::Cursor::new(body))
+            .ok()
+    }
+}
+
+// Rejects requests without valid credentials, or without the role their
+// method needs, before the route runs, and charges each request to its
+// client's bucket. Callers are authenticated before they are charged, so a
+// failed attempt is charged to its address.
+#[derive(Clone)]
+struct Guarded(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Guarded {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: rocket::Data<'r>) -> Outcome<'r> {
+        let authenticator = req.rocket().state::<auth::Authenticator>()
+            .expect("authenticator is managed");
+        let limiter = req.rocket().state::<ratelimit::RateLimiter>()
+            .expect("rate limiter is managed");
+        let backend = req.rocket().state::<tls::Backend>()
+            .expect("backend is managed");
+        let authorized = authenticator.authorize(
+            req.method().as_str(),
+            req.uri().path().as_str(),
+            req.headers().get_one(auth::AUTHORIZATION_HEADER),
+            req.headers().get_one(auth::API_KEY_HEADER),
+        );
+        let ip = backend.client_ip(
+            req.headers().get_one(tls::TERMINATOR_HEADER),
+            req.headers().get_one(tls::REAL_IP_HEADER),
+            req.remote().map(|remote| remote.ip()),
+        );
+        let decision = limiter.check(req.method().as_str(), authorized.as_ref().ok(), ip);
+
+        let mut outcome = match (&decision, authorized) {
+            (Some(decision), _) if !decision.allowed => Outcome::from(req, TooManyRequests(decision.clone())),
+            (_, Err(err)) => Outcome::from(req, Denied(err)),
+            (_, Ok(_)) => self.0.handle(req, data).await,
+        };
+
+        if let Outcome::Success(res) = &mut outcome {
+            for (name, value) in decision.iter().flat_map(|d| d.headers()) {
+                res.set_raw_header(name, value);
+            }
+        }
+
+        outcome
+    }
+}
+
+fn guarded(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Guarded(route.handler));
+            route
+        })
+        .collect()
+}
+
+// The headers a scan request's bodies are negotiated from.
+struct Negotiation<'r> {
+    accept: Option<&'r str>,
+    accept_encoding: Option<&'r str>,
+    content_type: Option<&'r str>,
+    query: Option<&'r str>,
+    bodies: negotiate::BodyPolicy,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Negotiation<'r> {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+        request::Outcome::Success(Negotiation {
+            accept: req.headers().get_one(negotiate::ACCEPT_HEADER),
+            accept_encoding: req.headers().get_one(negotiate::ACCEPT_ENCODING_HEADER),
+            content_type: req.headers().get_one(negotiate::CONTENT_TYPE_HEADER),
+            query: req.uri().query().map(|query| query.as_str()),
+            bodies: *req.rocket().state::<negotiate::BodyPolicy>().expect("body policy is managed"),
+        })
+    }
+}
+
+impl Negotiation<'_> {
+    fn scans(&self, scans: &[data::Scan]) -> Negotiated {
+        Negotiated(negotiate::scans_reply(self.accept, self.accept_encoding, scans))
+    }
+
+    // Every scan of a tenant, in the order the query asks for.
+    async fn list(&self, store: &Db, tenant: &str) -> Negotiated {
+        match negotiate::sort_key(self.query) {
+            Ok(sort) => self.scans(&store.read().await.get_all_sorted_in(tenant, sort)),
+            Err(problem) => Negotiated(Err(problem)),
+        }
+    }
+
+    fn scan(&self, scan: Option<&data::Scan>) -> Negotiated {
+        Negotiated(negotiate::scan_reply(self.accept, self.accept_encoding, scan))
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<data::Scan, Rejected> {
+        self.bodies.decode_scan(self.content_type, body).map_err(Rejected)
+    }
+}
+
+// The parts of a create request its retries are recognised by.
+struct Idempotent<'r> {
+    key: Option<&'r str>,
+    path: &'r str,
+    authorization: Option<&'r str>,
+    api_key: Option<&'r str>,
+    content_type: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Idempotent<'r> {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+        request::Outcome::Success(Idempotent {
+            key: req.headers().get_one(idempotency::HEADER),
+            path: req.uri().path().as_str(),
+            authorization: req.headers().get_one(auth::AUTHORIZATION_HEADER),
+            api_key: req.headers().get_one(auth::API_KEY_HEADER),
+            content_type: req.headers().get_one(negotiate::CONTENT_TYPE_HEADER),
+        })
+    }
+}
+
+impl<'r> Idempotent<'r> {
+    fn request<'a>(&'a self, body: &'a [u8]) -> idempotency::Request<'a> {
+        idempotency::Request {
+            key: self.key,
+            path: self.path,
+            authorization: self.authorization,
+            api_key: self.api_key,
+            content_type: self.content_type,
+            body,
+        }
+    }
+}
+
+struct Recorded(idempotency::Recorded);
+
+impl<'r> Responder<'r, 'static> for Recorded {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = Response::build();
+        res.status(Status::new(self.0.status));
+        for (name, value) in self.0.headers() {
+            res.raw_header(name, value);
+        }
+        res.sized_body(self.0.body.len(), std::io::Cursor::new(self.0.body)).ok()
+    }
+}
+
+async fn create(
+    store: &Db, cache: &idempotency::Idempotency, idempotent: Idempotent<'_>, negotiation: Negotiation<'_>,
+    tenant: &str, body: LimitedBody,
+) -> Recorded {
+    Recorded(cache.run(idempotent.request(&body), async {
+        let scan = match negotiation.decode(&body) {
+            Ok(scan) => scan,
+            Err(Rejected(problem)) => return idempotency::Recorded::problem(&problem),
+        };
+
+        match store.write().await.insert_record_in(tenant, scan) {
+            Err(QUOTA_EXCEEDED) => idempotency::Recorded::problem(&problem::quota_exceeded()),
+            Err(_) => idempotency::Recorded::new(400),
+            Ok(_) => idempotency::Recorded::new(201),
+        }
+    }).await)
+}
+
+struct Rejected(Problem);
+
+impl<'r> Responder<'r, 'static> for Rejected {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = self.0.body();
+        Response::build()
+            .status(Status::new(self.0.status))
+            .raw_header("content-type", problem::CONTENT_TYPE)
+            .sized_body(body.len(), std::io::Cursor::new(body))
+            .ok()
+    }
+}
+
+struct Negotiated(Result<negotiate::Reply, Problem>);
+
+impl<'r> Responder<'r, 'static> for Negotiated {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let reply = match self.0 {
+            Ok(reply) => reply,
+            Err(problem) => return Rejected(problem).respond_to(req),
+        };
+
+        let mut res = Response::build();
+        for (name, value) in reply.headers() {
+            res.raw_header(name, value);
+        }
+        res.sized_body(reply.body.len(), std::io::Cursor::new(reply.body)).ok()
+    }
+}
+
+// Rocket's own byte guards answer a body over the `bytes` limit with a bare
+// `400`; this one fails with `413` so the catcher can name the limit.
+struct LimitedBody(Vec<u8>);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for LimitedBody {
+    type Error = std::io::Error;
+
+    async fn from_data(req: &'r Request<'_>, data: rocket::Data<'r>) -> DataOutcome<'r, Self> {
+        match <Capped<Vec<u8>>>::from_data(req, data).await {
+            DataOutcome::Success(body) if body.is_complete() => DataOutcome::Success(LimitedBody(body.into_inner())),
+            DataOutcome::Success(_) => DataOutcome::Failure((
+                Status::PayloadTooLarge, std::io::Error::new(std::io::ErrorKind::InvalidData, "body too large"),
+            )),
+            DataOutcome::Failure(e) => DataOutcome::Failure(e),
+            DataOutcome::Forward(f) => DataOutcome::Forward(f),
+        }
+    }
+}
+
+impl std::ops::Deref for LimitedBody {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[catch(413)]
+fn too_large(req: &Request<'_>) -> Rejected {
+    let bodies = req.rocket().state::<negotiate::BodyPolicy>().expect("body policy is managed");
+    Rejected(bodies.too_large())
+}
 
 #[get("/")]
-async fn get_all_scans(store: &State<Db>) -> Json<Vec<Scan>> {
-    Json(store.read().await.get_all())
+async fn get_all_scans(store: &State<Db>, negotiation: Negotiation<'_>) -> Negotiated {
+    negotiation.list(store, tenant::DEFAULT_TENANT).await
 }
 
 #[get("/<ip>/<port>")]
-async fn get_scan(store: &State<Db>, ip: &str, port: i16) -> Json<Option<Scan>> {
-    Json(store.read().await.get_record(ip, port))
+async fn get_scan(store: &State<Db>, negotiation: Negotiation<'_>, ip: &str, port: i16) -> Negotiated {
+    negotiation.scan(store.read().await.get_record(ip, port).as_ref())
 }
 
-#[post("/", data="<scan>")]
-async fn create_scan(store: &State<Db>, scan: Json<Scan>) -> Status {
-    let s = scan.into_inner().clone();
-    match store.write().await.insert_record(s) {
-        Err(_) => Status::BadRequest,
-        Ok(_) => Status::Created,
-    }
+#[post("/", data="<body>")]
+async fn create_scan(
+    store: &State<Db>, cache: &State<idempotency::Idempotency>, idempotent: Idempotent<'_>,
+    negotiation: Negotiation<'_>, body: LimitedBody,
+) -> Recorded {
+    create(store, cache, idempotent, negotiation, tenant::DEFAULT_TENANT, body).await
 }
 
-#[put("/", data="<scan>")]
-async fn update_scan(store: &State<Db>, scan: Json<Scan>) -> Status {
-    let s = scan.into_inner().clone();
+#[put("/", data="<body>")]
+async fn update_scan(store: &State<Db>, negotiation: Negotiation<'_>, body: LimitedBody) -> Result<Status, Rejected> {
+    let s = negotiation.decode(&body)?;
     match store.write().await.update_record(s) {
-        Err(_) => Status::BadRequest,
-        Ok(_) => Status::Ok,
+        Err(STALE_SCAN) => Err(Rejected(problem::stale_scan())),
+        Err(_) => Ok(Status::BadRequest),
+        Ok(_) => Ok(Status::Ok),
     }
 }
 
@@ -45,13 +403,296 @@ async fn delete_scan(store: &State<Db>, ip: &str, port: i16) -> Status {
     }
 }
 
-#[launch]
-fn rocket() -> _ {
-    let figment = rocket::Config::figment().merge(("port", 8080));
+// Rocket mount points can't hold dynamic segments, so the tenant-scoped
+// routes are mounted under `/v1/tenants` and name the tenant themselves.
+#[get("/<tenant>/scans")]
+async fn get_all_tenant_scans(store: &State<Db>, negotiation: Negotiation<'_>, tenant: &str) -> Negotiated {
+    negotiation.list(store, tenant).await
+}
+
+#[get("/<tenant>/scans/<ip>/<port>")]
+async fn get_tenant_scan(
+    store: &State<Db>, negotiation: Negotiation<'_>, tenant: &str, ip: &str, port: i16,
+) -> Negotiated {
+    negotiation.scan(store.read().await.get_record_in(tenant, ip, port).as_ref())
+}
+
+#[post("/<tenant>/scans", data="<body>")]
+async fn create_tenant_scan(
+    store: &State<Db>, cache: &State<idempotency::Idempotency>, idempotent: Idempotent<'_>,
+    negotiation: Negotiation<'_>, tenant: &str, body: LimitedBody,
+) -> Recorded {
+    create(store, cache, idempotent, negotiation, tenant, body).await
+}
+
+#[put("/<tenant>/scans", data="<body>")]
+async fn update_tenant_scan(
+    store: &State<Db>, negotiation: Negotiation<'_>, tenant: &str, body: LimitedBody,
+) -> Result<Status, Rejected> {
+    match store.write().await.update_record_in(tenant, negotiation.decode(&body)?) {
+        Err(STALE_SCAN) => Err(Rejected(problem::stale_scan())),
+        Err(_) => Ok(Status::BadRequest),
+        Ok(_) => Ok(Status::Ok),
+    }
+}
+
+#[delete("/<tenant>/scans/<ip>/<port>")]
+async fn delete_tenant_scan(store: &State<Db>, tenant: &str, ip: &str, port: i16) -> Status {
+    match store.write().await.delete_record_in(tenant, ip, port) {
+        Err(_) => Status::BadRequest,
+        Ok(_) => Status::Ok,
+    }
+}
+
+// What a GraphQL request carries besides its body.
+struct GraphQlRequest<'r> {
+    method: &'r str,
+    query_string: Option<&'r str>,
+    accept: Option<&'r str>,
+    authorization: Option<&'r str>,
+    api_key: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for GraphQlRequest<'r> {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+        request::Outcome::Success(GraphQlRequest {
+            method: req.method().as_str(),
+            query_string: req.uri().query().map(|query| query.as_str()),
+            accept: req.headers().get_one(negotiate::ACCEPT_HEADER),
+            authorization: req.headers().get_one(auth::AUTHORIZATION_HEADER),
+            api_key: req.headers().get_one(auth::API_KEY_HEADER),
+        })
+    }
+}
+
+impl GraphQlRequest<'_> {
+    async fn execute(&self, graphql: &graphql::GraphQl, body: &[u8]) -> GraphQlReply {
+        GraphQlReply(graphql.execute(graphql::HttpRequest {
+            method: self.method,
+            query_string: self.query_string,
+            body,
+            accept: self.accept,
+            authorization: self.authorization,
+            api_key: self.api_key,
+        }).await)
+    }
+}
+
+struct GraphQlReply(Result<graphql::Reply, Problem>);
+
+impl<'r> Responder<'r, 'r> for GraphQlReply {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        match self.0 {
+            Err(problem) => Rejected(problem).respond_to(req),
+            Ok(graphql::Reply::Json(body)) => Response::build()
+                .raw_header("content-type", graphql::JSON)
+                .sized_body(body.len(), std::io::Cursor::new(body))
+                .ok(),
+            Ok(graphql::Reply::Events(events)) => {
+                let mut res = ByteStream(events.map(|event| event.to_bytes())).respond_to(req)?;
+                res.set_raw_header("content-type", graphql::EVENT_STREAM);
+                res.set_raw_header("cache-control", "no-cache");
+                Ok(res)
+            },
+        }
+    }
+}
+
+#[get("/")]
+async fn graphql_query(graphql: &State<graphql::GraphQl>, request: GraphQlRequest<'_>) -> GraphQlReply {
+    request.execute(graphql, &[]).await
+}
+
+#[post("/", data="<body>")]
+async fn graphql_post(graphql: &State<graphql::GraphQl>, request: GraphQlRequest<'_>, body: LimitedBody) -> GraphQlReply {
+    request.execute(graphql, &body).await
+}
+
+#[post("/snapshots")]
+async fn create_snapshot(
+    store: &State<Db>, snapshots: &State<admin::Snapshots>, uri: &Origin<'_>,
+) -> Result<(Status, Json<admin::Taken>), Rejected> {
+    match snapshots.take(store, uri.query().map(|query| query.as_str())).await {
+        Ok(taken) => Ok((Status::Created, Json(taken))),
+        Err(problem) => Err(Rejected(problem)),
+    }
+}
+
+#[get("/healthz")]
+fn healthz() -> Json<health::HealthReport> {
+    Json(health::liveness())
+}
+
+#[get("/readyz")]
+async fn readyz(store: &State<Db>, shutdown: &State<Shutdown>) -> (Status, Json<health::HealthReport>) {
+    let report = health::readiness(store, shutdown).await;
+    let status = match report.is_up() {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+
+    (status, Json(report))
+}
+
+#[get("/metrics")]
+async fn get_metrics(store: &State<Db>) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, metrics::render(store).await)
+}
 
-    rocket::custom(figment)
-        .manage(Arc::new(RwLock::new(Store::new())))
-        .mount("/v1/scans", routes![
+#[get("/openapi.json")]
+fn get_openapi() -> (ContentType, &'static str) {
+    (ContentType::JSON, openapi::json())
+}
+
+#[get("/docs")]
+fn get_docs() -> (ContentType, &'static str) {
+    (ContentType::HTML, openapi::DOCS_HTML)
+}
+
+// Rocket 0.5 can't be handed a listener, so with TLS enabled it serves plain
+// HTTP on a loopback port and connections are terminated in front of it. The
+// client's address is passed on with the backend's secret, replacing any
+// the client sent; see `tls::Backend`.
+async fn terminate_tls(mut incoming: tls::Incoming, backend: u16, secret: String, shutdown: Shutdown) {
+    let client = hyper::Client::new();
+    let secret = hyper::header::HeaderValue::from_str(&secret).expect("secret is a valid header value");
+    let make_service = make_service_fn(move |conn: &tls::Connection| {
+        let client = client.clone();
+        let secret = secret.clone();
+        let peer = conn.peer_addr().ip().to_string();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: hyper::Request<hyper::Body>| {
+                let client = client.clone();
+                let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                let uri = format!("http://127.0.0.1:{}{}", backend, path);
+                *req.uri_mut() = uri.parse().expect("request path is a valid uri");
+                let headers = req.headers_mut();
+                headers.remove(tls::REAL_IP_HEADER);
+                headers.remove(tls::TERMINATOR_HEADER);
+                if let Ok(peer) = hyper::header::HeaderValue::from_str(&peer) {
+                    headers.insert(tls::REAL_IP_HEADER, peer);
+                    headers.insert(tls::TERMINATOR_HEADER, secret.clone());
+                }
+
+                async move {
+                    match client.request(req).await {
+                        Ok(res) => Ok::<_, Infallible>(res),
+                        Err(err) => {
+                            tracing::warn!(error = %err, "failed to reach rocket");
+                            let mut res = hyper::Response::new(hyper::Body::empty());
+                            *res.status_mut() = hyper::StatusCode::BAD_GATEWAY;
+                            Ok(res)
+                        },
+                    }
+                }
+            }))
+        }
+    });
+
+    let accept = hyper::server::accept::poll_fn(move |cx| {
+        incoming.poll_accept(cx).map(|conn| conn.map(Ok::<_, Infallible>))
+    });
+    if let Err(err) = hyper::Server::builder(accept)
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.wait().await })
+        .await
+    {
+        tracing::error!(error = %err, "tls server error");
+    }
+}
+
+#[rocket::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::from_env()?.with_args(std::env::args().skip(1))?;
+    let _telemetry = telemetry::init("rocket", &config)?;
+    let store = Db::new(Store::from_config(&config)?);
+    store.warm_in_background();
+    store.compact_in_background(config.compact_interval, config.snapshot_dir.as_deref());
+    let snapshots = admin::Snapshots::from_config(&config);
+    snapshots.schedule(&store);
+    let authenticator = auth::Authenticator::from_config(&config)?;
+    let limiter = ratelimit::RateLimiter::from_config(&config);
+    let tls = tls::Tls::from_config(&config)?;
+    let shutdown = Shutdown::on_signals();
+    let grpc = grpc::start(&config, store.clone(), authenticator.clone(), limiter.clone(), shutdown.clone()).await?;
+    let validator = validation::Validator::from_config(&config);
+    let graphql = graphql::GraphQl::new(store.clone(), authenticator.clone(), validator, shutdown.clone());
+    let bodies = negotiate::BodyPolicy::from_config(&config);
+
+    // Signals are handled by `Shutdown` so every binary stops the same way;
+    // Rocket only provides the grace period for draining.
+    // Behind TLS, Rocket itself listens on any free loopback port.
+    let (port, backend) = match tls {
+        Some(_) => (0, tls::Backend::terminated()),
+        None => (8080, tls::Backend::direct()),
+    };
+    let figment = rocket::Config::figment()
+        .merge(("port", port))
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()))
+        .merge(("shutdown.grace", config.shutdown_timeout.as_secs()))
+        .merge(("limits.bytes", bodies.limit));
+
+    let (backend_tx, backend_rx) = rocket::tokio::sync::oneshot::channel();
+    let rocket = rocket::custom(figment)
+        .manage(store.clone())
+        .manage(shutdown.clone())
+        .manage(authenticator)
+        .manage(limiter)
+        .manage(backend.clone())
+        .manage(graphql)
+        .manage(idempotency::Idempotency::from_config(&config))
+        .manage(snapshots)
+        .manage(bodies)
+        .register("/", catchers![too_large])
+        .attach(RequestTelemetry)
+        .attach(AdHoc::on_liftoff("Backend port", |rocket| Box::pin(async move {
+            let _ = backend_tx.send(rocket.config().port);
+        })))
+        .mount("/", traced(routes![healthz, readyz, get_metrics, get_openapi, get_docs]))
+        .mount("/v1/scans", traced(guarded(routes![
                get_all_scans, get_scan, create_scan, update_scan, delete_scan,
-        ])
+        ])))
+        .mount("/v1/tenants", traced(guarded(routes![
+               get_all_tenant_scans, get_tenant_scan, create_tenant_scan, update_tenant_scan,
+               delete_tenant_scan,
+        ])))
+        .mount("/v1/graphql", traced(guarded(routes![graphql_query, graphql_post])))
+        .mount("/v1/admin", traced(guarded(routes![create_snapshot])))
+        .ignite()
+        .await?;
+
+    if let Some(tls) = &tls {
+        let incoming = tls.bind("127.0.0.1:8080").await?;
+        let shutdown = shutdown.clone();
+        let secret = backend.secret().expect("a terminated backend has a secret").to_owned();
+        rocket::tokio::spawn(async move {
+            if let Ok(backend) = backend_rx.await {
+                tracing::info!(addr = "127.0.0.1:8080", backend, "terminating tls");
+                terminate_tls(incoming, backend, secret, shutdown).await;
+            }
+        });
+    }
+
+    let handle = rocket.shutdown();
+    rocket::tokio::spawn(async move {
+        shutdown.wait().await;
+        tracing::info!("shutdown requested, draining in-flight requests");
+        handle.notify();
+    });
+
+    let _ = rocket.launch().await?;
+
+    if let Some(grpc) = grpc {
+        grpc.drain(config.shutdown_timeout).await;
+    }
+
+    tracing::info!("server stopped, flushing store");
+    store.write().await.flush()?;
+
+    Ok(())
 }
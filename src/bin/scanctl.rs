@@ -0,0 +1,149 @@
+use chrono::{DateTime, Utc};
+use data::version::SCAN_VERSION;
+use data::{newest_snapshot_seq, Compaction, Migrated, Plan, Snapshot, SnapshotFormat, Store, Target, Wal};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: scanctl <command> [args]
+
+commands:
+  migrate <log>...   rewrite mutation logs at the current scan schema version
+  compact (--snapshots <dir> | --all) <log>...
+                     rewrite mutation logs down to the entries of live scans,
+                     through the newest snapshot of each in <dir>, or through
+                     their last entry with --all
+  restore <log> (--seq <n> | --at <time>) --out <path> [--snapshots <dir>] [--format <format>]
+                     write a snapshot of the store as it was just after log
+                     entry <n>, or at an RFC 3339 <time>, rebuilt from the
+                     newest snapshot in <dir> before it and the log after that";
+
+// Offline maintenance of the files the services persist. Nothing here may run
+// against a file a service has open.
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.split_first() {
+        Some((command, paths)) if command == "migrate" && !paths.is_empty() => {
+            for path in paths {
+                migrate(Path::new(path))?;
+            }
+            Ok(ExitCode::SUCCESS)
+        },
+        Some((command, args)) if command == "compact" => {
+            let (snapshots, paths) = match args {
+                [flag, paths @ ..] if flag == "--all" => (None, paths),
+                [flag, dir, paths @ ..] if flag == "--snapshots" => (Some(Path::new(dir)), paths),
+                _ => (None, &[][..]),
+            };
+            if paths.is_empty() {
+                eprintln!("{}", USAGE);
+                return Ok(ExitCode::from(2));
+            }
+
+            for path in paths {
+                compact(Path::new(path), snapshots)?;
+            }
+            Ok(ExitCode::SUCCESS)
+        },
+        Some((command, args)) if command == "restore" => match Restore::parse(args) {
+            Some(restore) => {
+                restore.run()?;
+                Ok(ExitCode::SUCCESS)
+            },
+            None => {
+                eprintln!("{}", USAGE);
+                Ok(ExitCode::from(2))
+            },
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            Ok(ExitCode::from(2))
+        },
+    }
+}
+
+fn migrate(path: &Path) -> Result<(), Box<dyn Error>> {
+    let Migrated { entries, upgraded } = Wal::migrate(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    match upgraded {
+        0 => println!("{}: {} entries already at version {}", path.display(), entries, SCAN_VERSION),
+        _ => println!("{}: upgraded {} of {} entries to version {}", path.display(), upgraded, entries, SCAN_VERSION),
+    }
+    Ok(())
+}
+
+// Without `--all` a log is only compacted as far as a snapshot of it, so
+// point-in-time recovery can still reach every entry after that.
+fn compact(path: &Path, snapshots: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let through = match snapshots {
+        None => u64::MAX,
+        Some(dir) => match newest_snapshot_seq(dir, path).map_err(|e| e.to_string())? {
+            Some(through) => through,
+            None => {
+                println!("{}: no snapshot of this log in {}, not compacted", path.display(), dir.display());
+                return Ok(());
+            },
+        },
+    };
+    let Compaction { before, after, through } = Wal::compact(path, through)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    println!("{}: compacted {} entries into {} through sequence {}", path.display(), before, after, through);
+    Ok(())
+}
+
+struct Restore {
+    log: PathBuf,
+    target: Target,
+    out: PathBuf,
+    snapshots: Option<PathBuf>,
+    format: SnapshotFormat,
+}
+
+impl Restore {
+    fn parse(args: &[String]) -> Option<Restore> {
+        let (log, mut flags) = args.split_first()?;
+        let (mut target, mut out, mut snapshots, mut format) = (None, None, None, SnapshotFormat::Json);
+
+        while let [flag, value, rest @ ..] = flags {
+            match flag.as_str() {
+                "--seq" => target = Some(Target::Seq(value.parse().ok()?)),
+                "--at" => target = Some(Target::At(value.parse::<DateTime<Utc>>().ok()?)),
+                "--out" => out = Some(PathBuf::from(value)),
+                "--snapshots" => snapshots = Some(PathBuf::from(value)),
+                "--format" => format = value.parse().ok()?,
+                _ => return None,
+            }
+            flags = rest;
+        }
+
+        match flags.is_empty() {
+            true => Some(Restore { log: PathBuf::from(log), target: target?, out: out?, snapshots, format }),
+            false => None,
+        }
+    }
+
+    // Only reads the log and snapshots, so it can run next to the service
+    // that writes them.
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let plan = Plan::new(&self.log, self.snapshots.as_deref(), self.target).map_err(|e| e.to_string())?;
+        let (seq, log_id, replayed) = (plan.seq, plan.log_id, plan.replayed());
+        let base = match &plan.snapshot {
+            Some(path) => format!("{} and ", path.display()),
+            None => String::new(),
+        };
+
+        let store = Store::recover(plan)?;
+        let snapshot = Snapshot { seq, log_id, ..store.snapshot() };
+        snapshot.write_to(&self.out, self.format)?;
+
+        println!(
+            "{}: restored {} scans as of sequence {} from {}{} log entries",
+            self.out.display(), snapshot.len(), seq, base, replayed,
+        );
+        Ok(())
+    }
+}
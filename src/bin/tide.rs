@@ -1,40 +1,247 @@
-use data::Store;
-use tide::{Body,Request,Response};
-use tokio::sync::RwLock;
-use std::sync::Arc;
+use data::{admin,auth,graphql,grpc,health,idempotency,metrics,negotiate,openapi,problem,ratelimit,telemetry,tenant,tls,validation,Config,Db,InFlight,Shutdown,Store,QUOTA_EXCEEDED,STALE_SCAN};
+use data::problem::Problem;
+use data::telemetry::{RequestContext,REQUEST_ID_HEADER,TRACEPARENT_HEADER};
+use futures_util::{AsyncReadExt,StreamExt,TryStreamExt};
+use tide::{Body,Next,Request,Response};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use std::task::{Context,Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead,AsyncWrite,ReadBuf};
+use tracing::Instrument;
 
-type Db = Arc<RwLock<Store>>;
+// Tide has no graceful shutdown of its own, so count requests in flight and
+// wait for them to finish once we stop listening.
+struct TrackInFlight(InFlight);
 
-async fn get_all_scans(req: Request<Db>) -> Result<Body, tide::Error> {
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for TrackInFlight {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let _guard = self.0.start();
+        Ok(next.run(req).await)
+    }
+}
+
+struct RequestTelemetry;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for RequestTelemetry {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.url().path().to_owned();
+        let ctx = RequestContext::from_headers(
+            req.header(REQUEST_ID_HEADER).map(|v| v.last().as_str()),
+            req.header(TRACEPARENT_HEADER).map(|v| v.last().as_str()),
+        );
+        let span = telemetry::request_span(&method, &path);
+        ctx.record(&span);
+
+        let mut res = next.run(req).instrument(span.clone()).await;
+        let status = res.status().into();
+        metrics::global().observe_request(&method, &path, status, start.elapsed());
+        telemetry::finish(&span, status, start.elapsed());
+
+        for (name, value) in ctx.response_headers() {
+            res.insert_header(name, value);
+        }
+
+        Ok(res)
+    }
+}
+
+// Rejects requests without valid credentials, or without the role their
+// method needs, and charges each request to its client's bucket. Callers are
+// authenticated before they are charged, so a failed attempt is charged to
+// its address.
+struct Guard(auth::Authenticator, ratelimit::RateLimiter);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Guard {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !auth::is_protected(req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+
+        let authorized = self.0.authorize(
+            req.method().as_ref(),
+            req.url().path(),
+            req.header(auth::AUTHORIZATION_HEADER).map(|v| v.last().as_str()),
+            req.header(auth::API_KEY_HEADER).map(|v| v.last().as_str()),
+        );
+        let ip = req.peer_addr()
+            .and_then(|addr| addr.parse::<std::net::SocketAddr>().ok())
+            .map(|addr| addr.ip());
+        let decision = self.1.check(req.method().as_ref(), authorized.as_ref().ok(), ip);
+
+        let mut res = match (&decision, authorized) {
+            (Some(decision), _) if !decision.allowed => Response::builder(tide::StatusCode::TooManyRequests)
+                .content_type(problem::CONTENT_TYPE)
+                .body(decision.problem().body())
+                .build(),
+            (_, Err(err)) => {
+                let status = tide::StatusCode::try_from(err.status())
+                    .unwrap_or(tide::StatusCode::Unauthorized);
+                let mut res = Response::builder(status)
+                    .content_type(problem::CONTENT_TYPE)
+                    .body(err.problem().body());
+                if let Some(challenge) = err.challenge() {
+                    res = res.header("www-authenticate", challenge);
+                }
+                res.build()
+            },
+            (_, Ok(_)) => next.run(req).await,
+        };
+
+        for (name, value) in decision.iter().flat_map(|d| d.headers()) {
+            res.insert_header(name, value);
+        }
+
+        Ok(res)
+    }
+}
+
+// Tide has no body limit of its own. This refuses bodies that announce
+// themselves as too large and leaves the policy for `read_body` to enforce
+// on the rest.
+struct LimitBodies(negotiate::BodyPolicy);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for LimitBodies {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(Err(problem)) = req.len().map(|len| self.0.check_len(len)) {
+            return Ok(problem_response(&problem));
+        }
+
+        req.set_ext(self.0);
+        Ok(next.run(req).await)
+    }
+}
+
+async fn read_body(req: &mut Request<Db>) -> Result<Vec<u8>, Problem> {
+    let bodies = *req.ext::<negotiate::BodyPolicy>().expect("body policy is set");
+    let mut body = Vec::new();
+    req.take_body()
+        .take(bodies.limit as u64 + 1)
+        .read_to_end(&mut body).await
+        .map_err(|e| Problem::new(400, "Bad Request").with_detail(e.to_string()))?;
+
+    bodies.check_len(body.len())?;
+    Ok(body)
+}
+
+// Routes under `/v1/tenants/:tenant` name their tenant; the plain `/v1`
+// routes belong to the default one.
+fn tenant(req: &Request<Db>) -> &str {
+    req.param("tenant").unwrap_or(tenant::DEFAULT_TENANT)
+}
+
+fn header<'a>(req: &'a Request<Db>, name: &str) -> Option<&'a str> {
+    req.header(name).map(|v| v.last().as_str())
+}
+
+fn problem_response(problem: &Problem) -> Response {
+    let status = tide::StatusCode::try_from(problem.status).unwrap_or(tide::StatusCode::BadRequest);
+    Response::builder(status)
+        .content_type(problem::CONTENT_TYPE)
+        .body(problem.body())
+        .build()
+}
+
+fn negotiated(reply: Result<negotiate::Reply, Problem>) -> Response {
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(problem) => return problem_response(&problem),
+    };
+
+    let mut res = Response::builder(tide::StatusCode::Ok);
+    for (name, value) in reply.headers() {
+        res = res.header(name, value);
+    }
+    res.body(reply.body).build()
+}
+
+fn recorded(recorded: idempotency::Recorded) -> Response {
+    let status = tide::StatusCode::try_from(recorded.status).unwrap_or(tide::StatusCode::Ok);
+    let mut res = Response::builder(status);
+    for (name, value) in recorded.headers() {
+        res = res.header(name, value);
+    }
+    res.body(recorded.body).build()
+}
+
+fn idempotent<'a>(req: &'a Request<Db>, body: &'a [u8]) -> idempotency::Request<'a> {
+    idempotency::Request {
+        key: header(req, idempotency::HEADER),
+        path: req.url().path(),
+        authorization: header(req, auth::AUTHORIZATION_HEADER),
+        api_key: header(req, auth::API_KEY_HEADER),
+        content_type: header(req, negotiate::CONTENT_TYPE_HEADER),
+        body,
+    }
+}
+
+async fn scan_body(req: &mut Request<Db>) -> Result<data::Scan, Problem> {
+    let body = read_body(req).await?;
+    let bodies = req.ext::<negotiate::BodyPolicy>().expect("body policy is set");
+    bodies.decode_scan(header(req, negotiate::CONTENT_TYPE_HEADER), &body)
+}
+
+async fn get_all_scans(req: Request<Db>) -> tide::Result<tide::Response> {
     let store = req.state();
-    let res = store.read().await.get_all();
+    let (accept, accept_encoding) = (header(&req, negotiate::ACCEPT_HEADER), header(&req, negotiate::ACCEPT_ENCODING_HEADER));
+    let reply = match negotiate::sort_key(req.url().query()) {
+        Ok(sort) => negotiate::scans_reply(accept, accept_encoding, &store.read().await.get_all_sorted_in(tenant(&req), sort)),
+        Err(problem) => Err(problem),
+    };
 
-    Body::from_json(&res)
+    Ok(negotiated(reply))
 }
 
-async fn get_scan(req: Request<Db>) -> Result<Body, tide::Error> {
+async fn get_scan(req: Request<Db>) -> tide::Result<tide::Response> {
     let store = req.state();
     let ip: &str = req.param("ip")?;
     let port: i16 = req.param("port")?.parse()?;
 
-    let res = store.read().await.get_record(ip, port);
+    let res = store.read().await.get_record_in(tenant(&req), ip, port);
 
-    Body::from_json(&res)
+    Ok(negotiated(negotiate::scan_reply(
+        header(&req, negotiate::ACCEPT_HEADER), header(&req, negotiate::ACCEPT_ENCODING_HEADER), res.as_ref(),
+    )))
 }
 
-async fn create_scan(mut req: Request<Db>) -> tide::Result<tide::Response> {
-    let scan = req.body_json().await?;
-    let store = req.state();
-    match store.write().await.insert_record(scan) {
-        Err(_) => Ok(Response::builder(tide::StatusCode::BadRequest).build()),
-        Ok(_) => Ok(Response::builder(tide::StatusCode::Created).build()),
-    }
+async fn create_scan(mut req: Request<Db>, cache: idempotency::Idempotency) -> tide::Result<tide::Response> {
+    let body = match read_body(&mut req).await {
+        Ok(body) => body,
+        Err(problem) => return Ok(problem_response(&problem)),
+    };
+    let bodies = req.ext::<negotiate::BodyPolicy>().expect("body policy is set");
+
+    let res = cache.run(idempotent(&req, &body), async {
+        let scan = match bodies.decode_scan(header(&req, negotiate::CONTENT_TYPE_HEADER), &body) {
+            Ok(scan) => scan,
+            Err(problem) => return idempotency::Recorded::problem(&problem),
+        };
+
+        match req.state().write().await.insert_record_in(tenant(&req), scan) {
+            Err(QUOTA_EXCEEDED) => idempotency::Recorded::problem(&problem::quota_exceeded()),
+            Err(_) => idempotency::Recorded::new(400),
+            Ok(_) => idempotency::Recorded::new(201),
+        }
+    }).await;
+
+    Ok(recorded(res))
 }
 
 async fn update_scan(mut req: Request<Db>) -> tide::Result<tide::Response> {
-    let scan = req.body_json().await?;
+    let scan = match scan_body(&mut req).await {
+        Ok(scan) => scan,
+        Err(problem) => return Ok(problem_response(&problem)),
+    };
     let store = req.state();
-    match store.write().await.update_record(scan) {
+    match store.write().await.update_record_in(tenant(&req), scan) {
+        Err(STALE_SCAN) => Ok(problem_response(&problem::stale_scan())),
         Err(_) => Ok(Response::builder(tide::StatusCode::BadRequest).build()),
         Ok(_) => Ok(Response::builder(tide::StatusCode::Ok).build()),
     }
@@ -45,26 +252,223 @@ async fn delete_scan(req: Request<Db>) -> tide::Result<tide::Response> {
     let ip: &str = req.param("ip")?;
     let port: i16 = req.param("port")?.parse()?;
 
-    match store.write().await.delete_record(ip, port) {
+    match store.write().await.delete_record_in(tenant(&req), ip, port) {
         Err(_) => Ok(Response::builder(tide::StatusCode::BadRequest).build()),
         Ok(_) => Ok(Response::builder(tide::StatusCode::Ok).build()),
     }
 }
 
+// Events are forwarded through a channel because tide needs a body it can
+// share between threads. Forwarding stops when the stream ends, which it does
+// at shutdown, or once the client has gone away.
+fn event_stream(mut events: futures_util::stream::BoxStream<'static, graphql::Event>) -> Body {
+    let (tx, rx) = async_std::channel::bounded::<Vec<u8>>(16);
+    async_std::task::spawn(async move {
+        while let Some(event) = events.next().await {
+            if tx.send(event.to_bytes()).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let reader = rx.map(Ok::<_, io::Error>).into_async_read();
+    Body::from_reader(async_std::io::BufReader::new(reader), None)
+}
+
+async fn graphql_endpoint(mut req: Request<Db>, graphql: graphql::GraphQl) -> tide::Result<tide::Response> {
+    let body = match read_body(&mut req).await {
+        Ok(body) => body,
+        Err(problem) => return Ok(problem_response(&problem)),
+    };
+    let reply = graphql.execute(graphql::HttpRequest {
+        method: req.method().as_ref(),
+        query_string: req.url().query(),
+        body: &body,
+        accept: header(&req, negotiate::ACCEPT_HEADER),
+        authorization: header(&req, auth::AUTHORIZATION_HEADER),
+        api_key: header(&req, auth::API_KEY_HEADER),
+    }).await;
+
+    Ok(match reply {
+        Err(problem) => problem_response(&problem),
+        Ok(graphql::Reply::Json(body)) => Response::builder(tide::StatusCode::Ok)
+            .content_type(graphql::JSON)
+            .body(body)
+            .build(),
+        Ok(graphql::Reply::Events(events)) => Response::builder(tide::StatusCode::Ok)
+            .content_type(graphql::EVENT_STREAM)
+            .header("cache-control", "no-cache")
+            .body(event_stream(events))
+            .build(),
+    })
+}
+
+async fn create_snapshot(req: Request<Db>, snapshots: admin::Snapshots) -> tide::Result<tide::Response> {
+    Ok(match snapshots.take(req.state(), req.url().query()).await {
+        Ok(taken) => Response::builder(tide::StatusCode::Created).body(Body::from_json(&taken)?).build(),
+        Err(problem) => problem_response(&problem),
+    })
+}
+
+async fn healthz(_req: Request<()>) -> Result<Body, tide::Error> {
+    Body::from_json(&health::liveness())
+}
+
+async fn readyz(store: Db, shutdown: Shutdown) -> tide::Result<tide::Response> {
+    let report = health::readiness(&store, &shutdown).await;
+    let status = match report.is_up() {
+        true => tide::StatusCode::Ok,
+        false => tide::StatusCode::ServiceUnavailable,
+    };
+
+    Ok(Response::builder(status).body(Body::from_json(&report)?).build())
+}
+
+async fn get_metrics(store: Db) -> tide::Result<tide::Response> {
+    let body = metrics::render(&store).await;
+    Ok(Response::builder(tide::StatusCode::Ok).content_type(metrics::CONTENT_TYPE).body(body).build())
+}
+
+async fn get_openapi(_req: Request<()>) -> tide::Result<tide::Response> {
+    Ok(Response::builder(tide::StatusCode::Ok).content_type(tide::http::mime::JSON).body(openapi::json()).build())
+}
+
+async fn get_docs(_req: Request<()>) -> tide::Result<tide::Response> {
+    Ok(Response::builder(tide::StatusCode::Ok).content_type(tide::http::mime::HTML).body(openapi::DOCS_HTML).build())
+}
+
+// async-h1 reads requests and writes responses through separate clones of
+// the connection, so a TLS stream is shared behind a lock.
+#[derive(Clone)]
+struct TlsIo(Arc<Mutex<tls::Connection>>);
+
+impl async_std::io::Read for TlsIo {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut conn = self.0.lock().unwrap();
+        let mut buf = ReadBuf::new(buf);
+        Pin::new(&mut *conn).poll_read(cx, &mut buf).map_ok(|_| buf.filled().len())
+    }
+}
+
+impl async_std::io::Write for TlsIo {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_shutdown(cx)
+    }
+}
+
+// Tide only listens on plain sockets, so TLS connections are decoded with
+// async-h1 and handed to the app the way its own listener would.
+async fn serve(app: tide::Server<()>, tls: Option<tls::Tls>, addr: &str) -> io::Result<()> {
+    let mut incoming = match tls {
+        Some(tls) => tls.bind(addr).await?,
+        None => return app.listen(addr).await,
+    };
+
+    let local = incoming.local_addr();
+    while let Some(conn) = incoming.accept().await {
+        let app = app.clone();
+        let peer = conn.peer_addr();
+        tokio::spawn(async move {
+            let io = TlsIo(Arc::new(Mutex::new(conn)));
+            let res = async_h1::accept(io, |mut req| {
+                let app = app.clone();
+                async move {
+                    req.set_peer_addr(Some(peer));
+                    req.set_local_addr(Some(local));
+                    app.respond(req).await
+                }
+            }).await;
+
+            if let Err(err) = res {
+                tracing::debug!(%peer, error = %err, "connection closed with error");
+            }
+        });
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> tide::Result<()> {
-    let store: Db = Arc::new(RwLock::new(Store::new()));
+    let config = Config::from_env()?.with_args(std::env::args().skip(1))?;
+    let _telemetry = telemetry::init("tide", &config)?;
+    let store = Db::new(Store::from_config(&config)?);
+    store.warm_in_background();
+    store.compact_in_background(config.compact_interval, config.snapshot_dir.as_deref());
+    let snapshots = admin::Snapshots::from_config(&config);
+    snapshots.schedule(&store);
+    let authenticator = auth::Authenticator::from_config(&config)?;
+    let limiter = ratelimit::RateLimiter::from_config(&config);
+    let tls = tls::Tls::from_config(&config)?;
+    let shutdown = Shutdown::on_signals();
+    let grpc = grpc::start(&config, store.clone(), authenticator.clone(), limiter.clone(), shutdown.clone()).await?;
+    let validator = validation::Validator::from_config(&config);
+    let graphql = graphql::GraphQl::new(store.clone(), authenticator.clone(), validator, shutdown.clone());
+    let idempotency = idempotency::Idempotency::from_config(&config);
+    let in_flight = InFlight::default();
 
     let mut app = tide::new();
+    app.with(TrackInFlight(in_flight.clone()));
+    app.with(RequestTelemetry);
+    // Nested apps only see the path below their prefix, so the guard goes on
+    // the outer one to judge the whole path.
+    app.with(Guard(authenticator, limiter));
+
+    app.at("/healthz").get(healthz);
+    app.at("/readyz").get({
+        let store = store.clone();
+        let shutdown = shutdown.clone();
+        move |_| readyz(store.clone(), shutdown.clone())
+    });
+
+    app.at("/metrics").get({
+        let store = store.clone();
+        move |_| get_metrics(store.clone())
+    });
+
+    app.at("/openapi.json").get(get_openapi);
+    app.at("/docs").get(get_docs);
 
     app.at("/v1").nest({
-        let mut scans = tide::with_state(store);
-        scans.at("/scans").get(get_all_scans).post(create_scan).put(update_scan);
+        let mut scans = tide::with_state(store.clone());
+        scans.with(LimitBodies(negotiate::BodyPolicy::from_config(&config)));
+        let create = move |req| create_scan(req, idempotency.clone());
+        scans.at("/scans").get(get_all_scans).post(create.clone()).put(update_scan);
         scans.at("/scans/:ip/:port").get(get_scan).delete(delete_scan);
+        scans.at("/tenants/:tenant/scans").get(get_all_scans).post(create).put(update_scan);
+        scans.at("/tenants/:tenant/scans/:ip/:port").get(get_scan).delete(delete_scan);
+        let endpoint = move |req| graphql_endpoint(req, graphql.clone());
+        scans.at("/graphql").get(endpoint.clone()).post(endpoint);
+        scans.at("/admin/snapshots").post(move |req| create_snapshot(req, snapshots.clone()));
         scans
     });
 
-    app.listen("127.0.0.1:8080").await?;
+    tracing::info!(addr = "127.0.0.1:8080", tls = tls.is_some(), "listening");
+
+    tokio::select! {
+        res = serve(app, tls, "127.0.0.1:8080") => res?,
+        _ = shutdown.wait() => {
+            tracing::info!(in_flight = in_flight.count(), "shutdown requested, draining in-flight requests");
+            if tokio::time::timeout(config.shutdown_timeout, in_flight.idle()).await.is_err() {
+                tracing::warn!("timed out draining in-flight requests");
+            }
+        },
+    }
+
+    if let Some(grpc) = grpc {
+        grpc.drain(config.shutdown_timeout).await;
+    }
+
+    tracing::info!("server stopped, flushing store");
+    store.write().await.flush()?;
 
     Ok(())
 }
@@ -1,13 +1,28 @@
-use data::Scan;
+use data::{auth,ErrorKind,Scan};
+use reqwest::header::{HeaderMap,HeaderValue};
 use std::error::Error;
 use chrono::{Utc,TimeZone};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let client = reqwest::Client::new();
+    // Servers with authentication enabled need a key for the scan API.
+    let mut headers = HeaderMap::new();
+    if let Ok(key) = std::env::var("SCANS_API_KEY") {
+        headers.insert(auth::API_KEY_HEADER, HeaderValue::from_str(&key)?);
+    }
+    let mut client = reqwest::Client::builder().default_headers(headers);
+
+    // Servers with TLS enabled are reached over https, usually with a
+    // certificate from a private CA.
+    let base = std::env::var("SCANS_URL").unwrap_or_else(|_| "http://localhost:8080".to_owned());
+    if let Ok(path) = std::env::var("SCANS_CA_FILE") {
+        client = client.add_root_certificate(reqwest::Certificate::from_pem(&std::fs::read(path)?)?);
+    }
+    let client = client.build()?;
+    let url = |path: &str| format!("{}{}", base.trim_end_matches('/'), path);
 
     // Check that scans are empty
-    let resp = client.get("http://localhost:8080/v1/scans")
+    let resp = client.get(url("/v1/scans"))
         .send()
         .await?
         .json::<Vec<Scan>>()
@@ -16,19 +31,19 @@ async fn main() -> Result<(), Box<dyn Error>> {
     assert_eq!(resp.len(), 0, "micro service instance is not in correct state");
 
     let scan0_body = "{\"ip\":\"8.8.8.8\",\"port\":80,\
-        \"content_hash\":\"foobar\",\"load_time_nanosec\":100,\
+        \"content_hash\":\"73d1a9ab21fce25e\",\"load_time_nanosec\":100,\
         \"timestamp\":\"2022-07-31T16:26:16Z\"}";
 
     let scan1_body = "{\"ip\":\"1.1.1.1\",\"port\":443,\
-        \"content_hash\":\"barfoo\",\"load_time_nanosec\":231,\
+        \"content_hash\":\"e52ecf12ba9a1d37\",\"load_time_nanosec\":231,\
         \"timestamp\":\"2022-06-20T17:10:32Z\"}";
 
     let scan1_update = "{\"ip\":\"1.1.1.1\",\"port\":443,\
-        \"content_hash\":\"bazbarfoo\",\"load_time_nanosec\":8912,\
+        \"content_hash\":\"9f86d081884c7d65\",\"load_time_nanosec\":8912,\
         \"timestamp\":\"2022-06-20T17:10:32Z\"}";
 
     // Create Scan 0
-    let resp = client.post("http://localhost:8080/v1/scans")
+    let resp = client.post(url("/v1/scans"))
         .body(scan0_body)
         .header("Content-Type", "application/json")
         .send()
@@ -37,7 +52,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     assert_eq!(resp.status(), 201, "create scan 0 should have correct status");
 
     // Create Scan 0 again
-    let resp = client.post("http://localhost:8080/v1/scans")
+    let resp = client.post(url("/v1/scans"))
         .body(scan0_body)
         .header("Content-Type", "application/json")
         .send()
@@ -46,7 +61,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     assert_eq!(resp.status(), 400, "second create scan 0 should have failed status");
 
     // Create Scan 1
-    let resp = client.post("http://localhost:8080/v1/scans")
+    let resp = client.post(url("/v1/scans"))
         .body(scan1_body)
         .header("Content-Type", "application/json")
         .send()
@@ -56,7 +71,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Read All Scans
     // Check that scans are empty
-    let resp = client.get("http://localhost:8080/v1/scans")
+    let resp = client.get(url("/v1/scans"))
         .send()
         .await?
         .json::<Vec<Scan>>()
@@ -64,11 +79,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     assert_eq!(resp.len(), 2, "creates did not work");
 
-    assert_eq!(resp.get(0).unwrap().ip, "1.1.1.1", "scans should be ordered correctly");
+    assert_eq!(resp.first().unwrap().ip, "1.1.1.1", "scans should be ordered correctly");
 
     // Read Scan 0
 
-    let resp = client.get("http://localhost:8080/v1/scans/8.8.8.8/80")
+    let resp = client.get(url("/v1/scans/8.8.8.8/80"))
         .send()
         .await?
         .json::<Option<Scan>>()
@@ -79,16 +94,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let scan = resp.unwrap();
     assert_eq!(scan.ip, "8.8.8.8", "ip should be correct");
     assert_eq!(scan.port, 80, "port should be correct");
-    assert_eq!(scan.content_hash, "foobar", "content hash should be correct");
+    assert_eq!(scan.content_hash, "73d1a9ab21fce25e", "content hash should be correct");
     assert_eq!(scan.load_time_nanosec, 100, "load time should be correct");
     assert_eq!(
         scan.timestamp,
-        Utc.ymd(2022, 7, 31).and_hms(16, 26, 16), 
+        Utc.with_ymd_and_hms(2022, 7, 31, 16, 26, 16).unwrap(), 
         "timestamp should be correct"
     );
 
     // Read Scan 1
-    let resp = client.get("http://localhost:8080/v1/scans/1.1.1.1/443")
+    let resp = client.get(url("/v1/scans/1.1.1.1/443"))
         .send()
         .await?
         .json::<Option<Scan>>()
@@ -99,16 +114,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let scan = resp.unwrap();
     assert_eq!(scan.ip, "1.1.1.1", "ip should be correct");
     assert_eq!(scan.port, 443, "port should be correct");
-    assert_eq!(scan.content_hash, "barfoo", "content hash should be correct");
+    assert_eq!(scan.content_hash, "e52ecf12ba9a1d37", "content hash should be correct");
     assert_eq!(scan.load_time_nanosec, 231, "load time should be correct");
     assert_eq!(
         scan.timestamp,
-        Utc.ymd(2022, 6, 20).and_hms(17, 10, 32), 
+        Utc.with_ymd_and_hms(2022, 6, 20, 17, 10, 32).unwrap(), 
         "timestamp should be correct"
     );
 
     // Read no scan
-    let resp = client.get("http://localhost:8080/v1/scans/1.1.1.1/80")
+    let resp = client.get(url("/v1/scans/1.1.1.1/80"))
         .send()
         .await?
         .json::<Option<Scan>>()
@@ -117,7 +132,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     assert!(resp.is_none(), "should return null for no scan");
 
     // Update Scan 1
-    let resp = client.put("http://localhost:8080/v1/scans")
+    let resp = client.put(url("/v1/scans"))
         .body(scan1_update)
         .header("Content-Type", "application/json")
         .send()
@@ -125,7 +140,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     assert_eq!(resp.status(), 200, "update should have a successful response");
 
-    let resp = client.get("http://localhost:8080/v1/scans/1.1.1.1/443")
+    let resp = client.get(url("/v1/scans/1.1.1.1/443"))
         .send()
         .await?
         .json::<Option<Scan>>()
@@ -136,20 +151,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let scan = resp.unwrap();
     assert_eq!(scan.ip, "1.1.1.1", "ip should be correct");
     assert_eq!(scan.port, 443, "port should be correct");
-    assert_eq!(scan.content_hash, "bazbarfoo", "content hash should be correct");
+    assert_eq!(scan.content_hash, "9f86d081884c7d65", "content hash should be correct");
     assert_eq!(scan.load_time_nanosec, 8912, "load time should be correct");
     assert_eq!(
         scan.timestamp,
-        Utc.ymd(2022, 6, 20).and_hms(17, 10, 32), 
+        Utc.with_ymd_and_hms(2022, 6, 20, 17, 10, 32).unwrap(), 
         "timestamp should be correct"
     );
 
     // Update non existant scan
     let scan0_non = "{\"ip\":\"8.8.8.8\",\"port\":443,\
-        \"content_hash\":\"bazbarfoo\",\"load_time_nanosec\":8912,\
+        \"content_hash\":\"9f86d081884c7d65\",\"load_time_nanosec\":8912,\
         \"timestamp\":\"2022-06-20T17:10:32Z\"}";
 
-    let resp = client.put("http://localhost:8080/v1/scans")
+    let resp = client.put(url("/v1/scans"))
         .body(scan0_non)
         .header("Content-Type", "application/json")
         .send()
@@ -157,21 +172,66 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     assert_eq!(resp.status(), 400, "updating non existing record should fail");
 
+    // Create an invalid scan
+    let invalid = "{\"ip\":\"not-an-ip\",\"port\":80,\
+        \"content_hash\":\"73d1a9ab21fce25e\",\"load_time_nanosec\":-1,\
+        \"timestamp\":\"2022-06-20T17:10:32Z\"}";
+
+    let resp = client.post(url("/v1/scans"))
+        .body(invalid)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+
+    assert_eq!(resp.status(), 422, "creating an invalid scan should fail");
+
+    // Create and read a scan with the optional fields
+    let detailed = "{\"ip\":\"9.9.9.9\",\"port\":443,\
+        \"content_hash\":\"\",\"load_time_nanosec\":5000000,\
+        \"timestamp\":\"2022-06-20T17:10:32Z\",\"protocol\":\"h2\",\
+        \"http_status\":503,\"headers\":{\"server\":\"nginx\"},\
+        \"tls\":{\"version\":\"TLSv1.3\"},\"error\":\"timeout\"}";
+
+    let resp = client.post(url("/v1/scans"))
+        .body(detailed)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+
+    assert_eq!(resp.status(), 201, "creating a detailed scan should succeed");
+
+    let scan = client.get(url("/v1/scans/9.9.9.9/443"))
+        .send()
+        .await?
+        .json::<Option<Scan>>()
+        .await?
+        .expect("detailed scan should exist");
+
+    assert_eq!(scan.http_status, Some(503), "http status should be correct");
+    assert_eq!(scan.headers.get("server").map(String::as_str), Some("nginx"), "headers should be correct");
+    assert_eq!(scan.error, Some(ErrorKind::Timeout), "error should be correct");
+
+    let resp = client.delete(url("/v1/scans/9.9.9.9/443"))
+        .send()
+        .await?;
+
+    assert_eq!(resp.status(), 200, "deleting the detailed scan should succeed");
+
     // Delete Scans
-    let resp = client.delete("http://localhost:8080/v1/scans/8.8.8.8/80")
+    let resp = client.delete(url("/v1/scans/8.8.8.8/80"))
         .send()
         .await?;
 
     assert_eq!(resp.status(), 200, "deleting scan 0 should succeed");
 
-    let resp = client.delete("http://localhost:8080/v1/scans/1.1.1.1/443")
+    let resp = client.delete(url("/v1/scans/1.1.1.1/443"))
         .send()
         .await?;
 
     assert_eq!(resp.status(), 200, "deleting scan 1 should succeed");
 
     // Check that scans are empty
-    let resp = client.get("http://localhost:8080/v1/scans")
+    let resp = client.get(url("/v1/scans"))
         .send()
         .await?
         .json::<Vec<Scan>>()
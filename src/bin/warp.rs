@@ -1,29 +1,98 @@
-use data::Store;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use data::{admin,auth,graphql,grpc,idempotency,metrics,negotiate,ratelimit,telemetry,tls,validation,Config,Db,Shutdown,Store};
+use data::telemetry::{RequestContext,REQUEST_ID_HEADER,TRACEPARENT_HEADER};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use warp::{Filter,Reply};
+use warp::http::header::{HeaderName,HeaderValue};
+use warp::hyper::service::{make_service_fn,service_fn,Service};
 
-pub type Db = Arc<RwLock<Store>>;
+#[derive(Clone, Copy)]
+struct PeerAddr(SocketAddr);
 
 mod filters {
-    use super::{handlers,Db};
-    use data::Scan;
+    use super::{handlers,Db,PeerAddr};
+    use data::{admin,auth,graphql,idempotency,negotiate,ratelimit,tenant,Scan,Shutdown};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
     use warp::{Filter,Reply,Rejection};
 
+    pub fn health(
+        store: Db, shutdown: Shutdown,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let healthz = warp::path!("healthz")
+            .and(warp::get())
+            .and_then(handlers::healthz);
+
+        let readyz = warp::path!("readyz")
+            .and(warp::get())
+            .and(with_store(store))
+            .and(warp::any().map(move || shutdown.clone()))
+            .and_then(handlers::readyz);
+
+        healthz.or(readyz)
+    }
+
+    pub fn metrics(
+        store: Db,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("metrics")
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::get_metrics)
+    }
+
+    pub fn docs() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let spec = warp::path!("openapi.json")
+            .and(warp::get())
+            .and_then(handlers::get_openapi);
+
+        let page = warp::path!("docs")
+            .and(warp::get())
+            .and_then(handlers::get_docs);
+
+        spec.or(page)
+    }
+
     pub fn scans(
-        store: Db
+        store: Db, authenticator: auth::Authenticator, limiter: ratelimit::RateLimiter,
+        graphql: graphql::GraphQl, idempotency: idempotency::Idempotency, bodies: negotiate::BodyPolicy,
+        snapshots: admin::Snapshots,
     ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        scans_list(store.clone())
-            .or(scan_read(store.clone()))
-            .or(scan_create(store.clone()))
-            .or(scan_update(store.clone()))
-            .or(scan_delete(store.clone()))
+        guarded(authenticator, limiter)
+            .and(
+                scans_list(store.clone())
+                    .or(scan_read(store.clone()))
+                    .or(scan_create(store.clone(), idempotency, bodies))
+                    .or(scan_update(store.clone(), bodies))
+                    .or(scan_delete(store.clone()))
+                    .or(graphql_endpoint(graphql, bodies))
+                    .or(snapshot_create(store.clone(), snapshots))
+            )
+            .map(handlers::with_rate_limit_headers)
+    }
+
+    fn guarded(
+        authenticator: auth::Authenticator, limiter: ratelimit::RateLimiter,
+    ) -> impl Filter<Extract = (Option<ratelimit::Decision>,), Error = Rejection> + Clone {
+        warp::method()
+            .and(warp::path::full())
+            .and(warp::header::optional::<String>(auth::AUTHORIZATION_HEADER))
+            .and(warp::header::optional::<String>(auth::API_KEY_HEADER))
+            .and(remote_addr())
+            .and(warp::any().map(move || (authenticator.clone(), limiter.clone())))
+            .and_then(handlers::guard)
     }
 
     pub fn scans_list(
         store: Db
     ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("v1" / "scans")
+        scans_path()
+            .and(warp::path::end())
             .and(warp::get())
+            .and(query_string())
+            .and(accepts())
             .and(with_store(store))
             .and_then(handlers::get_all_scans)
     }
@@ -31,28 +100,54 @@ mod filters {
     pub fn scan_read(
         store: Db,
     ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("v1" / "scans" / String / i16)
+        scans_path()
+            .and(warp::path::param::<String>())
+            .and(warp::path::param::<i16>())
+            .and(warp::path::end())
             .and(warp::get())
+            .and(accepts())
             .and(with_store(store))
             .and_then(handlers::get_scan)
     }
 
+    // Creates are decoded by the handler rather than `scan_body`, so a
+    // retried request is replayed before its body is looked at.
     pub fn scan_create(
-        store: Db,
+        store: Db, idempotency: idempotency::Idempotency, bodies: negotiate::BodyPolicy,
     ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("v1" / "scans")
+        scans_path()
+            .and(warp::path::end())
             .and(warp::post())
-            .and(json_body())
+            .and(idempotent())
+            .and(body(bodies))
             .and(with_store(store))
+            .and(warp::any().map(move || idempotency.clone()))
+            .and(warp::any().map(move || bodies))
             .and_then(handlers::create_scan)
     }
 
+    fn idempotent() -> impl Filter<Extract = (handlers::Idempotent,), Error = Rejection> + Clone {
+        warp::path::full()
+            .and(warp::header::optional::<String>(idempotency::HEADER))
+            .and(warp::header::optional::<String>(auth::AUTHORIZATION_HEADER))
+            .and(warp::header::optional::<String>(auth::API_KEY_HEADER))
+            .and(warp::header::optional::<String>(negotiate::CONTENT_TYPE_HEADER))
+            .map(|path: warp::path::FullPath, key, authorization, api_key, content_type| handlers::Idempotent {
+                path: path.as_str().to_owned(),
+                key,
+                authorization,
+                api_key,
+                content_type,
+            })
+    }
+
     pub fn scan_update(
-        store: Db,
+        store: Db, bodies: negotiate::BodyPolicy,
     ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("v1" / "scans")
+        scans_path()
+            .and(warp::path::end())
             .and(warp::put())
-            .and(json_body())
+            .and(scan_body(bodies))
             .and(with_store(store))
             .and_then(handlers::update_scan)
     }
@@ -60,76 +155,499 @@ mod filters {
     pub fn scan_delete(
         store: Db,
     ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("v1" / "scans" / String / i16)
+        scans_path()
+            .and(warp::path::param::<String>())
+            .and(warp::path::param::<i16>())
+            .and(warp::path::end())
             .and(warp::delete())
             .and(with_store(store))
             .and_then(handlers::delete_scan)
     }
 
+    pub fn graphql_endpoint(
+        graphql: graphql::GraphQl, bodies: negotiate::BodyPolicy,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        // Only POSTed requests have a body worth reading.
+        let get = warp::get().map(warp::hyper::body::Bytes::new);
+        let post = warp::post().and(body(bodies));
+
+        warp::path!("v1" / "graphql")
+            .and(get.or(post).unify())
+            .and(warp::method())
+            .and(query_string())
+            .and(warp::header::optional::<String>(negotiate::ACCEPT_HEADER))
+            .and(warp::header::optional::<String>(auth::AUTHORIZATION_HEADER))
+            .and(warp::header::optional::<String>(auth::API_KEY_HEADER))
+            .and(warp::any().map(move || graphql.clone()))
+            .and_then(handlers::graphql)
+    }
+
+    pub fn snapshot_create(
+        store: Db, snapshots: admin::Snapshots,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "admin" / "snapshots")
+            .and(warp::post())
+            .and(query_string())
+            .and(with_store(store))
+            .and(warp::any().map(move || snapshots.clone()))
+            .and_then(handlers::create_snapshot)
+    }
+
+    // Matches `/v1/scans` for the default tenant and `/v1/tenants/:tenant/scans`
+    // for a named one, extracting which it was.
+    fn scans_path() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        let named = warp::path("tenants").and(warp::path::param::<String>());
+        let default = warp::any().map(|| tenant::DEFAULT_TENANT.to_owned());
+
+        warp::path("v1")
+            .and(named.or(default).unify())
+            .and(warp::path("scans"))
+    }
+
+    // The raw query string, empty when the URL has none.
+    fn query_string() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+        warp::query::raw().or(warp::any().map(String::new)).unify()
+    }
+
+    // Connections served over TLS don't go through warp's own listener, so
+    // their peer address arrives as a request extension instead.
+    fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+        warp::addr::remote()
+            .and(warp::ext::optional::<PeerAddr>())
+            .map(|remote: Option<SocketAddr>, peer: Option<PeerAddr>| remote.or(peer.map(|p| p.0)))
+    }
+
     fn with_store(
         store: Db
-    ) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
+    ) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
         warp::any().map(move || store.clone())
     }
 
-    fn json_body() -> impl Filter<Extract = (Scan,), Error = warp::Rejection> + Clone {
-        // When accepting a body, we want a JSON body
-        // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    // The `Accept` and `Accept-Encoding` headers a response is negotiated
+    // from.
+    fn accepts() -> impl Filter<Extract = (Option<String>, Option<String>), Error = Rejection> + Clone {
+        warp::header::optional::<String>(negotiate::ACCEPT_HEADER)
+            .and(warp::header::optional::<String>(negotiate::ACCEPT_ENCODING_HEADER))
+    }
+
+    fn scan_body(bodies: negotiate::BodyPolicy) -> impl Filter<Extract = (Scan,), Error = warp::Rejection> + Clone {
+        // When accepting a body, we want a scan in one of the negotiated
+        // formats (and to reject huge payloads)...
+        warp::header::optional::<String>(negotiate::CONTENT_TYPE_HEADER)
+            .and(body(bodies))
+            .and(warp::any().map(move || bodies))
+            .and_then(handlers::decode_scan)
+    }
+
+    fn body(
+        bodies: negotiate::BodyPolicy,
+    ) -> impl Filter<Extract = (warp::hyper::body::Bytes,), Error = warp::Rejection> + Clone {
+        warp::body::content_length_limit(bodies.limit as u64)
+            .or_else(move |rejection| handlers::too_large(rejection, bodies))
+            .and(warp::body::bytes())
     }
 }
 
 mod handlers {
     use super::Db;
-    use data::Scan;
+    use data::{admin,auth,graphql,health,idempotency,metrics,negotiate,openapi,problem,ratelimit,Scan,Shutdown,QUOTA_EXCEEDED,STALE_SCAN};
+    use futures_util::StreamExt;
+    use data::problem::Problem;
     use std::convert::Infallible;
-    use warp::http::StatusCode;
+    use std::net::SocketAddr;
+    use warp::http::{Method,StatusCode};
+    use warp::path::FullPath;
+    use warp::http::HeaderValue;
+    use warp::{Rejection,Reply};
+
+    #[derive(Debug)]
+    struct Denied(auth::AuthError);
+
+    impl warp::reject::Reject for Denied {}
+
+    #[derive(Debug)]
+    struct Limited(ratelimit::Decision);
+
+    impl warp::reject::Reject for Limited {}
+
+    #[derive(Debug)]
+    struct Malformed(Problem);
+
+    impl warp::reject::Reject for Malformed {}
+
+    fn problem_response(problem: &Problem) -> warp::reply::Response {
+        let status = StatusCode::from_u16(problem.status).unwrap_or(StatusCode::BAD_REQUEST);
+        let reply = warp::reply::with_status(problem.body(), status);
+        warp::reply::with_header(reply, "content-type", problem::CONTENT_TYPE).into_response()
+    }
+
+    fn negotiated(reply: Result<negotiate::Reply, Problem>) -> warp::reply::Response {
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(problem) => return problem_response(&problem),
+        };
+
+        let headers = reply.headers();
+        let mut res = warp::reply::Response::new(reply.body.into());
+        for (name, value) in headers {
+            res.headers_mut().insert(name, HeaderValue::from_static(value));
+        }
+        res
+    }
+
+    // The parts of a create request its retries are recognised by.
+    pub struct Idempotent {
+        pub path: String,
+        pub key: Option<String>,
+        pub authorization: Option<String>,
+        pub api_key: Option<String>,
+        pub content_type: Option<String>,
+    }
+
+    impl Idempotent {
+        fn request<'a>(&'a self, body: &'a [u8]) -> idempotency::Request<'a> {
+            idempotency::Request {
+                key: self.key.as_deref(),
+                path: &self.path,
+                authorization: self.authorization.as_deref(),
+                api_key: self.api_key.as_deref(),
+                content_type: self.content_type.as_deref(),
+                body,
+            }
+        }
+    }
+
+    fn recorded(recorded: idempotency::Recorded) -> warp::reply::Response {
+        let headers = recorded.headers();
+        let mut res = warp::reply::Response::new(recorded.body.into());
+        *res.status_mut() = StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::OK);
+        for (name, value) in headers {
+            res.headers_mut().insert(name, HeaderValue::from_static(value));
+        }
+        res
+    }
+
+    pub async fn decode_scan(
+        content_type: Option<String>, body: warp::hyper::body::Bytes, bodies: negotiate::BodyPolicy,
+    ) -> Result<Scan, Rejection> {
+        bodies.decode_scan(content_type.as_deref(), &body)
+            .map_err(|problem| warp::reject::custom(Malformed(problem)))
+    }
+
+    // Gives bodies over the limit a problem response rather than warp's
+    // plain-text one.
+    pub async fn too_large(rejection: Rejection, bodies: negotiate::BodyPolicy) -> Result<(), Rejection> {
+        match rejection.find::<warp::reject::PayloadTooLarge>() {
+            Some(_) => Err(warp::reject::custom(Malformed(bodies.too_large()))),
+            None => Err(rejection),
+        }
+    }
+
+    // Authenticates the caller, then charges the request to the caller's
+    // bucket, or to their address when they didn't authenticate.
+    pub async fn guard(
+        method: Method, path: FullPath, authorization: Option<String>, api_key: Option<String>,
+        addr: Option<SocketAddr>, (authenticator, limiter): (auth::Authenticator, ratelimit::RateLimiter),
+    ) -> Result<Option<ratelimit::Decision>, Rejection> {
+        if !auth::is_protected(path.as_str()) {
+            return Ok(None);
+        }
+
+        let authorized = authenticator.authorize(
+            method.as_str(), path.as_str(), authorization.as_deref(), api_key.as_deref(),
+        );
+        match limiter.check(method.as_str(), authorized.as_ref().ok(), addr.map(|a| a.ip())) {
+            Some(decision) if !decision.allowed => Err(warp::reject::custom(Limited(decision))),
+            decision => authorized.map(|_| decision).map_err(|e| warp::reject::custom(Denied(e))),
+        }
+    }
+
+    pub fn with_rate_limit_headers(
+        decision: Option<ratelimit::Decision>, reply: impl Reply,
+    ) -> warp::reply::Response {
+        let mut res = reply.into_response();
+        for (name, value) in decision.iter().flat_map(|d| d.headers()) {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+        res
+    }
+
+    // Turns our own rejections into problem responses and leaves the rest to
+    // warp's defaults.
+    pub async fn recover(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+        if let Some(Limited(decision)) = rejection.find::<Limited>() {
+            let reply = warp::reply::with_status(
+                decision.problem().body(), StatusCode::TOO_MANY_REQUESTS,
+            );
+            let reply = warp::reply::with_header(reply, "content-type", problem::CONTENT_TYPE);
+            return Ok(with_rate_limit_headers(Some(decision.clone()), reply));
+        }
+
+        if let Some(Malformed(problem)) = rejection.find::<Malformed>() {
+            return Ok(problem_response(problem));
+        }
+
+        match rejection.find::<Denied>() {
+            Some(Denied(err)) => {
+                let status = StatusCode::from_u16(err.status()).unwrap_or(StatusCode::UNAUTHORIZED);
+                let reply = warp::reply::with_status(err.problem().body(), status);
+                let mut res = warp::reply::with_header(reply, "content-type", problem::CONTENT_TYPE)
+                    .into_response();
+                if let Some(challenge) = err.challenge() {
+                    res.headers_mut().insert("www-authenticate", HeaderValue::from_static(challenge));
+                }
+                Ok(res)
+            },
+            None => Err(rejection),
+        }
+    }
+
+    pub async fn graphql(
+        body: warp::hyper::body::Bytes, method: Method, query_string: String, accept: Option<String>,
+        authorization: Option<String>, api_key: Option<String>, graphql: graphql::GraphQl,
+    ) -> Result<warp::reply::Response, Infallible> {
+        let reply = graphql.execute(graphql::HttpRequest {
+            method: method.as_str(),
+            query_string: Some(&query_string),
+            body: &body,
+            accept: accept.as_deref(),
+            authorization: authorization.as_deref(),
+            api_key: api_key.as_deref(),
+        }).await;
+
+        let res = match reply {
+            Err(problem) => return Ok(problem_response(&problem)),
+            Ok(graphql::Reply::Json(body)) => {
+                let mut res = warp::reply::Response::new(body.into());
+                res.headers_mut().insert("content-type", HeaderValue::from_static(graphql::JSON));
+                res
+            },
+            Ok(graphql::Reply::Events(events)) => {
+                let body = events.map(|event| Ok::<_, Infallible>(event.to_bytes()));
+                let mut res = warp::reply::Response::new(warp::hyper::Body::wrap_stream(body));
+                res.headers_mut().insert("content-type", HeaderValue::from_static(graphql::EVENT_STREAM));
+                res.headers_mut().insert("cache-control", HeaderValue::from_static("no-cache"));
+                res
+            },
+        };
+        Ok(res)
+    }
 
-    pub async fn get_all_scans(store: Db) -> Result<impl warp::Reply, Infallible> {
-        let res = store.read().await.get_all();
-        Ok(warp::reply::json(&res))
+    pub async fn create_snapshot(
+        query_string: String, store: Db, snapshots: admin::Snapshots,
+    ) -> Result<warp::reply::Response, Infallible> {
+        match snapshots.take(&store, Some(&query_string)).await {
+            Ok(taken) => Ok(warp::reply::with_status(warp::reply::json(&taken), StatusCode::CREATED).into_response()),
+            Err(problem) => Ok(problem_response(&problem)),
+        }
+    }
+
+    pub async fn healthz() -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::json(&health::liveness()))
+    }
+
+    pub async fn readyz(
+        store: Db, shutdown: Shutdown,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let report = health::readiness(&store, &shutdown).await;
+        let status = match report.is_up() {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&report), status))
+    }
+
+    pub async fn get_metrics(store: Db) -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::with_header(
+            metrics::render(&store).await,
+            "content-type",
+            metrics::CONTENT_TYPE,
+        ))
+    }
+
+    pub async fn get_openapi() -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::with_header(openapi::json(), "content-type", "application/json"))
+    }
+
+    pub async fn get_docs() -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::html(openapi::DOCS_HTML))
+    }
+
+    pub async fn get_all_scans(
+        tenant: String, query_string: String, accept: Option<String>, accept_encoding: Option<String>, store: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let reply = match negotiate::sort_key(Some(&query_string)) {
+            Ok(sort) => negotiate::scans_reply(
+                accept.as_deref(), accept_encoding.as_deref(), &store.read().await.get_all_sorted_in(&tenant, sort),
+            ),
+            Err(problem) => Err(problem),
+        };
+        Ok(negotiated(reply))
     }
 
     pub async fn get_scan(
-        ip: String, port: i16, store: Db,
+        tenant: String, ip: String, port: i16, accept: Option<String>,
+        accept_encoding: Option<String>, store: Db,
     ) -> Result<impl warp::Reply, Infallible> {
-        let res = store.read().await.get_record(&ip, port);
-        Ok(warp::reply::json(&res))
+        let res = store.read().await.get_record_in(&tenant, &ip, port);
+        Ok(negotiated(negotiate::scan_reply(accept.as_deref(), accept_encoding.as_deref(), res.as_ref())))
     }
 
     pub async fn create_scan(
-        scan: Scan, store: Db,
+        tenant: String, idempotent: Idempotent, body: warp::hyper::body::Bytes, store: Db,
+        cache: idempotency::Idempotency, bodies: negotiate::BodyPolicy,
     ) -> Result<impl warp::Reply, Infallible> {
-        match store.write().await.insert_record(scan) {
-            Err(_) => Ok(StatusCode::BAD_REQUEST),
-            Ok(_) => Ok(StatusCode::CREATED),
-        }
+        let res = cache.run(idempotent.request(&body), async {
+            let scan = match bodies.decode_scan(idempotent.content_type.as_deref(), &body) {
+                Ok(scan) => scan,
+                Err(problem) => return idempotency::Recorded::problem(&problem),
+            };
+
+            match store.write().await.insert_record_in(&tenant, scan) {
+                Err(QUOTA_EXCEEDED) => idempotency::Recorded::problem(&problem::quota_exceeded()),
+                Err(_) => idempotency::Recorded::new(400),
+                Ok(_) => idempotency::Recorded::new(201),
+            }
+        }).await;
+
+        Ok(recorded(res))
     }
 
     pub async fn update_scan(
-        scan: Scan, store: Db,
-    ) -> Result<impl warp::Reply, Infallible> {
-        match store.write().await.update_record(scan) {
-            Err(_) => Ok(StatusCode::BAD_REQUEST),
-            Ok(_) => Ok(StatusCode::OK),
+        tenant: String, scan: Scan, store: Db,
+    ) -> Result<warp::reply::Response, Infallible> {
+        match store.write().await.update_record_in(&tenant, scan) {
+            Err(STALE_SCAN) => Ok(problem_response(&problem::stale_scan())),
+            Err(_) => Ok(StatusCode::BAD_REQUEST.into_response()),
+            Ok(_) => Ok(StatusCode::OK.into_response()),
         }
     }
 
     pub async fn delete_scan(
-        ip: String, port: i16, store: Db,
+        tenant: String, ip: String, port: i16, store: Db,
     ) -> Result<impl warp::Reply, Infallible> {
-        match store.write().await.delete_record(&ip, port) {
+        match store.write().await.delete_record_in(&tenant, &ip, port) {
             Err(_) => Ok(StatusCode::BAD_REQUEST),
             Ok(_) => Ok(StatusCode::OK),
         }
     }
 }
 
+// `warp::serve` only takes connections without their peer address, which the
+// rate limiter needs, so TLS connections are served through hyper directly.
+async fn serve_tls<F>(filter: F, mut incoming: tls::Incoming, signal: impl Future<Output = ()>)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let service = warp::service(filter);
+    let make_service = make_service_fn(move |conn: &tls::Connection| {
+        let service = service.clone();
+        let peer = PeerAddr(conn.peer_addr());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: warp::hyper::Request<warp::hyper::Body>| {
+                req.extensions_mut().insert(peer);
+                service.clone().call(req)
+            }))
+        }
+    });
+
+    let accept = warp::hyper::server::accept::poll_fn(move |cx| {
+        incoming.poll_accept(cx).map(|conn| conn.map(Ok::<_, Infallible>))
+    });
+    if let Err(err) = warp::hyper::Server::builder(accept)
+        .serve(make_service)
+        .with_graceful_shutdown(signal)
+        .await
+    {
+        tracing::error!(error = %err, "server error");
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    let store = Arc::new(RwLock::new(Store::new()));
-    let api = filters::scans(store);
+async fn main() -> std::io::Result<()> {
+    let config = Config::from_env()?.with_args(std::env::args().skip(1))?;
+    let _telemetry = telemetry::init("warp", &config)?;
+    let store = Db::new(Store::from_config(&config)?);
+    store.warm_in_background();
+    store.compact_in_background(config.compact_interval, config.snapshot_dir.as_deref());
+    let snapshots = admin::Snapshots::from_config(&config);
+    snapshots.schedule(&store);
+    let authenticator = auth::Authenticator::from_config(&config)?;
+    let tls = tls::Tls::from_config(&config)?;
+    let shutdown = Shutdown::on_signals();
+    let limiter = ratelimit::RateLimiter::from_config(&config);
+    let grpc = grpc::start(&config, store.clone(), authenticator.clone(), limiter.clone(), shutdown.clone()).await?;
+    let validator = validation::Validator::from_config(&config);
+    let graphql = graphql::GraphQl::new(store.clone(), authenticator.clone(), validator, shutdown.clone());
+    let idempotency = idempotency::Idempotency::from_config(&config);
+    let bodies = negotiate::BodyPolicy::from_config(&config);
+    let routes = filters::scans(store.clone(), authenticator, limiter, graphql, idempotency, bodies, snapshots)
+        .or(filters::health(store.clone(), shutdown.clone()))
+        .or(filters::metrics(store.clone()))
+        .or(filters::docs())
+        .recover(handlers::recover);
+
+    // `warp::trace` opens the request span before any headers are read, so
+    // the request context is recorded into it once they have been.
+    let api = warp::header::optional::<String>(REQUEST_ID_HEADER)
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .map(|request_id: Option<String>, traceparent: Option<String>| {
+            let ctx = RequestContext::from_headers(request_id.as_deref(), traceparent.as_deref());
+            ctx.record(&tracing::Span::current());
+            ctx
+        })
+        .and(routes)
+        .map(|ctx: RequestContext, reply| {
+            let mut res = Reply::into_response(reply);
+            for (name, value) in ctx.response_headers() {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    res.headers_mut().insert(HeaderName::from_static(name), value);
+                }
+            }
+            res
+        })
+        .with(warp::log::custom(|info| {
+            let status = info.status().as_u16();
+            metrics::global().observe_request(
+                info.method().as_str(), info.path(), status, info.elapsed(),
+            );
+            telemetry::finish(&tracing::Span::current(), status, info.elapsed());
+        }))
+        .with(warp::trace(|info| {
+            telemetry::request_span(info.method().as_str(), info.path())
+        }));
+
+    let signal = shutdown.clone();
+    let signal = async move { signal.wait().await };
+    let mut server: Pin<Box<dyn Future<Output = ()> + Send>> = match &tls {
+        Some(tls) => Box::pin(serve_tls(api, tls.bind("127.0.0.1:8080").await?, signal)),
+        None => Box::pin(warp::serve(api).bind_with_graceful_shutdown(([127, 0, 0, 1], 8080), signal).1),
+    };
+
+    // Graceful shutdown waits for every connection to close, so bound how
+    // long we keep draining once a signal has arrived.
+    tracing::info!(addr = "127.0.0.1:8080", tls = tls.is_some(), "listening");
+
+    tokio::select! {
+        _ = &mut server => {},
+        _ = shutdown.wait() => {
+            tracing::info!("shutdown requested, draining in-flight requests");
+            if tokio::time::timeout(config.shutdown_timeout, &mut server).await.is_err() {
+                tracing::warn!("timed out draining in-flight requests");
+            }
+        },
+    }
+
+    if let Some(grpc) = grpc {
+        grpc.drain(config.shutdown_timeout).await;
+    }
+
+    tracing::info!("server stopped, flushing store");
+    store.write().await.flush()?;
 
-    warp::serve(api).run(([127, 0, 0, 1], 8080)).await;
+    Ok(())
 }
@@ -0,0 +1,189 @@
+use crate::ratelimit::Budget;
+use crate::store::snapshot::SnapshotFormat;
+use crate::store::store::UpdatePolicy;
+use crate::store::tenant::Quotas;
+use crate::tls::ClientAuth;
+use crate::validation::{AddressPolicy, SkewPolicy};
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Settings shared by every service binary, read from `SCANS_*` environment
+// variables so the same build can be configured per deployment.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub wal_path: Option<PathBuf>,
+    pub compact_interval: Option<Duration>,
+    pub shutdown_timeout: Duration,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+    pub api_keys_file: Option<PathBuf>,
+    pub jwt_secret_file: Option<PathBuf>,
+    pub jwt_public_key_file: Option<PathBuf>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub auth_disabled: bool,
+    pub read_rate_limit: Option<Budget>,
+    pub write_rate_limit: Option<Budget>,
+    pub tenant_quotas: Quotas,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub tls_client_ca_file: Option<PathBuf>,
+    pub tls_client_auth: ClientAuth,
+    pub grpc_addr: Option<SocketAddr>,
+    pub idempotency_window: Duration,
+    pub idempotency_max_bytes: usize,
+    pub body_limit: usize,
+    pub strict_bodies: bool,
+    pub timestamp_skew: Duration,
+    pub address_policy: AddressPolicy,
+    pub skew_policy: SkewPolicy,
+    pub stamp_received_at: bool,
+    pub update_policy: UpdatePolicy,
+    pub snapshot_dir: Option<PathBuf>,
+    pub snapshot_interval: Option<Duration>,
+    pub snapshot_format: SnapshotFormat,
+    pub load_snapshot: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            wal_path: None,
+            compact_interval: None,
+            shutdown_timeout: Duration::from_secs(30),
+            log_format: LogFormat::Text,
+            otlp_endpoint: None,
+            api_keys_file: None,
+            jwt_secret_file: None,
+            jwt_public_key_file: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            auth_disabled: false,
+            read_rate_limit: None,
+            write_rate_limit: None,
+            tenant_quotas: Quotas::default(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_client_ca_file: None,
+            tls_client_auth: ClientAuth::Required,
+            grpc_addr: None,
+            idempotency_window: Duration::from_secs(24 * 60 * 60),
+            idempotency_max_bytes: 64 * 1024 * 1024,
+            body_limit: 16 * 1024,
+            strict_bodies: false,
+            timestamp_skew: Duration::from_secs(5 * 60),
+            address_policy: AddressPolicy::Any,
+            skew_policy: SkewPolicy::Reject,
+            stamp_received_at: false,
+            update_policy: UpdatePolicy::Overwrite,
+            snapshot_dir: None,
+            snapshot_interval: None,
+            snapshot_format: SnapshotFormat::Json,
+            load_snapshot: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> io::Result<Self> {
+        let defaults = Config::default();
+
+        Ok(Config {
+            wal_path: env::var_os("SCANS_WAL_PATH").map(PathBuf::from),
+            compact_interval: parse_var("SCANS_COMPACT_INTERVAL")?.map(Duration::from_secs),
+            shutdown_timeout: parse_var("SCANS_SHUTDOWN_TIMEOUT")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.shutdown_timeout),
+            log_format: parse_var("SCANS_LOG_FORMAT")?.unwrap_or(defaults.log_format),
+            otlp_endpoint: env::var("SCANS_OTLP_ENDPOINT").ok(),
+            api_keys_file: env::var_os("SCANS_API_KEYS_FILE").map(PathBuf::from),
+            jwt_secret_file: env::var_os("SCANS_JWT_SECRET_FILE").map(PathBuf::from),
+            jwt_public_key_file: env::var_os("SCANS_JWT_PUBLIC_KEY_FILE").map(PathBuf::from),
+            jwt_issuer: env::var("SCANS_JWT_ISSUER").ok(),
+            jwt_audience: env::var("SCANS_JWT_AUDIENCE").ok(),
+            auth_disabled: parse_var("SCANS_AUTH_DISABLED")?.unwrap_or(defaults.auth_disabled),
+            read_rate_limit: parse_var("SCANS_READ_RATE_LIMIT")?,
+            write_rate_limit: parse_var("SCANS_WRITE_RATE_LIMIT")?,
+            tenant_quotas: parse_var("SCANS_TENANT_QUOTAS")?.unwrap_or_default(),
+            tls_cert_file: env::var_os("SCANS_TLS_CERT_FILE").map(PathBuf::from),
+            tls_key_file: env::var_os("SCANS_TLS_KEY_FILE").map(PathBuf::from),
+            tls_client_ca_file: env::var_os("SCANS_TLS_CLIENT_CA_FILE").map(PathBuf::from),
+            tls_client_auth: parse_var("SCANS_TLS_CLIENT_AUTH")?.unwrap_or(defaults.tls_client_auth),
+            grpc_addr: parse_var("SCANS_GRPC_ADDR")?,
+            idempotency_window: parse_var("SCANS_IDEMPOTENCY_WINDOW")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.idempotency_window),
+            idempotency_max_bytes: parse_var("SCANS_IDEMPOTENCY_MAX_BYTES")?.unwrap_or(defaults.idempotency_max_bytes),
+            body_limit: parse_var("SCANS_BODY_LIMIT")?.unwrap_or(defaults.body_limit),
+            strict_bodies: parse_var("SCANS_STRICT_BODIES")?.unwrap_or(defaults.strict_bodies),
+            timestamp_skew: parse_var("SCANS_TIMESTAMP_SKEW")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.timestamp_skew),
+            address_policy: parse_var("SCANS_ADDRESS_POLICY")?.unwrap_or(defaults.address_policy),
+            skew_policy: parse_var("SCANS_SKEW_POLICY")?.unwrap_or(defaults.skew_policy),
+            stamp_received_at: parse_var("SCANS_STAMP_RECEIVED_AT")?.unwrap_or(defaults.stamp_received_at),
+            update_policy: parse_var("SCANS_UPDATE_POLICY")?.unwrap_or(defaults.update_policy),
+            snapshot_dir: env::var_os("SCANS_SNAPSHOT_DIR").map(PathBuf::from),
+            snapshot_interval: parse_var("SCANS_SNAPSHOT_INTERVAL")?.map(Duration::from_secs),
+            snapshot_format: parse_var("SCANS_SNAPSHOT_FORMAT")?.unwrap_or(defaults.snapshot_format),
+            load_snapshot: env::var_os("SCANS_LOAD_SNAPSHOT").map(PathBuf::from),
+        })
+    }
+
+    // Applies command line flags, which win over the environment. The only
+    // one is `--load-snapshot <path>`.
+    pub fn with_args(mut self, args: impl IntoIterator<Item = String>) -> io::Result<Self> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let path = match arg.strip_prefix("--load-snapshot") {
+                Some("") => args.next(),
+                Some(value) => value.strip_prefix('=').map(str::to_owned),
+                None => None,
+            };
+
+            match path.filter(|path| !path.is_empty()) {
+                Some(path) => self.load_snapshot = Some(PathBuf::from(path)),
+                None => return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unexpected argument {:?}; usage: [--load-snapshot <path>]", arg),
+                )),
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str) -> io::Result<Option<T>> {
+    match env::var(name) {
+        Err(_) => Ok(None),
+        Ok(value) => value.trim().parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid value for {}: {:?}", name, value),
+            )
+        }),
+    }
+}
@@ -0,0 +1,809 @@
+use crate::auth::{AuthError, Authenticator, Principal, Role};
+use crate::model::{self, Scan};
+use crate::problem::Problem;
+use crate::shutdown::Shutdown;
+use crate::store::db::Db;
+use crate::store::store::{self, Change, ChangeKind, QUOTA_EXCEEDED, STALE_SCAN};
+use crate::store::tenant::DEFAULT_TENANT;
+use crate::validation::Validator;
+use async_graphql::parser::types::OperationType;
+use async_graphql::{Context, Enum, ErrorExtensions, InputObject, Object, SimpleObject};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use tokio::sync::broadcast;
+
+pub const JSON: &str = "application/json";
+pub const EVENT_STREAM: &str = "text/event-stream";
+
+// Keeps a single request from walking arbitrarily deep or wide.
+const MAX_DEPTH: usize = 16;
+const MAX_COMPLEXITY: usize = 1000;
+
+type Result<T> = async_graphql::Result<T>;
+
+pub type ScanSchema = async_graphql::Schema<Query, Mutation, Subscription>;
+
+// The tenant an operation names, once the caller is known to be allowed it.
+fn tenant(ctx: &Context<'_>, name: Option<String>) -> Result<String> {
+    let tenant = name.unwrap_or_else(|| DEFAULT_TENANT.to_owned());
+    ctx.data::<Principal>()?.check_tenant(&tenant).map_err(|err| coded(err.to_string(), "FORBIDDEN"))?;
+    Ok(tenant)
+}
+
+fn port(port: i32) -> Result<i16> {
+    i16::try_from(port).map_err(|_| coded(format!("port {} is out of range", port), "BAD_USER_INPUT"))
+}
+
+fn coded(message: impl Into<String>, code: &'static str) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", code))
+}
+
+// A scan that failed validation, with each bad field listed under
+// `extensions.fields`.
+fn invalid(problem: Problem) -> async_graphql::Error {
+    let fields = async_graphql::Value::from_json(serde_json::json!(problem.errors)).unwrap_or_default();
+    coded(problem.detail.unwrap_or_default(), "BAD_USER_INPUT").extend_with(|_, e| e.set("fields", fields))
+}
+
+// Store errors are plain messages, so they are sorted into codes by what
+// they say.
+fn rejected(err: &'static str) -> async_graphql::Error {
+    let code = match err {
+        "record already exists" => "ALREADY_EXISTS",
+        "no record exists" | "no record for key" => "NOT_FOUND",
+        STALE_SCAN => "STALE",
+        "invalid tenant name" => "BAD_USER_INPUT",
+        QUOTA_EXCEEDED => "QUOTA_EXCEEDED",
+        _ => "UNAVAILABLE",
+    };
+    coded(err, code)
+}
+
+// Queries only need a caller the binary's middleware already let in; each
+// mutation also needs the role of the REST method it mirrors.
+fn require(ctx: &Context<'_>, method: &str) -> Result<()> {
+    let granted = ctx.data::<Principal>()?.role;
+    let required = Role::required_for(method);
+
+    match granted >= required {
+        true => Ok(()),
+        false => Err(coded(AuthError::Forbidden { required, granted }.to_string(), "FORBIDDEN")),
+    }
+}
+
+// A scan along with the tenant it belongs to, so its history can be found.
+pub struct ScanNode {
+    tenant: String,
+    scan: Scan,
+}
+
+#[Object(name = "Scan")]
+impl ScanNode {
+    async fn ip(&self) -> &str {
+        &self.scan.ip
+    }
+
+    async fn port(&self) -> i32 {
+        self.scan.port.into()
+    }
+
+    async fn load_time_nanosec(&self) -> i64 {
+        self.scan.load_time_nanosec
+    }
+
+    async fn content_hash(&self) -> &str {
+        &self.scan.content_hash
+    }
+
+    async fn timestamp(&self) -> DateTime<Utc> {
+        self.scan.timestamp
+    }
+
+    async fn protocol(&self) -> Option<Protocol> {
+        self.scan.protocol.map(Into::into)
+    }
+
+    async fn http_status(&self) -> Option<i32> {
+        self.scan.http_status.map(Into::into)
+    }
+
+    async fn headers(&self) -> Vec<Header> {
+        self.scan.headers.iter()
+            .map(|(name, value)| Header { name: name.clone(), value: value.clone() })
+            .collect()
+    }
+
+    async fn body_size(&self) -> Option<u64> {
+        self.scan.body_size
+    }
+
+    async fn tls(&self) -> Option<Tls> {
+        self.scan.tls.clone().map(Into::into)
+    }
+
+    async fn error(&self) -> Option<ErrorKind> {
+        self.scan.error.map(Into::into)
+    }
+
+    async fn received_at(&self) -> Option<DateTime<Utc>> {
+        self.scan.received_at
+    }
+
+    // The versions this scan replaced, newest first.
+    async fn history(&self, ctx: &Context<'_>, limit: Option<usize>) -> Result<Vec<ScanNode>> {
+        let history = ctx.data::<Db>()?.read().await
+            .get_history_in(&self.tenant, &self.scan.ip, self.scan.port);
+
+        Ok(history.into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|scan| ScanNode { tenant: self.tenant.clone(), scan })
+            .collect())
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "model::Protocol")]
+enum Protocol {
+    #[graphql(name = "HTTP1_0")]
+    Http10,
+    #[graphql(name = "HTTP1_1")]
+    Http11,
+    H2,
+    H3,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "model::TlsVersion")]
+enum TlsVersion {
+    #[graphql(name = "TLS1_0")]
+    Tls10,
+    #[graphql(name = "TLS1_1")]
+    Tls11,
+    #[graphql(name = "TLS1_2")]
+    Tls12,
+    #[graphql(name = "TLS1_3")]
+    Tls13,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "model::ErrorKind")]
+enum ErrorKind {
+    Timeout,
+    Dns,
+    ConnectionRefused,
+    ConnectionReset,
+    Tls,
+    Http,
+    TooManyRedirects,
+    Other,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "HeaderInput")]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "TlsInput")]
+struct Tls {
+    version: TlsVersion,
+    cipher: Option<String>,
+    certificate_expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<model::TlsInfo> for Tls {
+    fn from(tls: model::TlsInfo) -> Self {
+        Tls {
+            version: tls.version.into(),
+            cipher: tls.cipher,
+            certificate_expires_at: tls.certificate_expires_at,
+        }
+    }
+}
+
+impl From<Tls> for model::TlsInfo {
+    fn from(tls: Tls) -> Self {
+        model::TlsInfo {
+            version: tls.version.into(),
+            cipher: tls.cipher,
+            certificate_expires_at: tls.certificate_expires_at,
+        }
+    }
+}
+
+#[derive(InputObject)]
+struct ScanInput {
+    ip: String,
+    port: i32,
+    load_time_nanosec: i64,
+    content_hash: String,
+    timestamp: DateTime<Utc>,
+    protocol: Option<Protocol>,
+    http_status: Option<i32>,
+    #[graphql(default)]
+    headers: Vec<Header>,
+    body_size: Option<u64>,
+    tls: Option<Tls>,
+    error: Option<ErrorKind>,
+}
+
+impl ScanInput {
+    fn into_scan(self) -> Result<Scan> {
+        let http_status = self.http_status
+            .map(|status| u16::try_from(status)
+                .map_err(|_| coded(format!("HTTP status {} is out of range", status), "BAD_USER_INPUT")))
+            .transpose()?;
+
+        Ok(Scan {
+            port: port(self.port)?,
+            ip: self.ip,
+            load_time_nanosec: self.load_time_nanosec,
+            content_hash: self.content_hash,
+            timestamp: self.timestamp,
+            protocol: self.protocol.map(Into::into),
+            http_status,
+            headers: self.headers.into_iter().map(|header| (header.name, header.value)).collect(),
+            body_size: self.body_size,
+            tls: self.tls.map(Into::into),
+            error: self.error.map(Into::into),
+            received_at: None,
+        })
+    }
+}
+
+// Every field left out matches any scan. `until` is exclusive.
+#[derive(InputObject, Clone, Default)]
+struct ScanFilter {
+    ip: Option<String>,
+    port: Option<i32>,
+    content_hash: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+impl ScanFilter {
+    fn matches(&self, scan: &Scan) -> bool {
+        self.ip.as_ref().is_none_or(|ip| *ip == scan.ip)
+            && self.port.is_none_or(|port| port == i32::from(scan.port))
+            && self.content_hash.as_ref().is_none_or(|hash| *hash == scan.content_hash)
+            && self.since.is_none_or(|since| scan.timestamp >= since)
+            && self.until.is_none_or(|until| scan.timestamp < until)
+    }
+}
+
+// Which time `order` goes by.
+#[derive(Enum, Clone, Copy, Default, PartialEq, Eq)]
+#[graphql(remote = "store::SortKey")]
+enum ScanSort {
+    #[default]
+    Timestamp,
+    ReceivedAt,
+}
+
+#[derive(Enum, Clone, Copy, Default, PartialEq, Eq)]
+enum ScanOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+}
+
+#[derive(SimpleObject)]
+struct LoadTimeStats {
+    min: i64,
+    max: i64,
+    mean: f64,
+}
+
+#[derive(SimpleObject)]
+struct PortCount {
+    port: i32,
+    count: usize,
+}
+
+#[derive(SimpleObject)]
+struct ScanStats {
+    count: usize,
+    distinct_ips: usize,
+    // Absent when no scan matched.
+    load_time: Option<LoadTimeStats>,
+    oldest: Option<DateTime<Utc>>,
+    newest: Option<DateTime<Utc>>,
+    // Busiest ports first.
+    ports: Vec<PortCount>,
+}
+
+impl ScanStats {
+    // Expects scans oldest first, as the store returns them.
+    fn of(scans: &[Scan]) -> ScanStats {
+        let load_times = scans.iter().map(|scan| scan.load_time_nanosec);
+        let load_time = match scans.is_empty() {
+            true => None,
+            false => Some(LoadTimeStats {
+                min: load_times.clone().min().unwrap_or_default(),
+                max: load_times.clone().max().unwrap_or_default(),
+                mean: load_times.map(|n| n as f64).sum::<f64>() / scans.len() as f64,
+            }),
+        };
+
+        let mut ports = BTreeMap::new();
+        for scan in scans {
+            *ports.entry(i32::from(scan.port)).or_insert(0) += 1;
+        }
+        let mut ports: Vec<PortCount> = ports.into_iter().map(|(port, count)| PortCount { port, count }).collect();
+        ports.sort_by_key(|port| std::cmp::Reverse(port.count));
+
+        ScanStats {
+            count: scans.len(),
+            distinct_ips: scans.iter().map(|scan| &scan.ip).collect::<HashSet<_>>().len(),
+            load_time,
+            oldest: scans.first().map(|scan| scan.timestamp),
+            newest: scans.last().map(|scan| scan.timestamp),
+            ports,
+        }
+    }
+}
+
+async fn matching(ctx: &Context<'_>, tenant: &str, filter: Option<ScanFilter>, sort: ScanSort) -> Result<Vec<Scan>> {
+    let filter = filter.unwrap_or_default();
+    let mut scans = ctx.data::<Db>()?.read().await.get_all_sorted_in(tenant, sort.into());
+    scans.retain(|scan| filter.matches(scan));
+    Ok(scans)
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn scan(&self, ctx: &Context<'_>, tenant: Option<String>, ip: String, port: i32) -> Result<Option<ScanNode>> {
+        let tenant = self::tenant(ctx, tenant)?;
+        let scan = ctx.data::<Db>()?.read().await.get_record_in(&tenant, &ip, self::port(port)?);
+        Ok(scan.map(|scan| ScanNode { tenant, scan }))
+    }
+
+    async fn scans(
+        &self, ctx: &Context<'_>, tenant: Option<String>, filter: Option<ScanFilter>,
+        #[graphql(default)] sort: ScanSort, #[graphql(default)] order: ScanOrder, limit: Option<usize>,
+    ) -> Result<Vec<ScanNode>> {
+        let tenant = self::tenant(ctx, tenant)?;
+        let mut scans = matching(ctx, &tenant, filter, sort).await?;
+        if order == ScanOrder::NewestFirst {
+            scans.reverse();
+        }
+
+        Ok(scans.into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|scan| ScanNode { tenant: tenant.clone(), scan })
+            .collect())
+    }
+
+    async fn stats(&self, ctx: &Context<'_>, tenant: Option<String>, filter: Option<ScanFilter>) -> Result<ScanStats> {
+        Ok(ScanStats::of(&matching(ctx, &self::tenant(ctx, tenant)?, filter, ScanSort::Timestamp).await?))
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    async fn create_scan(&self, ctx: &Context<'_>, tenant: Option<String>, scan: ScanInput) -> Result<ScanNode> {
+        require(ctx, "POST")?;
+        let tenant = self::tenant(ctx, tenant)?;
+        let mut scan = scan.into_scan()?;
+        ctx.data::<Validator>()?.admit(&mut scan).map_err(invalid)?;
+
+        ctx.data::<Db>()?.write().await.insert_record_in(&tenant, scan.clone()).map_err(rejected)?;
+        Ok(ScanNode { tenant, scan })
+    }
+
+    async fn update_scan(&self, ctx: &Context<'_>, tenant: Option<String>, scan: ScanInput) -> Result<ScanNode> {
+        require(ctx, "PUT")?;
+        let tenant = self::tenant(ctx, tenant)?;
+        let mut scan = scan.into_scan()?;
+        ctx.data::<Validator>()?.admit(&mut scan).map_err(invalid)?;
+
+        ctx.data::<Db>()?.write().await.update_record_in(&tenant, scan.clone()).map_err(rejected)?;
+        Ok(ScanNode { tenant, scan })
+    }
+
+    // Returns the scan that was removed.
+    async fn delete_scan(&self, ctx: &Context<'_>, tenant: Option<String>, ip: String, port: i32) -> Result<ScanNode> {
+        require(ctx, "DELETE")?;
+        let tenant = self::tenant(ctx, tenant)?;
+        let port = self::port(port)?;
+
+        let mut store = ctx.data::<Db>()?.write().await;
+        let scan = store.get_record_in(&tenant, &ip, port).ok_or_else(|| rejected("no record for key"))?;
+        store.delete_record_in(&tenant, &ip, port).map_err(rejected)?;
+        Ok(ScanNode { tenant, scan })
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(name = "ChangeKind")]
+enum Kind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(SimpleObject)]
+struct ScanChange {
+    kind: Kind,
+    tenant: String,
+    // For deletions, the scan that was removed.
+    scan: ScanNode,
+}
+
+impl From<Change> for ScanChange {
+    fn from(change: Change) -> Self {
+        let kind = match change.kind {
+            ChangeKind::Created => Kind::Created,
+            ChangeKind::Updated => Kind::Updated,
+            ChangeKind::Deleted => Kind::Deleted,
+        };
+
+        ScanChange { kind, tenant: change.tenant.clone(), scan: ScanNode { tenant: change.tenant, scan: change.scan } }
+    }
+}
+
+pub struct Subscription;
+
+#[async_graphql::Subscription]
+impl Subscription {
+    // Every change to matching scans from the moment of subscribing, until
+    // the server shuts down. A subscriber that falls too far behind gets an
+    // error and is cut off rather than silently missing changes.
+    async fn scan_changes(
+        &self, ctx: &Context<'_>, tenant: Option<String>, filter: Option<ScanFilter>,
+    ) -> Result<impl Stream<Item = Result<ScanChange>>> {
+        let tenant = self::tenant(ctx, tenant)?;
+        let filter = filter.unwrap_or_default();
+        let changes = ctx.data::<Db>()?.read().await.subscribe();
+        let shutdown = ctx.data::<Shutdown>()?.clone();
+
+        let stream = stream::unfold(Some(changes), move |changes| {
+            let (tenant, filter) = (tenant.clone(), filter.clone());
+            async move {
+                let mut changes = changes?;
+                loop {
+                    match changes.recv().await {
+                        Ok(change) if change.tenant != tenant || !filter.matches(&change.scan) => continue,
+                        Ok(change) => return Some((Ok(change.into()), Some(changes))),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            let err = coded(format!("subscription fell behind and missed {} changes", missed), "ABORTED");
+                            return Some((Err(err), None));
+                        },
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+
+        Ok(stream.take_until(async move { shutdown.wait().await }))
+    }
+}
+
+// One server-sent event of the graphql-sse protocol: `next` carries a result
+// and `complete` ends the stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub name: &'static str,
+    pub data: String,
+}
+
+impl Event {
+    fn next(response: &async_graphql::Response) -> Event {
+        Event { name: "next", data: serde_json::to_string(response).expect("graphql response serializes") }
+    }
+
+    fn complete() -> Event {
+        Event { name: "complete", data: String::new() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("event: {}\ndata: {}\n\n", self.name, self.data).into_bytes()
+    }
+}
+
+// A request to `/v1/graphql` as the binaries see it. Credentials are passed
+// again so mutations can check the caller's role per field.
+pub struct HttpRequest<'a> {
+    pub method: &'a str,
+    pub query_string: Option<&'a str>,
+    pub body: &'a [u8],
+    pub accept: Option<&'a str>,
+    pub authorization: Option<&'a str>,
+    pub api_key: Option<&'a str>,
+}
+
+pub enum Reply {
+    // A complete `application/json` result, errors included.
+    Json(Vec<u8>),
+    // A `text/event-stream` of results, for subscriptions.
+    Events(BoxStream<'static, Event>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetParams {
+    #[serde(default)]
+    query: String,
+    operation_name: Option<String>,
+    variables: Option<String>,
+}
+
+fn bad_request(detail: impl Into<String>) -> Problem {
+    Problem::new(400, "Bad Request").with_detail(detail)
+}
+
+fn from_query_string(query_string: &str) -> std::result::Result<async_graphql::Request, Problem> {
+    let params: GetParams = serde_urlencoded::from_str(query_string)
+        .map_err(|e| bad_request(format!("invalid query string: {}", e)))?;
+    let variables = params.variables.as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| bad_request(format!("invalid variables: {}", e)))?
+        .unwrap_or_default();
+
+    let mut request = async_graphql::Request::new(params.query).variables(variables);
+    request.operation_name = params.operation_name;
+    Ok(request)
+}
+
+// The type of the operation a request will run, if the query parses and
+// names it unambiguously. Anything else is left for execution to report.
+fn operation_type(request: &mut async_graphql::Request) -> Option<OperationType> {
+    let wanted = request.operation_name.clone();
+    let document = request.parsed_query().ok()?;
+    let mut candidates = document.operations.iter()
+        .filter(|(name, _)| wanted.as_deref().is_none_or(|wanted| name.is_some_and(|name| name.as_str() == wanted)));
+
+    match (candidates.next(), candidates.next()) {
+        (Some((_, operation)), None) => Some(operation.node.ty),
+        _ => None,
+    }
+}
+
+// The scans API as a GraphQL schema over the shared store. Queries can run
+// over GET or POST; mutations need POST; subscriptions are streamed as
+// server-sent events to callers that accept `text/event-stream`.
+#[derive(Clone)]
+pub struct GraphQl {
+    schema: ScanSchema,
+    authenticator: Authenticator,
+}
+
+impl GraphQl {
+    pub fn new(store: Db, authenticator: Authenticator, validator: Validator, shutdown: Shutdown) -> Self {
+        let schema = ScanSchema::build(Query, Mutation, Subscription)
+            .data(store)
+            .data(validator)
+            .data(shutdown)
+            .limit_depth(MAX_DEPTH)
+            .limit_complexity(MAX_COMPLEXITY)
+            .finish();
+
+        GraphQl { schema, authenticator }
+    }
+
+    pub fn sdl(&self) -> String {
+        self.schema.sdl()
+    }
+
+    pub async fn execute(&self, req: HttpRequest<'_>) -> std::result::Result<Reply, Problem> {
+        let principal = self.authenticator.authenticate(req.authorization, req.api_key)
+            .map_err(|err| err.problem())?;
+
+        let mut request = match req.method {
+            "GET" => from_query_string(req.query_string.unwrap_or_default())?,
+            "POST" => serde_json::from_slice(req.body)
+                .map_err(|e| bad_request(format!("invalid graphql request: {}", e)))?,
+            _ => return Err(Problem::new(405, "Method Not Allowed")
+                .with_detail("graphql is served over GET and POST")),
+        };
+
+        let operation = operation_type(&mut request);
+        if req.method == "GET" && operation == Some(OperationType::Mutation) {
+            return Err(Problem::new(405, "Method Not Allowed").with_detail("mutations must be sent with POST"));
+        }
+
+        let request = request.data(principal);
+        if req.accept.is_some_and(|accept| accept.contains(EVENT_STREAM)) {
+            let events = self.schema.execute_stream(request)
+                .map(|response| Event::next(&response))
+                .chain(stream::once(async { Event::complete() }));
+            return Ok(Reply::Events(events.boxed()));
+        }
+
+        if operation == Some(OperationType::Subscription) {
+            return Err(Problem::new(406, "Not Acceptable")
+                .with_detail(format!("subscriptions are only served as {}", EVENT_STREAM)));
+        }
+
+        let response = self.schema.execute(request).await;
+        Ok(Reply::Json(serde_json::to_vec(&response).expect("graphql response serializes")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::store::store::Store;
+    use serde_json::{json, Value};
+
+    fn graphql(authenticator: Authenticator) -> (GraphQl, Shutdown) {
+        let shutdown = Shutdown::new();
+        (GraphQl::new(Db::new(Store::new()), authenticator, Validator::default(), shutdown.clone()), shutdown)
+    }
+
+    fn post(body: &[u8]) -> HttpRequest<'_> {
+        HttpRequest {
+            method: "POST",
+            query_string: None,
+            body,
+            accept: None,
+            authorization: None,
+            api_key: None,
+        }
+    }
+
+    async fn reply(api: &GraphQl, req: HttpRequest<'_>) -> Value {
+        match api.execute(req).await.unwrap() {
+            Reply::Json(body) => serde_json::from_slice(&body).unwrap(),
+            Reply::Events(_) => panic!("expected a json reply"),
+        }
+    }
+
+    async fn run(api: &GraphQl, body: &Value) -> Value {
+        reply(api, post(body.to_string().as_bytes())).await
+    }
+
+    const CREATE: &str = "mutation($tenant: String, $scan: ScanInput!) {
+        createScan(tenant: $tenant, scan: $scan) { ip port }
+    }";
+
+    fn scan(ip: &str, port: i32, load_time: i64, at: &str) -> Value {
+        json!({ "ip": ip, "port": port, "loadTimeNanosec": load_time, "contentHash": "73d1a9ab21fce25e", "timestamp": at })
+    }
+
+    #[tokio::test]
+    async fn queries_mutations_and_aggregates() {
+        let (api, _) = graphql(Authenticator::disabled());
+
+        for (ip, port, load_time, at) in [
+            ("1.2.3.4", 80, 10, "2022-07-31T14:17:00Z"),
+            ("1.2.3.4", 443, 30, "2022-07-31T14:16:00Z"),
+            ("8.8.8.8", 80, 20, "2022-07-31T14:18:00Z"),
+        ] {
+            let res = run(&api, &json!({ "query": CREATE, "variables": { "scan": scan(ip, port, load_time, at) } })).await;
+            assert_eq!(res["data"]["createScan"]["ip"], ip);
+        }
+
+        let res = run(&api, &json!({ "query": CREATE, "variables": { "scan": scan("8.8.8.8", 80, 0, "2022-07-31T14:18:00Z") } })).await;
+        assert_eq!(res["errors"][0]["extensions"]["code"], "ALREADY_EXISTS");
+
+        let res = run(&api, &json!({ "query": "{
+            scans(filter: { port: 80 }, order: NEWEST_FIRST) { ip }
+            stats { count distinctIps loadTime { min max mean } oldest newest ports { port count } }
+        }" })).await;
+        assert_eq!(res["data"]["scans"], json!([{ "ip": "8.8.8.8" }, { "ip": "1.2.3.4" }]));
+        assert_eq!(res["data"]["stats"], json!({
+            "count": 3,
+            "distinctIps": 2,
+            "loadTime": { "min": 10, "max": 30, "mean": 20.0 },
+            "oldest": "2022-07-31T14:16:00+00:00",
+            "newest": "2022-07-31T14:18:00+00:00",
+            "ports": [{ "port": 80, "count": 2 }, { "port": 443, "count": 1 }],
+        }));
+
+        let update = "mutation($scan: ScanInput!) { updateScan(scan: $scan) { loadTimeNanosec } }";
+        for load_time in [11, 12] {
+            run(&api, &json!({ "query": update, "variables": { "scan": scan("1.2.3.4", 80, load_time, "2022-07-31T14:19:00Z") } })).await;
+        }
+
+        let res = run(&api, &json!({ "query": "{
+            scan(ip: \"1.2.3.4\", port: 80) { loadTimeNanosec history(limit: 5) { loadTimeNanosec } }
+            other: scan(tenant: \"team-a\", ip: \"1.2.3.4\", port: 80) { ip }
+        }" })).await;
+        assert_eq!(res["data"]["scan"], json!({
+            "loadTimeNanosec": 12,
+            "history": [{ "loadTimeNanosec": 11 }, { "loadTimeNanosec": 10 }],
+        }));
+        assert_eq!(res["data"]["other"], Value::Null);
+
+        let res = run(&api, &json!({ "query": "mutation { deleteScan(ip: \"1.2.3.4\", port: 80) { loadTimeNanosec } }" })).await;
+        assert_eq!(res["data"]["deleteScan"]["loadTimeNanosec"], 12);
+        let res = run(&api, &json!({ "query": "mutation { deleteScan(ip: \"1.2.3.4\", port: 80) { ip } }" })).await;
+        assert_eq!(res["errors"][0]["extensions"]["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn get_serves_queries_only() {
+        let (api, _) = graphql(Authenticator::disabled());
+
+        let get = |query_string| HttpRequest { method: "GET", query_string: Some(query_string), ..post(b"") };
+        let res = reply(&api, get("query=query%20Q(%24ip%3A%20String!)%7Bscan(ip%3A%24ip%2C%20port%3A%2080)%7Bip%7D%7D&variables=%7B%22ip%22%3A%221.2.3.4%22%7D&operationName=Q")).await;
+        assert_eq!(res, json!({ "data": { "scan": null } }));
+
+        let problem = api.execute(get("query=mutation%7BdeleteScan(ip%3A%22x%22%2Cport%3A1)%7Bip%7D%7D")).await.err().unwrap();
+        assert_eq!(problem.status, 405);
+        let problem = api.execute(get("query=subscription%7BscanChanges%7Bkind%7D%7D")).await.err().unwrap();
+        assert_eq!(problem.status, 406);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_stream_events() {
+        let (api, shutdown) = graphql(Authenticator::disabled());
+
+        let body = json!({ "query": "subscription {
+            scanChanges(tenant: \"team-a\", filter: { ip: \"1.2.3.4\" }) { kind tenant scan { port } }
+        }" }).to_string();
+        let mut req = post(body.as_bytes());
+        req.accept = Some(EVENT_STREAM);
+        let mut events = match api.execute(req).await.unwrap() {
+            Reply::Events(events) => events,
+            Reply::Json(_) => panic!("expected an event stream"),
+        };
+
+        // The subscription only starts listening once it is first polled.
+        let first = tokio::spawn(async move {
+            let first = events.next().await;
+            (first, events)
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let create = |tenant: &str, ip| json!({ "query": CREATE, "variables": { "tenant": tenant, "scan": scan(ip, 80, 0, "2022-07-31T14:17:00Z") } });
+        run(&api, &create("team-b", "1.2.3.4")).await;
+        run(&api, &create("team-a", "8.8.8.8")).await;
+        run(&api, &create("team-a", "1.2.3.4")).await;
+
+        let (first, mut events) = first.await.unwrap();
+        let first = first.unwrap();
+        assert_eq!(first.name, "next");
+        assert_eq!(serde_json::from_str::<Value>(&first.data).unwrap(), json!({
+            "data": { "scanChanges": { "kind": "CREATED", "tenant": "team-a", "scan": { "port": 80 } } },
+        }));
+        assert!(first.to_bytes().starts_with(b"event: next\ndata: {"));
+
+        // Shutting down ends the stream so it doesn't hold the server open.
+        shutdown.trigger();
+        assert_eq!(events.next().await.unwrap(), Event::complete());
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mutations_check_roles() {
+        let keys = std::env::temp_dir().join(format!("scans-graphql-{}-keys", std::process::id()));
+        std::fs::write(&keys, "writer secret write\n").unwrap();
+        let config = Config { api_keys_file: Some(keys), ..Config::default() };
+        let (api, _) = graphql(Authenticator::from_config(&config).unwrap());
+
+        let stats = json!({ "query": "{ stats { count } }" }).to_string();
+        let problem = api.execute(post(stats.as_bytes())).await.err().unwrap();
+        assert_eq!(problem.status, 401);
+
+        let as_writer = |body: &Value| {
+            let body = body.to_string();
+            let api = api.clone();
+            async move { reply(&api, HttpRequest { api_key: Some("secret"), ..post(body.as_bytes()) }).await }
+        };
+        let res = as_writer(&json!({ "query": CREATE, "variables": { "scan": scan("1.2.3.4", 80, 0, "2022-07-31T14:17:00Z") } })).await;
+        assert_eq!(res["data"]["createScan"]["port"], 80);
+
+        let res = as_writer(&json!({ "query": "mutation { deleteScan(ip: \"1.2.3.4\", port: 80) { ip } }" })).await;
+        assert_eq!(res["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        let res = as_writer(&json!({ "query": "{ stats { count } }" })).await;
+        assert_eq!(res["data"]["stats"]["count"], 1);
+
+        // The key is only granted the default tenant.
+        let res = as_writer(&json!({ "query": "{ scans(tenant: \"team-a\") { ip } }" })).await;
+        assert_eq!(res["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        let res = as_writer(&json!({ "query": CREATE, "variables": { "tenant": "team-a", "scan": scan("1.2.3.4", 80, 0, "2022-07-31T14:17:00Z") } })).await;
+        assert_eq!(res["errors"][0]["extensions"]["code"], "FORBIDDEN");
+    }
+}
@@ -0,0 +1,77 @@
+use crate::auth::Authenticator;
+use crate::config::Config;
+use crate::ratelimit::RateLimiter;
+use crate::shutdown::Shutdown;
+use crate::store::db::Db;
+use std::io;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "grpc")]
+pub mod proto;
+#[cfg(feature = "grpc")]
+mod service;
+
+#[cfg(feature = "grpc")]
+pub use service::ScanApi;
+
+// The gRPC server running next to a binary's REST server. It stops accepting
+// calls once shutdown is triggered.
+pub struct Server {
+    handle: JoinHandle<()>,
+}
+
+impl Server {
+    // Waits for calls in flight to finish, for at most `timeout`.
+    pub async fn drain(self, timeout: Duration) {
+        if tokio::time::timeout(timeout, self.handle).await.is_err() {
+            tracing::warn!("timed out draining in-flight grpc calls");
+        }
+    }
+}
+
+// Serves the scans API over gRPC on `SCANS_GRPC_ADDR`, sharing the REST
+// server's store, credentials and rate limits. Returns `None` when no
+// address is configured.
+pub async fn start(
+    config: &Config, store: Db, authenticator: Authenticator, limiter: RateLimiter, shutdown: Shutdown,
+) -> io::Result<Option<Server>> {
+    let addr = match config.grpc_addr {
+        None => return Ok(None),
+        Some(addr) => addr,
+    };
+
+    #[cfg(feature = "grpc")]
+    {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let api = ScanApi::new(
+            store, authenticator, limiter, crate::validation::Validator::from_config(config), shutdown.clone(),
+        );
+
+        let handle = tokio::spawn(async move {
+            let res = tonic::transport::Server::builder()
+                .add_service(api.into_service())
+                .serve_with_incoming_shutdown(
+                    tokio_stream::wrappers::TcpListenerStream::new(listener),
+                    async move { shutdown.wait().await },
+                )
+                .await;
+
+            if let Err(err) = res {
+                tracing::error!(error = %err, "grpc server error");
+            }
+        });
+
+        tracing::info!(addr = %addr, "grpc listening");
+        Ok(Some(Server { handle }))
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    {
+        let _ = (addr, store, authenticator, limiter, shutdown);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SCANS_GRPC_ADDR is set but the `grpc` feature is not enabled",
+        ))
+    }
+}
@@ -0,0 +1,144 @@
+// Messages of `proto/scans.proto`, written out the way prost would generate
+// them. Tags and types must match the .proto file.
+#![allow(clippy::derive_partial_eq_without_eq)]
+
+use prost_types::Timestamp;
+use std::collections::BTreeMap;
+
+// `google.protobuf.Empty`, which prost represents as the unit type.
+pub type Empty = ();
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Scan {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(int32, tag = "2")]
+    pub port: i32,
+    #[prost(int64, tag = "3")]
+    pub load_time_nanosec: i64,
+    #[prost(string, tag = "4")]
+    pub content_hash: String,
+    #[prost(message, optional, tag = "5")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, optional, tag = "6")]
+    pub protocol: Option<String>,
+    #[prost(uint32, optional, tag = "7")]
+    pub http_status: Option<u32>,
+    #[prost(btree_map = "string, string", tag = "8")]
+    pub headers: BTreeMap<String, String>,
+    #[prost(uint64, optional, tag = "9")]
+    pub body_size: Option<u64>,
+    #[prost(message, optional, tag = "10")]
+    pub tls: Option<TlsInfo>,
+    #[prost(string, optional, tag = "11")]
+    pub error: Option<String>,
+    #[prost(message, optional, tag = "12")]
+    pub received_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TlsInfo {
+    #[prost(string, tag = "1")]
+    pub version: String,
+    #[prost(string, optional, tag = "2")]
+    pub cipher: Option<String>,
+    #[prost(message, optional, tag = "3")]
+    pub certificate_expires_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanFilter {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(int32, tag = "2")]
+    pub port: i32,
+    #[prost(message, optional, tag = "3")]
+    pub since: Option<Timestamp>,
+    #[prost(message, optional, tag = "4")]
+    pub until: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetScanRequest {
+    #[prost(string, tag = "1")]
+    pub tenant: String,
+    #[prost(string, tag = "2")]
+    pub ip: String,
+    #[prost(int32, tag = "3")]
+    pub port: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListScansRequest {
+    #[prost(string, tag = "1")]
+    pub tenant: String,
+    #[prost(message, optional, tag = "2")]
+    pub filter: Option<ScanFilter>,
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+    #[prost(string, tag = "4")]
+    pub sort: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListScansResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub scans: Vec<Scan>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateScanRequest {
+    #[prost(string, tag = "1")]
+    pub tenant: String,
+    #[prost(message, optional, tag = "2")]
+    pub scan: Option<Scan>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateScanRequest {
+    #[prost(string, tag = "1")]
+    pub tenant: String,
+    #[prost(message, optional, tag = "2")]
+    pub scan: Option<Scan>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteScanRequest {
+    #[prost(string, tag = "1")]
+    pub tenant: String,
+    #[prost(string, tag = "2")]
+    pub ip: String,
+    #[prost(int32, tag = "3")]
+    pub port: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WatchScansRequest {
+    #[prost(string, tag = "1")]
+    pub tenant: String,
+    #[prost(message, optional, tag = "2")]
+    pub filter: Option<ScanFilter>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanEvent {
+    #[prost(enumeration = "scan_event::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, tag = "2")]
+    pub tenant: String,
+    #[prost(message, optional, tag = "3")]
+    pub scan: Option<Scan>,
+}
+
+pub mod scan_event {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Unspecified = 0,
+        Created = 1,
+        Updated = 2,
+        Deleted = 3,
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/scans.v1.ScanService.rs"));
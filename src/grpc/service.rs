@@ -0,0 +1,534 @@
+// `tonic::Status` is large, but it is what every handler returns anyway.
+#![allow(clippy::result_large_err)]
+
+use super::proto::{self, scan_event::Kind, scan_service_server::{ScanService, ScanServiceServer}};
+use crate::auth::{self, AuthError, Authenticator, Principal};
+use crate::model::{ErrorKind, Protocol, Scan, TlsInfo};
+use crate::ratelimit::RateLimiter;
+use crate::shutdown::Shutdown;
+use crate::store::db::Db;
+use crate::store::store::{Change, ChangeKind, SortKey, QUOTA_EXCEEDED, STALE_SCAN};
+use crate::store::tenant::DEFAULT_TENANT;
+use crate::validation::Validator;
+use chrono::{DateTime, TimeZone, Utc};
+use prost_types::Timestamp;
+use std::str::FromStr;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Request, Response, Status};
+
+// Events a watcher may have queued before it is considered stuck.
+const WATCH_BUFFER: usize = 64;
+
+// Every call is authorized as if it were made on this path, which is never an
+// admin one.
+const SERVICE_PATH: &str = "/scans.v1.ScanService";
+
+// The scans API over gRPC. Every call is charged to the caller's rate limit
+// and needs the role of the REST method it mirrors.
+#[derive(Clone)]
+pub struct ScanApi {
+    store: Db,
+    authenticator: Authenticator,
+    limiter: RateLimiter,
+    validator: Validator,
+    shutdown: Shutdown,
+}
+
+impl ScanApi {
+    pub fn new(
+        store: Db, authenticator: Authenticator, limiter: RateLimiter, validator: Validator, shutdown: Shutdown,
+    ) -> Self {
+        ScanApi { store, authenticator, limiter, validator, shutdown }
+    }
+
+    // The scan as it will be stored, once the validator has admitted it.
+    fn checked(&self, mut scan: Scan) -> Result<Scan, Status> {
+        self.validator.admit(&mut scan)
+            .map_err(|problem| Status::invalid_argument(problem.detail.unwrap_or_default()))?;
+        Ok(scan)
+    }
+
+    pub fn into_service(self) -> ScanServiceServer<ScanApi> {
+        ScanServiceServer::new(self)
+    }
+
+    fn admit<T>(&self, req: &Request<T>, method: &str) -> Result<Principal, Status> {
+        let metadata = req.metadata();
+        let api_key = metadata.get(auth::API_KEY_HEADER).and_then(|v| v.to_str().ok());
+        let authorization = metadata.get(auth::AUTHORIZATION_HEADER).and_then(|v| v.to_str().ok());
+
+        // Failed calls are charged too, to the caller's address.
+        let authorized = self.authenticator.authorize(method, SERVICE_PATH, authorization, api_key);
+        let ip = req.remote_addr().map(|addr| addr.ip());
+        let decision = self.limiter.check(method, authorized.as_ref().ok(), ip);
+        if let Some(decision) = decision.filter(|d| !d.allowed) {
+            return Err(Status::resource_exhausted(decision.problem().detail.unwrap_or_default()));
+        }
+
+        authorized.map_err(|err| denied(&err))
+    }
+}
+
+fn denied(err: &AuthError) -> Status {
+    let code = match err.status() {
+        403 => Code::PermissionDenied,
+        _ => Code::Unauthenticated,
+    };
+    Status::new(code, err.to_string())
+}
+
+// The tenant a call names, once the caller is known to be allowed it.
+fn tenant<'a>(principal: &Principal, name: &'a str) -> Result<&'a str, Status> {
+    let tenant = match name {
+        "" => DEFAULT_TENANT,
+        name => name,
+    };
+    principal.check_tenant(tenant).map_err(|err| denied(&err))?;
+    Ok(tenant)
+}
+
+fn port(port: i32) -> Result<i16, Status> {
+    i16::try_from(port).map_err(|_| Status::invalid_argument(format!("port {} is out of range", port)))
+}
+
+// Store errors are plain messages, so they are sorted into codes by what
+// they say.
+fn rejected(err: &'static str) -> Status {
+    let code = match err {
+        "record already exists" => Code::AlreadyExists,
+        "no record exists" | "no record for key" => Code::NotFound,
+        STALE_SCAN => Code::FailedPrecondition,
+        "invalid tenant name" => Code::InvalidArgument,
+        QUOTA_EXCEEDED => Code::ResourceExhausted,
+        _ => Code::Unavailable,
+    };
+    Status::new(code, err)
+}
+
+fn timestamp(at: &DateTime<Utc>) -> Timestamp {
+    Timestamp { seconds: at.timestamp(), nanos: at.timestamp_subsec_nanos() as i32 }
+}
+
+fn date_time(at: &Timestamp) -> Result<DateTime<Utc>, Status> {
+    u32::try_from(at.nanos).ok()
+        .and_then(|nanos| Utc.timestamp_opt(at.seconds, nanos).single())
+        .ok_or_else(|| Status::invalid_argument("timestamp is out of range"))
+}
+
+impl From<Scan> for proto::Scan {
+    fn from(scan: Scan) -> Self {
+        proto::Scan {
+            timestamp: Some(timestamp(&scan.timestamp)),
+            ip: scan.ip,
+            port: scan.port.into(),
+            load_time_nanosec: scan.load_time_nanosec,
+            content_hash: scan.content_hash,
+            protocol: scan.protocol.map(Protocol::name),
+            http_status: scan.http_status.map(Into::into),
+            headers: scan.headers,
+            body_size: scan.body_size,
+            tls: scan.tls.map(|tls| proto::TlsInfo {
+                version: tls.version.name(),
+                cipher: tls.cipher,
+                certificate_expires_at: tls.certificate_expires_at.as_ref().map(timestamp),
+            }),
+            error: scan.error.map(ErrorKind::name),
+            received_at: scan.received_at.as_ref().map(timestamp),
+        }
+    }
+}
+
+impl TryFrom<proto::Scan> for Scan {
+    type Error = Status;
+
+    fn try_from(scan: proto::Scan) -> Result<Self, Status> {
+        let at = scan.timestamp.ok_or_else(|| Status::invalid_argument("scan has no timestamp"))?;
+
+        Ok(Scan {
+            ip: scan.ip,
+            port: port(scan.port)?,
+            load_time_nanosec: scan.load_time_nanosec,
+            content_hash: scan.content_hash,
+            timestamp: date_time(&at)?,
+            protocol: scan.protocol.as_deref().map(|name| parsed("protocol", name)).transpose()?,
+            http_status: scan.http_status
+                .map(|status| u16::try_from(status)
+                    .map_err(|_| Status::invalid_argument(format!("HTTP status {} is out of range", status))))
+                .transpose()?,
+            headers: scan.headers,
+            body_size: scan.body_size,
+            tls: scan.tls.map(|tls| -> Result<TlsInfo, Status> {
+                Ok(TlsInfo {
+                    version: parsed("tls.version", &tls.version)?,
+                    cipher: tls.cipher,
+                    certificate_expires_at: tls.certificate_expires_at.as_ref().map(date_time).transpose()?,
+                })
+            }).transpose()?,
+            error: scan.error.as_deref().map(|name| parsed("error", name)).transpose()?,
+            received_at: scan.received_at.as_ref().map(date_time).transpose()?,
+        })
+    }
+}
+
+fn parsed<T: FromStr<Err = String>>(field: &str, name: &str) -> Result<T, Status> {
+    name.parse().map_err(|e| Status::invalid_argument(format!("{}: {}", field, e)))
+}
+
+fn required(scan: Option<proto::Scan>) -> Result<Scan, Status> {
+    scan.ok_or_else(|| Status::invalid_argument("request has no scan"))?.try_into()
+}
+
+// A `ScanFilter` with its timestamps checked, so matching can't fail.
+#[derive(Default)]
+struct Filter {
+    ip: Option<String>,
+    port: Option<i16>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+    fn parse(filter: Option<proto::ScanFilter>) -> Result<Filter, Status> {
+        let filter = match filter {
+            None => return Ok(Filter::default()),
+            Some(filter) => filter,
+        };
+
+        Ok(Filter {
+            ip: Some(filter.ip).filter(|ip| !ip.is_empty()),
+            port: match filter.port {
+                0 => None,
+                n => Some(port(n)?),
+            },
+            since: filter.since.as_ref().map(date_time).transpose()?,
+            until: filter.until.as_ref().map(date_time).transpose()?,
+        })
+    }
+
+    fn matches(&self, scan: &Scan) -> bool {
+        self.ip.as_ref().is_none_or(|ip| *ip == scan.ip)
+            && self.port.is_none_or(|port| port == scan.port)
+            && self.since.is_none_or(|since| scan.timestamp >= since)
+            && self.until.is_none_or(|until| scan.timestamp < until)
+    }
+}
+
+fn event(change: Change) -> proto::ScanEvent {
+    let kind = match change.kind {
+        ChangeKind::Created => Kind::Created,
+        ChangeKind::Updated => Kind::Updated,
+        ChangeKind::Deleted => Kind::Deleted,
+    };
+
+    proto::ScanEvent { kind: kind as i32, tenant: change.tenant, scan: Some(change.scan.into()) }
+}
+
+#[tonic::async_trait]
+impl ScanService for ScanApi {
+    async fn get_scan(&self, req: Request<proto::GetScanRequest>) -> Result<Response<proto::Scan>, Status> {
+        let principal = self.admit(&req, "GET")?;
+        let req = req.into_inner();
+        let tenant = tenant(&principal, &req.tenant)?;
+
+        match self.store.read().await.get_record_in(tenant, &req.ip, port(req.port)?) {
+            Some(scan) => Ok(Response::new(scan.into())),
+            None => Err(Status::not_found(format!("no scan of {}:{}", req.ip, req.port))),
+        }
+    }
+
+    async fn list_scans(
+        &self, req: Request<proto::ListScansRequest>,
+    ) -> Result<Response<proto::ListScansResponse>, Status> {
+        let principal = self.admit(&req, "GET")?;
+        let req = req.into_inner();
+        let tenant = tenant(&principal, &req.tenant)?;
+        let filter = Filter::parse(req.filter)?;
+        let limit = match req.limit {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+
+        let sort = match req.sort.as_str() {
+            "" => SortKey::default(),
+            sort => sort.parse()
+                .map_err(|_| Status::invalid_argument(format!("sort must be timestamp or received_at, not {:?}", sort)))?,
+        };
+
+        let scans = self.store.read().await.get_all_sorted_in(tenant, sort)
+            .into_iter()
+            .filter(|scan| filter.matches(scan))
+            .take(limit)
+            .map(proto::Scan::from)
+            .collect();
+
+        Ok(Response::new(proto::ListScansResponse { scans }))
+    }
+
+    async fn create_scan(&self, req: Request<proto::CreateScanRequest>) -> Result<Response<()>, Status> {
+        let principal = self.admit(&req, "POST")?;
+        let req = req.into_inner();
+        let tenant = tenant(&principal, &req.tenant)?;
+        let scan = self.checked(required(req.scan)?)?;
+
+        self.store.write().await.insert_record_in(tenant, scan).map_err(rejected)?;
+        Ok(Response::new(()))
+    }
+
+    async fn update_scan(&self, req: Request<proto::UpdateScanRequest>) -> Result<Response<()>, Status> {
+        let principal = self.admit(&req, "PUT")?;
+        let req = req.into_inner();
+        let tenant = tenant(&principal, &req.tenant)?;
+        let scan = self.checked(required(req.scan)?)?;
+
+        self.store.write().await.update_record_in(tenant, scan).map_err(rejected)?;
+        Ok(Response::new(()))
+    }
+
+    async fn delete_scan(&self, req: Request<proto::DeleteScanRequest>) -> Result<Response<()>, Status> {
+        let principal = self.admit(&req, "DELETE")?;
+        let req = req.into_inner();
+        let tenant = tenant(&principal, &req.tenant)?;
+
+        self.store.write().await
+            .delete_record_in(tenant, &req.ip, port(req.port)?)
+            .map_err(rejected)?;
+        Ok(Response::new(()))
+    }
+
+    type WatchScansStream = ReceiverStream<Result<proto::ScanEvent, Status>>;
+
+    // Forwards matching changes until the caller goes away or the server
+    // shuts down. A watcher that falls too far behind is cut off rather than
+    // silently missing changes.
+    async fn watch_scans(
+        &self, req: Request<proto::WatchScansRequest>,
+    ) -> Result<Response<Self::WatchScansStream>, Status> {
+        let principal = self.admit(&req, "GET")?;
+        let req = req.into_inner();
+        let tenant = tenant(&principal, &req.tenant)?.to_owned();
+        let filter = Filter::parse(req.filter)?;
+
+        let mut changes = self.store.read().await.subscribe();
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        let shutdown = self.shutdown.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let change = tokio::select! {
+                    change = changes.recv() => change,
+                    _ = tx.closed() => return,
+                    _ = shutdown.wait() => return,
+                };
+
+                let sent = match change {
+                    Ok(change) if change.tenant != tenant || !filter.matches(&change.scan) => continue,
+                    Ok(change) => tx.send(Ok(event(change))).await,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        let _ = tx.send(Err(Status::aborted(format!(
+                            "watch fell behind and missed {} changes", missed,
+                        )))).await;
+                        return;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+
+                if sent.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::proto::scan_service_client::ScanServiceClient;
+    use crate::config::Config;
+    use crate::store::store::Store;
+    use std::net::SocketAddr;
+    use tokio_stream::StreamExt;
+    use tonic::transport::Channel;
+
+    fn scan(ip: &str, port: i32, seconds: i64) -> proto::Scan {
+        proto::Scan {
+            ip: ip.to_owned(),
+            port,
+            load_time_nanosec: 18,
+            content_hash: "73d1a9ab21fce25e".to_owned(),
+            timestamp: Some(Timestamp { seconds, nanos: 0 }),
+            ..Default::default()
+        }
+    }
+
+    async fn serve(authenticator: Authenticator) -> (ScanServiceClient<Channel>, Shutdown) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let api = ScanApi::new(
+            Db::new(Store::new()), authenticator, RateLimiter::new(None, None), Validator::default(),
+            shutdown.clone(),
+        );
+
+        let signal = shutdown.clone();
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(api.into_service())
+                .serve_with_incoming_shutdown(
+                    tokio_stream::wrappers::TcpListenerStream::new(listener),
+                    async move { signal.wait().await },
+                )
+                .await
+                .unwrap();
+        });
+
+        let client = ScanServiceClient::connect(format!("http://{}", addr)).await.unwrap();
+        (client, shutdown)
+    }
+
+    #[test]
+    fn scans_convert_both_ways() {
+        let original = scan("1.2.3.4", 80, 1_659_277_020);
+        let model = Scan::try_from(original.clone()).unwrap();
+        assert_eq!(model.timestamp, Utc.with_ymd_and_hms(2022, 7, 31, 14, 17, 0).unwrap());
+        assert_eq!(proto::Scan::from(model), original);
+
+        let status = Scan::try_from(scan("1.2.3.4", 70_000, 0)).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = Scan::try_from(proto::Scan { timestamp: None, ..original.clone() }).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let detailed = proto::Scan {
+            protocol: Some("h2".to_owned()),
+            http_status: Some(301),
+            headers: [("location".to_owned(), "/".to_owned())].into(),
+            tls: Some(proto::TlsInfo { version: "TLSv1.3".to_owned(), ..Default::default() }),
+            error: Some("too_many_redirects".to_owned()),
+            ..original
+        };
+        let model = Scan::try_from(detailed.clone()).unwrap();
+        assert_eq!(model.error, Some(ErrorKind::TooManyRedirects));
+        assert_eq!(proto::Scan::from(model), detailed);
+
+        let status = Scan::try_from(proto::Scan { protocol: Some("spdy".to_owned()), ..detailed }).unwrap_err();
+        assert!(status.message().starts_with("protocol: "), "{}", status.message());
+    }
+
+    #[tokio::test]
+    async fn crud_and_filtered_list() {
+        let (mut client, shutdown) = serve(Authenticator::disabled()).await;
+
+        for (ip, port, at) in [("1.2.3.4", 80, 30), ("1.2.3.4", 443, 10), ("8.8.8.8", 53, 20)] {
+            client.create_scan(proto::CreateScanRequest { tenant: String::new(), scan: Some(scan(ip, port, at)) })
+                .await.unwrap();
+        }
+
+        let status = client.create_scan(proto::CreateScanRequest {
+            tenant: String::new(), scan: Some(scan("8.8.8.8", 53, 20)),
+        }).await.unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let list = |filter: proto::ScanFilter, limit| proto::ListScansRequest {
+            tenant: String::new(), filter: Some(filter), limit, sort: String::new(),
+        };
+        let ports = |res: Response<proto::ListScansResponse>| {
+            res.into_inner().scans.into_iter().map(|s| s.port).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ports(client.list_scans(list(Default::default(), 0)).await.unwrap()), [443, 53, 80]);
+        assert_eq!(ports(client.list_scans(list(Default::default(), 2)).await.unwrap()), [443, 53]);
+        let by_ip = proto::ScanFilter { ip: "1.2.3.4".to_owned(), ..Default::default() };
+        assert_eq!(ports(client.list_scans(list(by_ip, 0)).await.unwrap()), [443, 80]);
+        let window = proto::ScanFilter {
+            since: Some(Timestamp { seconds: 15, nanos: 0 }),
+            until: Some(Timestamp { seconds: 30, nanos: 0 }),
+            ..Default::default()
+        };
+        assert_eq!(ports(client.list_scans(list(window, 0)).await.unwrap()), [53]);
+        let by_arrival = proto::ListScansRequest { sort: "received_at".to_owned(), ..list(Default::default(), 0) };
+        assert_eq!(ports(client.list_scans(by_arrival).await.unwrap()), [443, 53, 80]);
+        let status = client.list_scans(proto::ListScansRequest { sort: "ip".to_owned(), ..list(Default::default(), 0) })
+            .await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let mut updated = scan("1.2.3.4", 80, 40);
+        updated.content_hash = "e52ecf12ba9a1d37".to_owned();
+        client.update_scan(proto::UpdateScanRequest { tenant: String::new(), scan: Some(updated) })
+            .await.unwrap();
+        let got = client.get_scan(proto::GetScanRequest { tenant: String::new(), ip: "1.2.3.4".to_owned(), port: 80 })
+            .await.unwrap().into_inner();
+        assert_eq!(got.content_hash, "e52ecf12ba9a1d37");
+
+        client.delete_scan(proto::DeleteScanRequest { tenant: String::new(), ip: "1.2.3.4".to_owned(), port: 80 })
+            .await.unwrap();
+        let status = client.get_scan(proto::GetScanRequest {
+            tenant: String::new(), ip: "1.2.3.4".to_owned(), port: 80,
+        }).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = client.list_scans(proto::ListScansRequest {
+            tenant: "Not A Tenant".to_owned(), ..Default::default()
+        }).await.unwrap();
+        assert!(status.into_inner().scans.is_empty());
+
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn watch_streams_matching_changes() {
+        let (mut client, shutdown) = serve(Authenticator::disabled()).await;
+
+        let mut events = client.watch_scans(proto::WatchScansRequest {
+            tenant: "team-a".to_owned(),
+            filter: Some(proto::ScanFilter { ip: "1.2.3.4".to_owned(), ..Default::default() }),
+        }).await.unwrap().into_inner();
+
+        let create = |tenant: &str, ip| proto::CreateScanRequest {
+            tenant: tenant.to_owned(), scan: Some(scan(ip, 80, 10)),
+        };
+        client.create_scan(create("team-b", "1.2.3.4")).await.unwrap();
+        client.create_scan(create("team-a", "8.8.8.8")).await.unwrap();
+        client.create_scan(create("team-a", "1.2.3.4")).await.unwrap();
+        client.delete_scan(proto::DeleteScanRequest {
+            tenant: "team-a".to_owned(), ip: "1.2.3.4".to_owned(), port: 80,
+        }).await.unwrap();
+
+        let first = events.next().await.unwrap().unwrap();
+        assert_eq!((first.kind(), first.tenant.as_str()), (Kind::Created, "team-a"));
+        assert_eq!(first.scan.unwrap().ip, "1.2.3.4");
+        assert_eq!(events.next().await.unwrap().unwrap().kind(), Kind::Deleted);
+
+        // Shutting down ends the stream so it doesn't hold the server open.
+        shutdown.trigger();
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn calls_need_credentials() {
+        let keys = std::env::temp_dir().join(format!("scans-grpc-{}-keys", std::process::id()));
+        std::fs::write(&keys, "reader secret read\n").unwrap();
+        let config = Config { api_keys_file: Some(keys), ..Config::default() };
+        let (mut client, shutdown) = serve(Authenticator::from_config(&config).unwrap()).await;
+
+        let status = client.list_scans(proto::ListScansRequest::default()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut req = Request::new(proto::ListScansRequest::default());
+        req.metadata_mut().insert(auth::API_KEY_HEADER, "secret".parse().unwrap());
+        client.list_scans(req).await.unwrap();
+
+        let mut req = Request::new(proto::DeleteScanRequest { port: 80, ..Default::default() });
+        req.metadata_mut().insert(auth::API_KEY_HEADER, "secret".parse().unwrap());
+        assert_eq!(client.delete_scan(req).await.unwrap_err().code(), Code::PermissionDenied);
+
+        // The key is only granted the default tenant.
+        let mut req = Request::new(proto::ListScansRequest { tenant: "team-a".to_owned(), ..Default::default() });
+        req.metadata_mut().insert(auth::API_KEY_HEADER, "secret".parse().unwrap());
+        assert_eq!(client.list_scans(req).await.unwrap_err().code(), Code::PermissionDenied);
+
+        shutdown.trigger();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::shutdown::Shutdown;
+use crate::store::db::Db;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// How long readiness waits for the store lock before calling it unavailable.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize,Clone,Copy,Debug,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct Check {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn up(detail: impl Into<String>) -> Self {
+        Check { status: Status::Up, detail: Some(detail.into()) }
+    }
+
+    pub fn down(detail: impl Into<String>) -> Self {
+        Check { status: Status::Down, detail: Some(detail.into()) }
+    }
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct HealthReport {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl HealthReport {
+    fn from_checks(checks: BTreeMap<&'static str, Check>) -> Self {
+        let status = match checks.values().all(|c| c.status == Status::Up) {
+            true => Status::Up,
+            false => Status::Down,
+        };
+
+        HealthReport { status, checks }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.status == Status::Up
+    }
+}
+
+// Liveness only says the process is serving requests; it never looks at
+// dependencies, so an orchestrator won't restart us for a backend outage.
+pub fn liveness() -> HealthReport {
+    HealthReport::from_checks(BTreeMap::new())
+}
+
+pub async fn readiness(store: &Db, shutdown: &Shutdown) -> HealthReport {
+    let mut checks = BTreeMap::new();
+
+    let store_check = match tokio::time::timeout(LOCK_TIMEOUT, store.read()).await {
+        Ok(store) => store.health(),
+        Err(_) => Check::down("timed out waiting for the store lock"),
+    };
+    checks.insert("store", store_check);
+
+    let shutdown_check = match shutdown.is_triggered() {
+        false => Check::up("accepting requests"),
+        true => Check::down("draining for shutdown"),
+    };
+    checks.insert("shutdown", shutdown_check);
+
+    HealthReport::from_checks(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::store::Store;
+
+    #[test]
+    fn liveness_is_always_up() {
+        let report = liveness();
+
+        assert!(report.is_up());
+        assert_eq!(serde_json::to_string(&report).unwrap(), "{\"status\":\"up\",\"checks\":{}}");
+    }
+
+    #[tokio::test]
+    async fn readiness_reflects_store_and_shutdown() {
+        let store = Db::new(Store::new());
+        let shutdown = Shutdown::new();
+
+        let report = readiness(&store, &shutdown).await;
+        assert!(report.is_up());
+        assert_eq!(report.checks["store"].detail.as_deref(), Some("memory"));
+
+        shutdown.trigger();
+
+        let report = readiness(&store, &shutdown).await;
+        assert!(!report.is_up());
+        assert_eq!(report.checks["shutdown"].status, Status::Down);
+    }
+
+    #[tokio::test]
+    async fn readiness_is_down_while_store_is_locked() {
+        let store = Db::new(Store::new());
+        let shutdown = Shutdown::new();
+
+        let _writer = store.write().await;
+
+        let report = readiness(&store, &shutdown).await;
+        assert_eq!(report.checks["store"].status, Status::Down);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::config::Config;
+use crate::problem::{self, Problem};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+pub const HEADER: &str = "idempotency-key";
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+pub const MAX_KEY_LEN: usize = 255;
+
+// Roughly what a kept response costs beyond its body: the slot, its place in
+// the age order and the hash tables' own bookkeeping.
+const SLOT_OVERHEAD: usize = 128;
+
+// How often expired responses are swept, so an idle service lets go of them
+// too.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// A response as it was first sent, so a retry can be given the same one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recorded {
+    pub status: u16,
+    pub content_type: Option<&'static str>,
+    pub body: Vec<u8>,
+    pub replayed: bool,
+}
+
+impl Recorded {
+    pub fn new(status: u16) -> Self {
+        Recorded { status, content_type: None, body: Vec::new(), replayed: false }
+    }
+
+    pub fn problem(problem: &Problem) -> Self {
+        Recorded {
+            status: problem.status,
+            content_type: Some(problem::CONTENT_TYPE),
+            body: problem.body().into_bytes(),
+            replayed: false,
+        }
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn headers(&self) -> Vec<(&'static str, &'static str)> {
+        let mut headers = Vec::new();
+        if let Some(content_type) = self.content_type {
+            headers.push(("content-type", content_type));
+        }
+        if self.replayed {
+            headers.push((REPLAYED_HEADER, "true"));
+        }
+        headers
+    }
+}
+
+// What identifies a POST for deduplication. Keys are scoped to the caller's
+// credentials and the path, so two clients can't see each other's responses
+// by picking the same key.
+pub struct Request<'a> {
+    pub key: Option<&'a str>,
+    pub path: &'a str,
+    pub authorization: Option<&'a str>,
+    pub api_key: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+impl Request<'_> {
+    fn id(&self, key: &str) -> u64 {
+        hash(&(key, self.path, self.authorization, self.api_key))
+    }
+
+    fn fingerprint(&self) -> u64 {
+        hash(&(self.content_type, self.body))
+    }
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+enum Slot {
+    InProgress,
+    Done { fingerprint: u64, response: Recorded, at: Instant, age: u64 },
+}
+
+fn cost(response: &Recorded) -> usize {
+    response.body.len() + SLOT_OVERHEAD
+}
+
+// The slots by id, and the kept responses in the order they were kept, so
+// the oldest can be expired or evicted without walking the whole table.
+#[derive(Default)]
+struct Slots {
+    by_id: HashMap<u64, Slot>,
+    by_age: BTreeMap<u64, u64>,
+    kept: u64,
+    bytes: usize,
+}
+
+impl Slots {
+    fn remove(&mut self, id: u64) {
+        if let Some(Slot::Done { response, age, .. }) = self.by_id.remove(&id) {
+            self.by_age.remove(&age);
+            self.bytes -= cost(&response);
+        }
+    }
+
+    // Keeps `response` for `id`, dropping the oldest responses until the
+    // table fits in `max_bytes`. A response too big to fit on its own isn't
+    // kept at all.
+    fn keep(&mut self, id: u64, fingerprint: u64, response: Recorded, at: Instant, max_bytes: usize) {
+        self.remove(id);
+        if cost(&response) > max_bytes {
+            return;
+        }
+
+        self.kept += 1;
+        self.bytes += cost(&response);
+        self.by_age.insert(self.kept, id);
+        self.by_id.insert(id, Slot::Done { fingerprint, response, at, age: self.kept });
+        while self.bytes > max_bytes {
+            match self.by_age.first_key_value() {
+                Some((_, &oldest)) => self.remove(oldest),
+                None => break,
+            }
+        }
+    }
+
+    // Drops the responses kept for `window` or longer, oldest first.
+    fn expire(&mut self, window: Duration, now: Instant) {
+        while let Some((_, &oldest)) = self.by_age.first_key_value() {
+            match self.by_id.get(&oldest) {
+                Some(Slot::Done { at, .. }) if now.saturating_duration_since(*at) >= window => self.remove(oldest),
+                _ => break,
+            }
+        }
+    }
+}
+
+enum Begin {
+    Proceed,
+    Replay(Recorded),
+    Reject(Problem),
+}
+
+struct Inner {
+    window: Duration,
+    max_bytes: usize,
+    slots: Mutex<Slots>,
+}
+
+// Responses to POSTs that carried an `Idempotency-Key`, kept for a window so
+// a client retrying after a timeout gets the original answer instead of
+// having its write applied, or rejected, a second time.
+#[derive(Clone)]
+pub struct Idempotency {
+    inner: Arc<Inner>,
+}
+
+// Clears a slot whose request never finished, e.g. because the client went
+// away and the handler was dropped, so a retry isn't locked out.
+struct Claim<'a> {
+    cache: &'a Idempotency,
+    id: u64,
+    done: bool,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cache.inner.slots.lock().unwrap().remove(self.id);
+        }
+    }
+}
+
+impl Idempotency {
+    // A zero window turns deduplication off. Kept responses are dropped
+    // oldest first once their bodies take more than `max_bytes`.
+    pub fn new(window: Duration, max_bytes: usize) -> Self {
+        Idempotency { inner: Arc::new(Inner { window, max_bytes, slots: Mutex::default() }) }
+    }
+
+    // Also sweeps expired responses every minute, until the last clone is
+    // dropped.
+    pub fn from_config(config: &Config) -> Self {
+        let cache = Idempotency::new(config.idempotency_window, config.idempotency_max_bytes);
+        if cache.is_enabled() {
+            sweep(Arc::downgrade(&cache.inner));
+        }
+        cache
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.inner.window.is_zero()
+    }
+
+    // Runs `handle` unless `req` repeats a key seen within the window, in
+    // which case the first response is replayed. Reusing a key for a
+    // different body, or while the first request is still running, is
+    // rejected. Server errors aren't kept, so they can be retried.
+    pub async fn run(&self, req: Request<'_>, handle: impl Future<Output = Recorded>) -> Recorded {
+        let key = match req.key {
+            Some(key) if self.is_enabled() => key,
+            _ => return handle.await,
+        };
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Recorded::problem(&Problem::new(400, "Bad Request").with_detail(format!(
+                "{} must be 1-{} characters", HEADER, MAX_KEY_LEN,
+            )));
+        }
+
+        let id = req.id(key);
+        let fingerprint = req.fingerprint();
+        match self.begin(id, fingerprint, Instant::now()) {
+            Begin::Replay(response) => return response,
+            Begin::Reject(problem) => return Recorded::problem(&problem),
+            Begin::Proceed => {},
+        }
+
+        let mut claim = Claim { cache: self, id, done: false };
+        let response = handle.await;
+        if response.status < 500 {
+            self.finish(id, fingerprint, response.clone(), Instant::now());
+            claim.done = true;
+        }
+        response
+    }
+
+    fn begin(&self, id: u64, fingerprint: u64, now: Instant) -> Begin {
+        let window = self.inner.window;
+        let mut slots = self.inner.slots.lock().unwrap();
+        slots.expire(window, now);
+
+        let begin = match slots.by_id.get(&id) {
+            Some(Slot::Done { at, .. }) if now.saturating_duration_since(*at) >= window => Begin::Proceed,
+            None => Begin::Proceed,
+            Some(Slot::InProgress) => Begin::Reject(Problem::new(409, "Conflict").with_detail(format!(
+                "a request with this {} is still being processed", HEADER,
+            ))),
+            Some(Slot::Done { fingerprint: first, .. }) if *first != fingerprint => {
+                Begin::Reject(Problem::new(422, "Unprocessable Content").with_detail(format!(
+                    "this {} was already used with a different request body", HEADER,
+                )))
+            },
+            Some(Slot::Done { response, .. }) => Begin::Replay(Recorded { replayed: true, ..response.clone() }),
+        };
+
+        if let Begin::Proceed = begin {
+            slots.remove(id);
+            slots.by_id.insert(id, Slot::InProgress);
+        }
+        begin
+    }
+
+    fn finish(&self, id: u64, fingerprint: u64, response: Recorded, now: Instant) {
+        self.inner.slots.lock().unwrap().keep(id, fingerprint, response, now, self.inner.max_bytes);
+    }
+}
+
+fn sweep(inner: Weak<Inner>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(SWEEP_INTERVAL);
+
+        let Some(inner) = inner.upgrade() else { break };
+        inner.slots.lock().unwrap().expire(inner.window, Instant::now());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'a>(key: Option<&'a str>, body: &'a [u8]) -> Request<'a> {
+        Request {
+            key,
+            path: "/v1/scans",
+            authorization: None,
+            api_key: Some("secret"),
+            content_type: Some("application/json"),
+            body,
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_replayed() {
+        let cache = Idempotency::new(Duration::from_secs(60), 1 << 20);
+        let created = || async { Recorded::new(201) };
+        let exists = || async { Recorded::new(400).with_body("record already exists") };
+
+        let first = cache.run(request(Some("a"), b"{}"), created()).await;
+        assert_eq!(first, Recorded::new(201));
+
+        let again = cache.run(request(Some("a"), b"{}"), exists()).await;
+        assert_eq!(again, Recorded { replayed: true, ..Recorded::new(201) });
+        assert_eq!(again.headers(), vec![(REPLAYED_HEADER, "true")]);
+
+        // Without a key, or with another one, the request runs.
+        assert_eq!(cache.run(request(None, b"{}"), exists()).await.status, 400);
+        assert_eq!(cache.run(request(Some("b"), b"{}"), exists()).await.status, 400);
+
+        let other_client = Request { api_key: Some("other"), ..request(Some("a"), b"{}") };
+        assert_eq!(cache.run(other_client, exists()).await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn reused_keys_conflict() {
+        let cache = Idempotency::new(Duration::from_secs(60), 1 << 20);
+
+        cache.run(request(Some("a"), b"{}"), async { Recorded::new(201) }).await;
+        let changed = cache.run(request(Some("a"), b"{\"port\":1}"), async { Recorded::new(201) }).await;
+        assert_eq!(changed.status, 422);
+        assert_eq!(changed.content_type, Some(problem::CONTENT_TYPE));
+
+        let long = "k".repeat(MAX_KEY_LEN + 1);
+        assert_eq!(cache.run(request(Some(&long), b"{}"), async { Recorded::new(201) }).await.status, 400);
+
+        let now = Instant::now();
+        assert!(matches!(cache.begin(1, 1, now), Begin::Proceed));
+        assert!(matches!(cache.begin(1, 1, now), Begin::Reject(Problem { status: 409, .. })));
+    }
+
+    #[tokio::test]
+    async fn failures_and_abandoned_requests_are_forgotten() {
+        let cache = Idempotency::new(Duration::from_secs(60), 1 << 20);
+
+        let failed = cache.run(request(Some("a"), b"{}"), async { Recorded::new(503) }).await;
+        assert_eq!(failed.status, 503);
+        assert_eq!(cache.run(request(Some("a"), b"{}"), async { Recorded::new(201) }).await.status, 201);
+
+        let abandoned = cache.run(request(Some("b"), b"{}"), std::future::pending());
+        assert!(tokio::time::timeout(Duration::from_millis(10), abandoned).await.is_err());
+        assert_eq!(cache.run(request(Some("b"), b"{}"), async { Recorded::new(201) }).await.status, 201);
+    }
+
+    #[test]
+    fn responses_expire() {
+        let cache = Idempotency::new(Duration::from_secs(60), 1 << 20);
+        let start = Instant::now();
+
+        assert!(matches!(cache.begin(1, 1, start), Begin::Proceed));
+        cache.finish(1, 1, Recorded::new(201), start);
+
+        assert!(matches!(cache.begin(1, 1, start + Duration::from_secs(59)), Begin::Replay(_)));
+        assert!(matches!(cache.begin(1, 2, start + Duration::from_secs(60)), Begin::Proceed));
+    }
+
+    #[test]
+    fn oldest_responses_are_dropped_past_the_byte_cap() {
+        let cache = Idempotency::new(Duration::from_secs(60), 3 * (SLOT_OVERHEAD + 10));
+        let start = Instant::now();
+        let keep = |id: u64, at: Instant| {
+            assert!(matches!(cache.begin(id, 1, at), Begin::Proceed));
+            cache.finish(id, 1, Recorded::new(201).with_body([b'x'; 10]), at);
+        };
+
+        for id in 1..=4 {
+            keep(id, start);
+        }
+        assert!(matches!(cache.begin(1, 1, start), Begin::Proceed));
+        assert!(matches!(cache.begin(2, 1, start), Begin::Replay(_)));
+        assert!(matches!(cache.begin(4, 1, start), Begin::Replay(_)));
+
+        // Too big to keep at all, so the others stay.
+        assert!(matches!(cache.begin(5, 1, start), Begin::Proceed));
+        cache.finish(5, 1, Recorded::new(201).with_body(vec![b'x'; 1024]), start);
+        assert!(matches!(cache.begin(5, 1, start), Begin::Proceed));
+        assert!(matches!(cache.begin(3, 1, start), Begin::Replay(_)));
+
+        // Expiry goes oldest first and frees what the responses took.
+        keep(6, start + Duration::from_secs(30));
+        cache.inner.slots.lock().unwrap().expire(Duration::from_secs(60), start + Duration::from_secs(60));
+        let slots = cache.inner.slots.lock().unwrap();
+        assert_eq!(slots.by_age.values().copied().collect::<Vec<_>>(), vec![6]);
+        assert_eq!(slots.bytes, SLOT_OVERHEAD + 10);
+    }
+
+    #[tokio::test]
+    async fn zero_window_disables() {
+        let cache = Idempotency::new(Duration::ZERO, 1 << 20);
+
+        cache.run(request(Some("a"), b"{}"), async { Recorded::new(201) }).await;
+        assert_eq!(cache.run(request(Some("a"), b"{}"), async { Recorded::new(400) }).await.status, 400);
+    }
+}
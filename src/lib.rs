@@ -1,5 +1,32 @@
+pub mod admin;
+pub mod auth;
+mod config;
+pub mod graphql;
+pub mod grpc;
+pub mod health;
+pub mod idempotency;
+pub mod metrics;
+pub mod negotiate;
 mod model;
+pub mod openapi;
+pub mod problem;
+pub mod ratelimit;
+mod shutdown;
 mod store;
+pub mod telemetry;
+pub mod tls;
+pub mod validation;
 
-pub use model::Scan;
-pub use store::store::Store;
+pub use config::{Config, LogFormat};
+pub use model::{ErrorKind, Protocol, Scan, TlsInfo, TlsVersion};
+pub use model::version;
+pub use shutdown::{InFlight, InFlightGuard, Shutdown};
+pub use store::archive::{Archive, ArchivedScan};
+pub use store::db::Db;
+pub use store::snapshot::{Snapshot, SnapshotFormat};
+pub use store::store::{Change, ChangeKind, SortKey, Store, UpdatePolicy, QUOTA_EXCEEDED, STALE_SCAN};
+pub use store::tenant;
+#[cfg(feature = "wal")]
+pub use store::recovery::{newest_snapshot_seq, Plan, Target};
+#[cfg(feature = "wal")]
+pub use store::wal::{Compaction, Horizon, Migrated, Prepared, Wal};
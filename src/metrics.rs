@@ -0,0 +1,218 @@
+use crate::store::db::Db;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const LOCK_WAIT_BUCKETS: &[f64] = &[
+    0.00001, 0.0001, 0.001, 0.01, 0.1, 1.0,
+];
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram { buckets, counts: vec![0; buckets.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: BTreeMap<(&'static str, &'static str, u16), u64>,
+    latency: BTreeMap<(&'static str, &'static str), Histogram>,
+    lock_wait: BTreeMap<&'static str, Histogram>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+// Maps a request path onto the route it matched so label cardinality stays
+// bounded no matter which ips and ports are requested.
+pub fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["v1", "scans"] => "/v1/scans",
+        ["v1", "scans", _, _] => "/v1/scans/{ip}/{port}",
+        ["v1", "tenants", _, "scans"] => "/v1/tenants/{tenant}/scans",
+        ["v1", "tenants", _, "scans", _, _] => "/v1/tenants/{tenant}/scans/{ip}/{port}",
+        ["v1", "graphql"] => "/v1/graphql",
+        ["v1", "admin", "snapshots"] => "/v1/admin/snapshots",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        ["metrics"] => "/metrics",
+        ["openapi.json"] => "/openapi.json",
+        ["docs"] => "/docs",
+        _ => "unmatched",
+    }
+}
+
+// Methods are sent by the client too, so any it makes up share one label.
+pub fn method_label(method: &str) -> &'static str {
+    match method {
+        "GET" => "GET",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "PATCH" => "PATCH",
+        "DELETE" => "DELETE",
+        "HEAD" => "HEAD",
+        "OPTIONS" => "OPTIONS",
+        _ => "other",
+    }
+}
+
+impl Metrics {
+    pub fn observe_request(&self, method: &str, path: &str, status: u16, elapsed: Duration) {
+        let (method, route) = (method_label(method), route_label(path));
+        let mut inner = self.inner.lock().unwrap();
+
+        *inner.requests.entry((method, route, status)).or_insert(0) += 1;
+        inner.latency
+            .entry((method, route))
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_lock_wait(&self, mode: &'static str, elapsed: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.lock_wait
+            .entry(mode)
+            .or_insert_with(|| Histogram::new(LOCK_WAIT_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn render(&self, store_records: usize) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests served, by route and status code.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((method, route, status), count) in inner.requests.iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method, route, status, count,
+            );
+        }
+
+        out.push_str("# HELP http_request_duration_seconds Time spent serving requests, by route.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for ((method, route), histogram) in inner.latency.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+            histogram.render(&mut out, "http_request_duration_seconds", &labels);
+        }
+
+        out.push_str("# HELP store_records Scans currently held by the store.\n");
+        out.push_str("# TYPE store_records gauge\n");
+        let _ = writeln!(out, "store_records {}", store_records);
+
+        out.push_str("# HELP store_lock_wait_seconds Time spent waiting for the store lock.\n");
+        out.push_str("# TYPE store_lock_wait_seconds histogram\n");
+        for (mode, histogram) in inner.lock_wait.iter() {
+            let labels = format!("mode=\"{}\"", mode);
+            histogram.render(&mut out, "store_lock_wait_seconds", &labels);
+        }
+
+        out
+    }
+}
+
+// Renders the process-wide metrics along with the current store size.
+pub async fn render(store: &Db) -> String {
+    let records = store.read().await.len();
+    global().render(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_label_collapses_parameters() {
+        assert_eq!(route_label("/v1/scans"), "/v1/scans");
+        assert_eq!(route_label("/v1/scans/"), "/v1/scans");
+        assert_eq!(route_label("/v1/scans/8.8.8.8/80"), "/v1/scans/{ip}/{port}");
+        assert_eq!(route_label("/metrics"), "/metrics");
+        assert_eq!(route_label("/v1/graphql"), "/v1/graphql");
+        assert_eq!(route_label("/v1/admin/snapshots"), "/v1/admin/snapshots");
+        assert_eq!(route_label("/v1/scans/8.8.8.8"), "unmatched");
+        assert_eq!(route_label("/v1/tenants/team-a/scans"), "/v1/tenants/{tenant}/scans");
+        assert_eq!(
+            route_label("/v1/tenants/team-a/scans/8.8.8.8/80"), "/v1/tenants/{tenant}/scans/{ip}/{port}",
+        );
+    }
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = Metrics::default();
+
+        metrics.observe_request("GET", "/v1/scans/1.1.1.1/443", 200, Duration::from_millis(3));
+        metrics.observe_request("GET", "/v1/scans/8.8.8.8/80", 200, Duration::from_millis(30));
+        metrics.observe_request("POST", "/v1/scans", 400, Duration::from_millis(1));
+        metrics.observe_request("BREW", "/v1/scans", 405, Duration::from_millis(1));
+        metrics.observe_request("FROB", "/v1/scans", 405, Duration::from_millis(1));
+        metrics.observe_lock_wait("write", Duration::from_micros(50));
+
+        let out = metrics.render(7);
+
+        assert!(out.contains(
+            "http_requests_total{method=\"GET\",route=\"/v1/scans/{ip}/{port}\",status=\"200\"} 2\n"
+        ));
+        assert!(out.contains(
+            "http_requests_total{method=\"POST\",route=\"/v1/scans\",status=\"400\"} 1\n"
+        ));
+        assert!(out.contains(
+            "http_requests_total{method=\"other\",route=\"/v1/scans\",status=\"405\"} 2\n"
+        ));
+        assert!(!out.contains("BREW"));
+        assert!(out.contains(
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/v1/scans/{ip}/{port}\",le=\"0.005\"} 1\n"
+        ));
+        assert!(out.contains(
+            "http_request_duration_seconds_count{method=\"GET\",route=\"/v1/scans/{ip}/{port}\"} 2\n"
+        ));
+        assert!(out.contains("store_records 7\n"));
+        assert!(out.contains("store_lock_wait_seconds_bucket{mode=\"write\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("store_lock_wait_seconds_count{mode=\"write\"} 1\n"));
+    }
+}
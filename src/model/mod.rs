@@ -1,3 +1,4 @@
 mod scan;
+pub mod version;
 
-pub use scan::Scan;
+pub use scan::{ErrorKind, Protocol, Scan, TlsInfo, TlsVersion};
@@ -1,14 +1,193 @@
+use crate::problem::FieldError;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
 use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
 use std::string::String;
+use utoipa::ToSchema;
 
-#[derive(Serialize,Deserialize,Clone,Debug)]
+// Everything past `timestamp` was added after the first release, so each of
+// those fields has a default and payloads from older scanners still decode.
+#[derive(Serialize,Deserialize,Clone,Debug,Default,PartialEq,ToSchema)]
 pub struct Scan {
     pub ip: String,
     pub port: i16,
     pub load_time_nanosec: i64,
     pub content_hash: String,
     pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    // The response headers the scanner chose to keep, by lowercase name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsInfo>,
+    // Why the scan failed, if it did. A failed scan may have no content hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorKind>,
+    // When the server took the scan in, if it stamps them. Set by the server
+    // alone; whatever a client sends is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq,ToSchema)]
+pub enum Protocol {
+    #[serde(rename = "http/1.0")]
+    Http10,
+    #[serde(rename = "http/1.1")]
+    Http11,
+    #[serde(rename = "h2")]
+    H2,
+    #[serde(rename = "h3")]
+    H3,
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq,Eq,ToSchema)]
+pub struct TlsInfo {
+    pub version: TlsVersion,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate_expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq,ToSchema)]
+pub enum TlsVersion {
+    #[serde(rename = "TLSv1.0")]
+    Tls10,
+    #[serde(rename = "TLSv1.1")]
+    Tls11,
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq,ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Timeout,
+    Dns,
+    ConnectionRefused,
+    ConnectionReset,
+    Tls,
+    Http,
+    TooManyRedirects,
+    Other,
+}
+
+// Round-trips the enums above through the same names serde gives them, for
+// the APIs that carry them as plain strings.
+macro_rules! named {
+    ($($kind:ident),*) => {$(
+        impl $kind {
+            pub fn name(self) -> String {
+                match serde_json::to_value(self) {
+                    Ok(Value::String(name)) => name,
+                    _ => unreachable!("unit variants serialize to strings"),
+                }
+            }
+        }
+
+        impl std::str::FromStr for $kind {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                serde_json::from_value(Value::String(s.to_owned())).map_err(|e| e.to_string())
+            }
+        }
+    )*};
+}
+
+named!(Protocol, TlsVersion, ErrorKind);
+
+// Every field a scan body may carry.
+const REQUIRED_FIELDS: &[&str] = &["ip", "port", "load_time_nanosec", "content_hash", "timestamp"];
+const OPTIONAL_FIELDS: &[&str] = &["protocol", "http_status", "headers", "body_size", "tls", "error", "received_at"];
+const TLS_FIELDS: &[&str] = &["version", "cipher", "certificate_expires_at"];
+
+impl Scan {
+    // Builds a scan from a decoded body one field at a time, so a client hears
+    // about every bad field rather than only the first. Fields a scan doesn't
+    // have are an error when `strict` and ignored otherwise.
+    pub fn from_fields(fields: Map<String, Value>, strict: bool) -> Result<Scan, Vec<FieldError>> {
+        let mut fields = Fields { fields, errors: Vec::new() };
+        if strict {
+            let known = [REQUIRED_FIELDS, OPTIONAL_FIELDS].concat();
+            fields.errors = unknown_fields("", &fields.fields, &known);
+            if let Some(Value::Object(tls)) = fields.fields.get("tls") {
+                fields.errors.extend(unknown_fields("tls.", tls, TLS_FIELDS));
+            }
+        }
+
+        let ip = fields.required("ip");
+        let port = fields.required("port");
+        let load_time_nanosec = fields.required("load_time_nanosec");
+        let content_hash = fields.required("content_hash");
+        let timestamp = fields.required("timestamp");
+        let protocol = fields.optional("protocol");
+        let http_status = fields.optional("http_status");
+        let headers = fields.optional("headers");
+        let body_size = fields.optional("body_size");
+        let tls = fields.optional("tls");
+        let error = fields.optional("error");
+        let received_at = fields.optional("received_at");
+
+        match (ip, port, load_time_nanosec, content_hash, timestamp) {
+            (Some(ip), Some(port), Some(load_time_nanosec), Some(content_hash), Some(timestamp))
+                if fields.errors.is_empty() => Ok(Scan {
+                    ip, port, load_time_nanosec, content_hash, timestamp,
+                    protocol, http_status,
+                    headers: headers.unwrap_or_default(),
+                    body_size, tls, error, received_at,
+                }),
+            _ => Err(fields.errors),
+        }
+    }
+}
+
+fn unknown_fields(prefix: &str, fields: &Map<String, Value>, known: &[&str]) -> Vec<FieldError> {
+    fields.keys()
+        .filter(|name| !known.contains(&name.as_str()))
+        .map(|name| FieldError::new(format!("{}{}", prefix, name), "unknown field"))
+        .collect()
+}
+
+struct Fields {
+    fields: Map<String, Value>,
+    errors: Vec<FieldError>,
+}
+
+impl Fields {
+    fn required<T: DeserializeOwned>(&mut self, name: &str) -> Option<T> {
+        let value = match self.fields.remove(name) {
+            Some(value) => value,
+            None => {
+                self.errors.push(FieldError::new(name, "missing field"));
+                return None;
+            },
+        };
+
+        serde_json::from_value(value)
+            .map_err(|e| self.errors.push(FieldError::new(name, e.to_string())))
+            .ok()
+    }
+
+    // A missing or null optional field is left at its default.
+    fn optional<T: DeserializeOwned>(&mut self, name: &str) -> Option<T> {
+        match self.fields.remove(name) {
+            None | Some(Value::Null) => None,
+            Some(value) => serde_json::from_value(value)
+                .map_err(|e| self.errors.push(FieldError::new(name, e.to_string())))
+                .ok(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -32,8 +211,83 @@ mod tests {
         assert_eq!(s.port, 80);
         assert_eq!(s.load_time_nanosec, 50000);
         assert_eq!(s.content_hash, "73d1a9ab21fce25e");
-        assert_eq!(s.timestamp, Utc.ymd(2022, 7, 31).and_hms(14, 17, 0));
+        assert_eq!(s.timestamp, Utc.with_ymd_and_hms(2022, 7, 31, 14, 17, 0).unwrap());
 
         Ok(())
     }
+
+    #[test]
+    fn fields_are_checked_one_by_one() {
+        let fields = |value: Value| match value {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        };
+        let scan = serde_json::json!({
+            "ip": "8.8.8.8",
+            "port": 80,
+            "load_time_nanosec": 50000,
+            "content_hash": "73d1a9ab21fce25e",
+            "timestamp": "2022-07-31T14:17:00Z",
+        });
+        let scan_fields = fields(scan.clone());
+        assert_eq!(scan_fields.len(), REQUIRED_FIELDS.len());
+        assert!(REQUIRED_FIELDS.iter().all(|name| scan_fields.contains_key(*name)));
+
+        let mut extra = scan.clone();
+        extra["color"] = "blue".into();
+        assert!(Scan::from_fields(fields(extra.clone()), false).is_ok());
+        assert_eq!(
+            Scan::from_fields(fields(extra), true).unwrap_err(),
+            vec![FieldError::new("color", "unknown field")],
+        );
+
+        let mut bad = scan;
+        bad["port"] = 70000.into();
+        bad.as_object_mut().unwrap().remove("ip");
+        let errors = Scan::from_fields(fields(bad), true).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], FieldError::new("ip", "missing field"));
+        assert_eq!(errors[1].field, "port");
+        assert!(errors[1].message.contains("70000"), "{}", errors[1].message);
+    }
+
+    #[test]
+    fn optional_fields_default_and_round_trip() {
+        let old = serde_json::json!({
+            "ip": "8.8.8.8",
+            "port": 80,
+            "load_time_nanosec": 50000,
+            "content_hash": "73d1a9ab21fce25e",
+            "timestamp": "2022-07-31T14:17:00Z",
+        });
+        let scan: Scan = serde_json::from_value(old.clone()).unwrap();
+        assert_eq!(scan.protocol, None);
+        assert!(scan.headers.is_empty());
+        assert_eq!(serde_json::to_value(&scan).unwrap(), old);
+
+        let mut full = old;
+        full["protocol"] = "h2".into();
+        full["http_status"] = 200.into();
+        full["headers"] = serde_json::json!({"server": "nginx"});
+        full["body_size"] = 5120.into();
+        full["tls"] = serde_json::json!({"version": "TLSv1.3", "cipher": "TLS_AES_128_GCM_SHA256"});
+        full["error"] = "timeout".into();
+        let Value::Object(map) = full.clone() else { unreachable!() };
+        let scan = Scan::from_fields(map, true).unwrap();
+        assert_eq!(scan.protocol, Some(Protocol::H2));
+        assert_eq!(scan.tls.as_ref().unwrap().version, TlsVersion::Tls13);
+        assert_eq!(scan.error, Some(ErrorKind::Timeout));
+        assert_eq!(serde_json::to_value(&scan).unwrap(), full);
+        assert_eq!(serde_json::from_value::<Scan>(full).unwrap(), scan);
+
+        assert_eq!("http/1.1".parse(), Ok(Protocol::Http11));
+        assert_eq!(ErrorKind::ConnectionRefused.name(), "connection_refused");
+
+        let mut bad = serde_json::to_value(&scan).unwrap();
+        bad["protocol"] = "gopher".into();
+        bad["tls"]["curve"] = "x25519".into();
+        let Value::Object(map) = bad else { unreachable!() };
+        let fields: Vec<_> = Scan::from_fields(map, true).unwrap_err().into_iter().map(|e| e.field).collect();
+        assert_eq!(fields, ["tls.curve", "protocol"]);
+    }
 }
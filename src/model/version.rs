@@ -0,0 +1,117 @@
+use serde_json::{Map, Value};
+
+// The version of the scan schema this build writes into anything it
+// persists. Bump it whenever `Scan` changes in a way an older reader would get
+// wrong, and add the migration from the previous version to `MIGRATIONS`.
+//
+// 1: ip, port, load_time_nanosec, content_hash and timestamp.
+// 2: optional protocol, http_status, headers, body_size, tls and error.
+// 3: optional received_at.
+pub const SCAN_VERSION: u32 = 3;
+
+// Persisted documents from before versioning carry no version at all.
+const FIRST_VERSION: u32 = 1;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+// `MIGRATIONS[n]` takes a scan from version `n + 1` to `n + 2`.
+const MIGRATIONS: [Migration; SCAN_VERSION as usize - 1] = [v1_to_v2, v2_to_v3];
+
+// Reads the `version` of a persisted envelope, refusing one written by a
+// newer build.
+pub fn version_of(envelope: &Value) -> Result<u32, String> {
+    let version = match envelope.get("version") {
+        None => FIRST_VERSION,
+        Some(version) => version.as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= FIRST_VERSION)
+            .ok_or_else(|| format!("{} is not a schema version", version))?,
+    };
+
+    match version > SCAN_VERSION {
+        true => Err(unreadable(version, SCAN_VERSION)),
+        false => Ok(version),
+    }
+}
+
+fn unreadable(version: u32, current: u32) -> String {
+    format!("written at schema version {}, but this build only reads up to {}", version, current)
+}
+
+// Brings a scan written at version `from` up to `SCAN_VERSION`, one migration
+// at a time.
+pub fn migrate_scan(scan: &mut Value, from: u32) -> Result<(), String> {
+    migrate_with(&MIGRATIONS, scan, from)
+}
+
+fn migrate_with(migrations: &[Migration], scan: &mut Value, from: u32) -> Result<(), String> {
+    let current = FIRST_VERSION + migrations.len() as u32;
+    if from > current {
+        return Err(unreadable(from, current));
+    }
+
+    let fields = scan.as_object_mut().ok_or("a persisted scan must be an object")?;
+    for (step, migrate) in migrations.iter().enumerate().skip((from - FIRST_VERSION) as usize) {
+        let version = FIRST_VERSION + step as u32;
+        migrate(fields).map_err(|e| format!("migrating a scan from version {} to {}: {}", version, version + 1, e))?;
+    }
+    Ok(())
+}
+
+// Version 2 only added fields that default to unset, so a version 1 scan
+// already reads as a version 2 one.
+fn v1_to_v2(_: &mut Map<String, Value>) -> Result<(), String> {
+    Ok(())
+}
+
+// Scans from before the server stamped them were never stamped, which is
+// what a missing `received_at` says.
+fn v2_to_v3(_: &mut Map<String, Value>) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn versions_default_to_the_first() {
+        assert_eq!(version_of(&json!({"seq": 1})), Ok(1));
+        assert_eq!(version_of(&json!({"version": 2})), Ok(2));
+        assert!(version_of(&json!({"version": 0})).is_err());
+        assert!(version_of(&json!({"version": "2"})).is_err());
+        assert!(version_of(&json!({"version": SCAN_VERSION + 1})).unwrap_err().contains("only reads up to"));
+    }
+
+    #[test]
+    fn migrations_run_in_order_from_the_written_version() {
+        fn rename_hash(scan: &mut Map<String, Value>) -> Result<(), String> {
+            let hash = scan.remove("hash").ok_or("no hash")?;
+            scan.insert("content_hash".to_owned(), hash);
+            Ok(())
+        }
+        fn prefix_hash(scan: &mut Map<String, Value>) -> Result<(), String> {
+            let hash = scan["content_hash"].as_str().ok_or("hash is not a string")?;
+            scan["content_hash"] = format!("xxh64:{}", hash).into();
+            Ok(())
+        }
+        let migrations: [Migration; 2] = [rename_hash, prefix_hash];
+
+        let mut v1 = json!({"hash": "73d1a9ab21fce25e"});
+        migrate_with(&migrations, &mut v1, 1).unwrap();
+        assert_eq!(v1, json!({"content_hash": "xxh64:73d1a9ab21fce25e"}));
+
+        let mut v2 = json!({"content_hash": "73d1a9ab21fce25e"});
+        migrate_with(&migrations, &mut v2, 2).unwrap();
+        assert_eq!(v2, json!({"content_hash": "xxh64:73d1a9ab21fce25e"}));
+
+        let mut v3 = v2.clone();
+        migrate_with(&migrations, &mut v3, 3).unwrap();
+        assert_eq!(v3, v2);
+
+        let err = migrate_with(&migrations, &mut json!({}), 1).unwrap_err();
+        assert_eq!(err, "migrating a scan from version 1 to 2: no hash");
+        assert!(migrate_with(&migrations, &mut json!({}), 4).unwrap_err().contains("only reads up to 3"));
+    }
+}
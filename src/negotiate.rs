@@ -0,0 +1,561 @@
+use crate::config::Config;
+use crate::model::{ErrorKind, Protocol, Scan, TlsVersion};
+use crate::problem::{self, FieldError, Problem};
+use crate::store::store::SortKey;
+use crate::validation::Validator;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+
+pub const ACCEPT_HEADER: &str = "accept";
+pub const ACCEPT_ENCODING_HEADER: &str = "accept-encoding";
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+// Responses vary on both negotiated headers, so caches must key on them.
+pub const VARY: &str = "accept, accept-encoding";
+
+// Bodies smaller than this gain little from compression and aren't worth the
+// CPU.
+const MIN_COMPRESSED_LEN: usize = 1024;
+
+// Representations of scans the API speaks, in the order it prefers them when
+// a client accepts several equally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MessagePack,
+    Csv,
+}
+
+const FORMATS: [Format; 4] = [Format::Json, Format::Cbor, Format::MessagePack, Format::Csv];
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+            Format::Csv => "text/csv",
+        }
+    }
+
+    fn matches(&self, mime: &str) -> bool {
+        match self {
+            Format::MessagePack => {
+                matches!(mime, "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack")
+            },
+            format => mime == format.content_type(),
+        }
+    }
+
+    // Picks the format for a response from an `Accept` header.
+    pub fn from_accept(accept: Option<&str>) -> Result<Format, Problem> {
+        let ranges = match accept.map(str::trim) {
+            None | Some("") => return Ok(Format::Json),
+            Some(accept) => parse_weighted(accept),
+        };
+
+        best(&FORMATS, |format| {
+            let (kind, _) = format.content_type().split_once('/').expect("mime has a slash");
+            ranges.iter()
+                .filter_map(|(range, q)| {
+                    let specificity = match range.as_str() {
+                        "*/*" => 0,
+                        range if range.strip_suffix("/*") == Some(kind) => 1,
+                        range if format.matches(range) => 2,
+                        _ => return None,
+                    };
+                    Some((specificity, *q))
+                })
+                .max_by_key(|(specificity, _)| *specificity)
+                .map(|(_, q)| q)
+        })
+        .ok_or_else(|| Problem::new(406, "Not Acceptable").with_detail(format!(
+            "scans are available as {}", supported(&FORMATS),
+        )))
+    }
+
+    // Picks the format of a request body from its `Content-Type`. Bodies
+    // without one are read as JSON.
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Format, Problem> {
+        let mime = match content_type {
+            None => return Ok(Format::Json),
+            Some(content_type) => essence(content_type),
+        };
+
+        match FORMATS.iter().find(|format| format.matches(&mime)) {
+            Some(Format::Csv) => Err(unsupported("text/csv is only available for responses")),
+            Some(format) => Ok(*format),
+            None => Err(unsupported(format!(
+                "request bodies must be one of {}", supported(&FORMATS[..3]),
+            ))),
+        }
+    }
+
+    pub fn encode_scans(&self, scans: &[Scan]) -> Vec<u8> {
+        match self {
+            Format::Csv => csv(scans.iter()),
+            format => format.encode(&scans),
+        }
+    }
+
+    pub fn encode_scan(&self, scan: Option<&Scan>) -> Vec<u8> {
+        match self {
+            Format::Csv => csv(scan.into_iter()),
+            format => format.encode(&scan),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        match self {
+            Format::Json => serde_json::to_vec(value).expect("scans serialize"),
+            Format::Cbor => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(value, &mut body).expect("scans serialize");
+                body
+            },
+            Format::MessagePack => rmp_serde::to_vec_named(value).expect("scans serialize"),
+            Format::Csv => unreachable!("csv only encodes scans"),
+        }
+    }
+
+    // Bodies are read into a generic value first so objects can be checked
+    // field by field whatever format they came in.
+    pub fn decode_scan(&self, body: &[u8], strict: bool) -> Result<Scan, Problem> {
+        let value: Value = match self {
+            Format::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::de::from_reader(body).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            Format::Csv => Err("text/csv is only available for responses".to_owned()),
+        }.map_err(invalid_scan)?;
+
+        match value {
+            Value::Object(fields) => Scan::from_fields(fields, strict).map_err(invalid_fields),
+            // MessagePack clients may send a scan as an array of its fields.
+            value => serde_json::from_value(value).map_err(|e| invalid_scan(e.to_string())),
+        }
+    }
+}
+
+// How request bodies are accepted: how large they may be, whether fields a
+// scan doesn't have are refused or ignored, and what a scan must satisfy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyPolicy {
+    pub limit: usize,
+    pub strict: bool,
+    pub validator: Validator,
+}
+
+impl BodyPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        BodyPolicy {
+            limit: config.body_limit,
+            strict: config.strict_bodies,
+            validator: Validator::from_config(config),
+        }
+    }
+
+    pub fn too_large(&self) -> Problem {
+        Problem::new(413, "Content Too Large")
+            .with_detail(format!("request bodies are limited to {} bytes", self.limit))
+    }
+
+    pub fn check_len(&self, len: usize) -> Result<(), Problem> {
+        match len > self.limit {
+            true => Err(self.too_large()),
+            false => Ok(()),
+        }
+    }
+
+    pub fn decode_scan(&self, content_type: Option<&str>, body: &[u8]) -> Result<Scan, Problem> {
+        self.check_len(body.len())?;
+        let mut scan = Format::from_content_type(content_type)?.decode_scan(body, self.strict)?;
+        self.validator.admit(&mut scan)?;
+        Ok(scan)
+    }
+}
+
+impl Default for BodyPolicy {
+    fn default() -> Self {
+        BodyPolicy::from_config(&Config::default())
+    }
+}
+
+// Response compression, in the order the API prefers them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Identity,
+}
+
+const ENCODINGS: [Encoding; 4] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Identity];
+
+impl Encoding {
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    // Picks a coding from an `Accept-Encoding` header, falling back to none
+    // at all rather than refusing the request.
+    pub fn from_accept_encoding(accept_encoding: Option<&str>) -> Encoding {
+        let codings = parse_weighted(accept_encoding.unwrap_or(""));
+
+        best(&ENCODINGS, |encoding| {
+            let exact = codings.iter().find(|(coding, _)| coding == encoding.token());
+            let any = codings.iter().find(|(coding, _)| coding == "*");
+            match (exact.or(any), encoding) {
+                (Some((_, q)), _) => Some(*q),
+                // Identity is acceptable unless it was ruled out.
+                (None, Encoding::Identity) => Some(1),
+                (None, _) => None,
+            }
+        })
+        .unwrap_or(Encoding::Identity)
+    }
+
+    fn compress(&self, body: Vec<u8>) -> Vec<u8> {
+        match self {
+            Encoding::Identity => body,
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body).expect("writing to memory");
+                encoder.finish().expect("writing to memory")
+            },
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                    encoder.write_all(&body).expect("writing to memory");
+                }
+                compressed
+            },
+            Encoding::Zstd => zstd::encode_all(body.as_slice(), 0).expect("writing to memory"),
+        }
+    }
+}
+
+// A negotiated response body with the headers that describe it.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub content_type: &'static str,
+    pub content_encoding: Option<&'static str>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    fn new(format: Format, encoding: Encoding, body: Vec<u8>) -> Self {
+        let encoding = match body.len() < MIN_COMPRESSED_LEN {
+            true => Encoding::Identity,
+            false => encoding,
+        };
+
+        Reply {
+            content_type: format.content_type(),
+            content_encoding: match encoding {
+                Encoding::Identity => None,
+                encoding => Some(encoding.token()),
+            },
+            body: encoding.compress(body),
+        }
+    }
+
+    pub fn headers(&self) -> Vec<(&'static str, &'static str)> {
+        let mut headers = vec![("content-type", self.content_type), ("vary", VARY)];
+        if let Some(encoding) = self.content_encoding {
+            headers.push(("content-encoding", encoding));
+        }
+        headers
+    }
+}
+
+pub fn scans_reply(
+    accept: Option<&str>, accept_encoding: Option<&str>, scans: &[Scan],
+) -> Result<Reply, Problem> {
+    let format = Format::from_accept(accept)?;
+    let encoding = Encoding::from_accept_encoding(accept_encoding);
+    Ok(Reply::new(format, encoding, format.encode_scans(scans)))
+}
+
+// The order a list request asked for with `?sort=timestamp` or
+// `?sort=received_at`. Other query parameters are left alone.
+pub fn sort_key(query: Option<&str>) -> Result<SortKey, Problem> {
+    let bad_request = |detail: String| Problem::new(400, "Bad Request").with_detail(detail);
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query.unwrap_or_default())
+        .map_err(|e| bad_request(format!("invalid query: {}", e)))?;
+
+    match pairs.into_iter().find(|(name, _)| name == "sort") {
+        None => Ok(SortKey::default()),
+        Some((_, value)) => value.parse()
+            .map_err(|_| bad_request(format!("sort must be timestamp or received_at, not {:?}", value))),
+    }
+}
+
+pub fn scan_reply(
+    accept: Option<&str>, accept_encoding: Option<&str>, scan: Option<&Scan>,
+) -> Result<Reply, Problem> {
+    let format = Format::from_accept(accept)?;
+    let encoding = Encoding::from_accept_encoding(accept_encoding);
+    Ok(Reply::new(format, encoding, format.encode_scan(scan)))
+}
+
+// Splits a header like `text/csv;q=0.5, application/json` into lowercase
+// values and their weights in thousandths.
+fn parse_weighted(header: &str) -> Vec<(String, u16)> {
+    header.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let value = parts.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((value, (q.clamp(0.0, 1.0) * 1000.0).round() as u16))
+        })
+        .collect()
+}
+
+// The first candidate with the highest non-zero weight.
+fn best<T: Copy>(candidates: &[T], weight: impl Fn(&T) -> Option<u16>) -> Option<T> {
+    candidates.iter()
+        .filter_map(|candidate| weight(candidate).filter(|q| *q > 0).map(|q| (*candidate, q)))
+        .fold(None, |best: Option<(T, u16)>, (candidate, q)| match best {
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((candidate, q)),
+        })
+        .map(|(candidate, _)| candidate)
+}
+
+fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+fn supported(formats: &[Format]) -> String {
+    formats.iter().map(Format::content_type).collect::<Vec<_>>().join(", ")
+}
+
+fn invalid_scan(detail: String) -> Problem {
+    Problem::new(400, "Bad Request").with_detail(format!("invalid scan: {}", detail))
+}
+
+fn invalid_fields(errors: Vec<FieldError>) -> Problem {
+    invalid_scan(problem::summary(&errors)).with_errors(errors)
+}
+
+fn unsupported(detail: impl Into<String>) -> Problem {
+    Problem::new(415, "Unsupported Media Type").with_detail(detail)
+}
+
+// CSV has a fixed set of columns, so a scan's nested and optional fields are
+// flattened into them. Headers don't fit a fixed set and are left out.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    ip: &'a str,
+    port: i16,
+    load_time_nanosec: i64,
+    content_hash: &'a str,
+    timestamp: &'a DateTime<Utc>,
+    protocol: Option<Protocol>,
+    http_status: Option<u16>,
+    body_size: Option<u64>,
+    tls_version: Option<TlsVersion>,
+    tls_cipher: Option<&'a str>,
+    error: Option<ErrorKind>,
+    received_at: Option<&'a DateTime<Utc>>,
+}
+
+const CSV_COLUMNS: [&str; 12] = [
+    "ip", "port", "load_time_nanosec", "content_hash", "timestamp",
+    "protocol", "http_status", "body_size", "tls_version", "tls_cipher", "error", "received_at",
+];
+
+impl<'a> From<&'a Scan> for CsvRow<'a> {
+    fn from(scan: &'a Scan) -> Self {
+        CsvRow {
+            ip: &scan.ip,
+            port: scan.port,
+            load_time_nanosec: scan.load_time_nanosec,
+            content_hash: &scan.content_hash,
+            timestamp: &scan.timestamp,
+            protocol: scan.protocol,
+            http_status: scan.http_status,
+            body_size: scan.body_size,
+            tls_version: scan.tls.as_ref().map(|tls| tls.version),
+            tls_cipher: scan.tls.as_ref().and_then(|tls| tls.cipher.as_deref()),
+            error: scan.error,
+            received_at: scan.received_at.as_ref(),
+        }
+    }
+}
+
+fn csv<'a>(scans: impl ExactSizeIterator<Item = &'a Scan>) -> Vec<u8> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    if scans.len() == 0 {
+        writer.write_record(CSV_COLUMNS).expect("writing to memory");
+    }
+    for scan in scans {
+        writer.serialize(CsvRow::from(scan)).expect("scans serialize");
+    }
+    writer.into_inner().expect("writing to memory")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    use std::io::Read;
+
+    fn scan(port: i16) -> Scan {
+        Scan {
+            ip: "1.2.3.4".to_owned(),
+            port,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: "2022-07-31T14:17:00Z".parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accept_picks_format() {
+        assert_eq!(Format::from_accept(None), Ok(Format::Json));
+        assert_eq!(Format::from_accept(Some("*/*")), Ok(Format::Json));
+        assert_eq!(Format::from_accept(Some("application/cbor")), Ok(Format::Cbor));
+        assert_eq!(Format::from_accept(Some("application/x-msgpack")), Ok(Format::MessagePack));
+        assert_eq!(Format::from_accept(Some("text/*")), Ok(Format::Csv));
+        assert_eq!(
+            Format::from_accept(Some("application/json;q=0.5, text/csv")), Ok(Format::Csv),
+        );
+        assert_eq!(
+            Format::from_accept(Some("application/cbor;q=0, application/*")), Ok(Format::Json),
+        );
+        assert_eq!(Format::from_accept(Some("text/html")).unwrap_err().status, 406);
+    }
+
+    #[test]
+    fn content_type_picks_format() {
+        assert_eq!(Format::from_content_type(None), Ok(Format::Json));
+        assert_eq!(
+            Format::from_content_type(Some("application/json; charset=utf-8")), Ok(Format::Json),
+        );
+        assert_eq!(Format::from_content_type(Some("Application/CBOR")), Ok(Format::Cbor));
+        assert_eq!(Format::from_content_type(Some("text/csv")).unwrap_err().status, 415);
+        assert_eq!(Format::from_content_type(Some("text/plain")).unwrap_err().status, 415);
+    }
+
+    #[test]
+    fn binary_formats_round_trip() {
+        for format in [Format::Json, Format::Cbor, Format::MessagePack] {
+            let body = format.encode(&scan(80));
+            let decoded = format.decode_scan(&body, true).unwrap();
+            assert_eq!(decoded.port, 80, "{:?}", format);
+            assert_eq!(decoded.timestamp, scan(80).timestamp, "{:?}", format);
+        }
+
+        assert_eq!(Format::Cbor.decode_scan(b"{}", false).unwrap_err().status, 400);
+
+        let positional = rmp_serde::to_vec(&scan(80)).unwrap();
+        assert_eq!(Format::MessagePack.decode_scan(&positional, true).unwrap().port, 80);
+    }
+
+    #[test]
+    fn bodies_follow_the_policy() {
+        let lenient = BodyPolicy { limit: 256, strict: false, validator: Validator::default() };
+        let strict = BodyPolicy { strict: true, ..lenient };
+        let body = br#"{"ip":"1.2.3.4","port":80,"load_time_nanosec":18,"content_hash":"73d1a9ab21fce25e",
+            "timestamp":"2022-07-31T14:17:00Z","colour":"blue"}"#;
+
+        assert_eq!(lenient.decode_scan(None, body).unwrap().port, 80);
+        let problem = strict.decode_scan(None, body).unwrap_err();
+        assert_eq!(problem.status, 400);
+        assert_eq!(problem.errors, vec![FieldError::new("colour", "unknown field")]);
+        assert_eq!(problem.detail.as_deref(), Some("invalid scan: colour: unknown field"));
+
+        let problem = lenient.decode_scan(None, br#"{"port":"80"}"#).unwrap_err();
+        let fields: Vec<&str> = problem.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["ip", "port", "load_time_nanosec", "content_hash", "timestamp"]);
+
+        assert_eq!(lenient.decode_scan(None, &[b' '; 257]).unwrap_err().status, 413);
+        assert_eq!(lenient.decode_scan(None, b"[").unwrap_err().errors, vec![]);
+
+        let invalid = std::str::from_utf8(body).unwrap().replace("1.2.3.4", "1.2.3");
+        assert_eq!(lenient.decode_scan(None, invalid.as_bytes()).unwrap_err().status, 422);
+    }
+
+    #[test]
+    fn csv_has_a_header_and_a_row_per_scan() {
+        let tls = crate::model::TlsInfo {
+            version: TlsVersion::Tls12,
+            cipher: Some("ECDHE-RSA-AES128-GCM-SHA256".to_owned()),
+            certificate_expires_at: None,
+        };
+        let https = Scan { http_status: Some(200), tls: Some(tls), ..scan(443) };
+        let body = String::from_utf8(Format::Csv.encode_scans(&[scan(80), https])).unwrap();
+        assert_eq!(body, "\
+            ip,port,load_time_nanosec,content_hash,timestamp,protocol,http_status,body_size,tls_version,tls_cipher,error,received_at\n\
+            1.2.3.4,80,18,foobar,2022-07-31T14:17:00Z,,,,,,,\n\
+            1.2.3.4,443,18,foobar,2022-07-31T14:17:00Z,,200,,TLSv1.2,ECDHE-RSA-AES128-GCM-SHA256,,\n");
+
+        let empty = String::from_utf8(Format::Csv.encode_scan(None)).unwrap();
+        assert_eq!(empty, format!("{}\n", CSV_COLUMNS.join(",")));
+    }
+
+    #[test]
+    fn sort_comes_from_the_query() {
+        assert_eq!(sort_key(None), Ok(SortKey::Timestamp));
+        assert_eq!(sort_key(Some("limit=5&sort=received_at")), Ok(SortKey::ReceivedAt));
+        assert_eq!(sort_key(Some("sort=timestamp")), Ok(SortKey::Timestamp));
+        assert_eq!(sort_key(Some("sort=ip")).unwrap_err().status, 400);
+    }
+
+    #[test]
+    fn accept_encoding_picks_coding() {
+        assert_eq!(Encoding::from_accept_encoding(None), Encoding::Identity);
+        assert_eq!(Encoding::from_accept_encoding(Some("gzip, deflate")), Encoding::Gzip);
+        assert_eq!(Encoding::from_accept_encoding(Some("gzip, zstd, br")), Encoding::Brotli);
+        assert_eq!(Encoding::from_accept_encoding(Some("br;q=0.5, zstd")), Encoding::Zstd);
+        assert_eq!(Encoding::from_accept_encoding(Some("*")), Encoding::Brotli);
+        assert_eq!(Encoding::from_accept_encoding(Some("deflate")), Encoding::Identity);
+    }
+
+    #[test]
+    fn large_replies_are_compressed() {
+        let scans: Vec<Scan> = (0..50).map(scan).collect();
+        let plain = Format::Json.encode_scans(&scans);
+
+        for (coding, decompress) in [
+            ("gzip", Box::new(|body: &[u8]| {
+                let mut out = Vec::new();
+                flate2::read::GzDecoder::new(body).read_to_end(&mut out).unwrap();
+                out
+            }) as Box<dyn Fn(&[u8]) -> Vec<u8>>),
+            ("br", Box::new(|body: &[u8]| {
+                let mut out = Vec::new();
+                brotli::Decompressor::new(body, 4096).read_to_end(&mut out).unwrap();
+                out
+            })),
+            ("zstd", Box::new(|body: &[u8]| zstd::decode_all(body).unwrap())),
+        ] {
+            let reply = scans_reply(None, Some(coding), &scans).unwrap();
+            assert_eq!(reply.content_encoding, Some(coding));
+            assert!(reply.body.len() < plain.len());
+            assert_eq!(decompress(&reply.body), plain);
+        }
+
+        let small = scan_reply(None, Some("gzip"), Some(&scan(80))).unwrap();
+        assert_eq!(small.content_encoding, None);
+        assert_eq!(small.headers(), vec![("content-type", "application/json"), ("vary", VARY)]);
+    }
+}
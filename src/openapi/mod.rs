@@ -0,0 +1,449 @@
+use crate::admin::{self, Taken};
+use crate::auth::{self, Role};
+use crate::idempotency;
+use crate::model::{ErrorKind, Protocol, Scan, TlsInfo, TlsVersion};
+use crate::negotiate::Format;
+use crate::problem::{self, FieldError, Problem};
+use std::sync::OnceLock;
+use utoipa::ToSchema;
+use utoipa::openapi::{
+    Components, Content, HttpMethod, InfoBuilder, OpenApi, OpenApiBuilder, Paths,
+    Ref, RefOr, Required, Schema,
+};
+use utoipa::openapi::path::{Operation, OperationBuilder, ParameterBuilder, ParameterIn};
+use utoipa::openapi::request_body::RequestBodyBuilder;
+use utoipa::openapi::response::ResponseBuilder;
+use utoipa::openapi::security::{
+    ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme,
+};
+use utoipa::openapi::schema::{ArrayBuilder, ComponentsBuilder, ObjectBuilder, OneOfBuilder, Type};
+
+// A self-contained page that renders `/openapi.json`, so the docs work
+// without reaching out to a CDN.
+pub const DOCS_HTML: &str = include_str!("docs.html");
+
+pub fn document() -> OpenApi {
+    let mut paths = Paths::new();
+
+    for scope in [Scope::Default, Scope::Tenant] {
+        let scans = scope.path("/scans");
+        let scan = scope.path("/scans/{ip}/{port}");
+
+        paths.add_path_operation(&scans, vec![HttpMethod::Get], get_all_scans(scope));
+        paths.add_path_operation(&scans, vec![HttpMethod::Post], create_scan(scope));
+        paths.add_path_operation(&scans, vec![HttpMethod::Put], update_scan(scope));
+        paths.add_path_operation(&scan, vec![HttpMethod::Get], get_scan(scope));
+        paths.add_path_operation(&scan, vec![HttpMethod::Delete], delete_scan(scope));
+    }
+    paths.add_path_operation(admin::SNAPSHOTS_PATH, vec![HttpMethod::Post], create_snapshot());
+
+    OpenApiBuilder::new()
+        .info(
+            InfoBuilder::new()
+                .title("Scans API")
+                .version(env!("CARGO_PKG_VERSION"))
+                .description(Some("Stores the most recent scan of each ip and port."))
+                .build(),
+        )
+        .paths(paths)
+        .components(Some(components()))
+        .build()
+}
+
+// The document is the same for the life of the process, so render it once.
+pub fn json() -> &'static str {
+    static JSON: OnceLock<String> = OnceLock::new();
+    JSON.get_or_init(|| document().to_pretty_json().expect("openapi document serializes"))
+}
+
+// Every scan route exists twice: under `/v1` for the default tenant and under
+// `/v1/tenants/{tenant}` for a named one.
+#[derive(Clone, Copy)]
+enum Scope {
+    Default,
+    Tenant,
+}
+
+impl Scope {
+    fn path(&self, route: &str) -> String {
+        match self {
+            Scope::Default => format!("/v1{}", route),
+            Scope::Tenant => format!("/v1/tenants/{{tenant}}{}", route),
+        }
+    }
+
+    fn operation_id(&self, id: &str) -> String {
+        match self {
+            Scope::Default => id.to_owned(),
+            Scope::Tenant => id.replacen("scan", "tenant_scan", 1),
+        }
+    }
+
+    fn parameters(&self, with_ip_port: bool) -> Option<Vec<utoipa::openapi::path::Parameter>> {
+        let mut params = Vec::new();
+        if let Scope::Tenant = self {
+            params.push(path_param("tenant", "Tenant that owns the scans.", Type::String));
+        }
+        if with_ip_port {
+            params.extend(path_params());
+        }
+
+        match params.is_empty() {
+            true => None,
+            false => Some(params),
+        }
+    }
+}
+
+fn components() -> Components {
+    let bearer = HttpBuilder::new()
+        .scheme(HttpAuthScheme::Bearer)
+        .bearer_format("JWT")
+        .build();
+    let api_key = ApiKey::Header(ApiKeyValue::new(auth::API_KEY_HEADER));
+
+    ComponentsBuilder::new()
+        .schema_from::<Scan>()
+        .schema_from::<Protocol>()
+        .schema_from::<TlsInfo>()
+        .schema_from::<TlsVersion>()
+        .schema_from::<ErrorKind>()
+        .schema_from::<Problem>()
+        .schema_from::<FieldError>()
+        .schema_from::<Taken>()
+        .security_scheme("bearer", SecurityScheme::Http(bearer))
+        .security_scheme("api_key", SecurityScheme::ApiKey(api_key))
+        .build()
+}
+
+fn scan_ref() -> RefOr<Schema> {
+    RefOr::Ref(Ref::from_schema_name(Scan::name()))
+}
+
+fn json_content(schema: impl Into<RefOr<Schema>>) -> Content {
+    Content::new(Some(schema))
+}
+
+// Scans are read and written as JSON, CBOR or MessagePack with the same
+// schema; lists can also be fetched as CSV.
+fn scan_body() -> utoipa::openapi::request_body::RequestBody {
+    let mut body = RequestBodyBuilder::new().required(Some(Required::True));
+    for format in [Format::Json, Format::Cbor, Format::MessagePack] {
+        body = body.content(format.content_type(), json_content(scan_ref()));
+    }
+    body.build()
+}
+
+fn negotiated(description: &str, schema: impl Into<RefOr<Schema>>) -> utoipa::openapi::Response {
+    let schema = schema.into();
+    let csv = ObjectBuilder::new()
+        .schema_type(Type::String)
+        .description(Some("A header row followed by one row per scan."));
+
+    let mut response = ResponseBuilder::new().description(description);
+    for format in [Format::Json, Format::Cbor, Format::MessagePack] {
+        response = response.content(format.content_type(), json_content(schema.clone()));
+    }
+    response.content(Format::Csv.content_type(), json_content(csv)).build()
+}
+
+fn path_param(name: &str, description: &str, kind: Type) -> utoipa::openapi::path::Parameter {
+    let mut schema = ObjectBuilder::new().schema_type(kind.clone());
+    if kind == Type::Integer {
+        schema = schema.format(Some(
+            utoipa::openapi::SchemaFormat::KnownFormat(utoipa::openapi::KnownFormat::Int32),
+        ));
+    }
+
+    ParameterBuilder::new()
+        .name(name)
+        .parameter_in(ParameterIn::Path)
+        .required(Required::True)
+        .description(Some(description))
+        .schema(Some(schema))
+        .build()
+}
+
+fn idempotency_key() -> utoipa::openapi::path::Parameter {
+    ParameterBuilder::new()
+        .name("Idempotency-Key")
+        .parameter_in(ParameterIn::Header)
+        .required(Required::False)
+        .description(Some(
+            "Makes retries safe: a repeat with the same key and body gets the first response back.",
+        ))
+        .schema(Some(ObjectBuilder::new().schema_type(Type::String).max_length(Some(idempotency::MAX_KEY_LEN))))
+        .build()
+}
+
+fn sort_param() -> utoipa::openapi::path::Parameter {
+    ParameterBuilder::new()
+        .name("sort")
+        .parameter_in(ParameterIn::Query)
+        .required(Required::False)
+        .description(Some(
+            "Which time to order by. Scans without a `received_at` sort by their `timestamp` under `received_at`.",
+        ))
+        .schema(Some(ObjectBuilder::new()
+            .schema_type(Type::String)
+            .enum_values(Some(["timestamp", "received_at"]))
+            .default(Some("timestamp".into()))))
+        .build()
+}
+
+fn path_params() -> Vec<utoipa::openapi::path::Parameter> {
+    vec![
+        path_param("ip", "Address that was scanned.", Type::String),
+        path_param("port", "Port that was scanned.", Type::Integer),
+    ]
+}
+
+fn empty(description: &str) -> utoipa::openapi::Response {
+    ResponseBuilder::new().description(description).build()
+}
+
+fn problem_response(description: &str) -> utoipa::openapi::Response {
+    ResponseBuilder::new()
+        .description(description)
+        .content(problem::CONTENT_TYPE, json_content(RefOr::Ref(Ref::from_schema_name(Problem::name()))))
+        .build()
+}
+
+fn not_acceptable() -> utoipa::openapi::Response {
+    problem_response("None of the `Accept`ed formats is available.")
+}
+
+fn too_large() -> utoipa::openapi::Response {
+    problem_response("The body is over the configured size limit.")
+}
+
+fn unsupported_media_type() -> utoipa::openapi::Response {
+    problem_response("The body's `Content-Type` is not JSON, CBOR or MessagePack.")
+}
+
+// Every scan operation accepts either a bearer token or an API key, and needs
+// the role that `Role::required_for` its method.
+fn operation(scope: Scope, id: &str, summary: &str, method: &str) -> OperationBuilder {
+    secured("scans", &scope.operation_id(id), summary, Role::required_for(method))
+}
+
+fn secured(tag: &str, id: &str, summary: &str, role: Role) -> OperationBuilder {
+    OperationBuilder::new()
+        .tag(tag)
+        .operation_id(Some(id))
+        .summary(Some(summary))
+        .description(Some(format!("Requires the `{}` role.", role.as_str())))
+        .security(SecurityRequirement::new("bearer", Vec::<String>::new()))
+        .security(SecurityRequirement::new("api_key", Vec::<String>::new()))
+        .response("401", problem_response("Missing or invalid credentials."))
+        .response("403", problem_response("The caller's role or tenants do not allow this operation."))
+        .response("429", problem_response("The caller's rate limit is used up; see `Retry-After`."))
+}
+
+fn get_all_scans(scope: Scope) -> Operation {
+    let scans = ArrayBuilder::new().items(scan_ref());
+
+    operation(scope, "get_all_scans", "List every scan, oldest first", "GET")
+        .parameters(scope.parameters(false))
+        .parameter(sort_param())
+        .response("200", negotiated("All scans ordered by `sort`.", scans))
+        .response("400", problem_response("`sort` is not a time scans can be ordered by."))
+        .response("406", not_acceptable())
+        .build()
+}
+
+fn get_scan(scope: Scope) -> Operation {
+    let scan_or_null = OneOfBuilder::new()
+        .item(scan_ref())
+        .item(ObjectBuilder::new().schema_type(Type::Null));
+
+    operation(scope, "get_scan", "Fetch the scan of one ip and port", "GET")
+        .parameters(scope.parameters(true))
+        .response("200", negotiated("The scan, or `null` when there is none.", scan_or_null))
+        .response("406", not_acceptable())
+        .build()
+}
+
+fn create_scan(scope: Scope) -> Operation {
+    operation(scope, "create_scan", "Record a scan of a new ip and port", "POST")
+        .parameters(scope.parameters(false))
+        .parameter(idempotency_key())
+        .request_body(Some(scan_body()))
+        .response("201", empty("The scan was stored."))
+        .response("400", empty("A scan of this ip and port already exists, or the body is not a scan."))
+        .response("403", problem_response(
+            "The caller's role or tenants do not allow this operation, or the tenant holds as many scans as its \
+             quota allows.",
+        ))
+        .response("409", problem_response("A request with this `Idempotency-Key` is still being processed."))
+        .response("413", too_large())
+        .response("415", unsupported_media_type())
+        .response("422", problem_response(
+            "The scan failed validation, listed per field in `errors`, or the `Idempotency-Key` was already \
+             used with a different body.",
+        ))
+        .build()
+}
+
+fn update_scan(scope: Scope) -> Operation {
+    operation(scope, "update_scan", "Replace the scan of an existing ip and port", "PUT")
+        .parameters(scope.parameters(false))
+        .request_body(Some(scan_body()))
+        .response("200", empty("The scan was replaced."))
+        .response("400", empty("No scan of this ip and port exists, or the body is not a scan."))
+        .response("409", problem_response(
+            "With `SCANS_UPDATE_POLICY=newer`, the stored scan's timestamp is the same as or later than this one's.",
+        ))
+        .response("413", too_large())
+        .response("415", unsupported_media_type())
+        .response("422", problem_response("The scan failed validation, listed per field in `errors`."))
+        .build()
+}
+
+fn delete_scan(scope: Scope) -> Operation {
+    operation(scope, "delete_scan", "Remove the scan of one ip and port", "DELETE")
+        .parameters(scope.parameters(true))
+        .response("200", empty("The scan was removed."))
+        .response("400", empty("No scan of this ip and port exists."))
+        .build()
+}
+
+fn create_snapshot() -> Operation {
+    let format = ParameterBuilder::new()
+        .name("format")
+        .parameter_in(ParameterIn::Query)
+        .required(Required::False)
+        .description(Some("`binary` is zstd-compressed MessagePack behind a checksummed header; `archive` can be memory-mapped and served from in place."))
+        .schema(Some(ObjectBuilder::new()
+            .schema_type(Type::String)
+            .enum_values(Some(["json", "binary", "archive"]))
+            .default(Some("json".into()))))
+        .build();
+
+    secured("admin", "create_snapshot", "Write a snapshot of every live scan", Role::Admin)
+        .parameter(format)
+        .response("201", ResponseBuilder::new()
+            .description("The snapshot was written into `SCANS_SNAPSHOT_DIR`.")
+            .content("application/json", json_content(RefOr::Ref(Ref::from_schema_name(Taken::name()))))
+            .build())
+        .response("400", problem_response("The `format` is not `json`, `binary` or `archive`."))
+        .response("404", problem_response("`SCANS_SNAPSHOT_DIR` is not set."))
+        .response("500", problem_response("The snapshot could not be written."))
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::route_label;
+    use chrono::Utc;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    fn doc() -> Value {
+        serde_json::from_str(json()).unwrap()
+    }
+
+    #[test]
+    fn scan_schema_matches_serialized_scan() {
+        let scan = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            protocol: Some(Protocol::Http11),
+            http_status: Some(200),
+            headers: [("server".to_owned(), "nginx".to_owned())].into(),
+            body_size: Some(1024),
+            tls: Some(TlsInfo { version: TlsVersion::Tls13, cipher: None, certificate_expires_at: None }),
+            error: Some(ErrorKind::Other),
+            received_at: Some(Utc::now()),
+        };
+
+        let serialized: BTreeSet<String> = match serde_json::to_value(&scan).unwrap() {
+            Value::Object(map) => map.keys().cloned().collect(),
+            _ => panic!("scan should serialize to an object"),
+        };
+
+        let doc = doc();
+        let schema = &doc["components"]["schemas"]["Scan"];
+        let properties: BTreeSet<String> = schema["properties"].as_object().unwrap()
+            .keys().cloned().collect();
+
+        assert_eq!(properties, serialized);
+
+        // Older scans lack the newer fields, so only the original ones are
+        // required.
+        let required: BTreeSet<&str> = schema["required"].as_array().unwrap()
+            .iter().map(|name| name.as_str().unwrap()).collect();
+        assert_eq!(required, ["ip", "port", "load_time_nanosec", "content_hash", "timestamp"].into());
+    }
+
+    #[test]
+    fn paths_match_served_routes() {
+        let doc = doc();
+
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            assert_eq!(route_label(path), path, "{} is not a served route", path);
+            for operation in item.as_object().unwrap().values() {
+                assert!(operation["operationId"].is_string());
+            }
+        }
+    }
+
+    #[test]
+    fn schema_refs_resolve() {
+        fn refs(value: &Value, found: &mut Vec<String>) {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(target)) = map.get("$ref") {
+                        found.push(target.clone());
+                    }
+                    map.values().for_each(|value| refs(value, found));
+                },
+                Value::Array(items) => items.iter().for_each(|value| refs(value, found)),
+                _ => {},
+            }
+        }
+
+        let doc = doc();
+        let mut found = Vec::new();
+        refs(&doc, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(doc["components"]["schemas"][name].is_object(), "{} does not resolve", target);
+        }
+    }
+
+    #[test]
+    fn scan_routes_are_documented() {
+        let doc = doc();
+        let paths = doc["paths"].as_object().unwrap();
+
+        assert_eq!(paths["/v1/scans"].as_object().unwrap().len(), 3);
+        assert_eq!(paths["/v1/scans/{ip}/{port}"].as_object().unwrap().len(), 2);
+        assert_eq!(paths["/v1/tenants/{tenant}/scans"].as_object().unwrap().len(), 3);
+        assert_eq!(paths["/v1/tenants/{tenant}/scans/{ip}/{port}"].as_object().unwrap().len(), 2);
+        assert!(paths["/v1/admin/snapshots"]["post"]["description"].as_str().unwrap().contains("`admin`"));
+    }
+
+    #[test]
+    fn negotiated_formats_are_documented() {
+        let doc = doc();
+        let scans = &doc["paths"]["/v1/scans"];
+
+        let listed: BTreeSet<&str> = scans["get"]["responses"]["200"]["content"]
+            .as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(listed, BTreeSet::from([
+            "application/json", "application/cbor", "application/msgpack", "text/csv",
+        ]));
+
+        let accepted: BTreeSet<&str> = scans["post"]["requestBody"]["content"]
+            .as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(accepted, BTreeSet::from(["application/json", "application/cbor", "application/msgpack"]));
+        assert!(scans["post"]["responses"]["415"].is_object());
+        assert!(scans["get"]["responses"]["406"].is_object());
+    }
+}
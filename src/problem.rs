@@ -0,0 +1,74 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+// An RFC 9457 problem details body, used for every error response that
+// carries more than a bare status code.
+#[derive(Serialize,Clone,Debug,PartialEq,Eq,ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+// One field of a request body that was missing, unknown or malformed,
+// reported in a problem's `errors` member.
+#[derive(Serialize,Clone,Debug,PartialEq,Eq,ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError { field: field.into(), message: message.into() }
+    }
+}
+
+// Field errors on one line, for a problem's `detail` or APIs that only have
+// room for a message.
+pub fn summary(errors: &[FieldError]) -> String {
+    errors.iter()
+        .map(|error| format!("{}: {}", error.field, error.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// An update the store turned away under `UpdatePolicy::Newer`.
+pub fn stale_scan() -> Problem {
+    Problem::new(409, "Conflict")
+        .with_detail("stale scan: the stored scan's timestamp is the same or later, and only newer scans replace it")
+}
+
+// An insert into a tenant that already holds as many scans as its quota
+// allows.
+pub fn quota_exceeded() -> Problem {
+    Problem::new(403, "Forbidden")
+        .with_detail("tenant quota exceeded: the tenant holds as many scans as its quota allows")
+}
+
+impl Problem {
+    pub fn new(status: u16, title: &'static str) -> Self {
+        Problem { kind: "about:blank", title, status, detail: None, errors: Vec::new() }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    pub fn body(&self) -> String {
+        serde_json::to_string(self).expect("problem serializes")
+    }
+}
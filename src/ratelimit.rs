@@ -0,0 +1,311 @@
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::problem::Problem;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The most clients whose buckets are kept. Past it the bucket used least
+// recently is dropped, as the one most likely to have refilled anyway.
+const MAX_CLIENTS: usize = 100_000;
+
+// Requests allowed per period, e.g. `100/60` for a hundred a minute. The
+// bucket holds up to `requests` tokens and refills evenly over `period`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Budget {
+    fn refill_per_sec(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for Budget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, secs) = s.split_once('/').ok_or(())?;
+        let requests: u32 = requests.trim().parse().map_err(|_| ())?;
+        let secs: u64 = secs.trim().parse().map_err(|_| ())?;
+
+        match requests > 0 && secs > 0 {
+            true => Ok(Budget { requests, period: Duration::from_secs(secs) }),
+            false => Err(()),
+        }
+    }
+}
+
+// Reads and writes draw on separate buckets so a busy writer can't use up
+// what readers are allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Class {
+    Read,
+    Write,
+}
+
+impl Class {
+    pub fn of(method: &str) -> Class {
+        match method {
+            "GET" | "HEAD" | "OPTIONS" => Class::Read,
+            _ => Class::Write,
+        }
+    }
+}
+
+// The outcome of charging one request to its client's bucket, with what the
+// `RateLimit-*` headers should tell the caller.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until the bucket is full again.
+    pub reset: u64,
+    // Seconds until the next request would be allowed, when this one wasn't.
+    pub retry_after: Option<u64>,
+}
+
+impl Decision {
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", self.reset.to_string()),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            headers.push(("retry-after", retry_after.to_string()));
+        }
+        headers
+    }
+
+    pub fn problem(&self) -> Problem {
+        Problem::new(429, "Too Many Requests").with_detail(format!(
+            "rate limit of {} requests exceeded, retry in {}s",
+            self.limit, self.retry_after.unwrap_or(1),
+        ))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    used: u64,
+}
+
+// The buckets, and the order they were last used in so the least recently
+// used one can be found without a scan.
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<(u64, Class), Bucket>,
+    by_use: BTreeMap<u64, (u64, Class)>,
+    uses: u64,
+}
+
+struct Inner {
+    read: Option<Budget>,
+    write: Option<Budget>,
+    max_clients: usize,
+    buckets: Mutex<Buckets>,
+}
+
+// Token buckets per client and class. Authenticated clients are identified by
+// their subject and everyone else by address, so sending made-up credentials
+// doesn't get a caller a fresh bucket.
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+impl RateLimiter {
+    pub fn new(read: Option<Budget>, write: Option<Budget>) -> Self {
+        RateLimiter::with_max_clients(read, write, MAX_CLIENTS)
+    }
+
+    fn with_max_clients(read: Option<Budget>, write: Option<Budget>, max_clients: usize) -> Self {
+        RateLimiter { inner: Arc::new(Inner { read, write, max_clients, buckets: Mutex::default() }) }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        RateLimiter::new(config.read_rate_limit, config.write_rate_limit)
+    }
+
+    // Charges a request, or returns `None` when its class isn't limited.
+    // `principal` is who the request authenticated as, if it did.
+    pub fn check(&self, method: &str, principal: Option<&Principal>, ip: Option<IpAddr>) -> Option<Decision> {
+        self.check_at(Class::of(method), &client_id(principal, ip), Instant::now())
+    }
+
+    fn check_at(&self, class: Class, client: &str, now: Instant) -> Option<Decision> {
+        let budget = match class {
+            Class::Read => self.inner.read?,
+            Class::Write => self.inner.write?,
+        };
+        let capacity = budget.requests as f64;
+        let rate = budget.refill_per_sec();
+
+        let key = (hash(client), class);
+        let mut guard = self.inner.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+        if !buckets.by_client.contains_key(&key) && buckets.by_client.len() >= self.inner.max_clients {
+            if let Some((_, oldest)) = buckets.by_use.pop_first() {
+                buckets.by_client.remove(&oldest);
+            }
+        }
+
+        buckets.uses += 1;
+        let used = buckets.uses;
+        let bucket = buckets.by_client.entry(key)
+            .or_insert(Bucket { tokens: capacity, updated: now, used });
+        buckets.by_use.remove(&bucket.used);
+        buckets.by_use.insert(used, key);
+        bucket.used = used;
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let retry_after = match allowed {
+            true => None,
+            false => Some(((1.0 - bucket.tokens) / rate).ceil() as u64),
+        };
+
+        Some(Decision {
+            allowed,
+            limit: budget.requests,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after,
+        })
+    }
+}
+
+fn client_id(principal: Option<&Principal>, ip: Option<IpAddr>) -> String {
+    match (principal.filter(|principal| !principal.is_anonymous()), ip) {
+        (Some(principal), _) => format!("sub:{}", principal.subject),
+        (None, Some(ip)) => format!("ip:{}", ip),
+        (None, None) => "unknown".to_owned(),
+    }
+}
+
+// Clients are only held as hashes, which keeps the table's entries small.
+fn hash(client: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    client.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new("2/10".parse().ok(), "1/60".parse().ok())
+    }
+
+    #[test]
+    fn parse_budget() {
+        assert_eq!("100/60".parse(), Ok(Budget { requests: 100, period: Duration::from_secs(60) }));
+        assert!("100".parse::<Budget>().is_err());
+        assert!("0/60".parse::<Budget>().is_err());
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let limiter = limiter();
+        let start = Instant::now();
+
+        let first = limiter.check_at(Class::Read, "a", start).unwrap();
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert_eq!(first.reset, 5);
+
+        assert!(limiter.check_at(Class::Read, "a", start).unwrap().allowed);
+
+        let denied = limiter.check_at(Class::Read, "a", start).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Some(5));
+
+        let later = limiter.check_at(Class::Read, "a", start + Duration::from_secs(5)).unwrap();
+        assert!(later.allowed);
+    }
+
+    #[test]
+    fn budgets_are_per_client_and_class() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(Class::Write, "a", now).unwrap().allowed);
+        assert!(!limiter.check_at(Class::Write, "a", now).unwrap().allowed);
+
+        assert!(limiter.check_at(Class::Write, "b", now).unwrap().allowed);
+        assert!(limiter.check_at(Class::Read, "a", now).unwrap().allowed);
+    }
+
+    #[test]
+    fn unlimited_class() {
+        let limiter = RateLimiter::new(None, "1/60".parse().ok());
+
+        assert!(limiter.check("GET", None, None).is_none());
+        assert!(limiter.check("DELETE", None, None).is_some());
+    }
+
+    #[test]
+    fn clients_are_told_apart_by_subject_or_address() {
+        let limiter = RateLimiter::new(None, "1/60".parse().ok());
+        let ip = "10.0.0.1".parse().ok();
+        let principal = |subject: &str| Principal {
+            subject: subject.to_owned(),
+            role: crate::auth::Role::Write,
+            tenants: crate::auth::Tenants::All,
+        };
+
+        assert!(limiter.check("POST", None, ip).unwrap().allowed);
+        assert!(!limiter.check("POST", Some(&principal("anonymous")), ip).unwrap().allowed);
+        assert!(!limiter.check("POST", Some(&principal("")), ip).unwrap().allowed);
+
+        assert!(limiter.check("POST", Some(&principal("scanner")), ip).unwrap().allowed);
+        assert!(!limiter.check("POST", Some(&principal("scanner")), "10.0.0.2".parse().ok()).unwrap().allowed);
+    }
+
+    #[test]
+    fn least_recently_used_buckets_are_dropped() {
+        let limiter = RateLimiter::with_max_clients(None, "1/60".parse().ok(), 2);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(Class::Write, "a", now).unwrap().allowed);
+        assert!(limiter.check_at(Class::Write, "b", now).unwrap().allowed);
+        assert!(!limiter.check_at(Class::Write, "a", now).unwrap().allowed);
+
+        // `b` was used least recently, so it makes room for `c`.
+        assert!(limiter.check_at(Class::Write, "c", now).unwrap().allowed);
+        assert_eq!(limiter.inner.buckets.lock().unwrap().by_client.len(), 2);
+        assert!(!limiter.check_at(Class::Write, "a", now).unwrap().allowed);
+        assert!(limiter.check_at(Class::Write, "b", now).unwrap().allowed);
+    }
+
+    #[test]
+    fn headers() {
+        let decision = Decision { allowed: false, limit: 5, remaining: 0, reset: 12, retry_after: Some(3) };
+
+        assert_eq!(decision.headers(), vec![
+            ("ratelimit-limit", "5".to_owned()),
+            ("ratelimit-remaining", "0".to_owned()),
+            ("ratelimit-reset", "12".to_owned()),
+            ("retry-after", "3".to_owned()),
+        ]);
+        assert_eq!(decision.problem().status, 429);
+    }
+}
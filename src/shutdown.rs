@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{watch, Notify};
+
+// A cloneable handle that resolves once the process has been asked to stop,
+// either by SIGTERM/SIGINT or by calling `trigger`.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Shutdown { tx: Arc::new(tx), rx }
+    }
+
+    // Must be called from within a tokio runtime.
+    pub fn on_signals() -> Self {
+        let shutdown = Shutdown::new();
+
+        let trigger = shutdown.clone();
+        tokio::spawn(async move {
+            signal().await;
+            trigger.trigger();
+        });
+
+        shutdown
+    }
+
+    pub fn trigger(&self) {
+        let _ = self.tx.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    let mut int = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+
+    tokio::select! {
+        _ = term.recv() => {},
+        _ = int.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Counts requests currently being served so a server without its own
+// graceful shutdown can wait for them to finish.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    count: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+pub struct InFlightGuard {
+    in_flight: InFlight,
+}
+
+impl InFlight {
+    pub fn start(&self) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard { in_flight: self.clone() }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub async fn idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.count() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.in_flight.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.in_flight.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn shutdown_wait_resolves_after_trigger() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_triggered());
+
+        let waiter = shutdown.clone();
+        let handle = tokio::spawn(async move { waiter.wait().await });
+
+        shutdown.trigger();
+
+        assert!(timeout(Duration::from_secs(1), handle).await.is_ok());
+        assert!(shutdown.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn in_flight_idle_waits_for_guards() {
+        let in_flight = InFlight::default();
+        let guard = in_flight.start();
+        assert_eq!(in_flight.count(), 1);
+
+        assert!(timeout(Duration::from_millis(20), in_flight.idle()).await.is_err());
+
+        drop(guard);
+
+        assert!(timeout(Duration::from_secs(1), in_flight.idle()).await.is_ok());
+        assert_eq!(in_flight.count(), 0);
+    }
+}
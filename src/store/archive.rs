@@ -0,0 +1,788 @@
+use crate::model::{version, ErrorKind, Protocol, Scan, TlsInfo, TlsVersion};
+use super::snapshot::Snapshot;
+use super::tenant;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::ops::{Deref, Range};
+use std::path::Path;
+
+// An archived snapshot is laid out so scans can be read where they lie, with
+// nothing decoded up front. Everything is little-endian:
+//
+//   header   64 bytes, see below
+//   tenants  TENANT_LEN bytes each, sorted by name
+//   records  RECORD_LEN bytes each, grouped by tenant and sorted by ip and port
+//   headers  HEADER_ENTRY_LEN bytes each, the kept response headers of every record
+//   strings  the bytes every string reference points into
+//
+// A string reference is a u64 offset into the strings and a u64 length.
+pub const MAGIC: &[u8; 8] = b"SCANARCH";
+
+const HEADER_LEN: usize = 64;
+const TENANT_LEN: usize = 32;
+const RECORD_LEN: usize = 120;
+const HEADER_ENTRY_LEN: usize = 32;
+
+// Header fields.
+const VERSION: usize = 8;
+// 0 for a snapshot without a log id.
+const LOG_ID: usize = 12;
+const SEQ: usize = 16;
+const TAKEN_AT: usize = 24;
+const TAKEN_AT_NANOS: usize = 32;
+const CHECKSUM: usize = 36;
+const TENANT_COUNT: usize = 40;
+const RECORD_COUNT: usize = 48;
+const HEADER_COUNT: usize = 56;
+
+// Tenant fields.
+const TENANT_NAME: usize = 0;
+const TENANT_FIRST: usize = 16;
+const TENANT_RECORDS: usize = 24;
+
+// Record fields.
+const IP: usize = 0;
+const CONTENT_HASH: usize = 16;
+const CIPHER: usize = 32;
+const LOAD_TIME: usize = 48;
+const TIMESTAMP: usize = 56;
+const RECEIVED_AT: usize = 64;
+const CERT_EXPIRES: usize = 72;
+const BODY_SIZE: usize = 80;
+const FIRST_HEADER: usize = 88;
+const TIMESTAMP_NANOS: usize = 96;
+const RECEIVED_AT_NANOS: usize = 100;
+const CERT_EXPIRES_NANOS: usize = 104;
+const HEADERS: usize = 108;
+const PORT: usize = 112;
+const HTTP_STATUS: usize = 114;
+const PROTOCOL: usize = 116;
+const TLS_VERSION: usize = 117;
+const ERROR: usize = 118;
+const FLAGS: usize = 119;
+
+// Which optional fields a record has. Enums are stored as one more than
+// their index, so 0 means unset and needs no flag.
+const HAS_HTTP_STATUS: u8 = 1;
+const HAS_BODY_SIZE: u8 = 1 << 1;
+const HAS_TLS: u8 = 1 << 2;
+const HAS_CIPHER: u8 = 1 << 3;
+const HAS_CERT_EXPIRES: u8 = 1 << 4;
+const HAS_RECEIVED_AT: u8 = 1 << 5;
+
+const PROTOCOLS: [Protocol; 4] = [Protocol::Http10, Protocol::Http11, Protocol::H2, Protocol::H3];
+const TLS_VERSIONS: [TlsVersion; 4] = [TlsVersion::Tls10, TlsVersion::Tls11, TlsVersion::Tls12, TlsVersion::Tls13];
+const ERROR_KINDS: [ErrorKind; 8] = [
+    ErrorKind::Timeout, ErrorKind::Dns, ErrorKind::ConnectionRefused, ErrorKind::ConnectionReset,
+    ErrorKind::Tls, ErrorKind::Http, ErrorKind::TooManyRedirects, ErrorKind::Other,
+];
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn le<const N: usize>(bytes: &[u8], at: usize) -> [u8; N] {
+    bytes[at..at + N].try_into().expect("slice is N bytes long")
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(le(bytes, at))
+}
+
+fn i64_at(bytes: &[u8], at: usize) -> i64 {
+    i64::from_le_bytes(le(bytes, at))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(le(bytes, at))
+}
+
+fn time_at(bytes: &[u8], secs: usize, nanos: usize) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(i64_at(bytes, secs), u32_at(bytes, nanos))
+}
+
+fn enum_at<T: Copy>(bytes: &[u8], at: usize, values: &[T]) -> Option<T> {
+    (bytes[at] as usize).checked_sub(1).and_then(|index| values.get(index).copied())
+}
+
+// The archive's bytes, mapped from its file where the platform allows.
+enum Bytes {
+    #[cfg(unix)]
+    Mapped(mmap::Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(unix)]
+            Bytes::Mapped(map) => map,
+            Bytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+// A snapshot read in place. Opening one checks its checksum and that every
+// reference in it is in bounds, so reading scans afterwards can't fail.
+pub struct Archive {
+    bytes: Bytes,
+    tenants: usize,
+    records: usize,
+    headers: usize,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> io::Result<Archive> {
+        let file = File::open(path)?;
+        #[cfg(unix)]
+        let bytes = match file.metadata()?.len() < HEADER_LEN as u64 {
+            true => return Err(invalid_data("archive header is truncated")),
+            false => Bytes::Mapped(mmap::Mmap::open(&file)?),
+        };
+        #[cfg(not(unix))]
+        let bytes = Bytes::Owned(io::Read::bytes(file).collect::<io::Result<_>>()?);
+
+        Archive::check(bytes)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> io::Result<Archive> {
+        Archive::check(Bytes::Owned(bytes))
+    }
+
+    fn check(bytes: Bytes) -> io::Result<Archive> {
+        if bytes.len() < HEADER_LEN || !bytes.starts_with(MAGIC) {
+            return Err(invalid_data("not an archived snapshot"));
+        }
+
+        // The layout is that of one schema version, so there is nothing to
+        // migrate an older archive with.
+        let written = u32_at(&bytes, VERSION);
+        if written != version::SCAN_VERSION {
+            return Err(invalid_data(format!(
+                "archive was written at schema version {}, but this build reads only version {}; \
+                 load it with the build that wrote it and take a new snapshot",
+                written, version::SCAN_VERSION,
+            )));
+        }
+
+        if crc32fast::hash(&bytes[HEADER_LEN..]) != u32_at(&bytes, CHECKSUM) {
+            return Err(invalid_data("archive checksum does not match its contents"));
+        }
+
+        let count = |at| usize::try_from(u64_at(&bytes, at)).map_err(|_| invalid_data("archive is too large"));
+        let (tenants, records, headers) = (count(TENANT_COUNT)?, count(RECORD_COUNT)?, count(HEADER_COUNT)?);
+        let tables = tenants.checked_mul(TENANT_LEN)
+            .zip(records.checked_mul(RECORD_LEN))
+            .zip(headers.checked_mul(HEADER_ENTRY_LEN))
+            .and_then(|((tenants, records), headers)| tenants.checked_add(records)?.checked_add(headers))
+            .and_then(|tables| tables.checked_add(HEADER_LEN));
+        if tables.is_none_or(|tables| tables > bytes.len()) {
+            return Err(invalid_data("archive tables overrun the file"));
+        }
+
+        let archive = Archive { bytes, tenants, records, headers };
+        if archive.taken_at().is_none() {
+            return Err(invalid_data("archive has an invalid timestamp"));
+        }
+        archive.check_tenants()?;
+        (0..archive.records).try_for_each(|index| archive.check_record(index))?;
+
+        Ok(archive)
+    }
+
+    fn check_tenants(&self) -> io::Result<()> {
+        let mut expected_first = 0;
+        let mut previous: Option<&str> = None;
+
+        for index in 0..self.tenants {
+            let at = self.tenant_offset(index);
+            let name = self.str_at(at + TENANT_NAME)?;
+            if !tenant::is_valid(name) || previous.is_some_and(|previous| previous >= name) {
+                return Err(invalid_data(format!("archive tenant {:?} is invalid or out of order", name)));
+            }
+
+            let records = self.tenant_records(index);
+            if records.start != expected_first || records.end > self.records {
+                return Err(invalid_data("archive tenants don't cover the records in order"));
+            }
+
+            for pair in records.start..records.end.saturating_sub(1) {
+                if self.key_at(pair)? >= self.key_at(pair + 1)? {
+                    return Err(invalid_data(format!("archive scans of tenant {:?} are out of order", name)));
+                }
+            }
+
+            expected_first = records.end;
+            previous = Some(name);
+        }
+
+        match expected_first == self.records {
+            true => Ok(()),
+            false => Err(invalid_data("archive tenants don't cover the records in order")),
+        }
+    }
+
+    fn check_record(&self, index: usize) -> io::Result<()> {
+        let at = self.record_offset(index);
+        let record = &self.bytes[at..at + RECORD_LEN];
+        let flags = record[FLAGS];
+
+        self.str_at(at + IP)?;
+        self.str_at(at + CONTENT_HASH)?;
+        if flags & HAS_CIPHER != 0 {
+            self.str_at(at + CIPHER)?;
+        }
+
+        let times_valid = time_at(record, TIMESTAMP, TIMESTAMP_NANOS).is_some()
+            && (flags & HAS_RECEIVED_AT == 0 || time_at(record, RECEIVED_AT, RECEIVED_AT_NANOS).is_some())
+            && (flags & HAS_CERT_EXPIRES == 0 || time_at(record, CERT_EXPIRES, CERT_EXPIRES_NANOS).is_some());
+        let enums_valid = (record[PROTOCOL] as usize) <= PROTOCOLS.len()
+            && (record[ERROR] as usize) <= ERROR_KINDS.len()
+            && (flags & HAS_TLS == 0 || enum_at(record, TLS_VERSION, &TLS_VERSIONS).is_some());
+        if !times_valid || !enums_valid {
+            return Err(invalid_data(format!("archived scan {} has an invalid field", index)));
+        }
+
+        let headers = self.header_range(record);
+        if headers.as_ref().is_none_or(|headers| headers.end > self.headers) {
+            return Err(invalid_data(format!("archived scan {} has headers out of bounds", index)));
+        }
+        for header in headers.unwrap_or_default() {
+            let at = self.header_offset(header);
+            self.str_at(at)?;
+            self.str_at(at + 16)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    pub fn seq(&self) -> u64 {
+        u64_at(&self.bytes, SEQ)
+    }
+
+    pub fn log_id(&self) -> Option<u32> {
+        Some(u32_at(&self.bytes, LOG_ID)).filter(|id| *id != 0)
+    }
+
+    fn taken_at(&self) -> Option<DateTime<Utc>> {
+        time_at(&self.bytes, TAKEN_AT, TAKEN_AT_NANOS)
+    }
+
+    pub fn tenants(&self) -> impl Iterator<Item = &str> {
+        (0..self.tenants).map(|index| self.tenant_name(index))
+    }
+
+    // The scans of `tenant` occupy this range of record indexes.
+    pub fn range_of(&self, tenant: &str) -> Range<usize> {
+        match self.find_tenant(tenant) {
+            Some(index) => self.tenant_records(index),
+            None => 0..0,
+        }
+    }
+
+    // The archived scan at `index`, with the tenant it belongs to.
+    pub fn entry(&self, index: usize) -> (&str, ArchivedScan<'_>) {
+        let scan = self.scan(index);
+
+        // The last tenant starting at or before `index`; any empty tenants
+        // starting there too sort before the one that holds it.
+        let (mut low, mut high) = (0, self.tenants);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.tenant_records(mid).start <= index {
+                true => low = mid + 1,
+                false => high = mid,
+            }
+        }
+        (self.tenant_name(low - 1), scan)
+    }
+
+    pub fn scan(&self, index: usize) -> ArchivedScan<'_> {
+        assert!(index < self.records, "archived scan {} of {}", index, self.records);
+        ArchivedScan { archive: self, index }
+    }
+
+    pub fn get(&self, tenant: &str, ip: &str, port: i16) -> Option<ArchivedScan<'_>> {
+        let records = self.range_of(tenant);
+        let found = binary_search(records.clone(), |index| self.scan(index).key().cmp(&(ip, port)))?;
+        Some(self.scan(found))
+    }
+
+    pub fn to_snapshot(&self) -> Snapshot {
+        let tenants = (0..self.tenants)
+            .map(|index| {
+                let scans = self.tenant_records(index).map(|record| self.scan(record).to_scan()).collect();
+                (self.tenant_name(index).to_owned(), scans)
+            })
+            .collect();
+
+        Snapshot {
+            version: version::SCAN_VERSION,
+            taken_at: self.taken_at().expect("checked when opened"),
+            seq: self.seq(),
+            log_id: self.log_id(),
+            tenants,
+        }
+    }
+
+    fn find_tenant(&self, tenant: &str) -> Option<usize> {
+        binary_search(0..self.tenants, |index| self.tenant_name(index).cmp(tenant))
+    }
+
+    fn tenant_name(&self, index: usize) -> &str {
+        self.str_at(self.tenant_offset(index) + TENANT_NAME).expect("checked when opened")
+    }
+
+    fn tenant_records(&self, index: usize) -> Range<usize> {
+        let at = self.tenant_offset(index);
+        let first = u64_at(&self.bytes, at + TENANT_FIRST) as usize;
+        first..first.saturating_add(u64_at(&self.bytes, at + TENANT_RECORDS) as usize)
+    }
+
+    fn key_at(&self, index: usize) -> io::Result<(&str, i16)> {
+        let at = self.record_offset(index);
+        Ok((self.str_at(at + IP)?, i16::from_le_bytes(le(&self.bytes, at + PORT))))
+    }
+
+    fn header_range(&self, record: &[u8]) -> Option<Range<usize>> {
+        let first = usize::try_from(u64_at(record, FIRST_HEADER)).ok()?;
+        Some(first..first.checked_add(u32_at(record, HEADERS) as usize)?)
+    }
+
+    fn tenant_offset(&self, index: usize) -> usize {
+        HEADER_LEN + index * TENANT_LEN
+    }
+
+    fn record_offset(&self, index: usize) -> usize {
+        HEADER_LEN + self.tenants * TENANT_LEN + index * RECORD_LEN
+    }
+
+    fn header_offset(&self, index: usize) -> usize {
+        HEADER_LEN + self.tenants * TENANT_LEN + self.records * RECORD_LEN + index * HEADER_ENTRY_LEN
+    }
+
+    fn strings(&self) -> &[u8] {
+        &self.bytes[self.header_offset(self.headers)..]
+    }
+
+    fn str_at(&self, at: usize) -> io::Result<&str> {
+        let strings = self.strings();
+        let bytes = usize::try_from(u64_at(&self.bytes, at)).ok()
+            .zip(usize::try_from(u64_at(&self.bytes, at + 8)).ok())
+            .and_then(|(offset, len)| strings.get(offset..offset.checked_add(len)?))
+            .ok_or_else(|| invalid_data("archive string is out of bounds"))?;
+        std::str::from_utf8(bytes).map_err(invalid_data)
+    }
+
+    // Lays a snapshot out as an archive. Scans are ordered the way lookups
+    // expect, and where a tenant holds two scans of one ip and port the later
+    // one is kept, as restoring the snapshot would.
+    pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+        let mut tables = Tables::default();
+        let mut tenant_table = Vec::new();
+
+        for (tenant, scans) in &snapshot.tenants {
+            let scans: BTreeMap<(&str, i16), &Scan> = scans.iter()
+                .map(|scan| ((scan.ip.as_str(), scan.port), scan))
+                .collect();
+
+            let mut entry = [0; TENANT_LEN];
+            entry[TENANT_NAME..TENANT_NAME + 16].copy_from_slice(&tables.string(tenant));
+            entry[TENANT_FIRST..TENANT_FIRST + 8].copy_from_slice(&(tables.records.len() / RECORD_LEN).to_le_bytes());
+            entry[TENANT_RECORDS..TENANT_RECORDS + 8].copy_from_slice(&(scans.len() as u64).to_le_bytes());
+            tenant_table.extend_from_slice(&entry);
+
+            for scan in scans.values() {
+                tables.record(scan);
+            }
+        }
+
+        let mut bytes = vec![0; HEADER_LEN];
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        // Decoded snapshots hold scans migrated to the current version,
+        // whatever version they were written at.
+        bytes[VERSION..VERSION + 4].copy_from_slice(&version::SCAN_VERSION.to_le_bytes());
+        bytes[LOG_ID..LOG_ID + 4].copy_from_slice(&snapshot.log_id.unwrap_or(0).to_le_bytes());
+        bytes[SEQ..SEQ + 8].copy_from_slice(&snapshot.seq.to_le_bytes());
+        bytes[TAKEN_AT..TAKEN_AT + 8].copy_from_slice(&snapshot.taken_at.timestamp().to_le_bytes());
+        bytes[TAKEN_AT_NANOS..TAKEN_AT_NANOS + 4].copy_from_slice(&snapshot.taken_at.timestamp_subsec_nanos().to_le_bytes());
+        bytes[TENANT_COUNT..TENANT_COUNT + 8].copy_from_slice(&(snapshot.tenants.len() as u64).to_le_bytes());
+        bytes[RECORD_COUNT..RECORD_COUNT + 8].copy_from_slice(&((tables.records.len() / RECORD_LEN) as u64).to_le_bytes());
+        bytes[HEADER_COUNT..HEADER_COUNT + 8].copy_from_slice(&((tables.headers.len() / HEADER_ENTRY_LEN) as u64).to_le_bytes());
+
+        bytes.extend_from_slice(&tenant_table);
+        bytes.extend_from_slice(&tables.records);
+        bytes.extend_from_slice(&tables.headers);
+        bytes.extend_from_slice(&tables.strings);
+
+        let checksum = crc32fast::hash(&bytes[HEADER_LEN..]);
+        bytes[CHECKSUM..CHECKSUM + 4].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+}
+
+// Finds the index in `range` that `compare` says is equal, given the range is
+// sorted by it.
+fn binary_search(range: Range<usize>, compare: impl Fn(usize) -> std::cmp::Ordering) -> Option<usize> {
+    let (mut low, mut high) = (range.start, range.end);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match compare(mid) {
+            std::cmp::Ordering::Less => low = mid + 1,
+            std::cmp::Ordering::Greater => high = mid,
+            std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+struct Tables {
+    records: Vec<u8>,
+    headers: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Tables {
+    fn string(&mut self, s: &str) -> [u8; 16] {
+        let mut reference = [0; 16];
+        reference[..8].copy_from_slice(&(self.strings.len() as u64).to_le_bytes());
+        reference[8..].copy_from_slice(&(s.len() as u64).to_le_bytes());
+        self.strings.extend_from_slice(s.as_bytes());
+        reference
+    }
+
+    fn record(&mut self, scan: &Scan) {
+        fn put(record: &mut [u8], at: usize, bytes: &[u8]) {
+            record[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        fn index<T: PartialEq>(values: &[T], value: Option<&T>) -> u8 {
+            value.and_then(|value| values.iter().position(|v| v == value)).map_or(0, |index| index as u8 + 1)
+        }
+
+        let mut record = [0; RECORD_LEN];
+        let mut flags = 0;
+
+        put(&mut record, IP, &self.string(&scan.ip));
+        put(&mut record, CONTENT_HASH, &self.string(&scan.content_hash));
+        put(&mut record, LOAD_TIME, &scan.load_time_nanosec.to_le_bytes());
+        put(&mut record, TIMESTAMP, &scan.timestamp.timestamp().to_le_bytes());
+        put(&mut record, TIMESTAMP_NANOS, &scan.timestamp.timestamp_subsec_nanos().to_le_bytes());
+        put(&mut record, PORT, &scan.port.to_le_bytes());
+        record[PROTOCOL] = index(&PROTOCOLS, scan.protocol.as_ref());
+        record[ERROR] = index(&ERROR_KINDS, scan.error.as_ref());
+
+        if let Some(status) = scan.http_status {
+            flags |= HAS_HTTP_STATUS;
+            put(&mut record, HTTP_STATUS, &status.to_le_bytes());
+        }
+        if let Some(size) = scan.body_size {
+            flags |= HAS_BODY_SIZE;
+            put(&mut record, BODY_SIZE, &size.to_le_bytes());
+        }
+        if let Some(received_at) = scan.received_at {
+            flags |= HAS_RECEIVED_AT;
+            put(&mut record, RECEIVED_AT, &received_at.timestamp().to_le_bytes());
+            put(&mut record, RECEIVED_AT_NANOS, &received_at.timestamp_subsec_nanos().to_le_bytes());
+        }
+        if let Some(tls) = &scan.tls {
+            flags |= HAS_TLS;
+            record[TLS_VERSION] = index(&TLS_VERSIONS, Some(&tls.version));
+            if let Some(cipher) = &tls.cipher {
+                flags |= HAS_CIPHER;
+                put(&mut record, CIPHER, &self.string(cipher));
+            }
+            if let Some(expires) = tls.certificate_expires_at {
+                flags |= HAS_CERT_EXPIRES;
+                put(&mut record, CERT_EXPIRES, &expires.timestamp().to_le_bytes());
+                put(&mut record, CERT_EXPIRES_NANOS, &expires.timestamp_subsec_nanos().to_le_bytes());
+            }
+        }
+
+        put(&mut record, FIRST_HEADER, &((self.headers.len() / HEADER_ENTRY_LEN) as u64).to_le_bytes());
+        put(&mut record, HEADERS, &(scan.headers.len() as u32).to_le_bytes());
+        for (name, value) in &scan.headers {
+            let name = self.string(name);
+            let value = self.string(value);
+            self.headers.extend_from_slice(&name);
+            self.headers.extend_from_slice(&value);
+        }
+
+        record[FLAGS] = flags;
+        self.records.extend_from_slice(&record);
+    }
+}
+
+// One scan as it lies in an archive. Fields are read on demand.
+#[derive(Clone, Copy)]
+pub struct ArchivedScan<'a> {
+    archive: &'a Archive,
+    index: usize,
+}
+
+impl<'a> ArchivedScan<'a> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    fn record(&self) -> &'a [u8] {
+        let at = self.archive.record_offset(self.index);
+        &self.archive.bytes[at..at + RECORD_LEN]
+    }
+
+    fn str_at(&self, field: usize) -> &'a str {
+        self.archive.str_at(self.archive.record_offset(self.index) + field).expect("checked when opened")
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.record()[FLAGS] & flag != 0
+    }
+
+    pub fn ip(&self) -> &'a str {
+        self.str_at(IP)
+    }
+
+    pub fn port(&self) -> i16 {
+        i16::from_le_bytes(le(self.record(), PORT))
+    }
+
+    fn key(&self) -> (&'a str, i16) {
+        (self.ip(), self.port())
+    }
+
+    pub fn content_hash(&self) -> &'a str {
+        self.str_at(CONTENT_HASH)
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        time_at(self.record(), TIMESTAMP, TIMESTAMP_NANOS).expect("checked when opened")
+    }
+
+    pub fn received_at(&self) -> Option<DateTime<Utc>> {
+        match self.has(HAS_RECEIVED_AT) {
+            true => time_at(self.record(), RECEIVED_AT, RECEIVED_AT_NANOS),
+            false => None,
+        }
+    }
+
+    pub fn to_scan(&self) -> Scan {
+        let record = self.record();
+        let tls = self.has(HAS_TLS).then(|| TlsInfo {
+            version: enum_at(record, TLS_VERSION, &TLS_VERSIONS).expect("checked when opened"),
+            cipher: self.has(HAS_CIPHER).then(|| self.str_at(CIPHER).to_owned()),
+            certificate_expires_at: match self.has(HAS_CERT_EXPIRES) {
+                true => time_at(record, CERT_EXPIRES, CERT_EXPIRES_NANOS),
+                false => None,
+            },
+        });
+        let headers = self.archive.header_range(record).unwrap_or_default()
+            .map(|header| {
+                let at = self.archive.header_offset(header);
+                let name = self.archive.str_at(at).expect("checked when opened");
+                let value = self.archive.str_at(at + 16).expect("checked when opened");
+                (name.to_owned(), value.to_owned())
+            })
+            .collect();
+
+        Scan {
+            ip: self.ip().to_owned(),
+            port: self.port(),
+            load_time_nanosec: i64_at(record, LOAD_TIME),
+            content_hash: self.content_hash().to_owned(),
+            timestamp: self.timestamp(),
+            protocol: enum_at(record, PROTOCOL, &PROTOCOLS),
+            http_status: self.has(HAS_HTTP_STATUS).then(|| u16::from_le_bytes(le(record, HTTP_STATUS))),
+            headers,
+            body_size: self.has(HAS_BODY_SIZE).then(|| u64_at(record, BODY_SIZE)),
+            tls,
+            error: enum_at(record, ERROR, &ERROR_KINDS),
+            received_at: self.received_at(),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod mmap {
+    use std::fs::File;
+    use std::io;
+    use std::ops::Deref;
+    use std::os::unix::io::AsRawFd;
+
+    // A read-only, private mapping of a whole file.
+    pub struct Mmap {
+        ptr: *const u8,
+        len: usize,
+    }
+
+    // The mapping is never written through, so sharing it is like sharing a
+    // `&[u8]`.
+    unsafe impl Send for Mmap {}
+    unsafe impl Sync for Mmap {}
+
+    impl Mmap {
+        pub fn open(file: &File) -> io::Result<Mmap> {
+            let len = usize::try_from(file.metadata()?.len())
+                .map_err(|_| io::Error::other("file is too large to map"))?;
+
+            // SAFETY: a fresh read-only private mapping of a file we hold
+            // open; the result is checked before it is used. Files are
+            // written beside their final name and renamed into place, so the
+            // mapped file is never modified underneath us.
+            let ptr = unsafe {
+                libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Mmap { ptr: ptr as *const u8, len })
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            // SAFETY: the mapping is `len` readable bytes until it is dropped.
+            unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            // SAFETY: unmaps exactly what `open` mapped, once.
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::error::Error;
+
+    fn scan(ip: &str, port: i16) -> Scan {
+        Scan{
+            ip: ip.to_owned(),
+            port,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc.with_ymd_and_hms(2021, 9, 20, 17, 10, 0).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn snapshot() -> Snapshot {
+        let detailed = Scan{
+            protocol: Some(Protocol::H2),
+            http_status: Some(200),
+            headers: [("server".to_owned(), "nginx".to_owned()), ("via".to_owned(), "1.1 cache".to_owned())].into(),
+            body_size: Some(0),
+            tls: Some(TlsInfo {
+                version: TlsVersion::Tls13,
+                cipher: Some("TLS_AES_128_GCM_SHA256".to_owned()),
+                certificate_expires_at: Some(Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap()),
+            }),
+            error: Some(ErrorKind::Other),
+            received_at: Some(Utc.with_ymd_and_hms(2021, 9, 20, 17, 10, 1).unwrap()),
+            ..scan("9.9.9.9", 443)
+        };
+
+        let mut tenants = BTreeMap::new();
+        tenants.insert("default".to_owned(), vec![scan("8.8.8.8", 80), scan("1.2.3.4", 443), scan("1.2.3.4", 80)]);
+        tenants.insert("acme".to_owned(), vec![detailed]);
+        tenants.insert("empty".to_owned(), vec![]);
+        Snapshot { log_id: Some(0x5ca9), ..Snapshot::new(42, tenants) }
+    }
+
+    #[test]
+    fn archives_are_read_in_place() {
+        let snapshot = snapshot();
+        let archive = Archive::from_bytes(Archive::encode(&snapshot)).unwrap();
+
+        assert_eq!(archive.len(), 4);
+        assert_eq!(archive.seq(), 42);
+        assert_eq!(archive.log_id(), Some(0x5ca9));
+        assert_eq!(archive.tenants().collect::<Vec<_>>(), ["acme", "default", "empty"]);
+        assert_eq!(archive.range_of("default"), 1..4);
+        assert_eq!(archive.range_of("nobody"), 0..0);
+
+        let found = archive.get("default", "1.2.3.4", 443).unwrap();
+        assert_eq!((found.ip(), found.port(), found.content_hash()), ("1.2.3.4", 443, "foobar"));
+        assert!(archive.get("default", "1.2.3.4", 8080).is_none());
+        assert!(archive.get("acme", "1.2.3.4", 443).is_none());
+
+        let (tenant, scan) = archive.entry(3);
+        assert_eq!((tenant, scan.ip()), ("default", "8.8.8.8"));
+        assert_eq!(archive.entry(0).0, "acme");
+
+        let mut sorted = snapshot.clone();
+        sorted.tenants.get_mut("default").unwrap().sort_by(|a, b| (&a.ip, a.port).cmp(&(&b.ip, b.port)));
+        assert_eq!(archive.to_snapshot(), sorted);
+    }
+
+    #[test]
+    fn later_duplicates_win() {
+        let mut tenants = BTreeMap::new();
+        tenants.insert("default".to_owned(), vec![scan("1.2.3.4", 80), Scan{ content_hash: "later".to_owned(), ..scan("1.2.3.4", 80) }]);
+        let archive = Archive::from_bytes(Archive::encode(&Snapshot::new(0, tenants))).unwrap();
+
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive.get("default", "1.2.3.4", 80).unwrap().content_hash(), "later");
+    }
+
+    #[test]
+    fn damaged_archives_are_refused() {
+        let bytes = Archive::encode(&snapshot());
+
+        let mut flipped = bytes.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0xff;
+        assert!(Archive::from_bytes(flipped).err().unwrap().to_string().contains("checksum"));
+
+        assert!(Archive::from_bytes(bytes[..HEADER_LEN - 1].to_vec()).is_err());
+        assert!(Archive::from_bytes(bytes[..bytes.len() - 1].to_vec()).is_err());
+
+        // A reference past the strings, with a checksum that matches it.
+        let mut overrun = bytes.clone();
+        let at = HEADER_LEN + 3 * TENANT_LEN + IP + 8;
+        overrun[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let checksum = crc32fast::hash(&overrun[HEADER_LEN..]);
+        overrun[CHECKSUM..CHECKSUM + 4].copy_from_slice(&checksum.to_le_bytes());
+        assert!(Archive::from_bytes(overrun).err().unwrap().to_string().contains("out of bounds"));
+
+        let mut newer = bytes;
+        newer[VERSION..VERSION + 4].copy_from_slice(&(version::SCAN_VERSION + 1).to_le_bytes());
+        assert!(Archive::from_bytes(newer).err().unwrap().to_string().contains("schema version"));
+    }
+
+    #[test]
+    fn archives_are_mapped_from_files() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("archive-{}.arch", std::process::id()));
+        std::fs::write(&path, Archive::encode(&snapshot()))?;
+
+        let archive = Archive::open(&path)?;
+        assert_eq!(archive.get("acme", "9.9.9.9", 443).unwrap().to_scan().headers["via"], "1.1 cache");
+
+        std::fs::write(&path, b"SCANARCH")?;
+        assert!(Archive::open(&path).is_err());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use super::store::Store;
+#[cfg(feature = "wal")]
+use super::recovery::newest_snapshot_seq;
+#[cfg(feature = "wal")]
+use super::wal::Wal;
+use crate::metrics;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// Scans copied in from an attached archive per write lock taken.
+const WARM_BATCH: usize = 10_000;
+
+// The store as shared between request handlers. Every lock acquisition is
+// timed so contention between readers and writers shows up in `/metrics`.
+#[derive(Clone)]
+pub struct Db {
+    inner: Arc<RwLock<Store>>,
+}
+
+impl Db {
+    pub fn new(store: Store) -> Self {
+        Db { inner: Arc::new(RwLock::new(store)) }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, Store> {
+        let start = Instant::now();
+        let guard = self.inner.read().await;
+        metrics::global().observe_lock_wait("read", start.elapsed());
+        guard
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, Store> {
+        let start = Instant::now();
+        let guard = self.inner.write().await;
+        metrics::global().observe_lock_wait("write", start.elapsed());
+        guard
+    }
+
+    // Copies an attached archive into the store in the background, taking
+    // the write lock a batch at a time so requests are served in between.
+    // Must be called from within a tokio runtime.
+    pub fn warm_in_background(&self) {
+        let db = self.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            loop {
+                let mut store = db.write().await;
+                if !store.is_warming() {
+                    return;
+                }
+
+                match store.warm(WARM_BATCH) {
+                    Ok(0) => {
+                        tracing::info!(scans = store.len(), elapsed = ?start.elapsed(), "warmed archived snapshot");
+                        return;
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        tracing::error!(error = e, "failed to warm archived snapshot, serving the rest from it");
+                        return;
+                    },
+                }
+
+                drop(store);
+                tokio::task::yield_now().await;
+            }
+        });
+    }
+
+    // Every `interval`, compacts the mutation log if it is due, but only
+    // through the newest snapshot of it in `snapshots`, so every point after
+    // that snapshot stays recoverable. Without a snapshot directory the log
+    // isn't compacted. The log is read and rewritten without holding the
+    // store; only appending what was logged meanwhile and swapping the new
+    // log in take the write lock. Does nothing without the `wal` backend.
+    // Must be called from within a tokio runtime.
+    pub fn compact_in_background(&self, interval: Option<Duration>, snapshots: Option<&Path>) {
+        #[cfg(feature = "wal")]
+        if let Some(interval) = interval.filter(|interval| !interval.is_zero()) {
+            let Some(snapshots) = snapshots.map(Path::to_owned) else {
+                tracing::warn!("SCANS_COMPACT_INTERVAL is set without SCANS_SNAPSHOT_DIR; the log won't be compacted");
+                return;
+            };
+
+            let db = self.clone();
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(interval);
+                ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                ticks.tick().await;
+                // Until a newer snapshot is taken there is nothing more to
+                // fold, however far the log is past the ratio.
+                let mut folded_through = 0;
+                loop {
+                    ticks.tick().await;
+                    let Some(path) = db.read().await.compaction_due() else {
+                        continue;
+                    };
+
+                    let start = Instant::now();
+                    let dir = snapshots.clone();
+                    let prepared = tokio::task::spawn_blocking(move || match newest_snapshot_seq(&dir, &path)? {
+                        Some(through) if through > folded_through => Wal::prepare_compaction(&path, through).map(Some),
+                        _ => Ok(None),
+                    });
+                    let compacted = match prepared.await {
+                        Ok(Ok(Some(prepared))) => db.write().await.finish_compaction(prepared),
+                        Ok(Ok(None)) => continue,
+                        Ok(Err(e)) => Err(e),
+                        Err(e) => Err(std::io::Error::other(e)),
+                    };
+                    match compacted {
+                        Ok(c) => {
+                            folded_through = c.through;
+                            tracing::info!(
+                                before = c.before, after = c.after, through = c.through, elapsed = ?start.elapsed(),
+                                "compacted mutation log",
+                            );
+                        },
+                        Err(e) => tracing::error!(error = %e, "failed to compact mutation log"),
+                    }
+                }
+            });
+        }
+
+        #[cfg(not(feature = "wal"))]
+        let _ = (interval, snapshots);
+    }
+}
@@ -1 +1,10 @@
+#[allow(clippy::module_inception)]
 pub mod store;
+pub mod archive;
+pub mod db;
+#[cfg(feature = "wal")]
+pub mod recovery;
+pub mod snapshot;
+pub mod tenant;
+#[cfg(feature = "wal")]
+pub mod wal;
@@ -0,0 +1,343 @@
+use super::snapshot::{Snapshot, SnapshotFormat};
+use super::wal::{Entry, Wal};
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The point a store is recovered to: just after a log entry, or as it stood
+// at a moment, i.e. just before the first entry logged after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Seq(u64),
+    At(DateTime<Utc>),
+}
+
+// How a store is rebuilt as of a target: from the newest snapshot taken at
+// or before it, then the log entries after that snapshot up to the target.
+// Without a usable snapshot the log is replayed from its first entry. A
+// compacted log no longer knows what came before its horizon, so targets
+// before it are refused, as are snapshots older than it. Snapshots taken of
+// another log are never used, whatever their sequence numbers.
+pub struct Plan {
+    // The last log entry the recovered store includes.
+    pub seq: u64,
+    // The id of the log recovered from, for snapshots of the recovered store.
+    pub log_id: Option<u32>,
+    pub snapshot: Option<PathBuf>,
+    pub(crate) base: Option<Snapshot>,
+    pub(crate) entries: Vec<Entry>,
+}
+
+fn invalid_input(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+fn in_file(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+impl Plan {
+    // Reads the log at `log` without opening it for writing, so a plan can
+    // be made while a service is appending to it. `snapshots` is searched
+    // for the snapshots that service took of the same log.
+    pub fn new(log: &Path, snapshots: Option<&Path>, target: Target) -> io::Result<Plan> {
+        let (entries, horizon) = Wal::read(log).map_err(in_file(log))?;
+        let log_id = Wal::id_of(log).map_err(in_file(log))?;
+        let compacted = horizon.map(|horizon| horizon.seq).unwrap_or(0);
+        let last = entries.last().map(|entry| entry.seq).unwrap_or(0).max(compacted);
+        let too_early = || invalid_input(format!(
+            "the log was compacted through sequence {}; it can't be recovered to before that",
+            compacted,
+        ));
+
+        let seq = match target {
+            Target::Seq(seq) if seq > last => {
+                return Err(invalid_input(format!("the log ends at sequence {}, before {}", last, seq)));
+            },
+            Target::Seq(seq) if seq < compacted => return Err(too_early()),
+            Target::Seq(seq) => seq,
+            Target::At(at) if horizon.is_some_and(|horizon| at < horizon.at) => return Err(too_early()),
+            Target::At(at) => match entries.iter().find(|entry| entry.seq > compacted && entry.at > at) {
+                Some(after) => after.seq - 1,
+                None => last,
+            },
+        };
+
+        let (snapshot, base) = match snapshots {
+            Some(dir) => match newest_snapshot(dir, log_id, compacted, seq)? {
+                Some(path) => {
+                    let snapshot = Snapshot::read_from(&path).map_err(in_file(&path))?;
+                    (Some(path), Some(snapshot))
+                },
+                None => (None, None),
+            },
+            None => (None, None),
+        };
+
+        // The compacted entries stand in for a snapshot at the horizon.
+        let from = base.as_ref().map(|base| base.seq).unwrap_or(compacted);
+        let entries: Vec<Entry> = entries.into_iter()
+            .filter(|entry| (base.is_none() || entry.seq > from) && entry.seq <= seq)
+            .collect();
+
+        // The log must hold every entry between the snapshot and the target,
+        // or the recovered store would silently miss mutations.
+        let mut expected = from + 1;
+        for entry in entries.iter().filter(|entry| entry.seq > from) {
+            if entry.seq != expected {
+                return Err(invalid_input(format!(
+                    "the log is missing entries {} to {}; recover from a snapshot taken after them",
+                    expected, entry.seq - 1,
+                )));
+            }
+            expected += 1;
+        }
+        if expected <= seq {
+            return Err(invalid_input(format!("the log is missing entries {} to {}", expected, seq)));
+        }
+
+        Ok(Plan { seq, log_id, snapshot, base, entries })
+    }
+
+    // How many log entries are replayed on top of the snapshot.
+    pub fn replayed(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+// The sequence number of the newest snapshot in `dir` taken of the log at
+// `log`. Compacting the log no further than that keeps every point after the
+// snapshot recoverable.
+pub fn newest_snapshot_seq(dir: &Path, log: &Path) -> io::Result<Option<u64>> {
+    let log_id = Wal::id_of(log).map_err(in_file(log))?;
+    match newest_snapshot(dir, log_id, 0, u64::MAX)? {
+        Some(path) => Ok(Some(Snapshot::origin_of(&path).map_err(in_file(&path))?.1)),
+        None => Ok(None),
+    }
+}
+
+// The snapshot in `dir` of the log `log_id` with the highest sequence number
+// from `from` to `seq`. The names `Snapshot::path_in` gives them set the
+// order they are tried in, but only the snapshot itself says which log it was
+// taken of, so one left by another log sharing the directory is skipped.
+fn newest_snapshot(dir: &Path, log_id: Option<u32>, from: u64, seq: u64) -> io::Result<Option<PathBuf>> {
+    let mut candidates = Vec::new();
+    for file in fs::read_dir(dir).map_err(in_file(dir))? {
+        let path = file.map_err(in_file(dir))?.path();
+        if let Some(taken) = snapshot_seq(&path).filter(|taken| (from..=seq).contains(taken)) {
+            candidates.push((taken, path));
+        }
+    }
+
+    // Names sort by when they were taken, so a later snapshot of the same
+    // entry is tried first.
+    candidates.sort();
+    for (_, path) in candidates.into_iter().rev() {
+        let (taken_of, taken) = Snapshot::origin_of(&path).map_err(in_file(&path))?;
+        if taken_of == log_id && (from..=seq).contains(&taken) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+fn snapshot_seq(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?.strip_prefix("scans-")?;
+    let (stem, extension) = name.rsplit_once('.')?;
+    let known = [SnapshotFormat::Json, SnapshotFormat::Binary, SnapshotFormat::Archive]
+        .iter()
+        .any(|format| format.extension() == extension);
+
+    match known {
+        true => stem.rsplit_once('-')?.1.parse().ok(),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Scan;
+    use crate::store::store::Store;
+    use std::error::Error;
+
+    fn scan(ip: &str, content_hash: &str) -> Scan {
+        Scan{
+            ip: ip.to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: content_hash.to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn snapshot_names_carry_their_sequence() {
+        assert_eq!(snapshot_seq(Path::new("snaps/scans-20220731T141000.000Z-1830.snap")), Some(1830));
+        assert_eq!(snapshot_seq(Path::new("scans-20220731T141000.000Z-7.arch")), Some(7));
+        assert_eq!(snapshot_seq(Path::new("scans-20220731T141000.000Z-7.snap.partial")), None);
+        assert_eq!(snapshot_seq(Path::new("notes-1.json")), None);
+    }
+
+    #[test]
+    fn stores_are_recovered_to_a_sequence_or_time() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("recovery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let log = dir.join("scans.log");
+
+        let mut store = Store::open(&log)?;
+        store.insert_record(scan("1.2.3.4", "foobar"))?;
+        store.insert_record(scan("8.8.8.8", "foobar"))?;
+        let snapshot = store.snapshot();
+        snapshot.write_to(&snapshot.path_in(&dir, SnapshotFormat::Binary), SnapshotFormat::Binary)?;
+        store.update_record(scan("1.2.3.4", "barfoo"))?;
+        store.delete_record("8.8.8.8", 80)?;
+        store.delete_record("1.2.3.4", 80)?;
+        drop(store);
+
+        let plan = Plan::new(&log, Some(&dir), Target::Seq(4))?;
+        assert_eq!((plan.seq, plan.replayed()), (4, 2));
+        assert!(plan.snapshot.is_some());
+        let recovered = Store::recover(plan)?;
+        assert_eq!(recovered.get_record("1.2.3.4", 80).unwrap().content_hash, "barfoo");
+        assert!(recovered.get_record("8.8.8.8", 80).is_none());
+
+        let plan = Plan::new(&log, None, Target::Seq(1))?;
+        assert_eq!(plan.replayed(), 1);
+        assert_eq!(Store::recover(plan)?.len(), 1);
+
+        let (entries, _) = Wal::read(&log)?;
+        let plan = Plan::new(&log, Some(&dir), Target::At(entries[2].at))?;
+        assert_eq!(plan.seq, 3);
+        assert_eq!(Store::recover(plan)?.get_record("1.2.3.4", 80).unwrap().content_hash, "barfoo");
+
+        let plan = Plan::new(&log, Some(&dir), Target::At(entries[0].at - chrono::Duration::seconds(1)))?;
+        assert_eq!((plan.seq, plan.replayed()), (0, 0));
+        assert!(plan.snapshot.is_none());
+        assert!(Store::recover(plan)?.is_empty());
+
+        let err = Plan::new(&log, Some(&dir), Target::Seq(6)).err().unwrap();
+        assert!(err.to_string().contains("ends at sequence 5"), "{}", err);
+
+        // Without the entries before it the log can only be recovered from
+        // the snapshot onwards.
+        let lines: Vec<String> = fs::read_to_string(&log)?.lines().map(|line| format!("{}\n", line)).collect();
+        fs::write(&log, [&lines[..1], &lines[2..]].concat().concat())?;
+        assert!(Plan::new(&log, Some(&dir), Target::Seq(5)).is_ok());
+        let err = Plan::new(&log, None, Target::Seq(5)).err().unwrap();
+        assert!(err.to_string().contains("missing entries 1 to 1"), "{}", err);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_logs_are_recovered_from_their_horizon() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("recovery-compacted-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let log = dir.join("scans.log");
+
+        let mut store = Store::open(&log)?;
+        store.insert_record(scan("1.2.3.4", "foobar"))?;
+        let snapshot = store.snapshot();
+        snapshot.write_to(&snapshot.path_in(&dir, SnapshotFormat::Json), SnapshotFormat::Json)?;
+        store.insert_record(scan("8.8.8.8", "foobar"))?;
+        store.delete_record("1.2.3.4", 80)?;
+        store.compact(u64::MAX)?;
+        store.update_record(scan("8.8.8.8", "barfoo"))?;
+        drop(store);
+
+        let err = Plan::new(&log, Some(&dir), Target::Seq(2)).err().unwrap();
+        assert!(err.to_string().contains("compacted through sequence 3"), "{}", err);
+        let (_, horizon) = Wal::read(&log)?;
+        assert!(Plan::new(&log, None, Target::At(horizon.unwrap().at - chrono::Duration::seconds(1))).is_err());
+
+        // The snapshot predates the horizon, so the compacted entries are
+        // replayed instead.
+        let plan = Plan::new(&log, Some(&dir), Target::Seq(3))?;
+        assert!(plan.snapshot.is_none());
+        let recovered = Store::recover(plan)?;
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered.get_record("8.8.8.8", 80).unwrap().content_hash, "foobar");
+
+        let plan = Plan::new(&log, Some(&dir), Target::Seq(4))?;
+        assert_eq!(Store::recover(plan)?.get_record("8.8.8.8", 80).unwrap().content_hash, "barfoo");
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_what_came_after_the_newest_snapshot() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("recovery-retained-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let log = dir.join("scans.log");
+
+        let mut store = Store::open(&log)?;
+        assert_eq!(newest_snapshot_seq(&dir, &log)?, None);
+        store.insert_record(scan("1.2.3.4", "foobar"))?;
+        store.insert_record(scan("8.8.8.8", "foobar"))?;
+        let snapshot = store.snapshot();
+        snapshot.write_to(&snapshot.path_in(&dir, SnapshotFormat::Json), SnapshotFormat::Json)?;
+        store.delete_record("1.2.3.4", 80)?;
+        store.delete_record("8.8.8.8", 80)?;
+
+        // A bulk delete after the snapshot is still there to be undone.
+        let through = newest_snapshot_seq(&dir, &log)?.unwrap();
+        assert_eq!(store.compact(through)?.through, 2);
+        drop(store);
+
+        assert_eq!(Store::recover(Plan::new(&log, Some(&dir), Target::Seq(2))?)?.len(), 2);
+        assert_eq!(Store::recover(Plan::new(&log, Some(&dir), Target::Seq(3))?)?.len(), 1);
+        assert!(Plan::new(&log, Some(&dir), Target::Seq(1)).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_of_other_logs_are_skipped() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("recovery-other-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut old = Store::open(&dir.join("old.log"))?;
+        for ip in ["1.1.1.1", "2.2.2.2", "3.3.3.3"] {
+            old.insert_record(scan(ip, "old"))?;
+        }
+        let snapshot = old.snapshot();
+        snapshot.write_to(&snapshot.path_in(&dir, SnapshotFormat::Archive), SnapshotFormat::Archive)?;
+
+        // A log started beside it, e.g. for a restored service, counts its
+        // sequence numbers from 1 again.
+        let log = dir.join("new.log");
+        let mut new = Store::open(&log)?;
+        new.insert_record(scan("8.8.8.8", "new"))?;
+        new.insert_record(scan("9.9.9.9", "new"))?;
+        let snapshot = new.snapshot();
+        let own = snapshot.path_in(&dir, SnapshotFormat::Binary);
+        snapshot.write_to(&own, SnapshotFormat::Binary)?;
+        new.delete_record("8.8.8.8", 80)?;
+        assert_ne!(old.snapshot().log_id, snapshot.log_id);
+
+        let plan = Plan::new(&log, Some(&dir), Target::Seq(3))?;
+        assert_eq!((plan.log_id, plan.snapshot.as_deref()), (snapshot.log_id, Some(own.as_path())));
+        assert_eq!(plan.replayed(), 1);
+        let recovered = Store::recover(plan)?;
+        assert_eq!(recovered.len(), 1);
+        assert!(recovered.get_record("9.9.9.9", 80).is_some());
+
+        fs::remove_file(&own)?;
+        let plan = Plan::new(&log, Some(&dir), Target::Seq(3))?;
+        assert!(plan.snapshot.is_none());
+        assert_eq!(Store::recover(plan)?.len(), 1);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
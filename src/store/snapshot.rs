@@ -0,0 +1,300 @@
+use crate::model::{version, Scan};
+use super::archive::{self, Archive};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Binary snapshots start with this, then a little-endian CRC-32 of the
+// compressed payload that follows it.
+const MAGIC: &[u8; 8] = b"SCANSNAP";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    #[default]
+    Json,
+    // zstd-compressed MessagePack, checksummed so a damaged file is refused
+    // rather than half restored.
+    Binary,
+    // Laid out to be memory-mapped and read in place, see `Archive`.
+    Archive,
+}
+
+impl SnapshotFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SnapshotFormat::Json => "json",
+            SnapshotFormat::Binary => "binary",
+            SnapshotFormat::Archive => "archive",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SnapshotFormat::Json => "json",
+            SnapshotFormat::Binary => "snap",
+            SnapshotFormat::Archive => "arch",
+        }
+    }
+
+    // Imports don't need telling the format; anything without a binary or
+    // archive header is read as JSON.
+    pub fn detect(bytes: &[u8]) -> SnapshotFormat {
+        if bytes.starts_with(MAGIC) {
+            SnapshotFormat::Binary
+        } else if bytes.starts_with(archive::MAGIC) {
+            SnapshotFormat::Archive
+        } else {
+            SnapshotFormat::Json
+        }
+    }
+
+    pub fn of_file(path: &Path) -> io::Result<SnapshotFormat> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        File::open(path)?.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+        Ok(SnapshotFormat::detect(&magic))
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(SnapshotFormat::Json),
+            "binary" => Ok(SnapshotFormat::Binary),
+            "archive" => Ok(SnapshotFormat::Archive),
+            _ => Err(()),
+        }
+    }
+}
+
+// Every live scan of a store at one moment, by tenant. History isn't kept, so
+// restored scans start their history afresh.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    // The scan schema version the snapshot was written at. Older snapshots
+    // are migrated as they are read, but keep the version they were written at.
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    // The last mutation log entry the snapshot includes, or 0 for a store
+    // without a log.
+    pub seq: u64,
+    // The id of the log `seq` counts entries of, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_id: Option<u32>,
+    pub tenants: BTreeMap<String, Vec<Scan>>,
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Snapshot {
+    pub fn new(seq: u64, tenants: BTreeMap<String, Vec<Scan>>) -> Self {
+        Snapshot { version: version::SCAN_VERSION, taken_at: Utc::now(), seq, log_id: None, tenants }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tenants.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn encode(&self, format: SnapshotFormat) -> Vec<u8> {
+        match format {
+            SnapshotFormat::Json => serde_json::to_vec(self).expect("snapshots serialize"),
+            SnapshotFormat::Binary => {
+                let packed = rmp_serde::to_vec_named(self).expect("snapshots serialize");
+                let payload = zstd::encode_all(packed.as_slice(), 0).expect("writing to memory");
+
+                let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+                bytes.extend_from_slice(MAGIC);
+                bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+                bytes.extend_from_slice(&payload);
+                bytes
+            },
+            SnapshotFormat::Archive => Archive::encode(self),
+        }
+    }
+
+    // Reads a snapshot in any format, bringing its scans up to the current
+    // schema version.
+    pub fn decode(bytes: &[u8]) -> io::Result<Snapshot> {
+        let mut value: Value = match SnapshotFormat::detect(bytes) {
+            // Archives only hold scans at the current version.
+            SnapshotFormat::Archive => return Ok(Archive::from_bytes(bytes.to_vec())?.to_snapshot()),
+            SnapshotFormat::Json => serde_json::from_slice(bytes).map_err(invalid_data)?,
+            SnapshotFormat::Binary => rmp_serde::from_slice(&unpack(bytes)?).map_err(invalid_data)?,
+        };
+
+        let written = version::version_of(&value).map_err(invalid_data)?;
+        if let Some(tenants) = value.get_mut("tenants").and_then(Value::as_object_mut) {
+            for scan in tenants.values_mut().filter_map(Value::as_array_mut).flatten() {
+                version::migrate_scan(scan, written).map_err(invalid_data)?;
+            }
+        }
+        value["version"] = written.into();
+
+        serde_json::from_value(value).map_err(invalid_data)
+    }
+
+    // Writes the snapshot beside `path` and renames it into place, so a crash
+    // never leaves a partial snapshot under the final name. Returns its size.
+    pub fn write_to(&self, path: &Path, format: SnapshotFormat) -> io::Result<u64> {
+        let bytes = self.encode(format);
+
+        let temp = partial(path);
+        let mut file = File::create(&temp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+
+        Ok(bytes.len() as u64)
+    }
+
+    pub fn read_from(path: &Path) -> io::Result<Snapshot> {
+        Snapshot::decode(&fs::read(path)?)
+    }
+
+    // The log id and sequence number of the snapshot at `path`, read without
+    // decoding its scans.
+    pub fn origin_of(path: &Path) -> io::Result<(Option<u32>, u64)> {
+        #[derive(Deserialize)]
+        struct Origin {
+            #[serde(default)]
+            log_id: Option<u32>,
+            #[serde(default)]
+            seq: u64,
+        }
+
+        if SnapshotFormat::of_file(path)? == SnapshotFormat::Archive {
+            let archive = Archive::open(path)?;
+            return Ok((archive.log_id(), archive.seq()));
+        }
+        let bytes = fs::read(path)?;
+        let origin: Origin = match SnapshotFormat::detect(&bytes) {
+            SnapshotFormat::Binary => rmp_serde::from_slice(&unpack(&bytes)?).map_err(invalid_data)?,
+            _ => serde_json::from_slice(&bytes).map_err(invalid_data)?,
+        };
+        Ok((origin.log_id, origin.seq))
+    }
+
+    // Where in `dir` a snapshot in `format` taken now is written. Names sort
+    // in the order they were taken.
+    pub fn path_in(&self, dir: &Path, format: SnapshotFormat) -> PathBuf {
+        dir.join(format!(
+            "scans-{}-{}.{}",
+            self.taken_at.format("%Y%m%dT%H%M%S%.3fZ"), self.seq, format.extension(),
+        ))
+    }
+}
+
+// The MessagePack inside a binary snapshot, once its checksum is checked.
+fn unpack(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let checksum = bytes.get(MAGIC.len()..HEADER_LEN)
+        .ok_or_else(|| invalid_data("snapshot header is truncated"))?;
+    let payload = &bytes[HEADER_LEN..];
+    if crc32fast::hash(payload).to_le_bytes() != checksum {
+        return Err(invalid_data("snapshot checksum does not match its contents"));
+    }
+
+    zstd::decode_all(payload).map_err(invalid_data)
+}
+
+fn partial(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::error::Error;
+
+    fn snapshot() -> Snapshot {
+        let scan = |ip: &str| Scan{
+            ip: ip.to_owned(),
+            port: 443,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc.with_ymd_and_hms(2021, 9, 20, 17, 10, 0).unwrap(),
+            http_status: Some(200),
+            ..Default::default()
+        };
+
+        let mut tenants = BTreeMap::new();
+        tenants.insert("default".to_owned(), vec![scan("1.2.3.4"), scan("8.8.8.8")]);
+        tenants.insert("acme".to_owned(), vec![scan("9.9.9.9")]);
+        Snapshot { log_id: Some(0x5ca9), ..Snapshot::new(7, tenants) }
+    }
+
+    #[test]
+    fn snapshots_round_trip_in_both_formats() {
+        let snapshot = snapshot();
+        assert_eq!(snapshot.len(), 3);
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary, SnapshotFormat::Archive] {
+            let bytes = snapshot.encode(format);
+            assert_eq!(SnapshotFormat::detect(&bytes), format);
+            assert_eq!(Snapshot::decode(&bytes).unwrap(), snapshot);
+        }
+    }
+
+    #[test]
+    fn damaged_binary_snapshots_are_refused() {
+        let mut bytes = snapshot().encode(SnapshotFormat::Binary);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(Snapshot::decode(&bytes).unwrap_err().to_string().contains("checksum"));
+
+        assert!(Snapshot::decode(&bytes[..HEADER_LEN - 1]).unwrap_err().to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn old_snapshots_are_migrated() {
+        let v1 = "{\"taken_at\":\"2021-09-20T17:10:00Z\",\"seq\":0,\"tenants\":{\"default\":[{\"ip\":\"1.2.3.4\",\
+                  \"port\":80,\"load_time_nanosec\":18,\"content_hash\":\"foobar\",\"timestamp\":\"2021-09-20T17:10:00Z\"}]}}";
+
+        let snapshot = Snapshot::decode(v1.as_bytes()).unwrap();
+        assert_eq!((snapshot.version, snapshot.log_id), (1, None));
+        assert_eq!(snapshot.tenants["default"][0].ip, "1.2.3.4");
+
+        let newer = format!("{{\"version\":{},\"taken_at\":\"2021-09-20T17:10:00Z\",\"seq\":0,\"tenants\":{{}}}}", version::SCAN_VERSION + 1);
+        assert!(Snapshot::decode(newer.as_bytes()).unwrap_err().to_string().contains("only reads up to"));
+    }
+
+    #[test]
+    fn snapshots_are_written_whole() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        let snapshot = snapshot();
+        let path = snapshot.path_in(&dir, SnapshotFormat::Binary);
+        assert!(path.to_string_lossy().ends_with("-7.snap"));
+
+        let written = snapshot.write_to(&path, SnapshotFormat::Binary)?;
+        assert_eq!(fs::metadata(&path)?.len(), written);
+        assert!(!partial(&path).exists());
+        assert_eq!(Snapshot::read_from(&path)?, snapshot);
+        assert_eq!(SnapshotFormat::of_file(&path)?, SnapshotFormat::Binary);
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary, SnapshotFormat::Archive] {
+            let path = snapshot.path_in(&dir, format);
+            snapshot.write_to(&path, format)?;
+            assert_eq!(Snapshot::origin_of(&path)?, (Some(0x5ca9), 7));
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
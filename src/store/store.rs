@@ -1,18 +1,629 @@
+use crate::config::Config;
+use crate::health::Check;
 use crate::model::Scan;
-use std::collections::HashMap;
+use super::archive::{Archive, ArchivedScan};
+use super::snapshot::{Snapshot, SnapshotFormat};
+use super::tenant::{self, Quotas, DEFAULT_TENANT};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 use std::string::String;
+use tokio::sync::broadcast;
 
+#[cfg(feature = "wal")]
+use super::recovery::Plan;
+#[cfg(feature = "wal")]
+use super::wal::{Compaction, Mutation, Prepared, Wal};
+#[cfg(feature = "wal")]
+use std::path::PathBuf;
+
+// Watchers that fall this far behind miss changes rather than hold up writes.
+const CHANGE_BUFFER: usize = 1024;
+
+// Superseded versions kept per ip and port, newest first.
+const HISTORY_DEPTH: usize = 16;
+
+// A log is due for compaction once it holds this many entries per live scan.
+#[cfg(feature = "wal")]
+const COMPACT_RATIO: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+// The time scans are listed in order of. Scans the server didn't stamp fall
+// back to their own timestamp when sorted by `ReceivedAt`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Timestamp,
+    ReceivedAt,
+}
+
+impl SortKey {
+    fn of(self, scan: &Scan) -> DateTime<Utc> {
+        match self {
+            SortKey::Timestamp => scan.timestamp,
+            SortKey::ReceivedAt => scan.received_at.unwrap_or(scan.timestamp),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timestamp" => Ok(SortKey::Timestamp),
+            "received_at" => Ok(SortKey::ReceivedAt),
+            _ => Err(()),
+        }
+    }
+}
+
+// Whether an update may replace a scan with an older one. Under `Newer`,
+// scanners reporting the same target out of order can't undo each other: only
+// a scan with a later `timestamp` than the stored one is accepted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpdatePolicy {
+    #[default]
+    Overwrite,
+    Newer,
+}
+
+impl FromStr for UpdatePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(UpdatePolicy::Overwrite),
+            "newer" => Ok(UpdatePolicy::Newer),
+            _ => Err(()),
+        }
+    }
+}
+
+// The error an update gets when `UpdatePolicy::Newer` turns it away, so the
+// APIs can tell it apart from the others.
+pub const STALE_SCAN: &str = "stored scan is at least as new";
+
+// The error an insert gets when its tenant already holds as many scans as
+// its quota allows.
+pub const QUOTA_EXCEEDED: &str = "tenant quota exceeded";
+
+// An acknowledged mutation, as seen by watchers. Deletions carry the scan that
+// was removed.
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub tenant: String,
+    pub kind: ChangeKind,
+    pub scan: Scan,
+}
+
+// An archive the store answers reads from while its scans are copied in. A
+// scan stays cold until it is warmed or a write takes it over; records before
+// `next` have all been, and `taken` holds those after it that have.
+struct Warming {
+    archive: Archive,
+    next: usize,
+    taken: HashSet<usize>,
+    cold: HashMap<String, usize>,
+}
+
+impl Warming {
+    fn new(archive: Archive) -> Self {
+        let cold = archive.tenants()
+            .map(|tenant| (tenant.to_owned(), archive.range_of(tenant).len()))
+            .filter(|(_, records)| *records > 0)
+            .collect();
+        Warming { archive, next: 0, taken: HashSet::new(), cold }
+    }
+
+    fn is_cold(&self, index: usize) -> bool {
+        index >= self.next && !self.taken.contains(&index)
+    }
+
+    fn get(&self, tenant: &str, ip: &str, port: i16) -> Option<ArchivedScan<'_>> {
+        self.archive.get(tenant, ip, port).filter(|scan| self.is_cold(scan.index()))
+    }
+
+    fn cold_in(&self, tenant: &str) -> impl Iterator<Item = ArchivedScan<'_>> {
+        self.archive.range_of(tenant)
+            .skip_while(|index| *index < self.next)
+            .filter(|index| !self.taken.contains(index))
+            .map(|index| self.archive.scan(index))
+    }
+
+    fn cold_len(&self, tenant: &str) -> usize {
+        self.cold.get(tenant).copied().unwrap_or(0)
+    }
+
+    fn remaining(&self) -> usize {
+        self.cold.values().sum()
+    }
+
+    fn take(&mut self, tenant: &str, index: usize) {
+        match index == self.next {
+            true => self.next += 1,
+            false => { self.taken.insert(index); },
+        }
+        if let Some(cold) = self.cold.get_mut(tenant) {
+            *cold -= 1;
+            if *cold == 0 {
+                self.cold.remove(tenant);
+            }
+        }
+    }
+}
+
+// Scans are kept per tenant, so the same ip and port can be recorded by
+// several tenants without them seeing each other's data.
 pub struct Store {
-    map: HashMap<String, Scan>
+    tenants: HashMap<String, HashMap<String, Scan>>,
+    // Keyed by tenant and record key. Deleting a scan forgets its history.
+    history: HashMap<(String, String), VecDeque<Scan>>,
+    quotas: Quotas,
+    update_policy: UpdatePolicy,
+    changes: broadcast::Sender<Change>,
+    warming: Option<Warming>,
+    #[cfg(feature = "wal")]
+    log: Option<Wal>,
+    #[cfg(feature = "wal")]
+    replayed: usize,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Store::new()
+    }
 }
 
 impl Store {
     pub fn new() -> Self {
         Store{
-            map: HashMap::new(),
+            tenants: HashMap::new(),
+            history: HashMap::new(),
+            quotas: Quotas::default(),
+            update_policy: UpdatePolicy::default(),
+            changes: broadcast::channel(CHANGE_BUFFER).0,
+            warming: None,
+            #[cfg(feature = "wal")]
+            log: None,
+            #[cfg(feature = "wal")]
+            replayed: 0,
         }
     }
 
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let store = match &config.wal_path {
+            #[cfg(feature = "wal")]
+            Some(path) => Store::open(path)?,
+            #[cfg(not(feature = "wal"))]
+            Some(_) => return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SCANS_WAL_PATH is set but the `wal` feature is not enabled",
+            )),
+            None => Store::new(),
+        };
+        let mut store = store.with_quotas(config.tenant_quotas.clone()).with_update_policy(config.update_policy);
+
+        // A replayed log is at least as new as any snapshot it was seeded
+        // from, so the snapshot only fills a store that starts out empty.
+        if let Some(path) = &config.load_snapshot {
+            match store.is_empty() {
+                true if SnapshotFormat::of_file(path)? == SnapshotFormat::Archive => {
+                    let attached = store.attach_archive(Archive::open(path)?)?;
+                    tracing::info!(path = %path.display(), scans = attached, "serving archived snapshot");
+
+                    // Until every scan is in the log, a restart would find
+                    // the log not empty and skip the archive, so a logged
+                    // store takes it all in before serving.
+                    #[cfg(feature = "wal")]
+                    if store.log.is_some() {
+                        store.warm(usize::MAX).map_err(io::Error::other)?;
+                    }
+                },
+                true => {
+                    let loaded = store.load_snapshot(path)?;
+                    tracing::info!(path = %path.display(), scans = loaded, "loaded snapshot");
+                },
+                false => tracing::warn!(path = %path.display(), "store is not empty, skipping snapshot"),
+            }
+        }
+
+        Ok(store)
+    }
+
+    // Quotas stop new inserts and snapshot imports; a log replayed past them
+    // is kept whole.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn with_update_policy(mut self, policy: UpdatePolicy) -> Self {
+        self.update_policy = policy;
+        self
+    }
+
+    // Opens a store backed by the mutation log at `path`, replaying any
+    // existing entries before returning.
+    #[cfg(feature = "wal")]
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (log, entries) = Wal::open(path)?;
+
+        let mut store = Store::new();
+        store.replayed = entries.len();
+        for entry in entries {
+            store.apply(&entry.tenant, entry.mutation);
+        }
+        store.log = Some(log);
+
+        Ok(store)
+    }
+
+    // Rebuilds the store as it stood at the plan's target. The recovered
+    // store has no log; write it out as a snapshot to serve it again.
+    #[cfg(feature = "wal")]
+    pub fn recover(plan: Plan) -> io::Result<Self> {
+        let mut store = Store::new();
+        if let Some(base) = plan.base {
+            store.restore(base, false)?;
+        }
+        for entry in plan.entries {
+            store.apply(&entry.tenant, entry.mutation);
+        }
+
+        Ok(store)
+    }
+
+    #[cfg(feature = "wal")]
+    fn apply(&mut self, tenant: &str, mutation: Mutation) {
+        match mutation {
+            Mutation::Insert(scan) | Mutation::Update(scan) => {
+                self.put(tenant, scan);
+            },
+            Mutation::Delete { ip, port } => {
+                self.remove(tenant, &Store::key_for_ip_port(&ip, port));
+            },
+        }
+    }
+
+    #[cfg(feature = "wal")]
+    fn log(&mut self, tenant: &str, mutation: &Mutation) -> Result<(), &'static str> {
+        match &mut self.log {
+            None => Ok(()),
+            Some(log) => match log.append(tenant, mutation) {
+                Err(e) => {
+                    tracing::error!(error = %e, "failed to append to the mutation log");
+                    Err("failed to write to log")
+                },
+                Ok(_) => Ok(()),
+            },
+        }
+    }
+
+    // The log to compact, once it has grown well past the scans it holds.
+    #[cfg(feature = "wal")]
+    pub fn compaction_due(&self) -> Option<PathBuf> {
+        let log = self.log.as_ref().filter(|log| !log.is_poisoned())?;
+        match log.entries() > COMPACT_RATIO * self.len().max(1) {
+            true => Some(log.path().to_owned()),
+            false => None,
+        }
+    }
+
+    // Swaps in a log compacted by `Wal::prepare_compaction`.
+    #[cfg(feature = "wal")]
+    pub fn finish_compaction(&mut self, prepared: Prepared) -> io::Result<Compaction> {
+        match &mut self.log {
+            Some(log) => log.finish_compaction(prepared),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "the store has no log to compact")),
+        }
+    }
+
+    // Compacts the log through `through` in one go, holding the store
+    // throughout.
+    #[cfg(feature = "wal")]
+    pub fn compact(&mut self, through: u64) -> io::Result<Compaction> {
+        let path = self.log.as_ref()
+            .map(|log| log.path().to_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the store has no log to compact"))?;
+        let prepared = Wal::prepare_compaction(&path, through)?;
+        self.finish_compaction(prepared)
+    }
+
+    // Makes every acknowledged mutation durable. Called on shutdown; a no-op
+    // for the in-memory backend.
+    #[tracing::instrument(skip_all)]
+    pub fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature = "wal")]
+        if let Some(log) = &mut self.log {
+            log.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        let cold = self.warming.as_ref().map(Warming::remaining).unwrap_or(0);
+        self.tenants.values().map(HashMap::len).sum::<usize>() + cold
+    }
+
+    // Every live scan as of the last logged mutation.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut tenants: BTreeMap<String, Vec<Scan>> = self.tenants.iter()
+            .map(|(tenant, records)| (tenant.clone(), records.values().cloned().collect()))
+            .collect();
+        if let Some(warming) = &self.warming {
+            for tenant in warming.cold.keys() {
+                tenants.entry(tenant.clone()).or_default().extend(warming.cold_in(tenant).map(|scan| scan.to_scan()));
+            }
+        }
+        for scans in tenants.values_mut() {
+            scans.sort_by(|a, b| (&a.ip, a.port).cmp(&(&b.ip, b.port)));
+        }
+
+        Snapshot { log_id: self.log_id(), ..Snapshot::new(self.last_seq(), tenants) }
+    }
+
+    fn log_id(&self) -> Option<u32> {
+        #[cfg(feature = "wal")]
+        if let Some(log) = &self.log {
+            return log.id();
+        }
+
+        None
+    }
+
+    fn last_seq(&self) -> u64 {
+        #[cfg(feature = "wal")]
+        if let Some(log) = &self.log {
+            return log.next_seq() - 1;
+        }
+
+        0
+    }
+
+    pub fn export_snapshot(&self, format: SnapshotFormat) -> Vec<u8> {
+        self.snapshot().encode(format)
+    }
+
+    pub fn save_snapshot(&self, path: &Path, format: SnapshotFormat) -> io::Result<Snapshot> {
+        let snapshot = self.snapshot();
+        snapshot.write_to(path, format)?;
+        Ok(snapshot)
+    }
+
+    // Puts every scan of a snapshot in either format into the store,
+    // replacing any held for the same ip and port, and returns how many there
+    // were. Each is logged like any other write, so the log alone still
+    // rebuilds the store, and announced to subscribers.
+    pub fn import_snapshot(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.restore(Snapshot::decode(bytes)?, true)
+    }
+
+    // Loading happens at startup, before anyone could be subscribed, so it
+    // isn't announced.
+    pub fn load_snapshot(&mut self, path: &Path) -> io::Result<usize> {
+        self.restore(Snapshot::read_from(path)?, false)
+    }
+
+    // A snapshot that would put a tenant past its quota is refused before
+    // anything is restored.
+    fn restore(&mut self, snapshot: Snapshot, announce: bool) -> io::Result<usize> {
+        for (tenant, scans) in &snapshot.tenants {
+            Store::check_tenant(tenant)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", e, tenant)))?;
+
+            let added = scans.iter()
+                .filter(|scan| self.get_record_in(tenant, &scan.ip, scan.port).is_none())
+                .count();
+            self.check_quota(tenant, added)?;
+        }
+
+        let restored = snapshot.len();
+        for (tenant, scans) in snapshot.tenants {
+            for scan in scans {
+                self.promote(&tenant, &scan.ip, scan.port).map_err(io::Error::other)?;
+
+                let kind = match self.get_record_in(&tenant, &scan.ip, scan.port) {
+                    None => ChangeKind::Created,
+                    Some(_) => ChangeKind::Updated,
+                };
+
+                #[cfg(feature = "wal")]
+                {
+                    let mutation = match kind {
+                        ChangeKind::Created => Mutation::Insert(scan.clone()),
+                        _ => Mutation::Update(scan.clone()),
+                    };
+                    self.log(&tenant, &mutation).map_err(io::Error::other)?;
+                }
+
+                if announce {
+                    self.announce(&tenant, kind, scan.clone());
+                }
+                self.put(&tenant, scan);
+            }
+        }
+
+        Ok(restored)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty() && self.warming.is_none()
+    }
+
+    // Serves the scans of an archived snapshot straight from it, so a store
+    // can answer reads before it has copied them in. Each scan is copied in
+    // by `warm`, or by the first write to it. Only an empty store can take
+    // an archive, since nothing it holds could be told apart from the
+    // archive's scans.
+    pub fn attach_archive(&mut self, archive: Archive) -> io::Result<usize> {
+        if !self.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "archives can only be attached to an empty store"));
+        }
+
+        for tenant in archive.tenants() {
+            self.check_quota(tenant, archive.range_of(tenant).len())?;
+        }
+
+        let attached = archive.len();
+        if !archive.is_empty() {
+            self.warming = Some(Warming::new(archive));
+        }
+        Ok(attached)
+    }
+
+    pub fn is_warming(&self) -> bool {
+        self.warming.is_some()
+    }
+
+    // Copies up to `batch` cold scans in from the attached archive, logging
+    // each as an insert, and returns how many are left. The archive is
+    // dropped once the last has been.
+    pub fn warm(&mut self, batch: usize) -> Result<usize, &'static str> {
+        let mut warmed = 0;
+        while let Some(warming) = &mut self.warming {
+            if warming.next == warming.archive.len() {
+                self.warming = None;
+                break;
+            }
+            if warmed == batch {
+                return Ok(warming.remaining());
+            }
+
+            let index = warming.next;
+            match warming.taken.remove(&index) {
+                true => warming.next += 1,
+                false => {
+                    self.warm_one(index)?;
+                    warmed += 1;
+                },
+            }
+        }
+
+        Ok(0)
+    }
+
+    // Takes the archived scan for `ip` and `port` over before a write, so
+    // the write sees it like any other stored scan.
+    fn promote(&mut self, tenant: &str, ip: &str, port: i16) -> Result<(), &'static str> {
+        let cold = self.warming.as_ref().and_then(|warming| warming.get(tenant, ip, port)).map(|scan| scan.index());
+        match cold {
+            None => Ok(()),
+            Some(index) => self.warm_one(index),
+        }
+    }
+
+    fn warm_one(&mut self, index: usize) -> Result<(), &'static str> {
+        let warming = self.warming.as_mut().expect("warming an archived scan");
+        let (tenant, scan) = warming.archive.entry(index);
+        let (tenant, scan) = (tenant.to_owned(), scan.to_scan());
+
+        #[cfg(feature = "wal")]
+        self.log(&tenant, &Mutation::Insert(scan.clone()))?;
+
+        self.warming.as_mut().expect("warming an archived scan").take(&tenant, index);
+        self.put(&tenant, scan);
+        Ok(())
+    }
+
+    // Streams every mutation acknowledged from now on. Replayed log entries
+    // are not announced.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    fn announce(&self, tenant: &str, kind: ChangeKind, scan: Scan) {
+        // Sending only fails when nobody is watching.
+        let _ = self.changes.send(Change { tenant: tenant.to_owned(), kind, scan });
+    }
+
+    fn records(&self, tenant: &str) -> Option<&HashMap<String, Scan>> {
+        self.tenants.get(tenant)
+    }
+
+    // How many scans `tenant` holds, counting those still in an attached
+    // archive.
+    fn held(&self, tenant: &str) -> usize {
+        let cold = self.warming.as_ref().map(|warming| warming.cold_len(tenant)).unwrap_or(0);
+        self.records(tenant).map(HashMap::len).unwrap_or(0) + cold
+    }
+
+    // Checks `tenant` can take `added` more scans from an import.
+    fn check_quota(&self, tenant: &str, added: usize) -> io::Result<()> {
+        let held = self.held(tenant) + added;
+        match self.quotas.limit_for(tenant) {
+            Some(limit) if held > limit => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: {:?} would hold {} scans, past its quota of {}", QUOTA_EXCEEDED, tenant, held, limit),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn put(&mut self, tenant: &str, scan: Scan) {
+        let key = Store::key_for_record(&scan);
+        let previous = self.tenants.entry(tenant.to_owned())
+            .or_default()
+            .insert(key.clone(), scan);
+
+        if let Some(previous) = previous {
+            let versions = self.history.entry((tenant.to_owned(), key)).or_default();
+            versions.push_front(previous);
+            versions.truncate(HISTORY_DEPTH);
+        }
+    }
+
+    // Drops the tenant along with its last scan so that empty tenants don't
+    // accumulate.
+    fn remove(&mut self, tenant: &str, key: &str) -> Option<Scan> {
+        let records = self.tenants.get_mut(tenant)?;
+        let removed = records.remove(key);
+        self.history.remove(&(tenant.to_owned(), key.to_owned()));
+        if records.is_empty() {
+            self.tenants.remove(tenant);
+        }
+        removed
+    }
+
+    fn check_tenant(tenant: &str) -> Result<(), &'static str> {
+        match tenant::is_valid(tenant) {
+            true => Ok(()),
+            false => Err("invalid tenant name"),
+        }
+    }
+
+    // Reports whether the backend can still accept writes.
+    pub fn health(&self) -> Check {
+        let warming = match &self.warming {
+            Some(warming) => format!(", warming: {} of {} archived scans left", warming.remaining(), warming.archive.len()),
+            None => String::new(),
+        };
+
+        #[cfg(feature = "wal")]
+        if let Some(log) = &self.log {
+            let detail = format!(
+                "wal: replayed {} entries, next sequence {}{}",
+                self.replayed, log.next_seq(), warming,
+            );
+
+            return match log.is_poisoned() {
+                false => Check::up(detail),
+                true => Check::down(format!("{}, log is poisoned by a write error", detail)),
+            };
+        }
+
+        Check::up(format!("memory{}", warming))
+    }
+
     fn key_for_record(scan: &Scan) -> String {
         Store::key_for_ip_port(&scan.ip, scan.port)
     }
@@ -22,10 +633,25 @@ impl Store {
     }
 
     pub fn insert_record(&mut self, scan: Scan) -> Result<(), &'static str> {
-        match self.get_record(&scan.ip, scan.port) {
+        self.insert_record_in(DEFAULT_TENANT, scan)
+    }
+
+    #[tracing::instrument(skip_all, fields(tenant = %tenant, ip = %scan.ip, port = scan.port))]
+    pub fn insert_record_in(&mut self, tenant: &str, scan: Scan) -> Result<(), &'static str> {
+        Store::check_tenant(tenant)?;
+        self.promote(tenant, &scan.ip, scan.port)?;
+
+        match self.get_record_in(tenant, &scan.ip, scan.port) {
             None => {
-                let key = Store::key_for_record(&scan);
-                self.map.insert(key, scan);
+                if self.quotas.limit_for(tenant).is_some_and(|limit| self.held(tenant) >= limit) {
+                    return Err(QUOTA_EXCEEDED);
+                }
+
+                #[cfg(feature = "wal")]
+                self.log(tenant, &Mutation::Insert(scan.clone()))?;
+
+                self.announce(tenant, ChangeKind::Created, scan.clone());
+                self.put(tenant, scan);
                 Ok(())
             },
             Some(_) => {
@@ -35,40 +661,97 @@ impl Store {
     }
 
     pub fn get_all(&self) -> Vec<Scan> {
+        self.get_all_in(DEFAULT_TENANT)
+    }
+
+    pub fn get_all_in(&self, tenant: &str) -> Vec<Scan> {
+        self.get_all_sorted_in(tenant, SortKey::Timestamp)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_all_sorted_in(&self, tenant: &str, sort: SortKey) -> Vec<Scan> {
         let mut res = Vec::new();
 
-        for (_, val) in self.map.iter() {
+        for (_, val) in self.records(tenant).into_iter().flatten() {
             res.push(val.clone())
         }
+        if let Some(warming) = &self.warming {
+            res.extend(warming.cold_in(tenant).map(|scan| scan.to_scan()));
+        }
         
-       res.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+       res.sort_by_key(|a| sort.of(a));
 
        res
     }
 
     pub fn get_record(&self, ip: &str, port: i16) -> Option<Scan> {
+        self.get_record_in(DEFAULT_TENANT, ip, port)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_record_in(&self, tenant: &str, ip: &str, port: i16) -> Option<Scan> {
         let key = format!("{}:{}", ip, port);
-        let res: Option<&Scan> = self.map.get(&key);
-        return res.cloned();
+        let res: Option<&Scan> = self.records(tenant).and_then(|records| records.get(&key));
+        match res {
+            Some(scan) => Some(scan.clone()),
+            None => self.warming.as_ref()?.get(tenant, ip, port).map(|scan| scan.to_scan()),
+        }
+    }
+
+    // The versions an update replaced, newest first. Only the last
+    // `HISTORY_DEPTH` are kept.
+    #[tracing::instrument(skip(self))]
+    pub fn get_history_in(&self, tenant: &str, ip: &str, port: i16) -> Vec<Scan> {
+        let key = (tenant.to_owned(), Store::key_for_ip_port(ip, port));
+        self.history.get(&key).map(|versions| versions.iter().cloned().collect()).unwrap_or_default()
     }
 
     pub fn update_record(&mut self, scan: Scan) -> Result<(), &'static str> {
-        match self.get_record(&scan.ip, scan.port) {
+        self.update_record_in(DEFAULT_TENANT, scan)
+    }
+
+    #[tracing::instrument(skip_all, fields(tenant = %tenant, ip = %scan.ip, port = scan.port))]
+    pub fn update_record_in(&mut self, tenant: &str, scan: Scan) -> Result<(), &'static str> {
+        Store::check_tenant(tenant)?;
+        self.promote(tenant, &scan.ip, scan.port)?;
+
+        match self.get_record_in(tenant, &scan.ip, scan.port) {
             None => Err("no record exists"),
+            Some(stored) if self.update_policy == UpdatePolicy::Newer && scan.timestamp <= stored.timestamp => {
+                Err(STALE_SCAN)
+            },
             Some(_) => {
-                let key = Store::key_for_record(&scan);
-                self.map.insert(key, scan);
+                #[cfg(feature = "wal")]
+                self.log(tenant, &Mutation::Update(scan.clone()))?;
+
+                self.announce(tenant, ChangeKind::Updated, scan.clone());
+                self.put(tenant, scan);
                 Ok(())
             }
         }
     }
 
     pub fn delete_record(&mut self, ip: &str, port: i16) -> Result<(), &'static str> {
+        self.delete_record_in(DEFAULT_TENANT, ip, port)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn delete_record_in(&mut self, tenant: &str, ip: &str, port: i16) -> Result<(), &'static str> {
+        Store::check_tenant(tenant)?;
+        self.promote(tenant, ip, port)?;
+
         let key = Store::key_for_ip_port(ip, port);
-        match self.map.remove(&key) {
-            None => Err("no record for key"),
-            Some(_) => Ok(()),
+        if !self.records(tenant).is_some_and(|records| records.contains_key(&key)) {
+            return Err("no record for key");
+        }
+
+        #[cfg(feature = "wal")]
+        self.log(tenant, &Mutation::Delete { ip: ip.to_owned(), port })?;
+
+        if let Some(scan) = self.remove(tenant, &key) {
+            self.announce(tenant, ChangeKind::Deleted, scan);
         }
+        Ok(())
     }
 }
 
@@ -88,6 +771,7 @@ mod tests {
             load_time_nanosec: 18,
             content_hash: "foobar".to_owned(),
             timestamp: Utc::now(),
+            ..Default::default()
         };
 
         let res = store.insert_record(record.clone());
@@ -96,7 +780,7 @@ mod tests {
         let res = store.insert_record(record.clone());
         assert!(res.is_err());
 
-        assert_eq!(store.map.contains_key("1.2.3.4:80"), true);
+        assert!(store.records(DEFAULT_TENANT).unwrap().contains_key("1.2.3.4:80"));
 
         Ok(())
     }
@@ -113,6 +797,7 @@ mod tests {
             load_time_nanosec: 18,
             content_hash: "foobar".to_owned(),
             timestamp: now,
+            ..Default::default()
         };
 
         store.insert_record(record)?;
@@ -145,6 +830,7 @@ mod tests {
             load_time_nanosec: 18,
             content_hash: "foobar".to_owned(),
             timestamp: Utc::now(),
+            ..Default::default()
         };
 
         store.insert_record(record)?;
@@ -153,7 +839,7 @@ mod tests {
 
         assert!(res.is_ok());
 
-        assert_eq!(store.map.contains_key("1.2.3.4:80"), false);
+        assert!(!store.records(DEFAULT_TENANT).is_some_and(|r| r.contains_key("1.2.3.4:80")));
 
 
         Ok(())
@@ -170,6 +856,7 @@ mod tests {
             load_time_nanosec: 18,
             content_hash: "foobar".to_owned(),
             timestamp: Utc::now(),
+            ..Default::default()
         };
 
         store.insert_record(record)?;
@@ -179,19 +866,48 @@ mod tests {
             port: 443,
             load_time_nanosec: 500,
             content_hash: "prev".to_owned(),
-            timestamp: Utc.ymd(2021, 9, 20).and_hms(17, 10, 0),
+            timestamp: Utc.with_ymd_and_hms(2021, 9, 20, 17, 10, 0).unwrap(),
+            ..Default::default()
         };
 
         store.insert_record(record)?;
 
         let res = store.get_all();
 
-        assert_eq!(res.get(0).unwrap().ip, "8.8.8.8");
+        assert_eq!(res.first().unwrap().ip, "8.8.8.8");
         assert_eq!(res.get(1).unwrap().ip, "1.2.3.4");
 
         Ok(())
     }
 
+    #[test]
+    fn store_get_all_sorted_by_received_at() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new();
+        let at = |hour| Utc.with_ymd_and_hms(2022, 7, 31, hour, 0, 0).unwrap();
+        let scan = |ip: &str, timestamp, received_at| Scan{
+            ip: ip.to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp,
+            received_at,
+            ..Default::default()
+        };
+
+        // A scanner whose clock runs hours ahead, one that is right, and a
+        // scan from before the server stamped them.
+        store.insert_record(scan("1.1.1.1", at(20), Some(at(12))))?;
+        store.insert_record(scan("2.2.2.2", at(13), Some(at(13))))?;
+        store.insert_record(scan("3.3.3.3", at(11), None))?;
+
+        let ips = |sort| store.get_all_sorted_in(DEFAULT_TENANT, sort).into_iter().map(|s| s.ip).collect::<Vec<_>>();
+        assert_eq!(ips(SortKey::Timestamp), ["3.3.3.3", "2.2.2.2", "1.1.1.1"]);
+        assert_eq!(ips(SortKey::ReceivedAt), ["3.3.3.3", "1.1.1.1", "2.2.2.2"]);
+        assert_eq!("received_at".parse(), Ok(SortKey::ReceivedAt));
+
+        Ok(())
+    }
+
     #[test]
     fn store_update() -> Result<(), Box<dyn Error>> {
         let mut store = Store::new();
@@ -202,6 +918,7 @@ mod tests {
             load_time_nanosec: 18,
             content_hash: "foobar".to_owned(),
             timestamp: Utc::now(),
+            ..Default::default()
         };
 
         let res = store.update_record(record.clone());
@@ -228,4 +945,441 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn store_update_newer_only() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new().with_update_policy(UpdatePolicy::Newer);
+        let at = |minute| Utc.with_ymd_and_hms(2022, 7, 31, 14, minute, 0).unwrap();
+        let scan = |minute, content_hash: &str| Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: content_hash.to_owned(),
+            timestamp: at(minute),
+            ..Default::default()
+        };
+
+        store.insert_record(scan(10, "first"))?;
+        let changes = store.subscribe();
+
+        assert_eq!(store.update_record(scan(5, "older")), Err(STALE_SCAN));
+        assert_eq!(store.update_record(scan(10, "same time")), Err(STALE_SCAN));
+        assert_eq!(store.get_record("1.2.3.4", 80).unwrap().content_hash, "first");
+        assert!(store.get_history_in(DEFAULT_TENANT, "1.2.3.4", 80).is_empty());
+        assert!(changes.is_empty(), "stale updates aren't announced");
+
+        store.update_record(scan(20, "newer"))?;
+        assert_eq!(store.get_record("1.2.3.4", 80).unwrap().content_hash, "newer");
+
+        let mut overwrite = Store::new();
+        overwrite.insert_record(scan(10, "first"))?;
+        overwrite.update_record(scan(5, "older"))?;
+        assert_eq!(overwrite.get_record("1.2.3.4", 80).unwrap().content_hash, "older");
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_keeps_history() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new();
+
+        let mut record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 0,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+
+        store.insert_record_in("team-a", record.clone())?;
+        assert!(store.get_history_in("team-a", "1.2.3.4", 80).is_empty());
+
+        for n in 1..=HISTORY_DEPTH as i64 + 2 {
+            record.load_time_nanosec = n;
+            store.update_record_in("team-a", record.clone())?;
+        }
+
+        let history = store.get_history_in("team-a", "1.2.3.4", 80);
+        assert_eq!(history.len(), HISTORY_DEPTH);
+        assert_eq!(history.first().unwrap().load_time_nanosec, HISTORY_DEPTH as i64 + 1);
+        assert!(store.get_history_in("team-b", "1.2.3.4", 80).is_empty());
+
+        store.delete_record_in("team-a", "1.2.3.4", 80)?;
+        store.insert_record_in("team-a", record)?;
+        assert!(store.get_history_in("team-a", "1.2.3.4", 80).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_isolates_tenants() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new();
+
+        let mut record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "team-a".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+
+        store.insert_record_in("team-a", record.clone())?;
+        record.content_hash = "team-b".to_owned();
+        store.insert_record_in("team-b", record.clone())?;
+
+        assert_eq!(store.get_record_in("team-a", "1.2.3.4", 80).unwrap().content_hash, "team-a");
+        assert_eq!(store.get_record_in("team-b", "1.2.3.4", 80).unwrap().content_hash, "team-b");
+        assert!(store.get_record("1.2.3.4", 80).is_none());
+        assert!(store.get_all().is_empty());
+        assert_eq!(store.get_all_in("team-a").len(), 1);
+        assert_eq!(store.len(), 2);
+
+        record.content_hash = "changed".to_owned();
+        assert!(store.update_record_in("team-c", record.clone()).is_err());
+        store.update_record_in("team-b", record)?;
+        assert_eq!(store.get_record_in("team-a", "1.2.3.4", 80).unwrap().content_hash, "team-a");
+
+        store.delete_record_in("team-a", "1.2.3.4", 80)?;
+        assert!(store.get_record_in("team-a", "1.2.3.4", 80).is_none());
+        assert!(store.get_record_in("team-b", "1.2.3.4", 80).is_some());
+        assert!(store.delete_record_in("team-a", "1.2.3.4", 80).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_enforces_tenant_quotas() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new().with_quotas("team-a=1,*=2".parse().unwrap());
+
+        let mut record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+
+        store.insert_record_in("team-a", record.clone())?;
+        store.insert_record_in("team-b", record.clone())?;
+        store.update_record_in("team-a", record.clone())?;
+
+        record.port = 443;
+        assert_eq!(store.insert_record_in("team-a", record.clone()), Err(QUOTA_EXCEEDED));
+        store.insert_record_in("team-b", record.clone())?;
+
+        record.port = 8080;
+        assert!(store.insert_record_in("team-b", record.clone()).is_err());
+
+        store.delete_record_in("team-a", "1.2.3.4", 80)?;
+        store.insert_record_in("team-a", record)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_rejects_invalid_tenants() {
+        let mut store = Store::new();
+
+        let record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+
+        assert_eq!(store.insert_record_in("Not A Tenant", record.clone()), Err("invalid tenant name"));
+        assert_eq!(store.update_record_in("Not A Tenant", record), Err("invalid tenant name"));
+        assert_eq!(store.delete_record_in("Not A Tenant", "1.2.3.4", 80), Err("invalid tenant name"));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn store_announces_changes() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new();
+        let mut changes = store.subscribe();
+
+        let mut record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+
+        store.insert_record_in("team-a", record.clone())?;
+        record.content_hash = "barfoo".to_owned();
+        store.update_record_in("team-a", record.clone())?;
+        assert!(store.update_record(record.clone()).is_err());
+        store.delete_record_in("team-a", "1.2.3.4", 80)?;
+
+        let seen: Vec<(String, ChangeKind, String)> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| (change.tenant, change.kind, change.scan.content_hash))
+            .collect();
+        assert_eq!(seen, vec![
+            ("team-a".to_owned(), ChangeKind::Created, "foobar".to_owned()),
+            ("team-a".to_owned(), ChangeKind::Updated, "barfoo".to_owned()),
+            ("team-a".to_owned(), ChangeKind::Deleted, "barfoo".to_owned()),
+        ]);
+
+        // Imports are announced too, as a create or an update of each scan.
+        let mut source = Store::new();
+        source.insert_record_in("team-a", record.clone())?;
+        source.insert_record_in("team-a", Scan{ ip: "2.2.2.2".to_owned(), ..record.clone() })?;
+        store.insert_record_in("team-a", Scan{ content_hash: "foobar".to_owned(), ..record.clone() })?;
+        let _ = changes.try_recv();
+
+        assert_eq!(store.import_snapshot(&source.export_snapshot(SnapshotFormat::Json))?, 2);
+        let mut seen: Vec<(String, ChangeKind, String)> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| (change.tenant, change.kind, change.scan.ip))
+            .collect();
+        seen.sort_by(|a, b| a.2.cmp(&b.2));
+        assert_eq!(seen, vec![
+            ("team-a".to_owned(), ChangeKind::Updated, "1.2.3.4".to_owned()),
+            ("team-a".to_owned(), ChangeKind::Created, "2.2.2.2".to_owned()),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_snapshot_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut store = Store::new();
+        let record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+        store.insert_record(record.clone())?;
+        store.insert_record_in("team-a", Scan{ ip: "8.8.8.8".to_owned(), ..record.clone() })?;
+        store.update_record(Scan{ content_hash: "barfoo".to_owned(), ..record.clone() })?;
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary, SnapshotFormat::Archive] {
+            let bytes = store.export_snapshot(format);
+
+            let mut restored = Store::new();
+            restored.insert_record(Scan{ ip: "9.9.9.9".to_owned(), ..record.clone() })?;
+            assert_eq!(restored.import_snapshot(&bytes)?, 2);
+
+            assert_eq!(restored.len(), 3);
+            assert_eq!(restored.get_record("1.2.3.4", 80).unwrap().content_hash, "barfoo");
+            assert!(restored.get_record_in("team-a", "8.8.8.8", 80).is_some());
+            assert!(restored.get_history_in(DEFAULT_TENANT, "1.2.3.4", 80).is_empty());
+        }
+
+        let mut snapshot = store.snapshot();
+        assert_eq!(snapshot.seq, 0);
+        snapshot.tenants.insert("Not A Tenant".to_owned(), Vec::new());
+        let err = Store::new().import_snapshot(&snapshot.encode(SnapshotFormat::Json)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Imports are held to quotas like inserts, and one past them changes
+        // nothing.
+        let mut limited = Store::new().with_quotas("team-a=1,default=1".parse().unwrap());
+        limited.insert_record_in("team-a", Scan{ ip: "9.9.9.9".to_owned(), ..record.clone() })?;
+        let err = limited.import_snapshot(&store.export_snapshot(SnapshotFormat::Json)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("past its quota of 1"), "{}", err);
+        assert_eq!(limited.len(), 1);
+
+        let mut limited = Store::new().with_quotas("team-a=1,default=1".parse().unwrap());
+        assert_eq!(limited.import_snapshot(&store.export_snapshot(SnapshotFormat::Json))?, 2);
+        assert_eq!(limited.import_snapshot(&store.export_snapshot(SnapshotFormat::Binary))?, 2);
+        let mut limited = Store::new().with_quotas("team-a=0".parse().unwrap());
+        let err = limited.attach_archive(Archive::from_bytes(store.export_snapshot(SnapshotFormat::Archive))?).unwrap_err();
+        assert!(err.to_string().contains("past its quota of 0"), "{}", err);
+        assert!(!limited.is_warming());
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_serves_archives_while_warming() -> Result<(), Box<dyn Error>> {
+        let record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc.with_ymd_and_hms(2022, 7, 31, 14, 10, 0).unwrap(),
+            ..Default::default()
+        };
+        let mut source = Store::new();
+        for ip in ["1.2.3.4", "2.2.2.2", "3.3.3.3", "4.4.4.4"] {
+            source.insert_record(Scan{ ip: ip.to_owned(), ..record.clone() })?;
+        }
+        source.insert_record_in("team-a", record.clone())?;
+        let archive = || Archive::from_bytes(source.export_snapshot(SnapshotFormat::Archive));
+
+        let mut store = Store::new().with_quotas("default=4".parse().unwrap());
+        assert_eq!(store.attach_archive(archive()?)?, 5);
+        assert!(store.is_warming());
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.get_record("2.2.2.2", 80).unwrap().content_hash, "foobar");
+        assert_eq!(store.get_all().len(), 4);
+        assert!(store.health().detail.unwrap().contains("warming: 5 of 5"));
+
+        // Writes take cold scans over; a deleted one doesn't come back.
+        store.update_record(Scan{ ip: "3.3.3.3".to_owned(), content_hash: "barfoo".to_owned(), ..record.clone() })?;
+        store.delete_record("4.4.4.4", 80)?;
+        assert_eq!(store.insert_record(record.clone()), Err("record already exists"));
+        store.insert_record(Scan{ ip: "5.5.5.5".to_owned(), ..record.clone() })?;
+        assert_eq!(store.insert_record(Scan{ ip: "6.6.6.6".to_owned(), ..record.clone() }), Err(QUOTA_EXCEEDED));
+        assert_eq!(store.get_all().len(), 4);
+        assert_eq!(store.snapshot().len(), 5);
+
+        assert_eq!(store.warm(1)?, 1);
+        assert_eq!(store.warm(10)?, 0);
+        assert!(!store.is_warming());
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.get_record("3.3.3.3", 80).unwrap().content_hash, "barfoo");
+        assert!(store.get_record("4.4.4.4", 80).is_none());
+        assert!(store.get_record_in("team-a", "1.2.3.4", 80).is_some());
+        assert_eq!(store.get_history_in(DEFAULT_TENANT, "3.3.3.3", 80).len(), 1);
+
+        assert_eq!(store.attach_archive(archive()?).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn store_logs_imported_snapshots() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir()
+            .join(format!("store-snapshot-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut source = Store::new();
+        source.insert_record(Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        })?;
+
+        let mut store = Store::open(&path)?;
+        store.import_snapshot(&source.export_snapshot(SnapshotFormat::Binary))?;
+        assert_eq!(store.snapshot().seq, 1);
+
+        let store = Store::open(&path)?;
+        assert_eq!(store.get_record("1.2.3.4", 80).unwrap().content_hash, "foobar");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn store_logs_warmed_scans() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir()
+            .join(format!("store-warm-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut source = Store::new();
+        for ip in ["1.2.3.4", "8.8.8.8"] {
+            source.insert_record(Scan{
+                ip: ip.to_owned(),
+                port: 80,
+                load_time_nanosec: 18,
+                content_hash: "foobar".to_owned(),
+                timestamp: Utc::now(),
+                ..Default::default()
+            })?;
+        }
+
+        let mut store = Store::open(&path)?;
+        store.attach_archive(Archive::from_bytes(source.export_snapshot(SnapshotFormat::Archive))?)?;
+        store.delete_record("8.8.8.8", 80)?;
+        assert_eq!(store.warm(usize::MAX)?, 0);
+        assert_eq!(store.snapshot().seq, 3);
+
+        let store = Store::open(&path)?;
+        assert!(store.get_record("1.2.3.4", 80).is_some());
+        assert!(store.get_record("8.8.8.8", 80).is_none());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn store_compacts_its_log() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir()
+            .join(format!("store-compact-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = Store::open(&path)?;
+        let record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+        store.insert_record(record.clone())?;
+        store.update_record(Scan{ content_hash: "barfoo".to_owned(), ..record.clone() })?;
+        assert!(store.compaction_due().is_none());
+        store.insert_record(Scan{ ip: "8.8.8.8".to_owned(), ..record.clone() })?;
+        store.delete_record("8.8.8.8", 80)?;
+        assert_eq!(store.compaction_due(), Some(path.clone()));
+
+        assert_eq!(store.compact(u64::MAX)?.after, 1);
+        assert!(store.compaction_due().is_none());
+        store.insert_record_in("team-a", record.clone())?;
+
+        let store = Store::open(&path)?;
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get_record("1.2.3.4", 80).unwrap().content_hash, "barfoo");
+        assert_eq!(store.snapshot().seq, 5);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn store_replays_log() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir()
+            .join(format!("store-replay-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = Store::open(&path)?;
+
+        let mut record = Scan{
+            ip: "1.2.3.4".to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        };
+
+        store.insert_record(record.clone())?;
+        record.ip = "8.8.8.8".to_owned();
+        store.insert_record(record.clone())?;
+        record.content_hash = "barfoo".to_owned();
+        store.insert_record_in("team-a", record.clone())?;
+        store.update_record(record)?;
+        store.delete_record("1.2.3.4", 80)?;
+
+        let store = Store::open(&path)?;
+
+        assert!(store.get_record("1.2.3.4", 80).is_none());
+        assert_eq!(store.get_record_in("team-a", "8.8.8.8", 80).unwrap().content_hash, "barfoo");
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get_record("8.8.8.8", 80).unwrap().content_hash, "barfoo");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
 }
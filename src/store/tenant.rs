@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Where scans land when a request doesn't name a tenant, i.e. everything
+// under `/v1/scans`.
+pub const DEFAULT_TENANT: &str = "default";
+
+// Tenant names end up in URLs, log lines and the mutation log, so keep them
+// to short lowercase slugs.
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+// Maximum number of scans each tenant may hold, e.g. `team-a=500,*=1000`
+// where `*` covers every tenant not named.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Quotas {
+    default: Option<usize>,
+    per_tenant: HashMap<String, usize>,
+}
+
+impl Quotas {
+    pub fn limit_for(&self, tenant: &str) -> Option<usize> {
+        self.per_tenant.get(tenant).copied().or(self.default)
+    }
+}
+
+impl FromStr for Quotas {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quotas = Quotas::default();
+
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (tenant, limit) = pair.split_once('=').ok_or(())?;
+            let (tenant, limit) = (tenant.trim(), limit.trim().parse().map_err(|_| ())?);
+
+            match tenant {
+                "*" => quotas.default = Some(limit),
+                tenant if is_valid(tenant) => {
+                    quotas.per_tenant.insert(tenant.to_owned(), limit);
+                },
+                _ => return Err(()),
+            }
+        }
+
+        Ok(quotas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_names() {
+        assert!(is_valid("team-a"));
+        assert!(is_valid(DEFAULT_TENANT));
+        assert!(!is_valid(""));
+        assert!(!is_valid("Team A"));
+        assert!(!is_valid("../etc"));
+    }
+
+    #[test]
+    fn parse_quotas() {
+        let quotas: Quotas = "team-a=5, *=100".parse().unwrap();
+
+        assert_eq!(quotas.limit_for("team-a"), Some(5));
+        assert_eq!(quotas.limit_for("team-b"), Some(100));
+        assert_eq!(Quotas::default().limit_for("team-a"), None);
+        assert!("team-a".parse::<Quotas>().is_err());
+        assert!("Team=5".parse::<Quotas>().is_err());
+    }
+}
@@ -0,0 +1,675 @@
+use crate::model::{version, Scan};
+use crate::store::tenant::DEFAULT_TENANT;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub enum Mutation {
+    Insert(Scan),
+    Update(Scan),
+    Delete { ip: String, port: i16 },
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct Entry {
+    // The scan schema version the entry was written at. Older entries are
+    // migrated as they are read, but keep the version they were written at.
+    pub version: u32,
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    // Logs written before tenants existed hold only default tenant scans.
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub mutation: Mutation,
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_owned()
+}
+
+// What an offline migration did to a log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Migrated {
+    pub entries: usize,
+    pub upgraded: usize,
+}
+
+// Where compaction left off: every entry up to `seq`, logged `at`, has been
+// folded into the live records at the start of the log.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Horizon {
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+}
+
+// The first line of a log. A log is given a random id when it is created,
+// which its snapshots record so recovery can't pair them with another log
+// whose sequence numbers happen to line up; logs from before ids have no
+// header. A compacted log's header also says how far it was compacted, and
+// its checksum covers the `entries` lines after it, which are written before
+// the log is renamed into place, so any damage to them is refused rather
+// than replayed.
+#[derive(Serialize, Deserialize, Default)]
+struct Header {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    log_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compacted: Option<Horizon>,
+    #[serde(default)]
+    entries: usize,
+    #[serde(default)]
+    checksum: u32,
+}
+
+fn is_header(line: &str) -> bool {
+    line.starts_with("{\"log_id\"") || line.starts_with("{\"compacted\"")
+}
+
+// What a compaction did to a log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compaction {
+    pub before: usize,
+    pub after: usize,
+    pub through: u64,
+}
+
+// A compacted log written beside the live one, waiting for the entries
+// appended since it was read.
+pub struct Prepared {
+    temp: PathBuf,
+    read_len: u64,
+    read: usize,
+    kept: usize,
+    through: u64,
+}
+
+// A log as read from disk.
+struct Scanned {
+    log_id: Option<u32>,
+    entries: Vec<Entry>,
+    horizon: Option<Horizon>,
+    valid_len: u64,
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Reads one logged entry, bringing the scan it carries up to the current
+// schema version.
+fn decode(line: &str) -> io::Result<Entry> {
+    let mut value: Value = serde_json::from_str(line).map_err(invalid_data)?;
+    let written = version::version_of(&value).map_err(invalid_data)?;
+
+    for kind in ["Insert", "Update"] {
+        if let Some(scan) = value.pointer_mut(&format!("/mutation/{}", kind)) {
+            version::migrate_scan(scan, written).map_err(invalid_data)?;
+        }
+    }
+    value["version"] = written.into();
+
+    serde_json::from_value(value).map_err(invalid_data)
+}
+
+// Append-only log of every mutation applied to a `Store`, one JSON entry per
+// line. Replaying it from the start rebuilds the store.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    id: Option<u32>,
+    next_seq: u64,
+    entries: usize,
+    poisoned: bool,
+}
+
+impl Wal {
+    pub fn open(path: &Path) -> io::Result<(Wal, Vec<Entry>)> {
+        let Scanned { log_id, entries, horizon, valid_len } = if path.exists() {
+            Wal::scan_entries(path)?
+        } else {
+            Scanned { log_id: None, entries: Vec::new(), horizon: None, valid_len: 0 }
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        // Cut off any torn tail so new entries start on a clean line.
+        if file.metadata()?.len() > valid_len {
+            file.set_len(valid_len)?;
+        }
+
+        // A new log is given its id before anything is logged to it.
+        let id = match valid_len {
+            0 => {
+                let id = rand::random::<u32>().max(1);
+                let mut line = serde_json::to_vec(&Header { log_id: Some(id), ..Header::default() })?;
+                line.push(b'\n');
+                file.write_all(&line)?;
+                file.sync_data()?;
+                sync_dir(path)?;
+                Some(id)
+            },
+            _ => log_id,
+        };
+
+        // Compaction may have dropped the last entries, but their sequence
+        // numbers stay used.
+        let last = entries.last().map(|e| e.seq).unwrap_or(0);
+        let next_seq = last.max(horizon.map(|h| h.seq).unwrap_or(0)) + 1;
+
+        let wal = Wal {
+            path: path.to_owned(),
+            file,
+            id,
+            next_seq,
+            entries: entries.len(),
+            poisoned: false,
+        };
+
+        Ok((wal, entries))
+    }
+
+    // Every whole entry of the log at `path`, and how far it was compacted,
+    // without opening it for writing or cutting off a torn tail.
+    pub fn read(path: &Path) -> io::Result<(Vec<Entry>, Option<Horizon>)> {
+        let Scanned { entries, horizon, .. } = Wal::scan_entries(path)?;
+        Ok((entries, horizon))
+    }
+
+    // The id the log at `path` was given when it was created, if it has one.
+    pub fn id_of(path: &Path) -> io::Result<Option<u32>> {
+        let mut line = String::new();
+        BufReader::new(File::open(path)?).read_line(&mut line)?;
+        match is_header(&line) && line.ends_with('\n') {
+            true => Ok(serde_json::from_str::<Header>(&line).map_err(invalid_data)?.log_id),
+            false => Ok(None),
+        }
+    }
+
+    fn scan_entries(path: &Path) -> io::Result<Scanned> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        let mut valid_len = 0;
+        let mut line = String::new();
+        // The header of the log, and the checksum of its compacted lines so
+        // far.
+        let mut compacted: Option<(Header, crc32fast::Hasher)> = None;
+
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+
+            // A torn final line means we crashed mid-append; the mutation
+            // was never acknowledged so it is safe to drop.
+            if !line.ends_with('\n') {
+                break;
+            }
+
+            if valid_len == 0 && is_header(&line) {
+                let header: Header = serde_json::from_str(&line).map_err(invalid_data)?;
+                compacted = Some((header, crc32fast::Hasher::new()));
+            } else if !line.trim().is_empty() {
+                if let Some((_, checksum)) = compacted.as_mut().filter(|(header, _)| entries.len() < header.entries) {
+                    checksum.update(line.as_bytes());
+                }
+                entries.push(decode(&line)?);
+            }
+
+            valid_len += read as u64;
+        }
+
+        let (log_id, horizon) = match compacted {
+            None => (None, None),
+            Some((header, checksum)) => match entries.len() >= header.entries && checksum.finalize() == header.checksum {
+                true => (header.log_id, header.compacted),
+                false => return Err(invalid_data("the compacted entries of the log are damaged")),
+            },
+        };
+
+        Ok(Scanned { log_id, entries, horizon, valid_len })
+    }
+
+    pub fn append(&mut self, tenant: &str, mutation: &Mutation) -> io::Result<u64> {
+        // After a failed write the tail of the file is unknown, so refuse to
+        // append anything that could land after a torn entry.
+        if self.poisoned {
+            return Err(io::Error::other("log is poisoned by an earlier write error"));
+        }
+
+        let entry = Entry {
+            version: version::SCAN_VERSION,
+            seq: self.next_seq,
+            at: Utc::now(),
+            tenant: tenant.to_owned(),
+            mutation: mutation.clone(),
+        };
+
+        // The entry is synced before the mutation is applied and
+        // acknowledged, so an acknowledged write survives a power loss. A
+        // failed sync leaves the tail as unknown as a failed write.
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line).and_then(|_| self.file.sync_data()) {
+            self.poisoned = true;
+            return Err(e);
+        }
+
+        self.next_seq += 1;
+        self.entries += 1;
+        Ok(entry.seq)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.id
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // How many entries the log holds, live or superseded.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    // Writes the log at `path` beside it, with the entries up to `through`
+    // folded down to the last insert or update of every scan still live
+    // after them. Later entries are kept as they are, so the log can still be
+    // recovered to any point after `through`. Entries keep their sequence
+    // numbers, so point-in-time recovery can tell what is missing. Nothing is
+    // locked while the log is read and written; entries appended meanwhile
+    // are picked up by `finish_compaction`.
+    pub fn prepare_compaction(path: &Path, through: u64) -> io::Result<Prepared> {
+        let Scanned { log_id, entries, horizon, valid_len } = Wal::scan_entries(path)?;
+        let read = entries.len();
+        let last = entries.iter()
+            .rev()
+            .find(|entry| entry.seq <= through)
+            .filter(|last| horizon.is_none_or(|h| last.seq > h.seq));
+        let horizon = last.map(|last| Horizon { seq: last.seq, at: last.at }).or(horizon);
+        let (folded, after): (Vec<Entry>, Vec<Entry>) = entries.into_iter()
+            .partition(|entry| horizon.is_some_and(|h| entry.seq <= h.seq));
+
+        let mut live = HashMap::new();
+        for entry in folded {
+            let key = match &entry.mutation {
+                Mutation::Insert(scan) | Mutation::Update(scan) => (entry.tenant.clone(), scan.ip.clone(), scan.port),
+                Mutation::Delete { ip, port } => {
+                    live.remove(&(entry.tenant.clone(), ip.clone(), *port));
+                    continue;
+                },
+            };
+            live.insert(key, entry);
+        }
+        // Every entry was migrated as it was read, so it is written back at
+        // the current version.
+        let mut kept: Vec<Entry> = live.into_values()
+            .chain(after)
+            .map(|entry| Entry { version: version::SCAN_VERSION, ..entry })
+            .collect();
+        kept.sort_by_key(|entry| entry.seq);
+
+        let temp = sibling(path, "compacting");
+        write_log(&temp, log_id, horizon, &kept)?;
+
+        // Read back what was written before it can replace the log.
+        let written = Wal::scan_entries(&temp)?;
+        if written.entries.len() != kept.len() || written.horizon != horizon || written.log_id != log_id {
+            return Err(invalid_data("the compacted log does not read back as written"));
+        }
+
+        Ok(Prepared {
+            temp,
+            read_len: valid_len,
+            read,
+            kept: kept.len(),
+            through: horizon.map(|h| h.seq).unwrap_or(0),
+        })
+    }
+
+    // Appends what was logged since `prepared` read the log to it and renames
+    // it over the log. Holding the log while this runs keeps writers out for
+    // only as long as that takes. A crash leaves either log whole.
+    pub fn finish_compaction(&mut self, prepared: Prepared) -> io::Result<Compaction> {
+        let result = self.swap_in(&prepared);
+        if result.is_err() {
+            let _ = fs::remove_file(&prepared.temp);
+        }
+        result
+    }
+
+    fn swap_in(&mut self, prepared: &Prepared) -> io::Result<Compaction> {
+        if self.poisoned {
+            return Err(io::Error::other("log is poisoned by an earlier write error"));
+        }
+
+        let mut log = File::open(&self.path)?;
+        if log.metadata()?.len() < prepared.read_len {
+            return Err(invalid_data("the log shrank while it was being compacted"));
+        }
+        let mut tail = Vec::new();
+        log.seek(SeekFrom::Start(prepared.read_len))?;
+        log.read_to_end(&mut tail)?;
+        let appended = tail.iter().filter(|b| **b == b'\n').count();
+
+        let mut temp = OpenOptions::new().append(true).open(&prepared.temp)?;
+        temp.write_all(&tail)?;
+        temp.sync_all()?;
+        fs::rename(&prepared.temp, &self.path)?;
+        sync_dir(&self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries = prepared.kept + appended;
+
+        Ok(Compaction {
+            before: prepared.read + appended,
+            after: self.entries,
+            through: prepared.through,
+        })
+    }
+
+    // Compacts the log at `path` through `through` in one go. The log must
+    // not be open elsewhere.
+    pub fn compact(path: &Path, through: u64) -> io::Result<Compaction> {
+        let (mut wal, _) = Wal::open(path)?;
+        let prepared = Wal::prepare_compaction(path, through)?;
+        wal.finish_compaction(prepared)
+    }
+
+    // Rewrites every entry of the log at `path` at the current schema
+    // version, so it no longer needs migrating on load. The log must not be
+    // open elsewhere. The new log is written beside the old one and renamed
+    // over it, so a crash leaves one or the other whole.
+    pub fn migrate(path: &Path) -> io::Result<Migrated> {
+        let Scanned { log_id, entries, horizon, .. } = Wal::scan_entries(path)?;
+        let upgraded = entries.iter().filter(|e| e.version < version::SCAN_VERSION).count();
+        let migrated = Migrated { entries: entries.len(), upgraded };
+        if upgraded == 0 {
+            return Ok(migrated);
+        }
+
+        let entries: Vec<Entry> = entries.into_iter()
+            .map(|entry| Entry { version: version::SCAN_VERSION, ..entry })
+            .collect();
+        let temp = sibling(path, "migrating");
+        let renamed = write_log(&temp, log_id, horizon, &entries).and_then(|_| fs::rename(&temp, path));
+        if renamed.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        renamed?;
+        sync_dir(path)?;
+
+        Ok(migrated)
+    }
+}
+
+// Writes `entries` to a new log at `path` and syncs it. Those up to the
+// horizon are the compacted ones, covered by the header's checksum.
+fn write_log(path: &Path, log_id: Option<u32>, horizon: Option<Horizon>, entries: &[Entry]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    if log_id.is_some() || horizon.is_some() {
+        let mut compacted = Vec::new();
+        let mut count = 0;
+        if let Some(horizon) = horizon {
+            for entry in entries.iter().take_while(|entry| entry.seq <= horizon.seq) {
+                serde_json::to_writer(&mut compacted, entry)?;
+                compacted.push(b'\n');
+                count += 1;
+            }
+        }
+
+        let header = Header { log_id, compacted: horizon, entries: count, checksum: crc32fast::hash(&compacted) };
+        serde_json::to_writer(&mut out, &header)?;
+        out.write_all(b"\n")?;
+        out.write_all(&compacted)?;
+    }
+
+    for entry in entries.iter().filter(|entry| horizon.is_none_or(|h| entry.seq > h.seq)) {
+        serde_json::to_writer(&mut out, entry)?;
+        out.write_all(b"\n")?;
+    }
+
+    out.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()
+}
+
+// Makes a rename into the directory holding `path` durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+// A file next to `path` with `suffix` added to its name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::path::PathBuf;
+
+    fn scan(ip: &str) -> Scan {
+        Scan{
+            ip: ip.to_owned(),
+            port: 80,
+            load_time_nanosec: 18,
+            content_hash: "foobar".to_owned(),
+            timestamp: Utc::now(),
+            ..Default::default()
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("wal-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn wal_round_trip() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("round-trip");
+
+        let (mut wal, entries) = Wal::open(&path)?;
+        assert!(entries.is_empty());
+
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("1.2.3.4")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Delete{ ip: "1.2.3.4".to_owned(), port: 80 })?;
+
+        let (mut wal, entries) = Wal::open(&path)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].seq, 1);
+        assert_eq!(entries[1].seq, 2);
+
+        assert_eq!(wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("8.8.8.8")))?, 3);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn wal_ignores_torn_tail() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("torn-tail");
+
+        let (mut wal, _) = Wal::open(&path)?;
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("1.2.3.4")))?;
+        wal.file.write_all(b"{\"seq\":2,\"at\"")?;
+
+        assert_eq!(Wal::read(&path)?.0.len(), 1);
+
+        let (mut wal, _) = Wal::open(&path)?;
+        assert_eq!(wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("8.8.8.8")))?, 2);
+        assert_eq!(Wal::read(&path)?.0.len(), 2);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn wal_compacts_to_live_entries() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("compact");
+        let delete = |ip: &str| Mutation::Delete{ ip: ip.to_owned(), port: 80 };
+
+        let (mut wal, _) = Wal::open(&path)?;
+        let id = wal.id();
+        assert!(id.is_some());
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("1.2.3.4")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("8.8.8.8")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Update(scan("1.2.3.4")))?;
+        wal.append(DEFAULT_TENANT, &delete("8.8.8.8"))?;
+        wal.append("team-a", &Mutation::Insert(scan("8.8.8.8")))?;
+        wal.append("team-a", &delete("8.8.8.8"))?;
+
+        // Entries appended while the compacted log is written are kept.
+        let prepared = Wal::prepare_compaction(&path, u64::MAX)?;
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("9.9.9.9")))?;
+        assert_eq!(wal.finish_compaction(prepared)?, Compaction { before: 7, after: 2, through: 6 });
+        assert_eq!(wal.entries(), 2);
+        assert!(!sibling(&path, "compacting").exists());
+        assert_eq!(wal.append(DEFAULT_TENANT, &delete("9.9.9.9"))?, 8);
+        drop(wal);
+
+        let (entries, horizon) = Wal::read(&path)?;
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [3, 7, 8]);
+        assert_eq!(horizon.map(|h| h.seq), Some(6));
+
+        // Dropped entries keep their sequence numbers used.
+        assert_eq!(Wal::compact(&path, u64::MAX)?, Compaction { before: 3, after: 1, through: 8 });
+        let (mut wal, entries) = Wal::open(&path)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!((wal.id(), Wal::id_of(&path)?), (id, id));
+        assert_eq!(wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("8.8.8.8")))?, 9);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_stops_at_through() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("compact-through");
+
+        let (mut wal, _) = Wal::open(&path)?;
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("1.2.3.4")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Update(scan("1.2.3.4")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("8.8.8.8")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Delete{ ip: "8.8.8.8".to_owned(), port: 80 })?;
+        wal.append(DEFAULT_TENANT, &Mutation::Update(scan("1.2.3.4")))?;
+        drop(wal);
+
+        // Entries after `through` are kept even where they supersede.
+        assert_eq!(Wal::compact(&path, 3)?, Compaction { before: 5, after: 4, through: 3 });
+        let (entries, horizon) = Wal::read(&path)?;
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [2, 3, 4, 5]);
+        assert_eq!(horizon.map(|h| h.seq), Some(3));
+
+        assert_eq!(Wal::compact(&path, 1)?, Compaction { before: 4, after: 4, through: 3 });
+        assert_eq!(Wal::compact(&path, u64::MAX)?, Compaction { before: 4, after: 1, through: 5 });
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_compacted_logs_are_refused() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("compact-damaged");
+
+        let (mut wal, _) = Wal::open(&path)?;
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("1.2.3.4")))?;
+        wal.append(DEFAULT_TENANT, &Mutation::Update(scan("1.2.3.4")))?;
+        drop(wal);
+        Wal::compact(&path, u64::MAX)?;
+
+        let compacted = std::fs::read_to_string(&path)?;
+        std::fs::write(&path, compacted.replace("foobar", "foobaz"))?;
+        let err = Wal::open(&path).err().unwrap();
+        assert!(err.to_string().contains("damaged"), "{}", err);
+
+        let header = compacted.lines().next().unwrap();
+        std::fs::write(&path, format!("{}\n", header))?;
+        assert!(Wal::open(&path).is_err());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn wal_reads_entries_without_tenant() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("no-tenant");
+        std::fs::write(
+            &path,
+            "{\"seq\":1,\"at\":\"2021-09-20T17:10:00Z\",\"mutation\":{\"Delete\":{\"ip\":\"1.2.3.4\",\"port\":80}}}\n",
+        )?;
+
+        let (_, entries) = Wal::open(&path)?;
+        assert_eq!(entries[0].tenant, DEFAULT_TENANT);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn old_entries_are_migrated_on_load_and_offline() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("migrate");
+        let v1 = "{\"seq\":1,\"at\":\"2021-09-20T17:10:00Z\",\"mutation\":{\"Insert\":{\"ip\":\"1.2.3.4\",\"port\":80,\
+                  \"load_time_nanosec\":18,\"content_hash\":\"foobar\",\"timestamp\":\"2021-09-20T17:10:00Z\"}}}\n";
+        std::fs::write(&path, v1)?;
+
+        let (mut wal, entries) = Wal::open(&path)?;
+        assert_eq!((entries[0].version, wal.id()), (1, None));
+        wal.append(DEFAULT_TENANT, &Mutation::Insert(scan("8.8.8.8")))?;
+        drop(wal);
+
+        assert_eq!(Wal::migrate(&path)?, Migrated { entries: 2, upgraded: 1 });
+        let (entries, _) = Wal::read(&path)?;
+        assert!(entries.iter().all(|e| e.version == version::SCAN_VERSION));
+        assert!(matches!(&entries[0].mutation, Mutation::Insert(scan) if scan.ip == "1.2.3.4"));
+        assert_eq!(Wal::migrate(&path)?, Migrated { entries: 2, upgraded: 0 });
+        assert!(!sibling(&path, "migrating").exists());
+        assert_eq!(Wal::id_of(&path)?, None);
+
+        // Compaction writes entries back migrated, so they are stamped with
+        // the version they now have.
+        std::fs::write(&path, v1)?;
+        Wal::compact(&path, u64::MAX)?;
+        let (entries, _) = Wal::read(&path)?;
+        assert_eq!(entries[0].version, version::SCAN_VERSION);
+        assert!(matches!(&entries[0].mutation, Mutation::Insert(scan) if scan.ip == "1.2.3.4"));
+
+        std::fs::write(&path, format!("{{\"version\":{},\"seq\":1}}\n", version::SCAN_VERSION + 1))?;
+        let err = Wal::open(&path).err().unwrap();
+        assert!(err.to_string().contains("only reads up to"), "{}", err);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+mod trace_context;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
+pub use trace_context::{new_span_id, new_trace_id, TraceParent, TRACEPARENT_HEADER};
+
+use crate::config::{Config, LogFormat};
+use std::io;
+use std::time::Duration;
+use tracing::Span;
+use tracing::field::Empty;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Keeps the exporter alive; dropping it flushes any spans still buffered.
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    _exporter: Option<otlp::Exporter>,
+}
+
+// Installs the global subscriber: human readable or JSON logs filtered by
+// `RUST_LOG` (default `info`), plus an OTLP exporter when one is configured.
+pub fn init(service: &'static str, config: &Config) -> io::Result<Telemetry> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let (text, json) = match config.log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json().flatten_event(true))),
+    };
+
+    #[cfg(feature = "otlp")]
+    let (otlp_layer, exporter) = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, exporter) = otlp::layer(service, endpoint);
+            (Some(layer), Some(exporter))
+        },
+        None => (None, None),
+    };
+
+    #[cfg(not(feature = "otlp"))]
+    if config.otlp_endpoint.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SCANS_OTLP_ENDPOINT is set but the `otlp` feature is not enabled",
+        ));
+    }
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json);
+
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(otlp_layer);
+
+    registry.try_init().map_err(io::Error::other)?;
+
+    tracing::info!(service, "telemetry initialised");
+
+    Ok(Telemetry {
+        #[cfg(feature = "otlp")]
+        _exporter: exporter,
+    })
+}
+
+// Identifies a single request: the caller's `X-Request-Id` (or a fresh one)
+// and its position in a distributed trace.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub flags: u8,
+}
+
+impl RequestContext {
+    pub fn from_headers(request_id: Option<&str>, traceparent: Option<&str>) -> Self {
+        let request_id = request_id
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_owned)
+            .unwrap_or_else(new_trace_id);
+
+        match traceparent.and_then(TraceParent::parse) {
+            Some(parent) => RequestContext {
+                request_id,
+                trace_id: parent.trace_id,
+                span_id: new_span_id(),
+                parent_span_id: Some(parent.parent_id),
+                flags: parent.flags,
+            },
+            None => RequestContext {
+                request_id,
+                trace_id: new_trace_id(),
+                span_id: new_span_id(),
+                parent_span_id: None,
+                flags: 1,
+            },
+        }
+    }
+
+    // The `traceparent` to hand on to anything downstream of this request.
+    pub fn traceparent(&self) -> String {
+        TraceParent {
+            trace_id: self.trace_id.clone(),
+            parent_id: self.span_id.clone(),
+            flags: self.flags,
+        }.to_header()
+    }
+
+    // Headers every response carries so callers can correlate their logs.
+    pub fn response_headers(&self) -> [(&'static str, String); 2] {
+        [
+            (REQUEST_ID_HEADER, self.request_id.clone()),
+            (TRACEPARENT_HEADER, self.traceparent()),
+        ]
+    }
+
+    pub fn record(&self, span: &Span) {
+        span.record("request_id", self.request_id.as_str());
+        span.record("trace_id", self.trace_id.as_str());
+        span.record("span_id", self.span_id.as_str());
+        if let Some(parent) = &self.parent_span_id {
+            span.record("parent_span_id", parent.as_str());
+        }
+    }
+}
+
+// Request ids are echoed back in a header and written to logs, so only accept
+// short printable tokens from callers.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+// The root span for a request. Identifiers are left empty so that frameworks
+// which create the span before the headers are parsed can fill them in later
+// with `RequestContext::record`.
+pub fn request_span(method: &str, path: &str) -> Span {
+    tracing::info_span!(
+        "request",
+        %method,
+        %path,
+        request_id = Empty,
+        trace_id = Empty,
+        span_id = Empty,
+        parent_span_id = Empty,
+        status = Empty,
+    )
+}
+
+pub fn finish(span: &Span, status: u16, elapsed: Duration) {
+    span.record("status", status);
+    span.in_scope(|| {
+        tracing::info!(status, elapsed_ms = elapsed.as_secs_f64() * 1000.0, "request completed");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_context_continues_incoming_trace() {
+        let ctx = RequestContext::from_headers(
+            Some("abc-123"),
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+
+        assert_eq!(ctx.request_id, "abc-123");
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_ne!(ctx.span_id, "00f067aa0ba902b7");
+
+        let next = TraceParent::parse(&ctx.traceparent()).unwrap();
+        assert_eq!(next.trace_id, ctx.trace_id);
+        assert_eq!(next.parent_id, ctx.span_id);
+    }
+
+    #[test]
+    fn request_context_starts_new_trace() {
+        let ctx = RequestContext::from_headers(Some("bad id\n"), Some("nonsense"));
+
+        assert_ne!(ctx.request_id, "bad id\n");
+        assert_eq!(ctx.request_id.len(), 32);
+        assert!(ctx.parent_span_id.is_none());
+        assert!(TraceParent::parse(&ctx.traceparent()).is_some());
+    }
+}
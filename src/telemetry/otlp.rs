@@ -0,0 +1,340 @@
+use super::{new_span_id, new_trace_id};
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::Subscriber;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+const BATCH_SIZE: usize = 512;
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// OTLP span kinds.
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+enum Message {
+    Span(Value),
+    Shutdown,
+}
+
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: u64,
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    trace_id: Option<String>,
+    span_id: Option<String>,
+    parent_span_id: Option<String>,
+    attributes: Vec<(String, String)>,
+}
+
+impl FieldVisitor {
+    fn apply(self, data: &mut SpanData) {
+        if let Some(trace_id) = self.trace_id {
+            data.trace_id = trace_id;
+        }
+        if let Some(span_id) = self.span_id {
+            data.span_id = span_id;
+        }
+        if let Some(parent) = self.parent_span_id {
+            data.parent_span_id = Some(parent);
+        }
+        data.attributes.extend(self.attributes);
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "trace_id" => self.trace_id = Some(value.to_owned()),
+            "span_id" => self.span_id = Some(value.to_owned()),
+            "parent_span_id" => self.parent_span_id = Some(value.to_owned()),
+            name => self.attributes.push((name.to_owned(), value.to_owned())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+// Exports closed spans to an OTLP/HTTP collector as JSON. Request spans carry
+// the ids from `RequestContext`; every other span inherits its trace from its
+// parent.
+pub struct OtlpLayer {
+    tx: mpsc::Sender<Message>,
+}
+
+// Owns the export thread; dropping it sends whatever is still buffered.
+pub struct Exporter {
+    tx: mpsc::Sender<Message>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Message::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+pub fn layer(service: &'static str, endpoint: &str) -> (OtlpLayer, Exporter) {
+    let (tx, rx) = mpsc::channel();
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+
+    let handle = thread::Builder::new()
+        .name("otlp-exporter".to_owned())
+        .spawn(move || export_loop(service, &url, rx))
+        .expect("failed to spawn otlp exporter thread");
+
+    let exporter = Exporter { tx: tx.clone(), handle: Some(handle) };
+    (OtlpLayer { tx }, exporter)
+}
+
+fn export_loop(service: &str, url: &str, rx: mpsc::Receiver<Message>) {
+    let client = match reqwest::blocking::Client::builder().timeout(EXPORT_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to build otlp client, spans will not be exported");
+            return;
+        },
+    };
+
+    let mut batch = Vec::new();
+    loop {
+        match rx.recv_timeout(BATCH_INTERVAL) {
+            Ok(Message::Span(span)) => {
+                batch.push(span);
+                if batch.len() >= BATCH_SIZE {
+                    export(&client, service, url, &mut batch);
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => export(&client, service, url, &mut batch),
+            Ok(Message::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                export(&client, service, url, &mut batch);
+                return;
+            },
+        }
+    }
+}
+
+fn export(client: &reqwest::blocking::Client, service: &str, url: &str, batch: &mut Vec<Value>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", service)],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": std::mem::take(batch),
+            }],
+        }],
+    });
+
+    let res = client.post(url).json(&body).send().and_then(|res| res.error_for_status());
+    if let Err(e) = res {
+        tracing::warn!(error = %e, "failed to export spans");
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<SpanData>()
+                .map(|data| (data.trace_id.clone(), data.span_id.clone()))
+        });
+
+        let mut data = match parent {
+            Some((trace_id, parent_span_id)) => SpanData {
+                trace_id,
+                span_id: new_span_id(),
+                parent_span_id: Some(parent_span_id),
+                start: now_nanos(),
+                attributes: Vec::new(),
+            },
+            None => SpanData {
+                trace_id: new_trace_id(),
+                span_id: new_span_id(),
+                parent_span_id: None,
+                start: now_nanos(),
+                attributes: Vec::new(),
+            },
+        };
+
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        visitor.apply(&mut data);
+
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = FieldVisitor::default();
+            values.record(&mut visitor);
+
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                visitor.apply(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let data = match span.extensions_mut().remove::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+
+        let kind = match span.name() {
+            "request" => KIND_SERVER,
+            _ => KIND_INTERNAL,
+        };
+
+        let mut otlp = json!({
+            "traceId": data.trace_id,
+            "spanId": data.span_id,
+            "name": span.name(),
+            "kind": kind,
+            "startTimeUnixNano": data.start.to_string(),
+            "endTimeUnixNano": now_nanos().to_string(),
+            "attributes": data.attributes.iter()
+                .map(|(k, v)| attribute(k, v))
+                .collect::<Vec<_>>(),
+        });
+        if let Some(parent) = data.parent_span_id {
+            otlp["parentSpanId"] = Value::String(parent);
+        }
+
+        let _ = self.tx.send(Message::Span(otlp));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{request_span, RequestContext};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Stands in for a collector: accepts one export and hands back its body.
+    fn collector() -> (String, mpsc::Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            assert!(request_line.starts_with("POST /v1/traces "));
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+
+            tx.send(serde_json::from_slice(&body).unwrap()).unwrap();
+        });
+
+        (endpoint, rx)
+    }
+
+    #[test]
+    fn exports_request_and_child_spans() {
+        let (endpoint, bodies) = collector();
+        let (layer, exporter) = layer("test", &endpoint);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        let ctx = RequestContext::from_headers(
+            None,
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = request_span("GET", "/v1/scans");
+            ctx.record(&span);
+            span.in_scope(|| {
+                tracing::info_span!("get_all").in_scope(|| {});
+            });
+        });
+
+        drop(exporter);
+
+        let body = bodies.recv_timeout(Duration::from_secs(10)).unwrap();
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "test");
+
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+
+        let child = spans.iter().find(|s| s["name"] == "get_all").unwrap();
+        let request = spans.iter().find(|s| s["name"] == "request").unwrap();
+
+        assert_eq!(request["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(request["spanId"], ctx.span_id.as_str());
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(request["kind"], KIND_SERVER);
+
+        assert_eq!(child["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(child["parentSpanId"], ctx.span_id.as_str());
+        assert_eq!(child["kind"], KIND_INTERNAL);
+    }
+}
@@ -0,0 +1,104 @@
+use rand::Rng;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+// A W3C trace context `traceparent` header:
+// `{version}-{trace-id}-{parent-id}-{trace-flags}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub flags: u8,
+}
+
+impl TraceParent {
+    pub fn parse(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 {
+            return None;
+        }
+
+        let (version, trace_id, parent_id, flags) = (parts[0], parts[1], parts[2], parts[3]);
+
+        // Version 00 has exactly four fields; later versions may append more
+        // but must keep the first four, so parse those and ignore the rest.
+        if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.len() != 4) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || is_zero(trace_id) {
+            return None;
+        }
+        if !is_hex(parent_id, 16) || is_zero(parent_id) {
+            return None;
+        }
+        if !is_hex(flags, 2) {
+            return None;
+        }
+
+        Some(TraceParent {
+            trace_id: trace_id.to_owned(),
+            parent_id: parent_id.to_owned(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        })
+    }
+
+    pub fn to_header(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(s: &str) -> bool {
+    s.bytes().all(|b| b == b'0')
+}
+
+pub fn new_trace_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen_range(1..=u128::MAX))
+}
+
+pub fn new_span_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen_range(1..=u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_round_trip() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let parsed = TraceParent::parse(header).unwrap();
+
+        assert_eq!(parsed.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parsed.parent_id, "00f067aa0ba902b7");
+        assert_eq!(parsed.flags, 1);
+        assert_eq!(parsed.to_header(), header);
+    }
+
+    #[test]
+    fn traceparent_rejects_invalid() {
+        // Uppercase hex, zero ids, the forbidden version and extra fields on
+        // version 00 are all invalid.
+        assert!(TraceParent::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01").is_none());
+        assert!(TraceParent::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xx").is_none());
+        assert!(TraceParent::parse("garbage").is_none());
+    }
+
+    #[test]
+    fn traceparent_accepts_future_versions() {
+        let parsed = TraceParent::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra");
+        assert!(parsed.is_some());
+    }
+
+    #[test]
+    fn generated_ids_are_valid() {
+        assert!(is_hex(&new_trace_id(), 32));
+        assert!(is_hex(&new_span_id(), 16));
+    }
+}
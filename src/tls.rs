@@ -0,0 +1,526 @@
+use crate::auth::constant_time_eq;
+use crate::config::Config;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    NoClientAuth, ResolvesServerCert,
+};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// How often the certificate and key files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+// Connections that haven't finished their handshake by then are dropped, so a
+// slow client can't hold one open forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whether clients must present a certificate signed by the client CA, or may
+// connect without one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuth {
+    Required,
+    Optional,
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "required" => Ok(ClientAuth::Required),
+            "optional" => Ok(ClientAuth::Optional),
+            _ => Err(()),
+        }
+    }
+}
+
+// Modification time and length of a file, enough to notice it was rewritten.
+type Stamp = (SystemTime, u64);
+
+// The server's certificate chain and key. Every handshake asks for the
+// current pair, so swapping it here takes effect on the next connection.
+struct Certificates {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    stamp: Mutex<(Stamp, Stamp)>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    fn load(cert_file: &Path, key_file: &Path) -> io::Result<Self> {
+        let stamp = (stamp(cert_file)?, stamp(key_file)?);
+        let current = load_certified_key(cert_file, key_file)?;
+
+        Ok(Certificates {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            stamp: Mutex::new(stamp),
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    // Re-reads the pair if either file changed since it was last read. A pair
+    // that fails to load leaves the previous one in place.
+    fn reload_if_changed(&self) -> io::Result<bool> {
+        let stamp = (stamp(&self.cert_file)?, stamp(&self.key_file)?);
+        {
+            let mut last = self.stamp.lock().unwrap();
+            if *last == stamp {
+                return Ok(false);
+            }
+            *last = stamp;
+        }
+
+        let key = load_certified_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// TLS termination shared by every binary: rustls configured from the
+// `SCANS_TLS_*` settings, with the certificate reloaded when its files change.
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<ServerConfig>,
+}
+
+impl Tls {
+    // Returns `None` when no certificate is configured, i.e. plain HTTP.
+    pub fn from_config(config: &Config) -> io::Result<Option<Self>> {
+        let (cert_file, key_file) = match (&config.tls_cert_file, &config.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+            (None, None) => return Ok(None),
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SCANS_TLS_CERT_FILE and SCANS_TLS_KEY_FILE must be set together",
+            )),
+        };
+
+        let certificates = Arc::new(Certificates::load(cert_file, key_file)?);
+        let verifier = match &config.tls_client_ca_file {
+            Some(path) => {
+                let roots = load_roots(path)?;
+                match config.tls_client_auth {
+                    ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+                    ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+                }
+            },
+            None => NoClientAuth::new(),
+        };
+
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(certificates.clone());
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        watch(Arc::downgrade(&certificates));
+
+        Ok(Some(Tls { config: Arc::new(server_config) }))
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.config.clone()
+    }
+
+    // Listens on `addr` and hands out connections once their handshake is
+    // done. Handshakes run on their own tasks so one slow client doesn't hold
+    // up the others.
+    pub async fn bind(&self, addr: impl ToSocketAddrs) -> io::Result<Incoming> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(self.config.clone());
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            tracing::warn!(error = %err, "failed to accept connection");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        },
+                    },
+                    _ = tx.closed() => break,
+                };
+
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(Connection { stream, peer }).await;
+                        },
+                        Ok(Err(err)) => tracing::debug!(%peer, error = %err, "tls handshake failed"),
+                        Err(_) => tracing::debug!(%peer, "tls handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Incoming { local_addr, connections: rx })
+    }
+}
+
+pub struct Incoming {
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<Connection>,
+}
+
+impl Incoming {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn accept(&mut self) -> Option<Connection> {
+        self.connections.recv().await
+    }
+
+    pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<Connection>> {
+        self.connections.poll_recv(cx)
+    }
+}
+
+// Carries the client's address from a TLS terminator to the plain HTTP
+// backend behind it.
+pub const REAL_IP_HEADER: &str = "x-real-ip";
+// Proves a request came through the terminator. The terminator strips both
+// headers from what the client sent before setting its own.
+pub const TERMINATOR_HEADER: &str = "x-scans-terminator";
+
+// The plain HTTP side of a binary that terminates TLS in front of itself,
+// i.e. rocket. Each run has its own secret, known only to the terminator, so
+// the backend can refuse requests that didn't come through it: a local
+// process connecting to the loopback port directly would otherwise skip TLS
+// and the client certificate check, and pick its own client address.
+// Without TLS there is no terminator, and the address is always the peer's.
+#[derive(Clone)]
+pub struct Backend {
+    secret: Option<String>,
+}
+
+impl Backend {
+    pub fn direct() -> Self {
+        Backend { secret: None }
+    }
+
+    pub fn terminated() -> Self {
+        Backend { secret: Some(format!("{:032x}", rand::random::<u128>())) }
+    }
+
+    pub fn secret(&self) -> Option<&str> {
+        self.secret.as_deref()
+    }
+
+    // Whether a request carrying `secret` may be served.
+    pub fn admits(&self, secret: Option<&str>) -> bool {
+        self.secret.is_none() || self.via_terminator(secret)
+    }
+
+    fn via_terminator(&self, secret: Option<&str>) -> bool {
+        match (&self.secret, secret) {
+            (Some(expected), Some(secret)) => constant_time_eq(expected.as_bytes(), secret.as_bytes()),
+            _ => false,
+        }
+    }
+
+    // The address a request is charged and logged to: the one the
+    // terminator passed on, or the peer's.
+    pub fn client_ip(&self, secret: Option<&str>, real_ip: Option<&str>, peer: Option<IpAddr>) -> Option<IpAddr> {
+        match self.via_terminator(secret) {
+            true => real_ip.and_then(|ip| ip.parse().ok()),
+            false => peer,
+        }
+    }
+}
+
+// A connection that has completed its TLS handshake.
+pub struct Connection {
+    stream: TlsStream<TcpStream>,
+    peer: SocketAddr,
+}
+
+impl Connection {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Polls the certificate files until the resolver is dropped. Polling rather
+// than file events also catches the symlink swaps used by mounted secrets.
+fn watch(certificates: Weak<Certificates>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(RELOAD_INTERVAL);
+
+        let Some(certificates) = certificates.upgrade() else { break };
+        match certificates.reload_if_changed() {
+            Ok(true) => tracing::info!(
+                cert = %certificates.cert_file.display(), "reloaded tls certificate",
+            ),
+            Ok(false) => {},
+            Err(err) => tracing::warn!(error = %err, "keeping previous tls certificate"),
+        }
+    });
+}
+
+fn stamp(path: &Path) -> io::Result<Stamp> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> io::Result<CertifiedKey> {
+    let chain = load_certs(cert_file)?;
+
+    let mut reader = BufReader::new(File::open(key_file)?);
+    let key = rustls_pemfile::read_all(&mut reader)?.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(key_file, "no private key found"))?;
+    let key = sign::any_supported_type(&key).map_err(|e| invalid(key_file, e))?;
+
+    Ok(CertifiedKey::new(chain, key))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    match certs.is_empty() {
+        true => Err(invalid(path, "no certificates found")),
+        false => Ok(certs.into_iter().map(Certificate).collect()),
+    }
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(|e| invalid(path, e))?;
+    }
+    Ok(roots)
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa};
+    use rustls::{ClientConfig, ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("scans-tls-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn ca() -> rcgen::Certificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "scans test ca");
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    // Returns the PEM certificate and key of a leaf signed by `ca`.
+    fn leaf(ca: &rcgen::Certificate, name: &str, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+        let mut params = CertificateParams::new(vec![name.to_owned()]);
+        params.extended_key_usages = vec![usage];
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (cert.serialize_pem_with_signer(ca).unwrap(), cert.serialize_private_key_pem())
+    }
+
+    fn server_files(ca: &rcgen::Certificate, prefix: &str) -> (PathBuf, PathBuf) {
+        let (cert, key) = leaf(ca, "localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        (write_temp(&format!("{}-cert", prefix), &cert), write_temp(&format!("{}-key", prefix), &key))
+    }
+
+    fn client_config(ca: &rcgen::Certificate, identity: Option<(String, String)>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(ca.serialize_der().unwrap())).unwrap();
+        let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+
+        match identity {
+            Some((cert, key)) => {
+                let chain = rustls_pemfile::certs(&mut cert.as_bytes()).unwrap()
+                    .into_iter().map(Certificate).collect();
+                let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_bytes()).unwrap().remove(0);
+                builder.with_single_cert(chain, PrivateKey(key)).unwrap()
+            },
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    // Runs a handshake over an in-memory pipe and returns whether both ends
+    // completed it.
+    async fn handshake(tls: &Tls, client: ClientConfig) -> bool {
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = TlsAcceptor::from(tls.server_config());
+        let connector = TlsConnector::from(Arc::new(client));
+        let name = ServerName::try_from("localhost").unwrap();
+
+        // TLS 1.3 clients only learn their certificate was rejected once they
+        // read from the connection, so each side sends a byte.
+        let (server, client) = tokio::join!(
+            async move {
+                let mut stream = acceptor.accept(server_io).await?;
+                stream.write_all(b"s").await?;
+                stream.read_exact(&mut [0u8; 1]).await
+            },
+            async move {
+                let mut stream = connector.connect(name, client_io).await?;
+                stream.write_all(b"c").await?;
+                stream.read_exact(&mut [0u8; 1]).await
+            },
+        );
+        server.is_ok() && client.is_ok()
+    }
+
+    #[test]
+    fn plain_http_without_certificate() {
+        assert!(Tls::from_config(&Config::default()).unwrap().is_none());
+
+        let config = Config { tls_cert_file: Some("cert.pem".into()), ..Config::default() };
+        assert!(Tls::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn only_the_terminator_passes_on_client_addresses() {
+        let config = Config { write_rate_limit: "1/60".parse().ok(), ..Config::default() };
+        let limiter = crate::ratelimit::RateLimiter::from_config(&config);
+        let peer = Some("192.0.2.1".parse().unwrap());
+        let allowed = |backend: &Backend, secret: Option<&str>, real_ip: &str| {
+            let ip = backend.client_ip(secret, Some(real_ip), peer);
+            limiter.check("POST", None, ip).unwrap().allowed
+        };
+
+        // Forging `X-Real-IP` doesn't get a caller a fresh bucket.
+        let direct = Backend::direct();
+        assert!(allowed(&direct, None, "10.0.0.1"));
+        assert!(!allowed(&direct, None, "10.0.0.2"));
+        assert!(!allowed(&direct, Some("guess"), "10.0.0.3"));
+
+        let terminated = Backend::terminated();
+        assert!(!allowed(&terminated, Some("guess"), "10.0.0.4"));
+        assert!(allowed(&terminated, terminated.secret(), "10.0.0.5"));
+        assert!(!allowed(&terminated, terminated.secret(), "10.0.0.5"));
+        assert_ne!(Backend::terminated().secret(), terminated.secret());
+
+        // Behind a terminator, nothing else gets served.
+        assert!(direct.admits(None));
+        assert!(terminated.admits(terminated.secret()));
+        assert!(!terminated.admits(None));
+        assert!(!terminated.admits(Some("guess")));
+    }
+
+    #[test]
+    fn parse_client_auth() {
+        assert_eq!("required".parse(), Ok(ClientAuth::Required));
+        assert_eq!("optional".parse(), Ok(ClientAuth::Optional));
+        assert!("sometimes".parse::<ClientAuth>().is_err());
+    }
+
+    #[test]
+    fn certificates_reload_when_files_change() {
+        let ca = ca();
+        let (cert_file, key_file) = server_files(&ca, "reload");
+        let certificates = Certificates::load(&cert_file, &key_file).unwrap();
+        let first = certificates.current.read().unwrap().cert.clone();
+
+        assert!(!certificates.reload_if_changed().unwrap());
+
+        let (cert, key) = leaf(&ca, "localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        fs::write(&cert_file, cert).unwrap();
+        fs::write(&key_file, key).unwrap();
+
+        assert!(certificates.reload_if_changed().unwrap());
+        assert_ne!(certificates.current.read().unwrap().cert, first);
+    }
+
+    #[test]
+    fn broken_files_keep_previous_certificate() {
+        let ca = ca();
+        let (cert_file, key_file) = server_files(&ca, "broken");
+        let certificates = Certificates::load(&cert_file, &key_file).unwrap();
+        let first = certificates.current.read().unwrap().cert.clone();
+
+        fs::write(&cert_file, "not a certificate").unwrap();
+
+        assert!(certificates.reload_if_changed().is_err());
+        assert_eq!(certificates.current.read().unwrap().cert, first);
+    }
+
+    #[tokio::test]
+    async fn client_certificates() {
+        let ca = ca();
+        let other_ca = self::ca();
+        let (cert_file, key_file) = server_files(&ca, "mtls");
+        let config = |client_auth| Config {
+            tls_cert_file: Some(cert_file.clone()),
+            tls_key_file: Some(key_file.clone()),
+            tls_client_ca_file: Some(write_temp("client-ca", &ca.serialize_pem().unwrap())),
+            tls_client_auth: client_auth,
+            ..Config::default()
+        };
+        let identity = || Some(leaf(&ca, "client", ExtendedKeyUsagePurpose::ClientAuth));
+        let stranger = || Some(leaf(&other_ca, "client", ExtendedKeyUsagePurpose::ClientAuth));
+
+        let required = Tls::from_config(&config(ClientAuth::Required)).unwrap().unwrap();
+        assert!(handshake(&required, client_config(&ca, identity())).await);
+        assert!(!handshake(&required, client_config(&ca, None)).await);
+        assert!(!handshake(&required, client_config(&ca, stranger())).await);
+
+        let optional = Tls::from_config(&config(ClientAuth::Optional)).unwrap().unwrap();
+        assert!(handshake(&optional, client_config(&ca, identity())).await);
+        assert!(handshake(&optional, client_config(&ca, None)).await);
+        assert!(!handshake(&optional, client_config(&ca, stranger())).await);
+    }
+}
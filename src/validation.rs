@@ -0,0 +1,408 @@
+use crate::config::Config;
+use crate::model::Scan;
+use crate::problem::{self, FieldError, Problem};
+use chrono::{DateTime, Utc};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Page loads slower than this are a broken measurement rather than a slow
+// page.
+const MAX_LOAD_TIME_NANOSEC: i64 = 60 * 60 * 1_000_000_000;
+
+// Hash algorithms a `content_hash` may name with an `<algorithm>:` prefix,
+// and how many hex digits each one's digest has. An unprefixed hash must
+// have one of these lengths.
+const ALGORITHMS: [(&str, usize); 5] = [
+    ("xxh64", 16),
+    ("md5", 32),
+    ("sha1", 40),
+    ("sha256", 64),
+    ("sha512", 128),
+];
+
+// A scan keeps a handful of response headers, not all of them.
+const MAX_HEADERS: usize = 32;
+const MAX_HEADER_VALUE_LEN: usize = 1024;
+
+// Which addresses a scan may be of. Reserved addresses (loopback,
+// link-local, multicast, documentation and the like) can't be scanned over
+// the internet, and private ones only from inside a network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressPolicy {
+    Any,
+    NoReserved,
+    Public,
+}
+
+impl FromStr for AddressPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(AddressPolicy::Any),
+            "no-reserved" => Ok(AddressPolicy::NoReserved),
+            "public" => Ok(AddressPolicy::Public),
+            _ => Err(()),
+        }
+    }
+}
+
+// What becomes of a scan whose timestamp runs further ahead of the server's
+// clock than the allowed skew: refused, or pulled back to the time it arrived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkewPolicy {
+    Reject,
+    Clamp,
+}
+
+impl FromStr for SkewPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(SkewPolicy::Reject),
+            "clamp" => Ok(SkewPolicy::Clamp),
+            _ => Err(()),
+        }
+    }
+}
+
+// Checks a scan makes sense before it is stored, beyond having fields of the
+// right types. Every create and update runs it, whichever API it came in on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Validator {
+    pub max_skew: Duration,
+    pub skew: SkewPolicy,
+    pub addresses: AddressPolicy,
+    pub stamp_received_at: bool,
+}
+
+impl Validator {
+    pub fn from_config(config: &Config) -> Self {
+        Validator {
+            max_skew: config.timestamp_skew,
+            skew: config.skew_policy,
+            addresses: config.address_policy,
+            stamp_received_at: config.stamp_received_at,
+        }
+    }
+
+    // Readies a scan that has just arrived for the store: stamps when it was
+    // received, clamps its timestamp if the skew policy says to, and then
+    // validates it.
+    pub fn admit(&self, scan: &mut Scan) -> Result<(), Problem> {
+        self.admit_at(scan, Utc::now()).map_err(invalid)
+    }
+
+    fn admit_at(&self, scan: &mut Scan, now: DateTime<Utc>) -> Result<(), Vec<FieldError>> {
+        scan.received_at = self.stamp_received_at.then_some(now);
+        if self.skew == SkewPolicy::Clamp && self.too_far_ahead(scan.timestamp, now) {
+            scan.timestamp = now;
+        }
+        self.validate_at(scan, now)
+    }
+
+    fn validate_at(&self, scan: &Scan, now: DateTime<Utc>) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut check = |field: &str, result: Result<(), String>| {
+            if let Err(message) = result {
+                errors.push(FieldError::new(field, message));
+            }
+        };
+
+        check("ip", self.check_ip(&scan.ip));
+        check("port", match scan.port {
+            port if port < 1 => Err(format!("port {} is not between 1 and {}", port, i16::MAX)),
+            _ => Ok(()),
+        });
+        check("load_time_nanosec", match scan.load_time_nanosec {
+            nanos if !(0..=MAX_LOAD_TIME_NANOSEC).contains(&nanos) => Err(format!(
+                "{} is not between 0 and {}", nanos, MAX_LOAD_TIME_NANOSEC,
+            )),
+            _ => Ok(()),
+        });
+        // A failed scan may not have got as far as reading any content.
+        if !(scan.error.is_some() && scan.content_hash.is_empty()) {
+            check("content_hash", check_hash(&scan.content_hash));
+        }
+        check("timestamp", self.check_timestamp(scan.timestamp, now));
+        check("http_status", match scan.http_status {
+            Some(status) if !(100..=599).contains(&status) => {
+                Err(format!("{} is not between 100 and 599", status))
+            },
+            _ => Ok(()),
+        });
+        check("headers", match scan.headers.len() {
+            len if len > MAX_HEADERS => Err(format!("{} headers is more than {}", len, MAX_HEADERS)),
+            _ => Ok(()),
+        });
+        for (name, value) in &scan.headers {
+            check(&format!("headers.{}", name), check_header(name, value));
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    fn check_ip(&self, ip: &str) -> Result<(), String> {
+        let addr: IpAddr = ip.parse().map_err(|_| format!("{:?} is not an IPv4 or IPv6 address", ip))?;
+
+        match self.addresses {
+            AddressPolicy::NoReserved | AddressPolicy::Public if is_reserved(addr) => {
+                Err(format!("{} is a reserved address", addr))
+            },
+            AddressPolicy::Public if is_private(addr) => Err(format!("{} is a private address", addr)),
+            _ => Ok(()),
+        }
+    }
+
+    fn too_far_ahead(&self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        (timestamp - now).to_std().unwrap_or_default() > self.max_skew
+    }
+
+    fn check_timestamp(&self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), String> {
+        match self.too_far_ahead(timestamp, now) {
+            true => Err(format!(
+                "{} is more than {}s in the future", timestamp.to_rfc3339(), self.max_skew.as_secs(),
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator::from_config(&Config::default())
+    }
+}
+
+fn invalid(errors: Vec<FieldError>) -> Problem {
+    Problem::new(422, "Unprocessable Content")
+        .with_detail(format!("invalid scan: {}", problem::summary(&errors)))
+        .with_errors(errors)
+}
+
+fn check_hash(hash: &str) -> Result<(), String> {
+    let (algorithm, digest) = match hash.split_once(':') {
+        Some((algorithm, digest)) => (Some(algorithm), digest),
+        None => (None, hash),
+    };
+
+    if digest.is_empty() || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("digest must be hex digits".to_owned());
+    }
+
+    match algorithm {
+        Some(name) => match ALGORITHMS.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)) {
+            None => Err(format!("unknown hash algorithm {:?}; expected one of {}", name, algorithm_names())),
+            Some((name, len)) if digest.len() != *len => {
+                Err(format!("{} digests are {} hex digits, not {}", name, len, digest.len()))
+            },
+            Some(_) => Ok(()),
+        },
+        None => match ALGORITHMS.iter().any(|(_, len)| digest.len() == *len) {
+            true => Ok(()),
+            false => Err(format!(
+                "{} hex digits is not the length of any of {}", digest.len(), algorithm_names(),
+            )),
+        },
+    }
+}
+
+fn check_header(name: &str, value: &str) -> Result<(), String> {
+    let token = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit() || b"!#$%&'*+-.^_`|~".contains(&b);
+    if name.is_empty() || !name.bytes().all(token) {
+        return Err("names must be lowercase header tokens".to_owned());
+    }
+    if value.len() > MAX_HEADER_VALUE_LEN {
+        return Err(format!("values are limited to {} bytes", MAX_HEADER_VALUE_LEN));
+    }
+    match value.chars().any(|c| c.is_control() && c != '\t') {
+        true => Err("values may not contain control characters".to_owned()),
+        false => Ok(()),
+    }
+}
+
+fn algorithm_names() -> String {
+    ALGORITHMS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+}
+
+fn is_reserved(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => is_reserved_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_reserved_v4(v4),
+            None => is_reserved_v6(v6),
+        },
+    }
+}
+
+fn is_reserved_v4(addr: Ipv4Addr) -> bool {
+    let [a, b, ..] = addr.octets();
+    addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_link_local()
+        || addr.is_multicast()
+        || addr.is_broadcast()
+        || addr.is_documentation()
+        // "This network", benchmarking and the old class E space.
+        || a == 0
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240
+}
+
+fn is_reserved_v6(addr: Ipv6Addr) -> bool {
+    let [first, second, ..] = addr.segments();
+    addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_multicast()
+        || addr.is_unicast_link_local()
+        // Documentation.
+        || (first == 0x2001 && second == 0x0db8)
+}
+
+fn is_private(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => is_private_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_private_v4(v4),
+            None => v6.is_unique_local(),
+        },
+    }
+}
+
+fn is_private_v4(addr: Ipv4Addr) -> bool {
+    let [a, b, ..] = addr.octets();
+    // Shared address space for carrier-grade NAT counts as private too.
+    addr.is_private() || (a == 100 && (b & 0xc0) == 64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 7, 31, 14, 17, 0).unwrap()
+    }
+
+    fn scan() -> Scan {
+        Scan {
+            ip: "8.8.8.8".to_owned(),
+            port: 80,
+            load_time_nanosec: 50000,
+            content_hash: "73d1a9ab21fce25e".to_owned(),
+            timestamp: now(),
+            ..Default::default()
+        }
+    }
+
+    fn fields(validator: &Validator, scan: &Scan) -> Vec<String> {
+        match validator.validate_at(scan, now()) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.into_iter().map(|e| e.field).collect(),
+        }
+    }
+
+    #[test]
+    fn every_bad_field_is_reported() {
+        let validator = Validator::default();
+        assert_eq!(fields(&validator, &scan()), Vec::<String>::new());
+
+        let bad = Scan {
+            ip: "example.com".to_owned(),
+            port: -1,
+            load_time_nanosec: -5,
+            content_hash: String::new(),
+            timestamp: now() + chrono::Duration::hours(1),
+            ..Default::default()
+        };
+        assert_eq!(fields(&validator, &bad), ["ip", "port", "load_time_nanosec", "content_hash", "timestamp"]);
+
+        let problem = invalid(validator.validate_at(&bad, now()).unwrap_err());
+        assert_eq!(problem.status, 422);
+        assert_eq!(problem.errors.len(), 5);
+    }
+
+    #[test]
+    fn hashes_match_their_algorithm() {
+        let sha256 = "a".repeat(64);
+        for hash in ["73d1a9ab21fce25e", "MD5:d41d8cd98f00b204e9800998ecf8427e", &format!("sha256:{}", sha256)] {
+            assert_eq!(check_hash(hash), Ok(()), "{}", hash);
+        }
+
+        assert!(check_hash("foobar").unwrap_err().contains("hex"));
+        assert!(check_hash("abcd").unwrap_err().contains("4 hex digits"));
+        assert!(check_hash("sha1:73d1a9ab21fce25e").unwrap_err().contains("sha1 digests are 40"));
+        assert!(check_hash("crc32:73d1a9ab").unwrap_err().contains("unknown hash algorithm"));
+    }
+
+    #[test]
+    fn timestamps_may_run_slightly_ahead() {
+        let validator = Validator { max_skew: Duration::from_secs(60), ..Validator::default() };
+        let at = |seconds| Scan { timestamp: now() + chrono::Duration::seconds(seconds), ..scan() };
+
+        assert!(fields(&validator, &at(60)).is_empty());
+        assert!(fields(&validator, &at(-86_400)).is_empty());
+        assert_eq!(fields(&validator, &at(61)), ["timestamp"]);
+    }
+
+    #[test]
+    fn optional_fields_are_checked_when_present() {
+        let validator = Validator::default();
+        let mut headers = std::collections::BTreeMap::new();
+        headers.insert("server".to_owned(), "nginx".to_owned());
+        headers.insert("Content-Type".to_owned(), "text/html".to_owned());
+        headers.insert("x-note".to_owned(), "a\nb".to_owned());
+        let bad = Scan { http_status: Some(42), headers, ..scan() };
+        assert_eq!(fields(&validator, &bad), ["http_status", "headers.Content-Type", "headers.x-note"]);
+
+        let failed = Scan { content_hash: String::new(), error: Some(crate::model::ErrorKind::Timeout), ..scan() };
+        assert!(fields(&validator, &failed).is_empty());
+        assert_eq!(fields(&validator, &Scan { error: None, ..failed }), ["content_hash"]);
+    }
+
+    #[test]
+    fn admitting_stamps_and_clamps() {
+        let ahead = Scan { timestamp: now() + chrono::Duration::hours(1), ..scan() };
+
+        let mut rejected = Scan { received_at: Some(now() - chrono::Duration::days(1)), ..ahead.clone() };
+        let errors = Validator::default().admit_at(&mut rejected, now()).unwrap_err();
+        assert_eq!(errors[0].field, "timestamp");
+        assert_eq!(rejected.received_at, None, "clients can't set received_at");
+
+        let clamp = Validator { skew: SkewPolicy::Clamp, stamp_received_at: true, ..Validator::default() };
+        let mut clamped = ahead;
+        clamp.admit_at(&mut clamped, now()).unwrap();
+        assert_eq!(clamped.timestamp, now());
+        assert_eq!(clamped.received_at, Some(now()));
+
+        let mut behind = Scan { timestamp: now() - chrono::Duration::days(1), ..scan() };
+        clamp.admit_at(&mut behind, now()).unwrap();
+        assert_eq!(behind.timestamp, now() - chrono::Duration::days(1));
+    }
+
+    #[test]
+    fn addresses_follow_the_policy() {
+        let any = Validator { addresses: AddressPolicy::Any, ..Validator::default() };
+        let no_reserved = Validator { addresses: AddressPolicy::NoReserved, ..any };
+        let public = Validator { addresses: AddressPolicy::Public, ..any };
+        let allowed = |validator: &Validator, ip: &str| validator.check_ip(ip).is_ok();
+
+        for ip in ["127.0.0.1", "0.0.0.0", "224.0.0.1", "192.0.2.7", "198.19.0.1", "::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(allowed(&any, ip), "{}", ip);
+            assert!(!allowed(&no_reserved, ip), "{}", ip);
+        }
+        for ip in ["10.1.2.3", "172.16.0.1", "192.168.1.1", "100.64.0.1", "fd00::1"] {
+            assert!(allowed(&no_reserved, ip), "{}", ip);
+            assert!(!allowed(&public, ip), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "2606:4700::1111", "100.128.0.1"] {
+            assert!(allowed(&public, ip), "{}", ip);
+        }
+        assert!(!allowed(&any, "8.8.8"));
+    }
+}